@@ -0,0 +1,436 @@
+//! Per-camera media task
+//!
+//! Every registered camera gets a task that owns its frame reassembly, its
+//! audio ring buffer and the CMD 605 confirmation bookkeeping. The UDP
+//! routers hand it packets over a bounded channel without taking any lock, so
+//! a camera streaming at full rate never waits on an HTTP request; the web
+//! handlers and RTSP sessions ask the task for frames over the same channel.
+//!
+//! Control state (protocol state, TCP writer, viewers, recording) stays in
+//! `CameraConnection`, which the task only locks once per stream to move the
+//! camera from NatTraversal to Streaming.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+
+use crate::audio::{AudioBuffer, AUDIO_CMD};
+use crate::protocol::binary::{ProtocolHeader, RetransmissionConfirm, RetransmissionRequest};
+use crate::stats::StreamQuality;
+use crate::types::{CameraConnection, ProtocolState, ReassemblyStats, StreamBuffer, UdpPorts};
+
+/// Packets and requests queued per camera; beyond this the UDP routers drop packets
+pub const COMMAND_QUEUE: usize = 1024;
+
+/// How often a partial frame left behind by a stream that went quiet is discarded
+const INCOMPLETE_FRAME_CHECK: Duration = Duration::from_millis(500);
+
+/// A cmd 1/4/6/7 packet for the camera's media task
+#[derive(Debug)]
+pub struct MediaPacket {
+    pub header: ProtocolHeader,
+    pub payload: Bytes,
+    /// Socket and address the packet came in on; CMD 605 confirmations go back there.
+    /// `None` for media carried on the control connection.
+    pub reply_to: Option<(Arc<UdpSocket>, SocketAddr)>,
+}
+
+/// Live frames and audio, starting right after `latest_frame`
+#[derive(Debug)]
+pub struct MediaSubscription {
+    pub latest_frame: Option<Bytes>,
+    pub frames: broadcast::Receiver<Bytes>,
+    pub audio: broadcast::Receiver<Bytes>,
+}
+
+/// What the frame buffer holds at one moment
+#[derive(Debug, Clone)]
+pub struct BufferSnapshot {
+    pub max_frames: usize,
+    /// Oldest first
+    pub frame_sizes: Vec<usize>,
+    pub latest_frame: Option<Bytes>,
+    pub reassembly: ReassemblyStats,
+}
+
+impl BufferSnapshot {
+    pub fn total_bytes(&self) -> usize {
+        self.frame_sizes.iter().sum()
+    }
+}
+
+#[derive(Debug)]
+enum MediaCommand {
+    Packet(MediaPacket),
+    /// A new stream is being set up; it counts as started with its first frame
+    StreamStarting,
+    /// Drop buffered frames and audio
+    Clear,
+    Subscribe(oneshot::Sender<MediaSubscription>),
+    Buffer(oneshot::Sender<BufferSnapshot>),
+    Stats(oneshot::Sender<StreamQuality>),
+    AudioClip(Duration, oneshot::Sender<Vec<u8>>),
+}
+
+/// Cheap, cloneable reference to one camera
+///
+/// The media task stops once every handle to it is gone.
+#[derive(Debug, Clone)]
+pub struct CameraHandle {
+    pub device_id: String,
+    /// Source address of the camera's latest registration
+    pub addr: SocketAddr,
+    pub connection: Arc<RwLock<CameraConnection>>,
+    pub udp_ports: Arc<UdpPorts>,
+    commands: mpsc::Sender<MediaCommand>,
+    last_media: Arc<AtomicI64>, // Unix milliseconds of the latest media packet
+}
+
+impl CameraHandle {
+    /// Start the media task of `connection`
+    ///
+    /// With `request_retransmissions` the task asks the camera to resend the
+    /// pkg_ids it misses; without, gaps only wait out the reorder window.
+    pub fn spawn(connection: CameraConnection, request_retransmissions: bool) -> Self {
+        let (commands, receiver) = mpsc::channel(COMMAND_QUEUE);
+        let last_media = Arc::new(AtomicI64::new(Utc::now().timestamp_millis()));
+        let handle = Self {
+            device_id: connection.device_id.clone(),
+            addr: connection.addr,
+            udp_ports: connection.udp_ports.clone(),
+            connection: Arc::new(RwLock::new(connection)),
+            commands,
+            last_media: last_media.clone(),
+        };
+
+        let task = MediaTask::new(handle.device_id.clone(), handle.connection.clone(), last_media, request_retransmissions);
+        tokio::spawn(task.run(receiver));
+        handle
+    }
+
+    /// Whether both handles refer to the same registration of a camera
+    pub fn same_camera(&self, other: &CameraHandle) -> bool {
+        Arc::ptr_eq(&self.connection, &other.connection)
+    }
+
+    /// Queue a media packet without waiting
+    ///
+    /// Returns false if the packet was dropped because the task is behind.
+    pub fn push_packet(&self, packet: MediaPacket) -> bool {
+        match self.commands.try_send(MediaCommand::Packet(packet)) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::warn!("Media queue of camera {} is full, dropping a packet", self.device_id);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Tell the task a stream is being set up
+    pub async fn stream_starting(&self) {
+        let _ = self.commands.send(MediaCommand::StreamStarting).await;
+    }
+
+    /// Drop buffered frames and audio
+    pub async fn clear(&self) {
+        let _ = self.commands.send(MediaCommand::Clear).await;
+    }
+
+    /// Receive every frame and audio packet from now on
+    pub async fn subscribe(&self) -> Option<MediaSubscription> {
+        self.request(MediaCommand::Subscribe).await
+    }
+
+    pub async fn buffer(&self) -> Option<BufferSnapshot> {
+        self.request(MediaCommand::Buffer).await
+    }
+
+    /// Rolling stream quality and counters since registration
+    pub async fn stats(&self) -> Option<StreamQuality> {
+        self.request(MediaCommand::Stats).await
+    }
+
+    /// Raw G.711 of up to the last `duration`
+    pub async fn audio_clip(&self, duration: Duration) -> Vec<u8> {
+        self.request(|reply| MediaCommand::AudioClip(duration, reply)).await.unwrap_or_default()
+    }
+
+    /// When the latest video or audio packet arrived, or when the camera was first seen
+    pub fn last_media(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.last_media.load(Ordering::Relaxed)).unwrap_or_default()
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> MediaCommand) -> Option<T> {
+        let (reply, response) = oneshot::channel();
+        self.commands.send(command(reply)).await.ok()?;
+        response.await.ok()
+    }
+}
+
+/// A CMD 605 due to the camera
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Confirm(Vec<u8>),
+    Request(Vec<u8>),
+}
+
+struct MediaTask {
+    device_id: String,
+    connection: Arc<RwLock<CameraConnection>>,
+    last_media: Arc<AtomicI64>,
+    stream_buffer: StreamBuffer,
+    audio_buffer: AudioBuffer,
+    first_confirmation_sent: bool, // The first end frame of a stream gets an empty CMD 605
+    retransmission_bucket: Vec<u32>, // Package IDs received since the previous end frame
+    request_retransmissions: bool,
+    confirmations_sent: u64,
+    requests_sent: u64,
+    stream_announced: bool, // Whether this stream's first frame already moved the camera to Streaming
+}
+
+impl MediaTask {
+    fn new(device_id: String, connection: Arc<RwLock<CameraConnection>>, last_media: Arc<AtomicI64>, request_retransmissions: bool) -> Self {
+        Self {
+            device_id,
+            connection,
+            last_media,
+            stream_buffer: StreamBuffer::new(100), // Keep 100 frames
+            audio_buffer: AudioBuffer::new(Duration::from_secs(crate::audio::BUFFERED_SECS)),
+            first_confirmation_sent: false,
+            retransmission_bucket: Vec::new(),
+            request_retransmissions,
+            confirmations_sent: 0,
+            requests_sent: 0,
+            stream_announced: false,
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::Receiver<MediaCommand>) {
+        let mut incomplete_check = tokio::time::interval(INCOMPLETE_FRAME_CHECK);
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => self.handle(command).await,
+                    None => break,
+                },
+                _ = incomplete_check.tick() => {
+                    if self.stream_buffer.expire_stale(Instant::now()) {
+                        tracing::info!("Discarded a partial frame of camera {} after the stream went quiet", self.device_id);
+                    }
+                }
+            }
+        }
+        tracing::debug!("Media task of camera {} stopped", self.device_id);
+    }
+
+    async fn handle(&mut self, command: MediaCommand) {
+        match command {
+            MediaCommand::Packet(packet) => self.handle_packet(packet).await,
+            MediaCommand::StreamStarting => {
+                self.first_confirmation_sent = false;
+                self.stream_announced = false;
+                // The camera may number the new stream's packets from scratch
+                self.stream_buffer.reset_sequence();
+            }
+            MediaCommand::Clear => {
+                self.stream_buffer.clear();
+                self.audio_buffer.clear();
+                self.retransmission_bucket.clear();
+                self.stream_announced = false;
+                tracing::info!("Cleared video and audio buffers for camera {}", self.device_id);
+            }
+            MediaCommand::Subscribe(reply) => {
+                let _ = reply.send(MediaSubscription {
+                    latest_frame: self.stream_buffer.latest(),
+                    frames: self.stream_buffer.subscribe(),
+                    audio: self.audio_buffer.subscribe(),
+                });
+            }
+            MediaCommand::Buffer(reply) => {
+                let _ = reply.send(BufferSnapshot {
+                    max_frames: self.stream_buffer.max_frames,
+                    frame_sizes: self.stream_buffer.get_all_frames().iter().map(|frame| frame.len()).collect(),
+                    latest_frame: self.stream_buffer.latest(),
+                    reassembly: self.stream_buffer.stats(),
+                });
+            }
+            MediaCommand::Stats(reply) => {
+                let _ = reply.send(self.stream_buffer.quality(Instant::now(), self.confirmations_sent, self.requests_sent));
+            }
+            MediaCommand::AudioClip(duration, reply) => {
+                let _ = reply.send(self.audio_buffer.clip(duration));
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: MediaPacket) {
+        let MediaPacket { header, payload, reply_to } = packet;
+        self.last_media.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+
+        // Audio has its own ring buffer; frame reassembly still accounts for its pkg_id
+        if header.cmd == AUDIO_CMD {
+            self.audio_buffer.push(&payload);
+        }
+        let frame_complete = self.stream_buffer.add_fragment(header.cmd, header.msg_flag, header.pkg_id, &payload);
+
+        if let Some((socket, addr)) = &reply_to {
+            self.confirm(&header, socket, *addr).await;
+        }
+
+        // A late fragment can complete a frame as well as an end frame (MSG_FLAG=252)
+        if frame_complete {
+            tracing::debug!("Complete frame assembled and added to buffer for {}: {} bytes (buffer: {}/{} frames)",
+                self.device_id, self.stream_buffer.latest().map_or(0, |frame| frame.len()),
+                self.stream_buffer.frame_count(),
+                self.stream_buffer.max_frames);
+            if !self.stream_announced {
+                self.stream_announced = true;
+                let mut camera_guard = self.connection.write().await;
+                if *camera_guard.state.current() == ProtocolState::NatTraversal {
+                    camera_guard.set_state(ProtocolState::Streaming, "first video frame received");
+                }
+            }
+        }
+    }
+
+    /// CMD 605 bookkeeping: confirm what arrived and ask for what did not
+    async fn confirm(&mut self, header: &ProtocolHeader, socket: &UdpSocket, addr: SocketAddr) {
+        // Send to the port the camera is using to send video
+        for reply in self.replies(header, Instant::now()) {
+            let message = match &reply {
+                Reply::Confirm(message) | Reply::Request(message) => message,
+            };
+            match socket.send_to(message, addr).await {
+                Ok(_) => {
+                    match reply {
+                        Reply::Confirm(_) => self.confirmations_sent += 1,
+                        Reply::Request(_) => self.requests_sent += 1,
+                    }
+                    tracing::debug!("Sent CMD 605 to {} (total_len={})", addr, message.len());
+                }
+                Err(e) => tracing::error!("Failed to send CMD 605 to {}: {}", addr, e),
+            }
+        }
+    }
+
+    /// The CMD 605s due once the packet with `header` has been added to the stream buffer
+    ///
+    /// Every end frame confirms the package IDs received since the previous
+    /// one; the first end frame of a stream gets an empty confirmation. If
+    /// requests are on, as soon as a gap trails the newest pkg_id by
+    /// `RETRANSMIT_AFTER`, a retransmission request lists the pkg_ids missing
+    /// so far.
+    fn replies(&mut self, header: &ProtocolHeader, now: Instant) -> Vec<Reply> {
+        // Track ALL package IDs (both cmd=1 video and cmd=6 audio) like the Python script
+        if !self.retransmission_bucket.contains(&header.pkg_id) {
+            self.retransmission_bucket.push(header.pkg_id);
+        }
+        tracing::debug!("Added pkg_id {} to retransmission bucket for {}", header.pkg_id, self.device_id);
+
+        let mut replies = Vec::new();
+        if self.request_retransmissions && self.stream_buffer.wants_retransmission() {
+            let missing = self.stream_buffer.request_missing(now);
+            tracing::debug!("Asking {} to resend pkg_ids {:?}", self.device_id, missing);
+            replies.push(Reply::Request(RetransmissionRequest::new(missing).to_bytes()));
+        }
+        if header.msg_flag != 252 {
+            return replies;
+        }
+
+        if !self.first_confirmation_sent {
+            // First end frame - send empty retransmission confirmation
+            self.first_confirmation_sent = true;
+            tracing::info!("First end frame received, sending empty retransmission confirmation to {}", self.device_id);
+            replies.push(Reply::Confirm(RetransmissionConfirm::empty().to_bytes()));
+        } else {
+            // Subsequent end frame - send batch retransmission with collected package IDs
+            let packages = std::mem::take(&mut self.retransmission_bucket);
+            tracing::debug!("Sending batch retransmission with {} package IDs to {}", packages.len(), self.device_id);
+            replies.push(Reply::Confirm(RetransmissionConfirm::new(packages).to_bytes()));
+        }
+        replies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(msg_flag: u8, pkg_id: u32, payload: &[u8]) -> MediaPacket {
+        MediaPacket {
+            header: ProtocolHeader::new(1, payload.len() as u32, msg_flag, pkg_id),
+            payload: Bytes::copy_from_slice(payload),
+            reply_to: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_frame_moves_camera_to_streaming() {
+        let addr: SocketAddr = "192.168.1.50:40000".parse().unwrap();
+        let camera = CameraHandle::spawn(CameraConnection::new("0800c00128F8".to_string(), addr.ip(), addr), false);
+        {
+            let mut camera_guard = camera.connection.write().await;
+            camera_guard.set_state(ProtocolState::Registering, "code 100");
+            camera_guard.set_state(ProtocolState::Idle, "code 101 sent");
+            camera_guard.set_state(ProtocolState::NatTraversal, "code 11 sent");
+        }
+        camera.stream_starting().await;
+        let mut subscription = camera.subscribe().await.unwrap();
+        assert!(subscription.latest_frame.is_none());
+
+        assert!(camera.push_packet(fragment(250, 1, &[0xFF, 0xD8, 0x01])));
+        assert!(camera.push_packet(fragment(251, 2, &[0x02])));
+        assert!(camera.push_packet(fragment(252, 3, &[0xFF, 0xD9, 6, 0, 0, 0])));
+
+        let frame = subscription.frames.recv().await.unwrap();
+        assert_eq!(&frame[..], &[0xFF, 0xD8, 0x01, 0x02, 0xFF, 0xD9]);
+        assert_eq!(camera.buffer().await.unwrap().frame_sizes, [6]);
+        assert_eq!(camera.connection.read().await.state.current(), &ProtocolState::Streaming);
+    }
+
+    fn media_task(request_retransmissions: bool) -> MediaTask {
+        let addr: SocketAddr = "192.168.1.50:40000".parse().unwrap();
+        let connection = CameraConnection::new("0800c00128F8".to_string(), addr.ip(), addr);
+        MediaTask::new(connection.device_id.clone(), Arc::new(RwLock::new(connection)), Arc::new(AtomicI64::new(0)), request_retransmissions)
+    }
+
+    fn replies(task: &mut MediaTask, msg_flag: u8, pkg_id: u32) -> Vec<Reply> {
+        let packet = fragment(msg_flag, pkg_id, &[0xFF, 0xD8]);
+        task.stream_buffer.add_fragment(1, msg_flag, pkg_id, &packet.payload);
+        task.replies(&packet.header, Instant::now())
+    }
+
+    #[test]
+    fn test_gaps_are_asked_for_by_missing_pkg_id() {
+        let mut task = media_task(true);
+
+        // The first end frame is confirmed empty
+        assert!(replies(&mut task, 250, 1).is_empty());
+        assert_eq!(replies(&mut task, 252, 2), [Reply::Confirm(RetransmissionConfirm::empty().to_bytes())]);
+
+        // pkg_ids 4 and 5 go missing; once 4 trails by two both are asked for, once
+        assert!(replies(&mut task, 250, 3).is_empty());
+        assert_eq!(replies(&mut task, 251, 6), [Reply::Request(RetransmissionRequest::new(vec![4, 5]).to_bytes())]);
+        assert!(replies(&mut task, 251, 7).is_empty());
+        assert!(replies(&mut task, 251, 8).is_empty());
+
+        // The end frame confirms only what arrived
+        assert_eq!(replies(&mut task, 252, 9), [Reply::Confirm(RetransmissionConfirm::new(vec![1, 2, 3, 6, 7, 8, 9]).to_bytes())]);
+    }
+
+    #[test]
+    fn test_gaps_are_not_asked_for_by_default() {
+        let mut task = media_task(false);
+        for (msg_flag, pkg_id) in [(250, 1), (252, 2), (250, 3), (251, 6), (251, 7)] {
+            assert!(replies(&mut task, msg_flag, pkg_id).iter().all(|reply| matches!(reply, Reply::Confirm(_))));
+        }
+        assert_eq!(task.stream_buffer.stats().retransmits_requested, 0);
+    }
+}
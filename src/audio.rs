@@ -0,0 +1,231 @@
+//! Camera audio: cmd 6 packets carry 8 kHz mono G.711
+//!
+//! Packets are kept raw in a per-camera ring buffer and published to live
+//! listeners; decoding to 16-bit PCM happens when they are served.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+use crate::config::AudioCodec;
+
+/// Header cmd of audio packets
+pub const AUDIO_CMD: u16 = 6;
+
+/// G.711 sample rate (one byte per sample, mono)
+pub const SAMPLE_RATE: u32 = 8000;
+
+/// Seconds of audio kept per camera, and so the longest clip there is
+pub const BUFFERED_SECS: u64 = 60;
+
+/// One cmd 6 payload as received
+#[derive(Debug, Clone)]
+pub struct AudioPacket {
+    pub received_at: DateTime<Utc>,
+    pub data: Bytes,
+}
+
+/// Ring buffer of the most recent audio packets of one camera
+#[derive(Debug)]
+pub struct AudioBuffer {
+    packets: VecDeque<AudioPacket>,
+    total_bytes: usize,
+    max_bytes: usize,
+    audio_tx: broadcast::Sender<Bytes>, // Publishes every packet to live listeners
+}
+
+impl AudioBuffer {
+    /// Packets (20 ms each from the firmware) a live listener may fall behind by
+    pub const BROADCAST_CAPACITY: usize = 64;
+
+    /// Keep up to `max_duration` of audio
+    pub fn new(max_duration: Duration) -> Self {
+        let (audio_tx, _) = broadcast::channel(Self::BROADCAST_CAPACITY);
+        Self {
+            packets: VecDeque::new(),
+            total_bytes: 0,
+            max_bytes: (max_duration.as_millis() as usize * SAMPLE_RATE as usize) / 1000,
+            audio_tx,
+        }
+    }
+
+    /// Store a cmd 6 payload, dropping the oldest packets beyond the buffer length
+    pub fn push(&mut self, payload: &[u8]) {
+        let data = Bytes::copy_from_slice(payload);
+        if self.audio_tx.receiver_count() > 0 {
+            // Only fails when the last receiver went away in the meantime
+            let _ = self.audio_tx.send(data.clone());
+        }
+
+        self.total_bytes += data.len();
+        self.packets.push_back(AudioPacket {
+            received_at: Utc::now(),
+            data,
+        });
+        while self.total_bytes > self.max_bytes {
+            match self.packets.pop_front() {
+                Some(oldest) => self.total_bytes -= oldest.data.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Receive every audio packet from now on, still G.711 encoded
+    pub fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.audio_tx.subscribe()
+    }
+
+    /// The last `duration` of buffered audio as raw G.711 samples
+    pub fn clip(&self, duration: Duration) -> Vec<u8> {
+        let wanted = (duration.as_millis() as usize * SAMPLE_RATE as usize) / 1000;
+        let mut clip = Vec::new();
+        let mut gathered = 0;
+        for packet in self.packets.iter().rev() {
+            if gathered >= wanted {
+                break;
+            }
+            gathered += packet.data.len();
+            clip.push(&packet.data);
+        }
+        let mut samples: Vec<u8> = clip.into_iter().rev().flat_map(|data| data.iter().copied()).collect();
+        let excess = samples.len().saturating_sub(wanted);
+        samples.drain(..excess);
+        samples
+    }
+
+    pub fn packet_count(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// Buffered audio length
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.total_bytes as u64 * 1000 / SAMPLE_RATE as u64)
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.total_bytes = 0;
+    }
+}
+
+/// Decode one A-law sample (ITU-T G.711) to 16-bit linear PCM
+pub fn alaw_to_linear(sample: u8) -> i16 {
+    let a = sample ^ 0x55;
+    let mut t = ((a & 0x0F) as i16) << 4;
+    let segment = (a & 0x70) >> 4;
+    match segment {
+        0 => t += 8,
+        1 => t += 0x108,
+        _ => t = (t + 0x108) << (segment - 1),
+    }
+    if a & 0x80 != 0 {
+        t
+    } else {
+        -t
+    }
+}
+
+/// Decode one µ-law sample (ITU-T G.711) to 16-bit linear PCM
+pub fn ulaw_to_linear(sample: u8) -> i16 {
+    let u = !sample;
+    let t = ((((u & 0x0F) as i16) << 3) + 0x84) << ((u & 0x70) >> 4);
+    if u & 0x80 != 0 {
+        0x84 - t
+    } else {
+        t - 0x84
+    }
+}
+
+/// Decode G.711 samples to little-endian 16-bit PCM, as WAV expects
+pub fn decode_pcm16(codec: AudioCodec, samples: &[u8]) -> Vec<u8> {
+    let decode = match codec {
+        AudioCodec::Alaw => alaw_to_linear,
+        AudioCodec::Ulaw => ulaw_to_linear,
+    };
+    samples.iter().flat_map(|&sample| decode(sample).to_le_bytes()).collect()
+}
+
+/// Data size used in the header of a WAV stream of unknown length
+pub const WAV_STREAMING_SIZE: u32 = u32::MAX - 36;
+
+/// 44-byte header for `data_size` bytes of 8 kHz mono 16-bit PCM
+pub fn wav_header(data_size: u32) -> [u8; 44] {
+    let byte_rate = SAMPLE_RATE * 2;
+    let mut header = [0u8; 44];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&data_size.saturating_add(36).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    header[20..22].copy_from_slice(&1u16.to_le_bytes()); // PCM
+    header[22..24].copy_from_slice(&1u16.to_le_bytes()); // mono
+    header[24..28].copy_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+    header[32..34].copy_from_slice(&2u16.to_le_bytes()); // block align
+    header[34..36].copy_from_slice(&16u16.to_le_bytes()); // bits per sample
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_g711_reference_values() {
+        // Silence and full scale of both laws
+        assert_eq!(alaw_to_linear(0xD5), 8);
+        assert_eq!(alaw_to_linear(0x55), -8);
+        assert_eq!(alaw_to_linear(0xAA), 32256);
+        assert_eq!(alaw_to_linear(0x2A), -32256);
+        assert_eq!(ulaw_to_linear(0xFF), 0);
+        assert_eq!(ulaw_to_linear(0x7F), 0);
+        assert_eq!(ulaw_to_linear(0x80), 32124);
+        assert_eq!(ulaw_to_linear(0x00), -32124);
+
+        assert_eq!(decode_pcm16(AudioCodec::Alaw, &[0xD5, 0xAA]), vec![8, 0, 0x00, 0x7E]);
+    }
+
+    #[test]
+    fn test_ring_buffer_trims_and_clips() {
+        // 100 ms holds five 20 ms packets
+        let mut buffer = AudioBuffer::new(Duration::from_millis(100));
+        for value in 0..8u8 {
+            buffer.push(&[value; 160]);
+        }
+        assert_eq!(buffer.packet_count(), 5);
+        assert_eq!(buffer.total_bytes(), 800);
+        assert_eq!(buffer.duration(), Duration::from_millis(100));
+
+        // 30 ms spans the tail of packet 6 and all of packet 7
+        let clip = buffer.clip(Duration::from_millis(30));
+        assert_eq!(clip.len(), 240);
+        assert!(clip[..80].iter().all(|&b| b == 6));
+        assert!(clip[80..].iter().all(|&b| b == 7));
+
+        assert_eq!(buffer.clip(Duration::from_secs(10)).len(), 800);
+    }
+
+    #[test]
+    fn test_clip_counts_bytes_across_packet_sizes() {
+        let mut buffer = AudioBuffer::new(Duration::from_secs(1));
+        buffer.push(&[1; 400]);
+        buffer.push(&[2; 40]);
+        buffer.push(&[3; 40]);
+        buffer.push(&[4; 40]);
+
+        // 50 ms is 400 bytes: three short packets and the tail of the long one
+        let clip = buffer.clip(Duration::from_millis(50));
+        assert_eq!(clip.len(), 400);
+        assert!(clip[..280].iter().all(|&b| b == 1));
+        assert!(clip[280..].iter().all(|&b| b != 1));
+    }
+}
@@ -0,0 +1,45 @@
+use a9_v720_server::protocol::{ConfigCheckResponse, ConfigData};
+use anyhow::{Context, Result};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Ask the cloud emulation endpoint where to register, like the firmware does at boot
+///
+/// The camera only ever makes this one request, so a minimal HTTP/1.1
+/// exchange with `Connection: close` is all that is needed.
+pub async fn config_check(server: &str, port: u16, uid: &str, token: &str) -> Result<ConfigData> {
+    let random: String = (0..6)
+        .map(|_| rand::thread_rng().gen_range(b'A'..=b'Z') as char)
+        .collect();
+    let path = format!(
+        "/app/api/ApiServer/getA9ConfCheck?devicesCode={}&random={}&token={}",
+        uid, random, token
+    );
+
+    let mut stream = TcpStream::connect((server, port))
+        .await
+        .with_context(|| format!("Failed to connect to {}:{}", server, port))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        path, server
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("Malformed HTTP response from config check")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status_line = head.lines().next().unwrap_or_default();
+    if !status_line.contains(" 200 ") {
+        anyhow::bail!("Config check failed: {}", status_line);
+    }
+
+    let body: ConfigCheckResponse = serde_json::from_slice(&response[split + 4..])
+        .context("Failed to parse config check response")?;
+    Ok(body.data)
+}
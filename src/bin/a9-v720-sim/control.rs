@@ -0,0 +1,277 @@
+use a9_v720_server::protocol::{
+    decode, encode, ForwardCommand, Message, NatProbeRequest, NatProbeResponse, ProtocolCodec,
+    ProtocolHeader, RegistrationRequest, SnapshotRequest,
+};
+use a9_v720_server::types::DeviceInfo;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, Instant};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::media::MediaCommand;
+
+type Writer = FramedWrite<OwnedWriteHalf, ProtocolCodec>;
+
+/// Camera side of the TCP control channel
+pub struct ControlSession {
+    pub uid: String,
+    pub token: String,
+    pub domain: String,
+    /// Local UDP port reported back in the code 12 NAT response
+    pub media_port: u16,
+    pub media: mpsc::Sender<MediaCommand>,
+    /// Settings reported in reply to 301/4 and changed by 301/6-9
+    pub device_info: DeviceInfo,
+    /// 301 content codes left unanswered, as a camera that lost the command would
+    pub ignored_commands: Vec<u32>,
+    /// Interval between binary cmd 99 keepalives on the control channel
+    pub keepalive_interval: Duration,
+    /// Go silent this long after registering while keeping the connection open, as a hung camera would
+    pub hang_after: Option<Duration>,
+}
+
+impl ControlSession {
+    /// Register with the server and answer its commands until the connection closes
+    ///
+    /// With `disconnect_after` set the connection is dropped on purpose once
+    /// that much time has passed, as a camera losing Wi-Fi would.
+    pub async fn run(mut self, addr: SocketAddr, disconnect_after: Option<Duration>) -> Result<()> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect control channel to {}", addr))?;
+        tracing::info!("Control channel connected to {}", addr);
+
+        let (read_half, write_half) = stream.into_split();
+        let mut frames = FramedRead::new(read_half, ProtocolCodec::new());
+        let mut writer = FramedWrite::new(write_half, ProtocolCodec::new());
+
+        send(&mut writer, &Message::Register(RegistrationRequest {
+            code: 100,
+            uid: self.uid.clone(),
+            token: self.token.clone(),
+            domain: self.domain.clone(),
+        }))
+        .await?;
+        tracing::info!("Registration (code 100) sent for {}", self.uid);
+
+        let disconnect = async {
+            match disconnect_after {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(disconnect);
+        let hang_after = self.hang_after;
+        let hang = async move {
+            match hang_after {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(hang);
+
+        let mut keepalive = interval_at(Instant::now() + self.keepalive_interval, self.keepalive_interval);
+        // Set by an echoed 301/3 until the server's closing 301/0 arrives
+        let mut start_pending = false;
+
+        loop {
+            tokio::select! {
+                frame = frames.next() => match frame {
+                    Some(Ok((header, payload))) => {
+                        self.handle_frame(header, &payload, &mut writer, &mut start_pending).await?;
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => {
+                        tracing::info!("Server closed the control channel");
+                        return Ok(());
+                    }
+                },
+                _ = &mut disconnect => {
+                    tracing::info!("Scripted disconnect of the control channel");
+                    return Ok(());
+                }
+                _ = &mut hang => {
+                    tracing::info!("Scripted hang: no more traffic on any channel");
+                    self.media.send(MediaCommand::Stop).await?;
+                    std::future::pending::<()>().await;
+                }
+                _ = keepalive.tick() => {
+                    writer.send((ProtocolHeader::binary(99, 0, 0), Bytes::new())).await?;
+                }
+            }
+        }
+    }
+
+    async fn handle_frame(
+        &mut self,
+        header: ProtocolHeader,
+        payload: &[u8],
+        writer: &mut Writer,
+        start_pending: &mut bool,
+    ) -> Result<()> {
+        if header.cmd == 99 {
+            tracing::debug!("Keepalive acknowledged");
+            return Ok(());
+        }
+        if header.cmd != 0 {
+            tracing::debug!("Ignoring binary cmd {} on control channel", header.cmd);
+            return Ok(());
+        }
+
+        match decode(payload)? {
+            Message::RegisterResponse(response) => {
+                if response.status != 200 {
+                    anyhow::bail!("Registration refused (code 101, status {})", response.status);
+                }
+                tracing::info!("Registered (code 101, status {})", response.status);
+            }
+            Message::NatRequest(request) => {
+                self.handle_nat_request(request, writer).await?;
+            }
+            Message::DeviceStatus(status) => {
+                tracing::info!("Device status (code 53) received: status={}", status.status);
+            }
+            Message::Forward(forward) => {
+                self.handle_forward(forward, header.pkg_id, writer, start_pending).await?;
+            }
+            Message::SnapshotResponse(response) => {
+                tracing::info!("Snapshot acknowledged (code 202, status {})", response.status);
+            }
+            message => {
+                tracing::debug!("Ignoring code {} on control channel", message.code());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Code 11: answer with 12, then start the UDP probe towards the advertised address
+    async fn handle_nat_request(&self, request: NatProbeRequest, writer: &mut Writer) -> Result<()> {
+        tracing::info!(
+            "NAT request (code 11): probe {}:{}",
+            request.cli_nat_ip,
+            request.cli_nat_port
+        );
+
+        let local_ip = writer.get_ref().local_addr()?.ip().to_string();
+        send(writer, &Message::NatResponse(NatProbeResponse {
+            code: 12,
+            status: 1,
+            dev_ip: local_ip.clone(),
+            dev_port: self.media_port,
+            dev_nat_ip: local_ip,
+            dev_nat_port: self.media_port,
+            cli_target: request.cli_target.clone(),
+            cli_token: request.cli_token.clone(),
+        }))
+        .await?;
+
+        let target = lookup(&request.cli_nat_ip, request.cli_nat_port).await?;
+        self.media.send(MediaCommand::Probe(target)).await?;
+        Ok(())
+    }
+
+    /// 301 forward commands are echoed back with their pkg_id, as the firmware does, then acted on
+    async fn handle_forward(
+        &mut self,
+        forward: ForwardCommand,
+        pkg_id: u32,
+        writer: &mut Writer,
+        start_pending: &mut bool,
+    ) -> Result<()> {
+        if self.ignored_commands.contains(&forward.content.code()) {
+            tracing::info!("Ignoring 301/{} (pkg_id {})", forward.content.code(), pkg_id);
+            return Ok(());
+        }
+
+        match *forward.content {
+            Message::DeviceInfoRequest { .. } => {
+                tracing::info!("Device info requested (301/4)");
+                let info = ForwardCommand {
+                    code: 301,
+                    target: forward.target.clone(),
+                    content: Box::new(Message::DeviceInfo(self.device_info.clone())),
+                };
+                reply(writer, pkg_id, &Message::Forward(info)).await?;
+            }
+            Message::StartStreaming => {
+                tracing::info!("Start streaming (301/3)");
+                reply(writer, pkg_id, &Message::Forward(forward)).await?;
+                *start_pending = true;
+                self.media.send(MediaCommand::Start).await?;
+            }
+            Message::StopStreaming => {
+                reply(writer, pkg_id, &Message::Forward(forward)).await?;
+                // The server closes the 301/3 handshake with a 301/0; only a later one stops the stream
+                if std::mem::take(start_pending) {
+                    tracing::info!("Streaming handshake completed (301/0)");
+                } else {
+                    tracing::info!("Stop streaming (301/0)");
+                    self.media.send(MediaCommand::Stop).await?;
+                }
+            }
+            Message::Snapshot => {
+                tracing::info!("Snapshot requested (301/5)");
+                reply(writer, pkg_id, &Message::Forward(forward)).await?;
+                send(writer, &Message::SnapshotRequest(SnapshotRequest {
+                    code: 201,
+                    uid: self.uid.clone(),
+                }))
+                .await?;
+                self.media.send(MediaCommand::Snapshot).await?;
+            }
+            Message::Setting(setting) => {
+                tracing::info!("Setting {} = {} (301/{})", setting.field(), setting.value(), setting.code());
+                setting.apply(&mut self.device_info);
+                reply(writer, pkg_id, &Message::Forward(forward)).await?;
+            }
+            ref content => {
+                tracing::debug!("Echoing 301/{}", content.code());
+                reply(writer, pkg_id, &Message::Forward(forward.clone())).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn send(writer: &mut Writer, message: &Message) -> Result<()> {
+    reply(writer, 0, message).await
+}
+
+/// Send `message` in answer to a command that carried `pkg_id`
+async fn reply(writer: &mut Writer, pkg_id: u32, message: &Message) -> Result<()> {
+    let payload = encode(message)?;
+    let header = ProtocolHeader::json(pkg_id, payload.len());
+    writer.send((header, Bytes::from(payload))).await
+}
+
+async fn lookup(host: &str, port: u16) -> Result<SocketAddr> {
+    tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .with_context(|| format!("No address for {}:{}", host, port))
+}
+
+/// Settings a freshly powered camera reports in reply to 301/4
+pub fn device_info() -> DeviceInfo {
+    DeviceInfo {
+        code: 4,
+        udp_play_back: Some(0),
+        dev_power: 100,
+        sd_move_mode: 0,
+        sd_dev_status: 0,
+        ir_led: 0,
+        inst_led: 1,
+        speed_grade: 1,
+        mirror_flip: 0,
+        wifi_name: "a9-v720-sim".to_string(),
+        version: concat!("sim-", env!("CARGO_PKG_VERSION")).to_string(),
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Scripted impairment of the media path: random loss and single-packet reordering
+pub struct Faults {
+    loss: f64,
+    reorder: f64,
+    rng: StdRng,
+    held: Option<Vec<u8>>,
+    pub sent: u64,
+    pub dropped: u64,
+    pub reordered: u64,
+}
+
+impl Faults {
+    pub fn new(loss: f64, reorder: f64, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            loss,
+            reorder,
+            rng,
+            held: None,
+            sent: 0,
+            dropped: 0,
+            reordered: 0,
+        }
+    }
+
+    /// Send a datagram, possibly dropping it or holding it back behind the next one
+    pub async fn send(&mut self, socket: &UdpSocket, addr: SocketAddr, datagram: Vec<u8>) -> Result<()> {
+        if self.rng.gen_bool(self.loss) {
+            self.dropped += 1;
+            return Ok(());
+        }
+        if self.held.is_none() && self.rng.gen_bool(self.reorder) {
+            self.reordered += 1;
+            self.held = Some(datagram);
+            return Ok(());
+        }
+
+        socket.send_to(&datagram, addr).await?;
+        self.sent += 1;
+        self.flush(socket, addr).await
+    }
+
+    /// Release a datagram still held back for reordering
+    pub async fn flush(&mut self, socket: &UdpSocket, addr: SocketAddr) -> Result<()> {
+        if let Some(held) = self.held.take() {
+            socket.send_to(&held, addr).await?;
+            self.sent += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+/// Built-in 640x480 colour bar test pattern
+///
+/// Baseline YCbCr 4:2:0 with the standard Huffman tables, like the camera
+/// firmware produces, so frames can be repacketized as RTP/JPEG.
+pub const TEST_PATTERN: &[u8] = include_bytes!("test_pattern.jpg");
+
+/// Fragment flags used by the firmware for JPEG frames over UDP
+pub const MSG_FLAG_START: u8 = 250;
+pub const MSG_FLAG_CONTINUE: u8 = 251;
+pub const MSG_FLAG_END: u8 = 252;
+
+/// Produces numbered JPEG frames from a base image
+///
+/// Each frame carries a COM segment with its sequence number, so frames
+/// differ on the wire and can be told apart after reassembly.
+pub struct FrameSource {
+    jpeg: Vec<u8>,
+    sequence: u64,
+}
+
+impl FrameSource {
+    pub fn new(jpeg: Vec<u8>) -> Self {
+        Self { jpeg, sequence: 0 }
+    }
+
+    pub fn next_frame(&mut self) -> Vec<u8> {
+        self.sequence += 1;
+        let comment = format!("a9-v720-sim frame {}", self.sequence);
+
+        let mut frame = Vec::with_capacity(self.jpeg.len() + comment.len() + 4);
+        frame.extend_from_slice(&self.jpeg[..2]); // SOI
+        frame.extend_from_slice(&[0xFF, 0xFE]);
+        frame.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+        frame.extend_from_slice(comment.as_bytes());
+        frame.extend_from_slice(&self.jpeg[2..]);
+        frame
+    }
+}
+
+/// Split a frame into `(msg_flag, payload)` fragments the way the camera sends them
+///
+/// The last fragment carries the total frame size as a trailing
+/// little-endian u32. There are always at least a start and an end fragment.
+pub fn fragment(frame: &[u8], fragment_size: usize) -> Vec<(u8, Vec<u8>)> {
+    let mut data = frame.to_vec();
+    data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+
+    let chunk_size = fragment_size.min(data.len().div_ceil(2)).max(1);
+    let chunks: Vec<&[u8]> = data.chunks(chunk_size).collect();
+    let last = chunks.len() - 1;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let msg_flag = match i {
+                0 => MSG_FLAG_START,
+                i if i == last => MSG_FLAG_END,
+                _ => MSG_FLAG_CONTINUE,
+            };
+            (msg_flag, chunk.to_vec())
+        })
+        .collect()
+}
+
+/// 20 ms of G.711 A-law silence at 8 kHz
+pub fn audio_packet() -> Vec<u8> {
+    vec![0xD5; 160]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragment_flags_and_trailing_size() {
+        let frame = FrameSource::new(TEST_PATTERN.to_vec()).next_frame();
+        assert_eq!(&frame[..2], &[0xFF, 0xD8]);
+        assert_eq!(&frame[frame.len() - 2..], &[0xFF, 0xD9]);
+
+        let fragments = fragment(&frame, 1024);
+        let flags: Vec<u8> = fragments.iter().map(|(flag, _)| *flag).collect();
+        assert_eq!(flags.first(), Some(&MSG_FLAG_START));
+        assert_eq!(flags.last(), Some(&MSG_FLAG_END));
+        assert!(flags[1..flags.len() - 1].iter().all(|&f| f == MSG_FLAG_CONTINUE));
+
+        let joined: Vec<u8> = fragments.into_iter().flat_map(|(_, chunk)| chunk).collect();
+        let (body, size) = joined.split_at(joined.len() - 4);
+        assert_eq!(body, &frame[..]);
+        assert_eq!(u32::from_le_bytes(size.try_into().unwrap()) as usize, frame.len());
+
+        // A frame smaller than one fragment is still sent as start + end
+        let flags: Vec<u8> = fragment(b"tiny", 1024).iter().map(|(flag, _)| *flag).collect();
+        assert_eq!(flags, vec![MSG_FLAG_START, MSG_FLAG_END]);
+    }
+}
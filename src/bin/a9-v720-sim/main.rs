@@ -0,0 +1,208 @@
+//! Simulated A9 V720 camera
+//!
+//! Plays the camera side of the protocol against a running server: the
+//! `getA9ConfCheck` cloud lookup, TCP registration, the NAT and UDP probe
+//! exchanges, and JPEG/audio streaming over UDP. Packet loss, reordering and
+//! disconnects can be scripted from the command line to exercise the routers.
+
+mod cloud;
+mod control;
+mod faults;
+mod frames;
+mod media;
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::mpsc;
+
+use crate::control::ControlSession;
+use crate::faults::Faults;
+use crate::frames::FrameSource;
+use crate::media::{MediaOptions, MediaSession};
+
+#[derive(Debug, Clone, Parser)]
+#[command(name = "a9-v720-sim", about = "Simulated A9 V720 camera for end-to-end testing")]
+struct Args {
+    /// Host serving the cloud emulation endpoints (`/app/api/...`)
+    #[arg(long, default_value = "127.0.0.1")]
+    server: String,
+
+    /// HTTP port of the cloud emulation endpoints
+    #[arg(long, default_value_t = 80)]
+    http_port: u16,
+
+    /// Connect the control channel here instead of the host/tcpPort from getA9ConfCheck
+    #[arg(long)]
+    control_addr: Option<String>,
+
+    /// Device uid sent as `devicesCode` and in the code 100 registration
+    #[arg(long, default_value = "0800c00128F8")]
+    uid: String,
+
+    /// Token sent with the config check
+    #[arg(long, default_value = "deadbeef")]
+    token: String,
+
+    /// Register with this token instead of the pwd from the config check
+    #[arg(long)]
+    registration_token: Option<String>,
+
+    /// JPEG used for every frame (defaults to a built-in 640x480 test pattern)
+    #[arg(long)]
+    jpeg: Option<PathBuf>,
+
+    /// Video frames per second while streaming
+    #[arg(long, default_value_t = 10)]
+    fps: u32,
+
+    /// Maximum JPEG bytes per UDP fragment
+    #[arg(long, default_value_t = 1024)]
+    fragment_size: usize,
+
+    /// Stop streaming after this many frames
+    #[arg(long)]
+    frames: Option<u64>,
+
+    /// Do not send cmd 6 audio packets alongside video
+    #[arg(long)]
+    no_audio: bool,
+
+    /// Number of code 51/50 probe exchanges after the code 21 response
+    #[arg(long, default_value_t = 3)]
+    probe_count: u32,
+
+    /// Probability (0.0-1.0) of dropping each outgoing media datagram
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    loss: f64,
+
+    /// Probability (0.0-1.0) of holding a media datagram back behind the next one
+    #[arg(long, default_value_t = 0.0, value_parser = probability)]
+    reorder: f64,
+
+    /// Seed for loss/reorder decisions, for reproducible runs
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Drop the TCP control connection this many seconds after registering
+    #[arg(long)]
+    disconnect_after: Option<u64>,
+
+    /// Start a new session (config check and registration) after a disconnect
+    #[arg(long)]
+    reconnect: bool,
+
+    /// Delay before reconnecting, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    reconnect_delay_ms: u64,
+
+    /// Interval between cmd 99 keepalives on the control channel, in milliseconds
+    #[arg(long, default_value_t = 15000)]
+    keepalive_ms: u64,
+
+    /// Stop sending and answering anything this many seconds after registering, keeping the connection open
+    #[arg(long)]
+    hang_after: Option<u64>,
+
+    /// Never answer 301 commands with this content code (repeatable)
+    #[arg(long)]
+    ignore_command: Vec<u32>,
+}
+
+fn probability(value: &str) -> Result<f64, String> {
+    let p: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if (0.0..=1.0).contains(&p) {
+        Ok(p)
+    } else {
+        Err(format!("{} is not between 0.0 and 1.0", p))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    let jpeg = match &args.jpeg {
+        Some(path) => std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+        None => frames::TEST_PATTERN.to_vec(),
+    };
+
+    loop {
+        if let Err(e) = run_session(&args, &jpeg).await {
+            tracing::error!("Session failed: {:#}", e);
+        }
+
+        if !args.reconnect {
+            break;
+        }
+        tracing::info!("Reconnecting in {} ms", args.reconnect_delay_ms);
+        tokio::time::sleep(Duration::from_millis(args.reconnect_delay_ms)).await;
+    }
+
+    Ok(())
+}
+
+/// One camera power cycle: config check, registration, then serve commands until disconnected
+async fn run_session(args: &Args, jpeg: &[u8]) -> Result<()> {
+    let conf = cloud::config_check(&args.server, args.http_port, &args.uid, &args.token).await?;
+    tracing::info!(
+        "Config check answered: host={}, tcpPort={}, domain={}",
+        conf.host,
+        conf.tcp_port,
+        conf.domain
+    );
+
+    let control_addr = match &args.control_addr {
+        Some(addr) => addr.clone(),
+        None => format!("{}:{}", conf.host, conf.tcp_port),
+    };
+    let control_addr: SocketAddr = lookup_host(&control_addr)
+        .await?
+        .next()
+        .with_context(|| format!("No address for {}", control_addr))?;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let media_port = socket.local_addr()?.port();
+
+    let media = MediaSession::new(
+        socket,
+        MediaOptions {
+            uid: args.uid.clone(),
+            fps: args.fps.max(1),
+            fragment_size: args.fragment_size.max(1),
+            max_frames: args.frames,
+            audio: !args.no_audio,
+            probe_count: args.probe_count,
+        },
+        FrameSource::new(jpeg.to_vec()),
+        Faults::new(args.loss, args.reorder, args.seed),
+    );
+    let (media_tx, media_rx) = mpsc::channel(16);
+    let media_handle = tokio::spawn(media.run(media_rx));
+
+    let session = ControlSession {
+        uid: args.uid.clone(),
+        token: args.registration_token.clone().unwrap_or(conf.pwd),
+        domain: conf.domain,
+        media_port,
+        media: media_tx,
+        device_info: control::device_info(),
+        ignored_commands: args.ignore_command.clone(),
+        keepalive_interval: Duration::from_millis(args.keepalive_ms.max(1)),
+        hang_after: args.hang_after.map(Duration::from_secs),
+    };
+    let result = session
+        .run(control_addr, args.disconnect_after.map(Duration::from_secs))
+        .await;
+
+    // The control session owned the only sender, so the media task winds down now
+    if let Err(e) = media_handle.await? {
+        tracing::error!("Media session failed: {:#}", e);
+    }
+
+    result
+}
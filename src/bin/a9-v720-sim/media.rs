@@ -0,0 +1,328 @@
+use a9_v720_server::protocol::{
+    decode, encode, Code51Response, Message, ProtocolHeader, RetransmissionConfirm, RetransmissionRequest,
+    UdpProbeRequest,
+};
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::{interval, timeout, MissedTickBehavior};
+
+use crate::faults::Faults;
+use crate::frames::{self, FrameSource};
+
+/// How long to wait for each reply during the UDP probe exchanges
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const PROBE_ATTEMPTS: u32 = 3;
+
+/// Sent media packets kept for retransmission until confirmed by CMD 605
+const MAX_UNCONFIRMED: usize = 4096;
+
+/// Commands from the control channel
+#[derive(Debug)]
+pub enum MediaCommand {
+    /// Code 20/21 then 51/50 exchange, starting at the address from code 11
+    Probe(SocketAddr),
+    Start,
+    Stop,
+    Snapshot,
+}
+
+pub struct MediaOptions {
+    pub uid: String,
+    pub fps: u32,
+    pub fragment_size: usize,
+    pub max_frames: Option<u64>,
+    pub audio: bool,
+    pub probe_count: u32,
+}
+
+struct SentPacket {
+    datagram: Vec<u8>,
+    retransmitted: bool,
+}
+
+/// Camera side of the UDP media path
+pub struct MediaSession {
+    socket: UdpSocket,
+    options: MediaOptions,
+    source: FrameSource,
+    faults: Faults,
+    /// Where media goes: the address from the code 21 response
+    peer: Option<SocketAddr>,
+    streaming: bool,
+    next_pkg_id: u32,
+    unconfirmed: BTreeMap<u32, SentPacket>,
+    frames_sent: u64,
+    confirmations: u64,
+    retransmitted: u64,
+}
+
+impl MediaSession {
+    pub fn new(socket: UdpSocket, options: MediaOptions, source: FrameSource, faults: Faults) -> Self {
+        Self {
+            socket,
+            options,
+            source,
+            faults,
+            peer: None,
+            streaming: false,
+            next_pkg_id: 1,
+            unconfirmed: BTreeMap::new(),
+            frames_sent: 0,
+            confirmations: 0,
+            retransmitted: 0,
+        }
+    }
+
+    /// Serve commands until the control session drops its sender
+    pub async fn run(mut self, mut commands: mpsc::Receiver<MediaCommand>) -> Result<()> {
+        let mut ticker = interval(Duration::from_secs(1) / self.options.fps);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut buffer = [0u8; 4096];
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => self.handle_command(command).await?,
+                    None => break,
+                },
+                _ = ticker.tick(), if self.streaming => {
+                    self.send_frame().await?;
+                }
+                received = self.socket.recv_from(&mut buffer) => {
+                    let (n, from) = received?;
+                    self.handle_datagram(&buffer[..n], from).await?;
+                }
+            }
+        }
+
+        tracing::info!(
+            "Media session finished: {} frames, {} datagrams sent, {} dropped, {} reordered, {} retransmitted, {} confirmations",
+            self.frames_sent,
+            self.faults.sent,
+            self.faults.dropped,
+            self.faults.reordered,
+            self.retransmitted,
+            self.confirmations
+        );
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: MediaCommand) -> Result<()> {
+        match command {
+            MediaCommand::Probe(target) => {
+                if let Err(e) = self.probe(target).await {
+                    tracing::error!("UDP probe towards {} failed: {:#}", target, e);
+                }
+            }
+            MediaCommand::Start => {
+                tracing::info!("Streaming started");
+                self.streaming = true;
+            }
+            MediaCommand::Stop => {
+                tracing::info!("Streaming stopped after {} frames", self.frames_sent);
+                self.streaming = false;
+            }
+            MediaCommand::Snapshot => {
+                self.send_frame().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Code 20 to the NAT address, then `probe_count` rounds of 51/50 with the port from code 21
+    async fn probe(&mut self, target: SocketAddr) -> Result<()> {
+        let header = ProtocolHeader::json(0, 0);
+        let reply = self
+            .exchange(target, header, &Message::UdpProbe(UdpProbeRequest { code: 20 }), 21)
+            .await?;
+        let Message::UdpProbeResponse(response) = reply else {
+            unreachable!("exchange only returns the expected code");
+        };
+
+        let ip = response.ip.parse().context("Invalid ip in code 21 response")?;
+        let peer = SocketAddr::new(ip, response.port);
+        tracing::info!("Code 21 received: media port {}", peer);
+        self.peer = Some(peer);
+
+        for round in 1..=self.options.probe_count {
+            // The router dispatches the probe on the header cmd rather than the JSON code
+            let header = ProtocolHeader::new(51, 0, 255, 0);
+            let message = Message::ProbeResponse(Code51Response {
+                code: 51,
+                dev_target: self.options.uid.clone(),
+                status: 1,
+            });
+            self.exchange(peer, header, &message, 50).await?;
+            tracing::info!("Probe exchange {}/{} completed", round, self.options.probe_count);
+        }
+
+        Ok(())
+    }
+
+    /// Send a JSON datagram and wait for the reply with `expected_code`, retrying on timeout
+    async fn exchange(
+        &mut self,
+        addr: SocketAddr,
+        mut header: ProtocolHeader,
+        message: &Message,
+        expected_code: u32,
+    ) -> Result<Message> {
+        let payload = encode(message)?;
+        header.length = payload.len() as u32;
+        let mut datagram = header.to_bytes();
+        datagram.extend_from_slice(&payload);
+
+        let mut buffer = [0u8; 4096];
+        for attempt in 1..=PROBE_ATTEMPTS {
+            self.socket.send_to(&datagram, addr).await?;
+
+            let deadline = tokio::time::Instant::now() + PROBE_TIMEOUT;
+            while let Ok(received) = timeout(
+                deadline.saturating_duration_since(tokio::time::Instant::now()),
+                self.socket.recv_from(&mut buffer),
+            )
+            .await
+            {
+                let (n, _) = received?;
+                let (reply_header, reply_payload) = match ProtocolHeader::from_bytes(&buffer[..n]) {
+                    Ok(parsed) => parsed,
+                    Err(_) => continue,
+                };
+                if reply_header.cmd != 0 {
+                    continue;
+                }
+                match decode(reply_payload) {
+                    Ok(reply) if reply.code() == expected_code => return Ok(reply),
+                    Ok(reply) => tracing::debug!("Ignoring code {} while waiting for {}", reply.code(), expected_code),
+                    Err(e) => tracing::debug!("Ignoring undecodable datagram: {:#}", e),
+                }
+            }
+
+            tracing::warn!(
+                "No code {} reply from {} (attempt {}/{})",
+                expected_code,
+                addr,
+                attempt,
+                PROBE_ATTEMPTS
+            );
+        }
+
+        anyhow::bail!("No code {} reply from {}", expected_code, addr)
+    }
+
+    /// One JPEG frame as 250/251/252 fragments, followed by an audio packet
+    async fn send_frame(&mut self) -> Result<()> {
+        let Some(peer) = self.peer else {
+            tracing::warn!("No media port negotiated yet, frame not sent");
+            return Ok(());
+        };
+
+        let frame = self.source.next_frame();
+        for (msg_flag, chunk) in frames::fragment(&frame, self.options.fragment_size) {
+            let header = ProtocolHeader::video_frame(0, chunk.len(), msg_flag);
+            self.send_media(peer, header, &chunk).await?;
+        }
+        if self.options.audio {
+            let audio = frames::audio_packet();
+            let header = ProtocolHeader::new(6, audio.len() as u32, 255, 0);
+            self.send_media(peer, header, &audio).await?;
+        }
+        self.faults.flush(&self.socket, peer).await?;
+
+        self.frames_sent += 1;
+        tracing::debug!("Frame {} sent ({} bytes)", self.frames_sent, frame.len());
+
+        if let Some(max_frames) = self.options.max_frames {
+            if self.frames_sent >= max_frames && self.streaming {
+                tracing::info!("Frame limit of {} reached, streaming stopped", max_frames);
+                self.streaming = false;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_media(&mut self, peer: SocketAddr, mut header: ProtocolHeader, payload: &[u8]) -> Result<()> {
+        header.pkg_id = self.next_pkg_id;
+        self.next_pkg_id = self.next_pkg_id.wrapping_add(1);
+
+        let mut datagram = header.to_bytes();
+        datagram.extend_from_slice(payload);
+
+        self.unconfirmed.insert(header.pkg_id, SentPacket {
+            datagram: datagram.clone(),
+            retransmitted: false,
+        });
+        while self.unconfirmed.len() > MAX_UNCONFIRMED {
+            self.unconfirmed.pop_first();
+        }
+
+        self.faults.send(&self.socket, peer, datagram).await
+    }
+
+    async fn handle_datagram(&mut self, data: &[u8], from: SocketAddr) -> Result<()> {
+        // CMD 605 has a shorter header of its own
+        if let Ok(request) = RetransmissionRequest::from_bytes(data) {
+            return self.handle_request(&request.missing_packets).await;
+        }
+        if let Ok(confirm) = RetransmissionConfirm::from_bytes(data) {
+            self.handle_confirm(&confirm.received_packets);
+            return Ok(());
+        }
+
+        let (header, payload) = match ProtocolHeader::from_bytes(data) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                tracing::debug!("Ignoring malformed datagram from {}: {}", from, e);
+                return Ok(());
+            }
+        };
+
+        match header.cmd {
+            0 => match decode(payload) {
+                Ok(message) => tracing::debug!("Code {} received on media socket from {}", message.code(), from),
+                Err(e) => tracing::debug!("Ignoring undecodable datagram from {}: {:#}", from, e),
+            },
+            cmd => tracing::debug!("Ignoring cmd {} on media socket from {}", cmd, from),
+        }
+        Ok(())
+    }
+
+    /// Forget confirmed packets
+    fn handle_confirm(&mut self, ids: &[u32]) {
+        self.confirmations += 1;
+        if ids.is_empty() {
+            tracing::debug!("Empty CMD 605 confirmation received");
+        }
+        for id in ids {
+            self.unconfirmed.remove(id);
+        }
+    }
+
+    /// Resend, once, each requested packet still held
+    async fn handle_request(&mut self, ids: &[u32]) -> Result<()> {
+        let Some(peer) = self.peer else {
+            return Ok(());
+        };
+        for id in ids {
+            let Some(packet) = self.unconfirmed.get_mut(id) else {
+                tracing::debug!("Requested pkg_id {} is no longer held", id);
+                continue;
+            };
+            if packet.retransmitted {
+                // Already resent once; give up on it
+                self.unconfirmed.remove(id);
+                continue;
+            }
+            packet.retransmitted = true;
+            let datagram = packet.datagram.clone();
+            self.retransmitted += 1;
+            tracing::debug!("Retransmitting requested pkg_id {}", id);
+            self.faults.send(&self.socket, peer, datagram).await?;
+        }
+        self.faults.flush(&self.socket, peer).await
+    }
+}
@@ -1,26 +1,189 @@
+//! Server configuration
+//!
+//! Layered from lowest to highest precedence: built-in defaults, a TOML or
+//! JSON file, `A9_*` environment variables and `--set key=value` flags.
+//! The result is validated before anything binds.
+//!
+//! While the server runs, `LiveConfig` reloads the file when it changes and
+//! takes updates from `PUT /api/config`. Most settings apply to the next
+//! message handled; those in `RESTART_REQUIRED` wait for a restart.
+
+use arc_swap::ArcSwap;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
 use std::fs;
-use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use toml_edit::{DocumentMut, Item};
+
+/// Files tried in the working directory when no `--config` is given
+pub const DEFAULT_PATHS: [&str; 2] = ["config.toml", "config.json"];
+
+/// Prefix of the environment variables overriding settings, e.g. `A9_WEB_PORT`
+pub const ENV_PREFIX: &str = "A9_";
+
+/// Environment variable naming the config file; read by the command line parser
+pub const CONFIG_ENV: &str = "A9_CONFIG";
+
+/// Settings only read when the listeners open or the registry loads
+pub const RESTART_REQUIRED: [&str; 13] = [
+    "registration_enabled",
+    "tcp_registration_port",
+    "tcp_protocol_port",
+    "udp_protocol_port",
+    "udp_stream_port_1",
+    "udp_stream_port_2",
+    "web_enabled",
+    "web_port",
+    "rtsp_enabled",
+    "rtsp_port",
+    "bind_ip",
+    "bind",
+    "registry_path",
+];
+
+/// What `GET /api/config` shows in place of tokens and password hashes
+pub const REDACTED: &str = "<redacted>";
+
+/// Settings that are secrets, or whose entries' values are
+const SECRETS: [&str; 4] = ["client_token", "server_token", "users", "api_keys"];
+
+/// How often the config file is checked for changes
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AppConfig {
     pub server_ip: String,
     pub domain: String,
     pub client_target: String,
     pub client_token: String,
     pub server_token: String,
+    pub uid_prefix: String, // Start of the uids handed out by the bootstrap registration
     
+    pub registration_enabled: bool, // Cloud emulation the cameras' DNS points at; off when something else answers them
     pub tcp_registration_port: u16,
     pub tcp_protocol_port: u16,
     pub udp_protocol_port: u16,
     pub udp_stream_port_1: u16,
     pub udp_stream_port_2: u16,
+    pub web_enabled: bool, // Management interface and API
     pub web_port: u16,
+    pub rtsp_enabled: bool,
+    pub rtsp_port: u16,
+
+    pub bind_ip: IpAddr, // Address every listener binds to unless `bind` names another
+    pub bind: BTreeMap<Listener, IpAddr>, // Per-listener bind addresses
+
+    pub users: BTreeMap<String, String>, // Web users: name to argon2 password hash; none and no API keys leaves the web interface open
+    pub api_keys: BTreeMap<String, String>, // Bearer keys `<name>.<secret>`: name to argon2 hash of the secret
+    pub session_ttl_secs: u64,
     
     pub max_retries: u32,
     pub retry_timeout_ms: u64,
     pub health_check_interval_ms: u64,
-    pub retransmission_interval_ms: u64,
+    pub eviction_grace_ms: u64, // How long a disconnected camera stays listed
+    pub request_retransmissions: bool, // Ask cameras to resend missing pkg_ids; no firmware is known to honour it
+
+    pub registry_path: String, // Device registry JSON file
+    pub pairing_window_secs: u64, // How long unknown devices may pair once the window opens
+    pub pair_on_start: bool, // Open the pairing window when the server starts
+
+    pub firmware_version: String, // Firmware offered in the config check to cameras running another version; empty offers none
+    pub firmware_url: String,
+
+    pub audio_codec: AudioCodec,
+    pub audio_clip_max_secs: u64, // Longest clip `/audio/clip` serves
+
+    pub recording_dir: String,
+    pub recording_segment_secs: u64,
+    pub recording_max_age_hours: u64, // 0 keeps segments forever
+    pub recording_max_total_mb: u64,  // 0 disables the size limit
+}
+
+/// A socket the server listens on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Listener {
+    /// Cloud emulation: HTTP config check and bootstrap registration
+    Registration,
+    TcpProtocol,
+    UdpProtocol,
+    UdpStream1,
+    UdpStream2,
+    Web,
+    Rtsp,
+}
+
+impl Listener {
+    pub const ALL: [Listener; 7] = [
+        Listener::Registration,
+        Listener::TcpProtocol,
+        Listener::UdpProtocol,
+        Listener::UdpStream1,
+        Listener::UdpStream2,
+        Listener::Web,
+        Listener::Rtsp,
+    ];
+
+    /// Name used in `bind` and in `A9_BIND_*` variables
+    pub fn name(&self) -> &'static str {
+        match self {
+            Listener::Registration => "registration",
+            Listener::TcpProtocol => "tcp_protocol",
+            Listener::UdpProtocol => "udp_protocol",
+            Listener::UdpStream1 => "udp_stream_1",
+            Listener::UdpStream2 => "udp_stream_2",
+            Listener::Web => "web",
+            Listener::Rtsp => "rtsp",
+        }
+    }
+
+    pub fn is_udp(&self) -> bool {
+        matches!(self, Listener::UdpProtocol | Listener::UdpStream1 | Listener::UdpStream2)
+    }
+
+    fn from_name(name: &str) -> Option<Listener> {
+        Listener::ALL.into_iter().find(|listener| listener.name() == name)
+    }
+}
+
+/// Why the configuration could not be loaded
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {source}", path.display())]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("{}: {message}", path.display())]
+    Parse { path: PathBuf, message: String },
+    #[error("{}: unsupported format, expected a .toml or .json file", .0.display())]
+    Format(PathBuf),
+    /// `origin` is the environment variable or `--set` flag
+    #[error("{origin}: {message}")]
+    Override { origin: String, message: String },
+    #[error("invalid configuration:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+    #[error("failed to save {}: {message}", path.display())]
+    Save { path: PathBuf, message: String },
+}
+
+/// What applying a new configuration changed
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConfigChange {
+    /// Settings now in effect
+    pub applied: Vec<String>,
+    /// Settings that differ from the running ones until the server restarts
+    pub restart_required: Vec<String>,
+}
+
+/// G.711 variant carried in the camera's cmd 6 audio packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Alaw,
+    Ulaw,
 }
 
 impl Default for AppConfig {
@@ -31,38 +194,587 @@ impl Default for AppConfig {
             client_target: "00112233445566778899aabbccddeeff".to_string(),
             client_token: "deadc0de".to_string(),
             server_token: "deadbeef".to_string(),
+            uid_prefix: "0800c001".to_string(),
             
+            registration_enabled: true,
             tcp_registration_port: 80,
             tcp_protocol_port: 6123,
             udp_protocol_port: 6123,
             udp_stream_port_1: 53221,
             udp_stream_port_2: 41234,
+            web_enabled: true,
             web_port: 8080,
+            rtsp_enabled: false,
+            rtsp_port: 8554,
+
+            bind_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bind: BTreeMap::new(),
+
+            users: BTreeMap::new(),
+            api_keys: BTreeMap::new(),
+            session_ttl_secs: 86400,
             
             max_retries: 3,
             retry_timeout_ms: 5000,
             health_check_interval_ms: 30000,
-            retransmission_interval_ms: 100,
+            eviction_grace_ms: 300000,
+            request_retransmissions: false,
+
+            registry_path: "devices.json".to_string(),
+            pairing_window_secs: 600,
+            pair_on_start: false,
+
+            firmware_version: String::new(),
+            firmware_url: String::new(),
+
+            audio_codec: AudioCodec::Alaw,
+            audio_clip_max_secs: 60,
+
+            recording_dir: "recordings".to_string(),
+            recording_segment_secs: 300,
+            recording_max_age_hours: 72,
+            recording_max_total_mb: 10240,
         }
     }
 }
 
 impl AppConfig {
-    pub fn load() -> Result<Self> {
-        // Try to load from config.json first
-        if let Ok(config_str) = fs::read_to_string("config.json") {
-            let config: AppConfig = serde_json::from_str(&config_str)?;
-            return Ok(config);
+    /// Defaults, then `path` (or the first of `DEFAULT_PATHS` that exists),
+    /// then `A9_*` environment variables, then `overrides` (`key=value`)
+    ///
+    /// The result is validated.
+    pub fn load(path: Option<&Path>, overrides: &[String]) -> Result<Self, ConfigError> {
+        let default_path = DEFAULT_PATHS.iter().map(Path::new).find(|path| path.exists());
+        let config = match path.or(default_path) {
+            Some(path) => {
+                tracing::info!("Loading configuration from {}", path.display());
+                Self::from_file(path)?
+            }
+            None => {
+                tracing::info!("No config file found, starting from defaults");
+                Self::default()
+            }
+        };
+        config.layered(overrides)
+    }
+
+    /// Apply the `A9_*` environment and `overrides` on top of this file layer, then validate
+    fn layered(mut self, overrides: &[String]) -> Result<Self, ConfigError> {
+        self.apply_env(std::env::vars())?;
+        for entry in overrides {
+            let origin = format!("--set {}", entry);
+            let (key, value) = entry.split_once('=').ok_or_else(|| ConfigError::Override {
+                origin: origin.clone(),
+                message: "expected key=value".to_string(),
+            })?;
+            self.set(key.trim(), value).map_err(|message| ConfigError::Override { origin, message })?;
         }
-        
-        // Fall back to default configuration
-        tracing::warn!("config.json not found, using default configuration");
-        Ok(AppConfig::default())
+        self.validate()?;
+        Ok(self)
     }
-    
-    pub fn save(&self) -> Result<()> {
-        let config_str = serde_json::to_string_pretty(self)?;
-        fs::write("config.json", config_str)?;
+
+    /// File the server reloads and saves to: `path`, else the first of `DEFAULT_PATHS` that exists, else the first
+    pub fn locate(path: Option<&Path>) -> PathBuf {
+        path.map(Path::to_path_buf)
+            .or_else(|| DEFAULT_PATHS.iter().map(PathBuf::from).find(|path| path.exists()))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_PATHS[0]))
+    }
+
+    /// Read a `.toml` or `.json` file; settings it leaves out keep their defaults
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read { path: path.to_path_buf(), source })?;
+        let parse_error = |message: String| ConfigError::Parse { path: path.to_path_buf(), message };
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| parse_error(e.to_string().trim_end().to_string())),
+            Some("json") => serde_json::from_str(&text).map_err(|e| parse_error(e.to_string())),
+            _ => Err(ConfigError::Format(path.to_path_buf())),
+        }
+    }
+
+    /// Apply every `A9_<SETTING>` variable among `vars`, e.g. `A9_WEB_PORT=9000` or `A9_BIND_WEB=127.0.0.1`
+    pub fn apply_env(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<(), ConfigError> {
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_ENV {
+                continue;
+            }
+            self.set(&key.to_ascii_lowercase(), &value)
+                .map_err(|message| ConfigError::Override { origin: name.clone(), message })?;
+        }
         Ok(())
     }
+
+    /// Set one setting from its text form; `bind_<listener>` sets a bind address
+    pub fn set(&mut self, key: &str, raw: &str) -> Result<(), String> {
+        if let Some(listener) = key.strip_prefix("bind_").and_then(Listener::from_name) {
+            let ip = raw.trim().parse().map_err(|_| format!("{:?} is not an IP address", raw))?;
+            self.bind.insert(listener, ip);
+            return Ok(());
+        }
+
+        let mut settings = serde_json::to_value(&*self).map_err(|e| e.to_string())?;
+        let settings_map = settings.as_object_mut().expect("AppConfig serializes to an object");
+        let value = match settings_map.get(key) {
+            None | Some(Value::Object(_)) => return Err(format!("unknown setting {:?}", key)),
+            Some(Value::String(_)) => Value::String(raw.to_string()),
+            Some(Value::Bool(_)) => match raw.trim() {
+                "true" | "1" | "yes" | "on" => Value::Bool(true),
+                "false" | "0" | "no" | "off" => Value::Bool(false),
+                _ => return Err(format!("{:?} is not a boolean", raw)),
+            },
+            Some(_) => serde_json::from_str(raw.trim()).map_err(|_| format!("{:?} is not a number", raw))?,
+        };
+        settings_map.insert(key.to_string(), value);
+        *self = serde_json::from_value(settings).map_err(|e| format!("{:?}: {}", raw, e))?;
+        Ok(())
+    }
+
+    /// Every setting by name, as JSON
+    fn settings(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(settings)) => settings,
+            _ => unreachable!("AppConfig serializes to an object"),
+        }
+    }
+
+    /// Every setting by name, with secrets replaced by `REDACTED`; user and key names stay
+    pub fn redacted(&self) -> Map<String, Value> {
+        fn redact(value: &mut Value) {
+            match value {
+                Value::Object(entries) => entries.values_mut().for_each(redact),
+                _ => *value = Value::String(REDACTED.to_string()),
+            }
+        }
+        let mut settings = self.settings();
+        for key in SECRETS {
+            if let Some(value) = settings.get_mut(key) {
+                redact(value);
+            }
+        }
+        settings
+    }
+
+    /// Copy with the settings in `patch` replaced, e.g. `{"retry_timeout_ms": 2000}`
+    ///
+    /// A secret sent back as `REDACTED` is refused rather than saved.
+    pub fn patched(&self, patch: &Map<String, Value>) -> Result<Self, ConfigError> {
+        fn redacted(value: &Value) -> bool {
+            match value {
+                Value::String(text) => text == REDACTED,
+                Value::Object(entries) => entries.values().any(redacted),
+                _ => false,
+            }
+        }
+        let mut settings = self.settings();
+        for (key, value) in patch {
+            if !settings.contains_key(key) {
+                return Err(ConfigError::Override { origin: key.clone(), message: "unknown setting".to_string() });
+            }
+            if SECRETS.contains(&key.as_str()) && redacted(value) {
+                return Err(ConfigError::Override {
+                    origin: key.clone(),
+                    message: format!("{} is a placeholder, leave the setting out to keep it", REDACTED),
+                });
+            }
+            settings.insert(key.clone(), value.clone());
+        }
+        serde_json::from_value(Value::Object(settings))
+            .map_err(|e| ConfigError::Override { origin: "update".to_string(), message: e.to_string() })
+    }
+
+    /// Names of the settings whose values differ in `other`
+    pub fn differences(&self, other: &AppConfig) -> Vec<String> {
+        let other_settings = other.settings();
+        self.settings()
+            .into_iter()
+            .filter(|(key, value)| other_settings.get(key) != Some(value))
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Check everything that would otherwise fail later or confuse the cameras, reporting all problems at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        match self.server_ip.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => problems.push(format!(
+                "server_ip {} is what cameras connect to and cannot be unspecified", ip
+            )),
+            Ok(_) => {}
+            Err(_) => problems.push(format!("server_ip {:?} is not an IP address", self.server_ip)),
+        }
+        for (name, value) in [
+            ("client_target", &self.client_target),
+            ("client_token", &self.client_token),
+            ("server_token", &self.server_token),
+            ("uid_prefix", &self.uid_prefix),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(format!("{} must not be empty", name));
+            }
+        }
+        for (setting, name, hash) in self
+            .users
+            .iter()
+            .map(|(name, hash)| ("users", name, hash))
+            .chain(self.api_keys.iter().map(|(name, hash)| ("api_keys", name, hash)))
+        {
+            if argon2::PasswordHash::new(hash).is_err() {
+                problems.push(format!("{}.{} is not a password hash; create one with --hash-password", setting, name));
+            }
+            if setting == "api_keys" && (name.is_empty() || name.contains('.')) {
+                problems.push(format!("api_keys name {:?} must be non-empty and without dots", name));
+            }
+        }
+        if !(1..=crate::audio::BUFFERED_SECS).contains(&self.audio_clip_max_secs) {
+            problems.push(format!(
+                "audio_clip_max_secs must be between 1 and {}, the audio kept per camera",
+                crate::audio::BUFFERED_SECS
+            ));
+        }
+        if self.firmware_version.is_empty() != self.firmware_url.is_empty() {
+            problems.push("firmware_version and firmware_url must be set together".to_string());
+        }
+        for (name, value) in [
+            ("health_check_interval_ms", self.health_check_interval_ms),
+            ("retry_timeout_ms", self.retry_timeout_ms),
+            ("pairing_window_secs", self.pairing_window_secs),
+            ("session_ttl_secs", self.session_ttl_secs),
+            ("recording_segment_secs", self.recording_segment_secs),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        let listeners: Vec<Listener> = self.listeners().collect();
+        for listener in &listeners {
+            if self.port(*listener) == 0 {
+                problems.push(format!("{} port must not be 0", listener.name()));
+            }
+        }
+        for (i, first) in listeners.iter().enumerate() {
+            for second in &listeners[i + 1..] {
+                let (a, b) = (self.bind_addr(*first), self.bind_addr(*second));
+                let overlapping = a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified();
+                if first.is_udp() == second.is_udp() && a.port() == b.port() && overlapping {
+                    problems.push(format!(
+                        "{} ({}) and {} ({}) collide",
+                        first.name(), a, second.name(), b
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Listeners the server opens, leaving out those disabled
+    pub fn listeners(&self) -> impl Iterator<Item = Listener> + '_ {
+        Listener::ALL.into_iter().filter(|listener| self.enabled(*listener))
+    }
+
+    pub fn enabled(&self, listener: Listener) -> bool {
+        match listener {
+            Listener::Registration => self.registration_enabled,
+            Listener::Web => self.web_enabled,
+            Listener::Rtsp => self.rtsp_enabled,
+            _ => true,
+        }
+    }
+
+    pub fn port(&self, listener: Listener) -> u16 {
+        match listener {
+            Listener::Registration => self.tcp_registration_port,
+            Listener::TcpProtocol => self.tcp_protocol_port,
+            Listener::UdpProtocol => self.udp_protocol_port,
+            Listener::UdpStream1 => self.udp_stream_port_1,
+            Listener::UdpStream2 => self.udp_stream_port_2,
+            Listener::Web => self.web_port,
+            Listener::Rtsp => self.rtsp_port,
+        }
+    }
+
+    /// Where `listener` binds
+    pub fn bind_addr(&self, listener: Listener) -> SocketAddr {
+        let ip = self.bind.get(&listener).copied().unwrap_or(self.bind_ip);
+        SocketAddr::new(ip, self.port(listener))
+    }
+    
+
+    /// Write the settings named in `keys` into the TOML or JSON file at `path`
+    ///
+    /// The rest of the file stays as it is, comments and layout included; a
+    /// file that does not exist yet gets just these settings.
+    pub fn save_settings<'a>(&self, path: &Path, keys: impl IntoIterator<Item = &'a str>) -> Result<(), ConfigError> {
+        let save_error = |message: String| ConfigError::Save { path: path.to_path_buf(), message };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(source) => return Err(ConfigError::Read { path: path.to_path_buf(), source }),
+        };
+        let config_str = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => {
+                let mut document: DocumentMut = text.parse().map_err(|e: toml_edit::TomlError| save_error(e.to_string()))?;
+                let settings = toml_edit::ser::to_document(self).map_err(|e| save_error(e.to_string()))?;
+                for key in keys {
+                    let mut item = settings[key].clone();
+                    if let Some(table) = item.as_inline_table() {
+                        item = Item::Table(table.clone().into_table());
+                    }
+                    // Keep a trailing comment on the line
+                    if let (Some(value), Some(old)) = (item.as_value_mut(), document.get(key).and_then(Item::as_value)) {
+                        *value.decor_mut() = old.decor().clone();
+                    }
+                    document[key] = item;
+                }
+                document.to_string()
+            }
+            Some("json") => {
+                let mut document: Map<String, Value> = if text.trim().is_empty() {
+                    Map::new()
+                } else {
+                    serde_json::from_str(&text).map_err(|e| save_error(e.to_string()))?
+                };
+                let settings = self.settings();
+                for key in keys {
+                    document.insert(key.to_string(), settings[key].clone());
+                }
+                serde_json::to_string_pretty(&document).map_err(|e| save_error(e.to_string()))?
+            }
+            _ => return Err(ConfigError::Format(path.to_path_buf())),
+        };
+        fs::write(path, config_str).map_err(|e| save_error(e.to_string()))
+    }
+}
+
+/// The running configuration
+///
+/// Readers take `current()` whenever they need a setting, so a reload reaches
+/// the next message handled without dropping any camera. Settings in
+/// `RESTART_REQUIRED` keep their running values until the next start.
+#[derive(Debug)]
+pub struct LiveConfig {
+    current: ArcSwap<AppConfig>,
+    path: Option<PathBuf>, // File reloaded and saved to; without one updates stay in memory
+    overrides: Vec<String>, // --set flags, applied again on every reload
+    pending_restart: std::sync::Mutex<Vec<String>>,
+    updates: tokio::sync::Mutex<()>, // Serializes reloads and updates
+}
+
+impl LiveConfig {
+    /// Configuration not backed by a file
+    pub fn new(config: AppConfig) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(config),
+            path: None,
+            overrides: Vec::new(),
+            pending_restart: std::sync::Mutex::new(Vec::new()),
+            updates: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Configuration loaded from `path` (which need not exist yet) with `overrides` on top
+    pub fn with_file(config: AppConfig, path: PathBuf, overrides: Vec<String>) -> Self {
+        Self {
+            path: Some(path),
+            overrides,
+            ..Self::new(config)
+        }
+    }
+
+    pub fn current(&self) -> Arc<AppConfig> {
+        self.current.load_full()
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Changed settings waiting for a restart
+    pub fn pending_restart(&self) -> Vec<String> {
+        self.pending_restart.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// Read the file again, with the environment and `--set` flags on top
+    pub async fn reload(&self) -> Result<ConfigChange, ConfigError> {
+        let _updating = self.updates.lock().await;
+        let Some(path) = &self.path else {
+            return Ok(ConfigChange::default());
+        };
+        let file = if path.exists() { AppConfig::from_file(path)? } else { AppConfig::default() };
+        Ok(self.apply(file.layered(&self.overrides)?))
+    }
+
+    /// Change the settings in `patch` and save them to the file
+    ///
+    /// Nothing is applied or saved unless the result validates. Only the
+    /// patched settings are written, so the file keeps its comments and the
+    /// environment and `--set` flags, which still take precedence, stay out of it.
+    pub async fn update(&self, patch: &Map<String, Value>) -> Result<ConfigChange, ConfigError> {
+        let _updating = self.updates.lock().await;
+        let Some(path) = &self.path else {
+            let config = self.current().patched(patch)?;
+            config.validate()?;
+            return Ok(self.apply(config));
+        };
+        let file = if path.exists() { AppConfig::from_file(path)? } else { AppConfig::default() };
+        let file = file.patched(patch)?;
+        let config = file.clone().layered(&self.overrides)?;
+        file.save_settings(path, patch.keys().map(String::as_str))?;
+        Ok(self.apply(config))
+    }
+
+    /// Make `config` current, keeping the running values of the settings that need a restart
+    fn apply(&self, config: AppConfig) -> ConfigChange {
+        let running = self.current();
+        let mut change = ConfigChange::default();
+        for key in running.differences(&config) {
+            if RESTART_REQUIRED.contains(&key.as_str()) {
+                change.restart_required.push(key);
+            } else {
+                change.applied.push(key);
+            }
+        }
+
+        let mut settings = config.settings();
+        let running_settings = running.settings();
+        for key in RESTART_REQUIRED {
+            settings.insert(key.to_string(), running_settings[key].clone());
+        }
+        let live = serde_json::from_value(Value::Object(settings)).expect("settings of a valid configuration");
+        self.current.store(Arc::new(live));
+        *self.pending_restart.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = change.restart_required.clone();
+
+        if !change.applied.is_empty() {
+            tracing::info!("Configuration applied: {}", change.applied.join(", "));
+        }
+        if !change.restart_required.is_empty() {
+            tracing::warn!("Configuration changes waiting for a restart: {}", change.restart_required.join(", "));
+        }
+        change
+    }
+
+    /// Reload whenever the file's modification time changes, forever
+    ///
+    /// A file that fails to load or validate leaves the running configuration as it is.
+    pub async fn watch(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        let mut last_modified: Option<SystemTime> = modified(path);
+        let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let current = modified(path);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+            tracing::info!("{} changed, reloading the configuration", path.display());
+            if let Err(e) = self.reload().await {
+                tracing::error!("Keeping the running configuration: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layers_and_validation() {
+        let path = std::env::temp_dir().join(format!("a9-v720-config-{}.toml", std::process::id()));
+        fs::write(&path, "server_ip = \"10.0.0.2\"\nweb_port = 9000\n\n[bind]\nweb = \"127.0.0.1\"\n").unwrap();
+        let mut config = AppConfig::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((config.server_ip.as_str(), config.web_port, config.tcp_protocol_port), ("10.0.0.2", 9000, 6123));
+        assert_eq!(config.bind_addr(Listener::Web), "127.0.0.1:9000".parse().unwrap());
+
+        let env = [
+            ("A9_WEB_PORT", "9100"),
+            ("A9_RTSP_ENABLED", "true"),
+            ("A9_BIND_RTSP", "10.0.0.2"),
+            ("PATH", "/usr/bin"),
+        ];
+        config.apply_env(env.map(|(name, value)| (name.to_string(), value.to_string()))).unwrap();
+        assert_eq!(config.web_port, 9100);
+        assert_eq!(config.bind_addr(Listener::Rtsp), "10.0.0.2:8554".parse().unwrap());
+        config.validate().unwrap();
+
+        // The cloud emulation and the management interface no longer share a port
+        config.set("web_port", "80").unwrap();
+        assert!(config.validate().is_err());
+        config.set("web_enabled", "false").unwrap();
+        config.validate().unwrap();
+        config.set("web_port", "9100").unwrap();
+        config.set("web_enabled", "true").unwrap();
+
+        let unknown = config.apply_env([("A9_WEB_PROT".to_string(), "1".to_string())]);
+        assert!(matches!(unknown, Err(ConfigError::Override { .. })));
+        assert!(config.set("max_retries", "many").is_err());
+
+        // All problems are reported together
+        config.set("server_ip", "cameras.local").unwrap();
+        config.set("server_token", "").unwrap();
+        config.set("udp_stream_port_2", "6123").unwrap();
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("configuration should be invalid");
+        };
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[2].contains("udp_protocol"), "{:?}", problems);
+    }
+
+    #[tokio::test]
+    async fn test_live_updates_are_saved_and_reloaded() {
+        let path = std::env::temp_dir().join(format!("a9-v720-live-{}.toml", std::process::id()));
+        fs::write(&path, "# Where the cameras reach us\nserver_ip = \"10.0.0.2\" # LAN\n\n[bind]\nweb = \"0.0.0.0\"\n").unwrap();
+        let overrides = vec!["max_retries=5".to_string()];
+        let config = AppConfig::load(Some(&path), &overrides).unwrap();
+        let live = LiveConfig::with_file(config, path.clone(), overrides);
+
+        let patch = serde_json::json!({"retry_timeout_ms": 2000, "web_port": 9000});
+        let change = live.update(patch.as_object().unwrap()).await.unwrap();
+        assert_eq!(change.applied, ["retry_timeout_ms"]);
+        assert_eq!(change.restart_required, ["web_port"]);
+        assert_eq!((live.current().retry_timeout_ms, live.current().web_port), (2000, 8080));
+        assert_eq!(live.pending_restart(), ["web_port"]);
+
+        // Merged into the file, without the --set override or any default
+        let saved = AppConfig::from_file(&path).unwrap();
+        assert_eq!((saved.retry_timeout_ms, saved.web_port, saved.max_retries), (2000, 9000, 3));
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# Where the cameras reach us\nserver_ip = \"10.0.0.2\" # LAN\n"), "{}", text);
+        assert!(text.contains("[bind]\nweb = \"0.0.0.0\"\n") && !text.contains("max_retries"), "{}", text);
+
+        // An invalid update changes nothing
+        let patch = serde_json::json!({"server_ip": "0.0.0.0"});
+        assert!(matches!(live.update(patch.as_object().unwrap()).await, Err(ConfigError::Invalid(_))));
+        assert_eq!(live.current().server_ip, "10.0.0.2");
+
+        // Secrets are shown redacted, and the placeholder cannot be saved back
+        let mut config = AppConfig::default();
+        config.users.insert("admin".to_string(), "$argon2id$...".to_string());
+        let redacted = config.redacted();
+        assert_eq!((&redacted["users"], &redacted["server_token"]), (&serde_json::json!({"admin": REDACTED}), &serde_json::json!(REDACTED)));
+        let patch = serde_json::json!({"users": redacted["users"]});
+        assert!(matches!(live.update(patch.as_object().unwrap()).await, Err(ConfigError::Override { .. })));
+
+        // An edit of the file applies on reload; --set still wins
+        fs::write(&path, "server_ip = \"10.0.0.3\"\nmax_retries = 1\n\n[bind]\nweb = \"0.0.0.0\"\n").unwrap();
+        let change = live.reload().await.unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(change.applied, ["retry_timeout_ms", "server_ip"]);
+        assert_eq!((live.current().server_ip.as_str(), live.current().max_retries), ("10.0.0.3", 5));
+        assert!(live.pending_restart().is_empty());
+    }
 }
@@ -0,0 +1,19 @@
+//! A9 V720 camera server
+//!
+//! The protocol, routers and web server are exposed as a library so the
+//! server binary, the camera simulator and the integration tests share them.
+
+pub mod actor;
+pub mod audio;
+pub mod config;
+pub mod metrics;
+pub mod protocol;
+pub mod recording;
+pub mod registry;
+pub mod router;
+pub mod rtsp;
+pub mod state;
+pub mod stats;
+pub mod types;
+pub mod watchdog;
+pub mod web;
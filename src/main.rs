@@ -1,28 +1,78 @@
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use anyhow::Context;
+use clap::Parser;
 use tokio::net::TcpListener;
 use tokio::net::UdpSocket;
 
-mod config;
-mod types;
-mod protocol;
-mod router;
-mod web;
+use a9_v720_server::config::{AppConfig, Listener, LiveConfig};
+use a9_v720_server::registry::DeviceRegistry;
+use a9_v720_server::router::{tcp::TcpRouter, udp::UdpRouter};
+use a9_v720_server::rtsp;
+use a9_v720_server::types::CameraManager;
+use a9_v720_server::watchdog;
+use a9_v720_server::web::auth;
+use a9_v720_server::web::cloud::serve_cloud;
+use a9_v720_server::web::server::serve_web;
 
-use crate::config::AppConfig;
-use crate::router::{tcp::TcpRouter, udp::UdpRouter};
-use crate::types::CameraManager;
-use crate::web::server::start_web_server;
+/// Cloud replacement server for A9/V720 cameras
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Config file, .toml or .json [default: ./config.toml or ./config.json]
+    #[arg(long, env = "A9_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Override a setting, e.g. `--set web_port=9000` or `--set bind_web=127.0.0.1` (repeatable)
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+
+    /// Validate the configuration, print it as TOML and exit
+    #[arg(long)]
+    check: bool,
+
+    /// Read a password or API key secret from standard input, print its hash for `users` or `api_keys` and exit
+    #[arg(long)]
+    hash_password: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+    if args.hash_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let hash = auth::hash_password(password.trim_end_matches(['\r', '\n']))
+            .map_err(|e| format!("Failed to hash the password: {}", e))?;
+        println!("{}", hash);
+        return Ok(());
+    }
 
-    tracing::info!("Starting A9 V720 Camera Server");
+    // Initialize logging; --check prints only the configuration
+    if !args.check {
+        tracing_subscriber::fmt::init();
+    }
 
-    // Load configuration
-    let config = AppConfig::load()?;
+    // Defaults, config file, A9_* environment, --set
+    let config = match AppConfig::load(args.config.as_deref(), &args.overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if args.check {
+        print!("{}", toml::to_string_pretty(&config.redacted())?);
+        return Ok(());
+    }
+
+    tracing::info!("Starting A9 V720 Camera Server");
+    if config.web_enabled && !auth::enabled(&config) {
+        tracing::warn!("No users or API keys configured: the web interface and API are open to anyone who can reach them");
+    }
+    if config.rtsp_enabled && !auth::enabled(&config) {
+        tracing::warn!("No users or API keys configured: RTSP streams are open to anyone who can reach them");
+    }
     tracing::info!(
         "Configuration loaded: server_ip={}, tcp_port={}, web_port={}",
         config.server_ip,
@@ -30,54 +80,85 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         config.web_port
     );
 
-    // Create camera manager
-    let camera_manager = Arc::new(RwLock::new(CameraManager::new(config.clone())));
+    // Load the device registry and create camera manager
+    let registry = DeviceRegistry::load(&config.registry_path)?;
+    tracing::info!("Device registry {} loaded: {} known devices", config.registry_path, registry.devices().count());
+    // Reloaded when the file changes; the environment and --set stay on top
+    let config_path = AppConfig::locate(args.config.as_deref());
+    let live_config = LiveConfig::with_file(config.clone(), config_path, args.overrides);
+    let camera_manager = Arc::new(CameraManager::with_live_config(live_config, registry));
 
     // Start TCP router
-    let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", config.tcp_protocol_port)).await?;
-    tracing::info!("TCP router listening on port {}", config.tcp_protocol_port);
+    let tcp_listener = bind_tcp(&config, Listener::TcpProtocol).await?;
+    tracing::info!("TCP router listening on {}", config.bind_addr(Listener::TcpProtocol));
 
-    let tcp_router = TcpRouter::new(config.clone(), camera_manager.clone());
+    let tcp_router = TcpRouter::new(camera_manager.clone());
     let tcp_handle = tokio::spawn(async move {
         tcp_router.run(tcp_listener).await
     });
 
     // Start UDP routers on all three ports
     // UDP Protocol Port (6123)
-    let udp_socket_1 = UdpSocket::bind(format!("0.0.0.0:{}", config.udp_protocol_port)).await?;
-    let config_1 = config.clone();
+    let udp_socket_1 = bind_udp(&config, Listener::UdpProtocol).await?;
     let camera_manager_1 = camera_manager.clone();
     let udp_handle_1 = tokio::spawn(async move {
-        UdpRouter::start(udp_socket_1, camera_manager_1, config_1).await
+        UdpRouter::start(udp_socket_1, camera_manager_1).await
     });
     
     // UDP Stream Port 1 (53221)
-    let udp_socket_2 = UdpSocket::bind(format!("0.0.0.0:{}", config.udp_stream_port_1)).await?;
-    let config_2 = config.clone();
+    let udp_socket_2 = bind_udp(&config, Listener::UdpStream1).await?;
     let camera_manager_2 = camera_manager.clone();
     let udp_handle_2 = tokio::spawn(async move {
-        UdpRouter::start(udp_socket_2, camera_manager_2, config_2).await
+        UdpRouter::start(udp_socket_2, camera_manager_2).await
     });
     
     // UDP Stream Port 2 (41234) - also used as "random" port for video streaming
-    let udp_socket_3 = UdpSocket::bind(format!("0.0.0.0:{}", config.udp_stream_port_2)).await?;
-    let config_3 = config.clone();
+    let udp_socket_3 = bind_udp(&config, Listener::UdpStream2).await?;
     let camera_manager_3 = camera_manager.clone();
     let udp_handle_3 = tokio::spawn(async move {
-        UdpRouter::start(udp_socket_3, camera_manager_3, config_3).await
+        UdpRouter::start(udp_socket_3, camera_manager_3).await
     });
 
-    // Start HTTP registration server on port 80
-    let registration_camera_manager = camera_manager.clone();
-    let registration_handle = tokio::spawn(async move {
-        start_web_server(registration_camera_manager, 80).await
-    });
+    // Start the cloud emulation the cameras register through, if enabled
+    let registration_handle = if config.registration_enabled {
+        let registration_listener = bind_tcp(&config, Listener::Registration).await?;
+        let registration_camera_manager = camera_manager.clone();
+        Some(tokio::spawn(async move {
+            serve_cloud(registration_listener, registration_camera_manager).await
+        }))
+    } else {
+        None
+    };
+
+    // Start HTTP web interface if enabled
+    let web_handle = if config.web_enabled {
+        let web_listener = bind_tcp(&config, Listener::Web).await?;
+        let web_camera_manager = camera_manager.clone();
+        Some(tokio::spawn(async move {
+            serve_web(web_listener, web_camera_manager).await
+        }))
+    } else {
+        None
+    };
+
+    // Start RTSP server if enabled
+    let rtsp_handle = if config.rtsp_enabled {
+        let rtsp_listener = bind_tcp(&config, Listener::Rtsp).await?;
+        let rtsp_camera_manager = camera_manager.clone();
+        Some(tokio::spawn(async move {
+            rtsp::serve(rtsp_listener, rtsp_camera_manager).await
+        }))
+    } else {
+        None
+    };
+
+    // Notice cameras that went silent
+    let watchdog_handle = tokio::spawn(watchdog::run(camera_manager.clone()));
 
-    // Start HTTP web interface on configured web port
-    let web_camera_manager = camera_manager.clone();
-    let web_port = config.web_port;
-    let web_handle = tokio::spawn(async move {
-        start_web_server(web_camera_manager, web_port).await
+    // Apply edits of the config file without a restart
+    let watch_camera_manager = camera_manager.clone();
+    tokio::spawn(async move {
+        watch_camera_manager.config.watch().await
     });
 
     tracing::info!("Server started successfully. Waiting for connections...");
@@ -104,17 +185,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 tracing::error!("UDP router (Stream Port 2) failed: {}", e);
             }
         }
-        result = registration_handle => {
+        Some(result) = async { Some(registration_handle?.await) } => {
             if let Err(e) = result {
                 tracing::error!("Registration server failed: {}", e);
             }
         }
-        result = web_handle => {
+        Some(result) = async { Some(web_handle?.await) } => {
             if let Err(e) = result {
                 tracing::error!("Web interface failed: {}", e);
             }
         }
+        result = watchdog_handle => {
+            if let Err(e) = result {
+                tracing::error!("Watchdog failed: {}", e);
+            }
+        }
+        Some(result) = async { Some(rtsp_handle?.await) } => {
+            match result {
+                Ok(Err(e)) => tracing::error!("RTSP server failed: {:#}", e),
+                Err(e) => tracing::error!("RTSP server failed: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
     }
     
     Ok(())
 }
+
+async fn bind_tcp(config: &AppConfig, listener: Listener) -> anyhow::Result<TcpListener> {
+    let addr = config.bind_addr(listener);
+    TcpListener::bind(addr).await.with_context(|| format!("Failed to bind {} on {}", listener.name(), addr))
+}
+
+async fn bind_udp(config: &AppConfig, listener: Listener) -> anyhow::Result<UdpSocket> {
+    let addr = config.bind_addr(listener);
+    UdpSocket::bind(addr).await.with_context(|| format!("Failed to bind {} on {}", listener.name(), addr))
+}
@@ -0,0 +1,208 @@
+//! Prometheus metrics
+//!
+//! Traffic counters (TCP connections, UDP datagrams per port, JSON messages
+//! per code, refused registrations, HTTP latency) are updated where the traffic is handled. Camera
+//! figures (state, viewers, reassembly counters, CMD 605s) already live with
+//! each camera, so `/metrics` collects them at scrape time rather than
+//! keeping a second copy.
+
+use std::time::Duration;
+
+use prometheus_client::encoding::text::{encode_eof, encode_registry};
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use crate::types::{CameraManager, ProtocolState};
+
+/// Content type of the OpenMetrics text format
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Port label of the random video ports negotiated per camera, which come and go
+pub const RANDOM_PORT: &str = "random";
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct PortLabels {
+    pub port: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MessageLabels {
+    pub transport: &'static str,
+    pub code: u32,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RejectionLabels {
+    pub reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct HttpLabels {
+    pub method: String,
+    /// Route pattern such as `/api/cameras/:device_id`, so device IDs do not multiply series
+    pub path: String,
+    pub status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StateLabels {
+    state: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CameraLabels {
+    device_id: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DropLabels {
+    device_id: String,
+    reason: &'static str,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+/// Server-wide counters, shared through `CameraManager`
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    tcp_connections: Gauge,
+    udp_packets: Family<PortLabels, Counter>,
+    udp_bytes: Family<PortLabels, Counter>,
+    json_messages: Family<MessageLabels, Counter>,
+    registrations_rejected: Family<RejectionLabels, Counter>,
+    http_requests: HistogramFamily<HttpLabels>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("a9");
+        let tcp_connections = Gauge::default();
+        registry.register("tcp_connections", "Open camera control connections", tcp_connections.clone());
+        let udp_packets = Family::<PortLabels, Counter>::default();
+        registry.register("udp_packets", "UDP datagrams received per server port", udp_packets.clone());
+        let udp_bytes = Family::<PortLabels, Counter>::default();
+        registry.register("udp_bytes", "UDP bytes received per server port", udp_bytes.clone());
+        let json_messages = Family::<MessageLabels, Counter>::default();
+        registry.register("json_messages", "JSON messages received from cameras per code", json_messages.clone());
+        let registrations_rejected = Family::<RejectionLabels, Counter>::default();
+        registry.register(
+            "registrations_rejected",
+            "Code 100 registrations refused per reason",
+            registrations_rejected.clone(),
+        );
+        let http_requests: HistogramFamily<HttpLabels> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.001, 2.0, 14)));
+        registry.register("http_request_duration_seconds", "HTTP request latency", http_requests.clone());
+
+        Self {
+            registry,
+            tcp_connections,
+            udp_packets,
+            udp_bytes,
+            json_messages,
+            registrations_rejected,
+            http_requests,
+        }
+    }
+
+    pub fn tcp_connection_opened(&self) {
+        self.tcp_connections.inc();
+    }
+
+    pub fn tcp_connection_closed(&self) {
+        self.tcp_connections.dec();
+    }
+
+    /// A datagram of `bytes` arrived on `port` (a number or `RANDOM_PORT`)
+    pub fn udp_received(&self, port: &str, bytes: usize) {
+        let labels = PortLabels { port: port.to_string() };
+        self.udp_packets.get_or_create(&labels).inc();
+        self.udp_bytes.get_or_create(&labels).inc_by(bytes as u64);
+    }
+
+    pub fn json_received(&self, transport: &'static str, code: u32) {
+        self.json_messages.get_or_create(&MessageLabels { transport, code }).inc();
+    }
+
+    pub fn registration_rejected(&self, reason: &'static str) {
+        self.registrations_rejected.get_or_create(&RejectionLabels { reason }).inc();
+    }
+
+    pub fn http_request(&self, labels: HttpLabels, elapsed: Duration) {
+        self.http_requests.get_or_create(&labels).observe(elapsed.as_secs_f64());
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Everything in the OpenMetrics text format
+pub async fn render(camera_manager: &CameraManager) -> Result<String, std::fmt::Error> {
+    let mut cameras = Registry::with_prefix("a9");
+    let by_state = Family::<StateLabels, Gauge>::default();
+    let viewers = Family::<CameraLabels, Gauge>::default();
+    // Built afresh for every scrape, so a counter holds exactly the camera's total
+    let frames_assembled = Family::<CameraLabels, Counter>::default();
+    let frames_dropped = Family::<DropLabels, Counter>::default();
+    let confirmations = Family::<CameraLabels, Counter>::default();
+    let requests = Family::<CameraLabels, Counter>::default();
+
+    // Every state is reported, zero included
+    for state in [
+        ProtocolState::Disconnected,
+        ProtocolState::Configuring,
+        ProtocolState::Registering,
+        ProtocolState::Idle,
+        ProtocolState::NatTraversal,
+        ProtocolState::Streaming,
+        ProtocolState::Snapshot,
+        ProtocolState::Error,
+    ] {
+        by_state.get_or_create(&StateLabels { state: format!("{:?}", state) }).set(0);
+    }
+
+    for camera in camera_manager.handles() {
+        let device = CameraLabels { device_id: camera.device_id.clone() };
+        {
+            let camera_guard = camera.connection.read().await;
+            by_state.get_or_create(&StateLabels { state: format!("{:?}", camera_guard.state.current()) }).inc();
+            viewers.get_or_create(&device).set(camera_guard.viewer_count() as i64);
+        }
+        let Some(stats) = camera.stats().await else {
+            continue;
+        };
+        let reassembly = stats.reassembly;
+        frames_assembled.get_or_create(&device).inc_by(reassembly.frames_completed);
+        for (reason, count) in [
+            ("missing", reassembly.frames_dropped),
+            ("corrupt", reassembly.frames_corrupt),
+            ("expired", reassembly.frames_expired),
+        ] {
+            let labels = DropLabels { device_id: camera.device_id.clone(), reason };
+            frames_dropped.get_or_create(&labels).inc_by(count);
+        }
+        confirmations.get_or_create(&device).inc_by(stats.confirmations_sent);
+        requests.get_or_create(&device).inc_by(stats.requests_sent);
+    }
+
+    cameras.register("cameras", "Registered cameras per protocol state", by_state);
+    cameras.register("viewers", "Live viewers per camera", viewers);
+    cameras.register("frames_assembled", "Frames reassembled per camera", frames_assembled);
+    cameras.register("frames_dropped", "Frames given up per camera and reason", frames_dropped);
+    cameras.register("retransmission_confirmations", "CMD 605 confirmations sent per camera", confirmations);
+    cameras.register("retransmission_requests", "CMD 605 retransmission requests sent per camera", requests);
+
+    let mut output = String::new();
+    encode_registry(&mut output, &camera_manager.metrics.registry)?;
+    encode_registry(&mut output, &cameras)?;
+    encode_eof(&mut output)?;
+    Ok(output)
+}
@@ -98,63 +98,129 @@ impl ProtocolHeader {
     pub fn heartbeat(pkg_id: u32) -> Self {
         Self::new(100, 20, 255, pkg_id) // 20-byte heartbeat
     }
-
-    /// Create header for retransmission confirmation
-    /// Note: Protocol spec says CMD=605, but u8 can only hold 0-255
-    /// Using 245 as a placeholder until we verify the correct CMD value
-    pub fn retransmission(pkg_id: u32, data_length: usize) -> Self {
-        Self::new(245, data_length as u32, 255, pkg_id)
-    }
-
-    /// Create header for retransmission confirmation (code 605)
-    /// Since u8 can't hold 605, we'll use a special value and handle it in the protocol layer
-    pub fn retransmission_confirm(pkg_count: u32) -> Self {
-        // Use CMD=245 as a placeholder for retransmission confirmation
-        // The actual protocol expects CMD=605, but we'll handle this in the UDP layer
-        Self::new(245, pkg_count * 4, 255, 0) // 4 bytes per package ID
-    }
 }
 
-/// Retransmission confirmation structure
-#[derive(Debug, Clone)]
+/// Retransmission confirmation (CMD 605)
+///
+/// Laid out as in the working pcap the original UDP router was written from,
+/// not with the regular 20-byte header:
+/// `[u32 length][u32 cmd = 605][target id, 8 bytes][package IDs]`, where
+/// `length = 4 + 8 + 4 * count` counts the cmd word, the target id and the
+/// little-endian u32 package IDs but not itself.
+#[derive(Debug, Clone, PartialEq)]
 pub struct RetransmissionConfirm {
+    pub target_id: [u8; 8],
     pub received_packets: Vec<u32>,
 }
 
 impl RetransmissionConfirm {
-    /// Parse retransmission confirmation from bytes
-    pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        if data.len() % 4 != 0 {
-            return Err(anyhow::anyhow!("Invalid retransmission data length"));
-        }
+    pub const CMD: u16 = 605;
 
-        let mut received_packets = Vec::new();
-        let mut buf = data;
+    /// Length word, cmd word and target id
+    pub const HEADER_SIZE: usize = 16;
+
+    /// Target id the camera expects when no device is addressed
+    pub const DEFAULT_TARGET: [u8; 8] = *b"00000000";
 
-        while buf.remaining() >= 4 {
-            received_packets.push(buf.get_u32_le());
+    /// Create a confirmation for the default target
+    pub fn new(received_packets: Vec<u32>) -> Self {
+        Self {
+            target_id: Self::DEFAULT_TARGET,
+            received_packets,
         }
+    }
 
-        Ok(Self { received_packets })
+    /// Parse retransmission confirmation from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let (target_id, received_packets) = parse_package_ids(data, Self::CMD as u32)?;
+        Ok(Self { target_id, received_packets })
     }
 
     /// Serialize retransmission confirmation to bytes
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut buf = BytesMut::with_capacity(self.received_packets.len() * 4);
-        
-        for &packet_id in &self.received_packets {
-            buf.put_u32_le(packet_id);
-        }
-
-        buf.to_vec()
+        package_ids_to_bytes(Self::CMD as u32, &self.target_id, &self.received_packets)
     }
 
     /// Create empty retransmission confirmation
     pub fn empty() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+/// Retransmission request: a CMD 605 with `msg_flag = 1`
+///
+/// Same layout as `RetransmissionConfirm`, with 1 in the third byte of the
+/// cmd word, where a regular header has its msg_flag; the package IDs listed
+/// are the ones that did not arrive and should be sent again. This is the
+/// server's own convention, implemented by the simulator; no camera firmware
+/// is known to act on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetransmissionRequest {
+    pub target_id: [u8; 8],
+    pub missing_packets: Vec<u32>,
+}
+
+impl RetransmissionRequest {
+    pub const MSG_FLAG: u8 = 1;
+
+    /// Cmd word: 605 with `MSG_FLAG` in its third byte
+    const CMD_WORD: u32 = RetransmissionConfirm::CMD as u32 | (Self::MSG_FLAG as u32) << 16;
+
+    /// Create a request for the default target
+    pub fn new(missing_packets: Vec<u32>) -> Self {
         Self {
-            received_packets: Vec::new(),
+            target_id: RetransmissionConfirm::DEFAULT_TARGET,
+            missing_packets,
         }
     }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let (target_id, missing_packets) = parse_package_ids(data, Self::CMD_WORD)?;
+        Ok(Self { target_id, missing_packets })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        package_ids_to_bytes(Self::CMD_WORD, &self.target_id, &self.missing_packets)
+    }
+}
+
+/// Target id and package IDs of a CMD 605 whose cmd word is `cmd_word`
+fn parse_package_ids(data: &[u8], cmd_word: u32) -> Result<([u8; 8], Vec<u32>)> {
+    if data.len() < RetransmissionConfirm::HEADER_SIZE {
+        return Err(anyhow::anyhow!("CMD 605 too short: {} bytes", data.len()));
+    }
+    let mut buf = data;
+    let length = buf.get_u32_le() as usize;
+    let cmd = buf.get_u32_le();
+    if cmd != cmd_word {
+        return Err(anyhow::anyhow!("Not a CMD 605 with cmd word {:#x}: {:#x}", cmd_word, cmd));
+    }
+    if length != data.len() - 4 || !buf.remaining().is_multiple_of(4) {
+        return Err(anyhow::anyhow!("Invalid retransmission data length"));
+    }
+
+    let mut target_id = [0u8; 8];
+    buf.copy_to_slice(&mut target_id);
+    let mut packets = Vec::with_capacity(buf.remaining() / 4);
+    while buf.remaining() >= 4 {
+        packets.push(buf.get_u32_le());
+    }
+
+    Ok((target_id, packets))
+}
+
+fn package_ids_to_bytes(cmd_word: u32, target_id: &[u8; 8], packets: &[u32]) -> Vec<u8> {
+    let length = 4 + 8 + packets.len() * 4; // cmd word, target id and package IDs
+    let mut buf = BytesMut::with_capacity(4 + length);
+    buf.put_u32_le(length as u32);
+    buf.put_u32_le(cmd_word);
+    buf.put_slice(target_id);
+
+    for &packet_id in packets {
+        buf.put_u32_le(packet_id);
+    }
+
+    buf.to_vec()
 }
 
 #[cfg(test)]
@@ -175,12 +241,58 @@ mod tests {
 
     #[test]
     fn test_retransmission_serialization() {
-        let confirm = RetransmissionConfirm {
-            received_packets: vec![1, 2, 3, 4, 5],
-        };
+        let confirm = RetransmissionConfirm::new(vec![1, 2, 3, 4, 5]);
         let bytes = confirm.to_bytes();
         let parsed_confirm = RetransmissionConfirm::from_bytes(&bytes).unwrap();
         
         assert_eq!(confirm.received_packets, parsed_confirm.received_packets);
     }
+
+    // The messages the original UDP router built byte by byte, "following the
+    // working pcap format exactly": length word (cmd + target + ids), cmd 605
+    // as a u32, target "00000000", then the package IDs.
+
+    // Empty CMD 605 sent after the first end frame and in reply to heartbeats
+    const EMPTY_CONFIRM: [u8; 16] = [
+        0x0c, 0x00, 0x00, 0x00, // length: 4 + 8
+        0x5d, 0x02, 0x00, 0x00, // cmd 605
+        b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0', // target id
+    ];
+
+    // CMD 605 confirming package IDs 0x1a2 and 0x1a3
+    const BATCH_CONFIRM: [u8; 24] = [
+        0x14, 0x00, 0x00, 0x00, // length: 4 + 8 + 2 * 4
+        0x5d, 0x02, 0x00, 0x00,
+        b'0', b'0', b'0', b'0', b'0', b'0', b'0', b'0',
+        0xa2, 0x01, 0x00, 0x00,
+        0xa3, 0x01, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_retransmission_confirm_layout() {
+        assert_eq!(RetransmissionConfirm::empty().to_bytes(), EMPTY_CONFIRM);
+        assert_eq!(RetransmissionConfirm::from_bytes(&EMPTY_CONFIRM).unwrap(), RetransmissionConfirm::empty());
+
+        let batch = RetransmissionConfirm::new(vec![0x1a2, 0x1a3]);
+        assert_eq!(batch.to_bytes(), BATCH_CONFIRM);
+        assert_eq!(RetransmissionConfirm::from_bytes(&BATCH_CONFIRM).unwrap(), batch);
+    }
+
+    #[test]
+    fn test_retransmission_confirm_rejects_truncated_ids() {
+        let mut bytes = BATCH_CONFIRM.to_vec();
+        bytes.pop();
+        assert!(RetransmissionConfirm::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_retransmission_request_is_told_apart_from_confirm() {
+        let request = RetransmissionRequest::new(vec![0x1a2, 0x1a3]);
+        let bytes = request.to_bytes();
+        assert_eq!(bytes[6], RetransmissionRequest::MSG_FLAG);
+        assert_eq!(bytes[8..], BATCH_CONFIRM[8..]);
+        assert_eq!(RetransmissionRequest::from_bytes(&bytes).unwrap(), request);
+        assert!(RetransmissionConfirm::from_bytes(&bytes).is_err());
+        assert!(RetransmissionRequest::from_bytes(&BATCH_CONFIRM).is_err());
+    }
 }
@@ -0,0 +1,153 @@
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio_util::codec::{Decoder, Encoder, FramedWrite};
+
+use super::binary::ProtocolHeader;
+
+/// Write half of a camera control connection, framed with [`ProtocolCodec`]
+pub type ControlWriter = FramedWrite<OwnedWriteHalf, ProtocolCodec>;
+
+/// Length-delimited codec for the camera control channel
+///
+/// Every frame is a 20-byte [`ProtocolHeader`] followed by exactly
+/// `header.length` bytes of payload. The decoder buffers partial reads and
+/// splits coalesced ones, so one TCP read no longer has to equal one message.
+#[derive(Debug, Clone)]
+pub struct ProtocolCodec {
+    max_payload_len: usize,
+}
+
+impl ProtocolCodec {
+    /// Largest payload accepted by default (a full JPEG frame fits comfortably)
+    pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 1024 * 1024;
+
+    pub fn new() -> Self {
+        Self {
+            max_payload_len: Self::DEFAULT_MAX_PAYLOAD_LEN,
+        }
+    }
+
+    /// Create a codec that rejects payloads larger than `max_payload_len`
+    pub fn with_max_payload_len(max_payload_len: usize) -> Self {
+        Self { max_payload_len }
+    }
+}
+
+impl Default for ProtocolCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for ProtocolCodec {
+    type Item = (ProtocolHeader, Bytes);
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < ProtocolHeader::SIZE {
+            return Ok(None);
+        }
+
+        let (header, _) = ProtocolHeader::from_bytes(&src[..ProtocolHeader::SIZE])?;
+        let payload_len = header.length as usize;
+        if payload_len > self.max_payload_len {
+            return Err(anyhow::anyhow!(
+                "Frame payload too large: {} bytes (max {})",
+                payload_len,
+                self.max_payload_len
+            ));
+        }
+
+        let frame_len = ProtocolHeader::SIZE + payload_len;
+        if src.len() < frame_len {
+            // Wait for the rest of the payload
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(ProtocolHeader::SIZE);
+        let payload = src.split_to(payload_len).freeze();
+        Ok(Some((header, payload)))
+    }
+}
+
+impl Encoder<(ProtocolHeader, Bytes)> for ProtocolCodec {
+    type Error = anyhow::Error;
+
+    /// Encode a frame, setting `header.length` from the payload
+    fn encode(&mut self, item: (ProtocolHeader, Bytes), dst: &mut BytesMut) -> Result<()> {
+        let (mut header, payload) = item;
+        header.length = payload.len() as u32;
+
+        dst.reserve(ProtocolHeader::SIZE + payload.len());
+        dst.put_slice(&header.to_bytes());
+        dst.put_slice(&payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(header: ProtocolHeader, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        ProtocolCodec::new()
+            .encode((header, Bytes::copy_from_slice(payload)), &mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_decode_coalesced_frames() {
+        let mut buf = frame(ProtocolHeader::new(0, 0, 0, 1), br#"{"code":100}"#);
+        buf.extend_from_slice(&frame(ProtocolHeader::new(99, 0, 0, 2), b""));
+        buf.extend_from_slice(&frame(ProtocolHeader::new(0, 0, 0, 3), br#"{"code":12}"#));
+
+        let mut codec = ProtocolCodec::new();
+        let (h1, p1) = codec.decode(&mut buf).unwrap().unwrap();
+        let (h2, p2) = codec.decode(&mut buf).unwrap().unwrap();
+        let (h3, p3) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!((h1.cmd, h1.pkg_id, &p1[..]), (0, 1, &br#"{"code":100}"#[..]));
+        assert_eq!((h2.cmd, h2.pkg_id, p2.len()), (99, 2, 0));
+        assert_eq!((h3.cmd, h3.pkg_id, &p3[..]), (0, 3, &br#"{"code":12}"#[..]));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_split_frame() {
+        let whole = frame(ProtocolHeader::json(7, 0), br#"{"code":51,"status":1}"#);
+        let mut codec = ProtocolCodec::new();
+        let mut buf = BytesMut::new();
+
+        // Header split in two, then payload split in two
+        for chunk in [&whole[..10], &whole[10..20], &whole[20..25]] {
+            buf.extend_from_slice(chunk);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+        buf.extend_from_slice(&whole[25..]);
+
+        let (header, payload) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(header.pkg_id, 7);
+        assert_eq!(header.length as usize, payload.len());
+        assert_eq!(&payload[..], br#"{"code":51,"status":1}"#);
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_payload() {
+        let mut buf = BytesMut::from(&ProtocolHeader::new(1, 4096, 250, 0).to_bytes()[..]);
+        let mut codec = ProtocolCodec::with_max_payload_len(1024);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_encode_sets_length() {
+        let buf = frame(ProtocolHeader::new(0, 999, 0, 0), b"abc");
+        let (header, payload) = ProtocolHeader::from_bytes(&buf).unwrap();
+        assert_eq!(header.length, 3);
+        assert_eq!(payload, b"abc");
+    }
+}
@@ -1,4 +1,6 @@
-use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
 
 // HTTP Messages
 
@@ -91,7 +93,8 @@ pub struct NatProbeRequest {
     pub cli_nat_port: u16,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct NatProbeResponse {
     pub code: u32,
     pub status: u32,
@@ -119,7 +122,7 @@ pub struct DeviceStatusRequest {
 pub struct ForwardCommand {
     pub code: u32,
     pub target: String,
-    pub content: serde_json::Value,
+    pub content: Box<Message>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -143,9 +146,11 @@ pub struct UdpProbeResponse {
     pub port: u16,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Code51Response {
     pub code: u32,
+    #[serde(rename = "devTarget")]
     pub dev_target: String,
     pub status: u32,
 }
@@ -155,10 +160,248 @@ pub struct Code50Request {
     pub code: u32,
 }
 
+/// A camera setting changed with a 301 forward command
+///
+/// Each setting has its own content code and carries its value under the
+/// same key as the matching `DeviceInfo` field, e.g.
+/// `{"code":6,"ir_led":1}`. The camera echoes the command once applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSetting {
+    IrLed(u32),      // 6
+    InstLed(u32),    // 7
+    MirrorFlip(u32), // 8
+    SpeedGrade(u32), // 9
+}
+
+impl DeviceSetting {
+    pub fn code(&self) -> u32 {
+        match self {
+            DeviceSetting::IrLed(_) => 6,
+            DeviceSetting::InstLed(_) => 7,
+            DeviceSetting::MirrorFlip(_) => 8,
+            DeviceSetting::SpeedGrade(_) => 9,
+        }
+    }
+
+    /// Name of the `DeviceInfo` field, also the JSON key of the value
+    pub fn field(&self) -> &'static str {
+        match self {
+            DeviceSetting::IrLed(_) => "ir_led",
+            DeviceSetting::InstLed(_) => "inst_led",
+            DeviceSetting::MirrorFlip(_) => "mirror_flip",
+            DeviceSetting::SpeedGrade(_) => "speed_grade",
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        match *self {
+            DeviceSetting::IrLed(value)
+            | DeviceSetting::InstLed(value)
+            | DeviceSetting::MirrorFlip(value)
+            | DeviceSetting::SpeedGrade(value) => value,
+        }
+    }
+
+    /// Parse a setting message, `None` if the code or value is not recognised
+    fn from_value(code: u32, value: &Value) -> Option<Self> {
+        let setting = |field: &str| value.get(field).and_then(Value::as_u64).map(|v| v as u32);
+        match code {
+            6 => setting("ir_led").map(DeviceSetting::IrLed),
+            7 => setting("inst_led").map(DeviceSetting::InstLed),
+            8 => setting("mirror_flip").map(DeviceSetting::MirrorFlip),
+            9 => setting("speed_grade").map(DeviceSetting::SpeedGrade),
+            _ => None,
+        }
+    }
+
+    /// Record the setting in cached device info
+    pub fn apply(&self, info: &mut crate::types::DeviceInfo) {
+        match *self {
+            DeviceSetting::IrLed(value) => info.ir_led = value,
+            DeviceSetting::InstLed(value) => info.inst_led = value,
+            DeviceSetting::MirrorFlip(value) => info.mirror_flip = value,
+            DeviceSetting::SpeedGrade(value) => info.speed_grade = value,
+        }
+    }
+}
+
+// Typed message vocabulary
+
+/// Every JSON message exchanged with the camera, keyed by its `code`
+///
+/// Codes 0, 3 to 9 and 298 normally travel as the `content` of a 301
+/// forward command. Anything not recognised is kept as `Unknown`.
+#[derive(Debug, Clone)]
+pub enum Message {
+    StopStreaming,                                  // 0
+    StartStreaming,                                 // 3
+    DeviceInfoRequest { unix_timer: i64 },          // 4 (server -> camera)
+    DeviceInfo(crate::types::DeviceInfo),           // 4 (camera -> server)
+    Snapshot,                                       // 5
+    Setting(DeviceSetting),                         // 6-9
+    NatRequest(NatProbeRequest),                    // 11
+    NatResponse(NatProbeResponse),                  // 12
+    UdpProbe(UdpProbeRequest),                      // 20
+    UdpProbeResponse(UdpProbeResponse),             // 21
+    ProbeRequest(Code50Request),                    // 50
+    ProbeResponse(Code51Response),                  // 51
+    DeviceStatus(DeviceStatusRequest),              // 53
+    Register(RegistrationRequest),                  // 100
+    RegisterResponse(RegistrationResponse),         // 101
+    SnapshotRequest(SnapshotRequest),               // 201
+    SnapshotResponse(SnapshotResponse),             // 202
+    Retransmission,                                 // 298
+    Forward(ForwardCommand),                        // 301 with target/content
+    StreamingRequest(StreamingRequest),             // 301 with uid
+    StreamingResponse(StreamingResponse),           // 302
+    Unknown { code: u32, raw: Value },
+}
+
+impl Message {
+    /// The JSON `code` this message is sent with
+    pub fn code(&self) -> u32 {
+        match self {
+            Message::StopStreaming => 0,
+            Message::StartStreaming => 3,
+            Message::DeviceInfoRequest { .. } => 4,
+            Message::DeviceInfo(info) => info.code,
+            Message::Snapshot => 5,
+            Message::Setting(setting) => setting.code(),
+            Message::NatRequest(m) => m.code,
+            Message::NatResponse(m) => m.code,
+            Message::UdpProbe(m) => m.code,
+            Message::UdpProbeResponse(m) => m.code,
+            Message::ProbeRequest(m) => m.code,
+            Message::ProbeResponse(m) => m.code,
+            Message::DeviceStatus(m) => m.code,
+            Message::Register(m) => m.code,
+            Message::RegisterResponse(m) => m.code,
+            Message::SnapshotRequest(m) => m.code,
+            Message::SnapshotResponse(m) => m.code,
+            Message::Retransmission => 298,
+            Message::Forward(m) => m.code,
+            Message::StreamingRequest(m) => m.code,
+            Message::StreamingResponse(m) => m.code,
+            Message::Unknown { code, .. } => *code,
+        }
+    }
+
+    /// Convert to the JSON object sent on the wire
+    pub fn to_value(&self) -> serde_json::Result<Value> {
+        match self {
+            Message::StopStreaming
+            | Message::StartStreaming
+            | Message::Snapshot
+            | Message::Retransmission => Ok(serde_json::json!({ "code": self.code() })),
+            Message::DeviceInfoRequest { unix_timer } => Ok(serde_json::json!({
+                "unixTimer": unix_timer,
+                "code": 4
+            })),
+            Message::Setting(setting) => {
+                let mut value = serde_json::json!({ "code": setting.code() });
+                value[setting.field()] = setting.value().into();
+                Ok(value)
+            }
+            Message::DeviceInfo(m) => serde_json::to_value(m),
+            Message::NatRequest(m) => serde_json::to_value(m),
+            Message::NatResponse(m) => serde_json::to_value(m),
+            Message::UdpProbe(m) => serde_json::to_value(m),
+            Message::UdpProbeResponse(m) => serde_json::to_value(m),
+            Message::ProbeRequest(m) => serde_json::to_value(m),
+            Message::ProbeResponse(m) => serde_json::to_value(m),
+            Message::DeviceStatus(m) => serde_json::to_value(m),
+            Message::Register(m) => serde_json::to_value(m),
+            Message::RegisterResponse(m) => serde_json::to_value(m),
+            Message::SnapshotRequest(m) => serde_json::to_value(m),
+            Message::SnapshotResponse(m) => serde_json::to_value(m),
+            Message::Forward(m) => serde_json::to_value(m),
+            Message::StreamingRequest(m) => serde_json::to_value(m),
+            Message::StreamingResponse(m) => serde_json::to_value(m),
+            Message::Unknown { raw, .. } => Ok(raw.clone()),
+        }
+    }
+
+    /// Build a message from a parsed JSON object, dispatching on `code`
+    pub fn from_value(value: Value) -> serde_json::Result<Self> {
+        use serde::de::Error;
+
+        let code = value
+            .get("code")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| serde_json::Error::custom("missing numeric \"code\" field"))? as u32;
+
+        let message = match code {
+            0 => Message::StopStreaming,
+            3 => Message::StartStreaming,
+            4 => match value.get("unixTimer").and_then(Value::as_i64) {
+                Some(unix_timer) => Message::DeviceInfoRequest { unix_timer },
+                None => match serde_json::from_value(value.clone()) {
+                    Ok(info) => Message::DeviceInfo(info),
+                    Err(_) => Message::Unknown { code, raw: value },
+                },
+            },
+            5 => Message::Snapshot,
+            6..=9 => match DeviceSetting::from_value(code, &value) {
+                Some(setting) => Message::Setting(setting),
+                None => Message::Unknown { code, raw: value },
+            },
+            11 => Message::NatRequest(serde_json::from_value(value)?),
+            12 => Message::NatResponse(serde_json::from_value(value)?),
+            20 => Message::UdpProbe(serde_json::from_value(value)?),
+            21 => Message::UdpProbeResponse(serde_json::from_value(value)?),
+            50 => Message::ProbeRequest(serde_json::from_value(value)?),
+            51 => Message::ProbeResponse(serde_json::from_value(value)?),
+            53 => Message::DeviceStatus(serde_json::from_value(value)?),
+            100 => Message::Register(serde_json::from_value(value)?),
+            101 => Message::RegisterResponse(serde_json::from_value(value)?),
+            201 => Message::SnapshotRequest(serde_json::from_value(value)?),
+            202 => Message::SnapshotResponse(serde_json::from_value(value)?),
+            298 => Message::Retransmission,
+            301 if value.get("target").is_some() && value.get("content").is_some() => {
+                Message::Forward(serde_json::from_value(value)?)
+            }
+            301 if value.get("uid").is_some() => Message::StreamingRequest(serde_json::from_value(value)?),
+            302 => Message::StreamingResponse(serde_json::from_value(value)?),
+            _ => Message::Unknown { code, raw: value },
+        };
+
+        Ok(message)
+    }
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.to_value()
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        Message::from_value(value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Serialize a message to the JSON payload carried after the protocol header
+pub fn encode(message: &Message) -> Result<Vec<u8>> {
+    serde_json::to_vec(message).context("Failed to encode JSON message")
+}
+
+/// Parse a JSON payload received after the protocol header
+///
+/// Leading NUL padding sent by some firmware versions is ignored.
+pub fn decode(payload: &[u8]) -> Result<Message> {
+    let start = payload.iter().position(|&b| b != 0).unwrap_or(payload.len());
+    serde_json::from_slice(&payload[start..]).context("Failed to decode JSON message")
+}
+
 // Message creation helpers
 
 impl ConfigCheckResponse {
-    pub fn new(device_id: &str, config: &crate::config::AppConfig) -> Self {
+    /// Where `device_id` registers, and the `pwd` it registers with
+    pub fn new(device_id: &str, pwd: &str, config: &crate::config::AppConfig) -> Self {
         Self {
             code: 200,
             message: "操作成功".to_string(),
@@ -170,11 +413,20 @@ impl ConfigCheckResponse {
                 update_url: None,
                 host: config.server_ip.clone(),
                 curr_time: chrono::Utc::now().timestamp().to_string(),
-                pwd: config.server_token.clone(),
+                pwd: pwd.to_string(),
                 version: None,
             },
         }
     }
+
+    /// Offer the staged firmware, unless the camera already runs it
+    pub fn with_firmware(mut self, config: &crate::config::AppConfig, running: Option<&str>) -> Self {
+        if !config.firmware_version.is_empty() && running != Some(config.firmware_version.as_str()) {
+            self.data.update_url = Some(config.firmware_url.clone());
+            self.data.version = Some(config.firmware_version.clone());
+        }
+        self
+    }
 }
 
 impl RegistrationResponse {
@@ -184,6 +436,20 @@ impl RegistrationResponse {
             status: 200,
         }
     }
+
+    /// Answer to a registration the server does not accept
+    pub fn refused() -> Self {
+        Self {
+            code: 101,
+            status: 403,
+        }
+    }
+}
+
+impl Default for RegistrationResponse {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NatProbeRequest {
@@ -216,6 +482,12 @@ impl Code50Request {
     }
 }
 
+impl Default for Code50Request {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DeviceStatusRequest {
     pub fn new() -> Self {
         Self {
@@ -225,41 +497,107 @@ impl DeviceStatusRequest {
     }
 }
 
+impl Default for DeviceStatusRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ForwardCommand {
-    pub fn retransmission_request(config: &crate::config::AppConfig) -> Self {
-        let content = serde_json::json!({
-            "code": 298
-        });
-        
+    /// Wrap `content` in a 301 forward command addressed to the configured client target
+    pub fn new(config: &crate::config::AppConfig, content: Message) -> Self {
         Self {
             code: 301,
             target: config.client_target.clone(),
-            content,
+            content: Box::new(content),
         }
     }
 
+    pub fn retransmission_request(config: &crate::config::AppConfig) -> Self {
+        Self::new(config, Message::Retransmission)
+    }
+
     pub fn device_info_request(config: &crate::config::AppConfig) -> Self {
-        let content = serde_json::json!({
-            "unixTimer": chrono::Utc::now().timestamp(),
-            "code": 4
-        });
-        
-        Self {
-            code: 301,
-            target: config.client_target.clone(),
-            content,
-        }
+        Self::new(config, Message::DeviceInfoRequest {
+            unix_timer: chrono::Utc::now().timestamp(),
+        })
     }
 
     pub fn start_streaming_request(config: &crate::config::AppConfig) -> Self {
-        let content = serde_json::json!({
-            "code": 3
-        });
-        
-        Self {
-            code: 301,
-            target: config.client_target.clone(),
-            content,
+        Self::new(config, Message::StartStreaming)
+    }
+
+    pub fn stop_streaming_request(config: &crate::config::AppConfig) -> Self {
+        Self::new(config, Message::StopStreaming)
+    }
+
+    pub fn snapshot_request(config: &crate::config::AppConfig) -> Self {
+        Self::new(config, Message::Snapshot)
+    }
+
+    pub fn setting_request(config: &crate::config::AppConfig, setting: DeviceSetting) -> Self {
+        Self::new(config, Message::Setting(setting))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_dispatches_on_code() {
+        let message = decode(b"\0\0{\"code\":100,\"uid\":\"0800c00128F8\",\"token\":\"deadbeef\",\"domain\":\"v720.naxclow.com\"}").unwrap();
+        match message {
+            Message::Register(request) => assert_eq!(request.uid, "0800c00128F8"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        let message = decode(br#"{"code":51,"devTarget":"00112233445566778899aabbccddeeff","status":1}"#).unwrap();
+        assert!(matches!(message, Message::ProbeResponse(ref r) if r.status == 1));
+
+        let message = decode(br#"{"code":999,"foo":"bar"}"#).unwrap();
+        assert!(matches!(message, Message::Unknown { code: 999, ref raw } if raw["foo"] == "bar"));
+    }
+
+    #[test]
+    fn test_forward_command_round_trip() {
+        let config = crate::config::AppConfig::default();
+        let bytes = encode(&Message::Forward(ForwardCommand::start_streaming_request(&config))).unwrap();
+        let value: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(value, serde_json::json!({
+            "code": 301,
+            "target": "00112233445566778899aabbccddeeff",
+            "content": { "code": 3 }
+        }));
+
+        match decode(&bytes).unwrap() {
+            Message::Forward(forward) => assert!(matches!(*forward.content, Message::StartStreaming)),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_setting_round_trip() {
+        let config = crate::config::AppConfig::default();
+        let forward = ForwardCommand::setting_request(&config, DeviceSetting::MirrorFlip(3));
+        let value = Message::Forward(forward).to_value().unwrap();
+        assert_eq!(value["content"], serde_json::json!({ "code": 8, "mirror_flip": 3 }));
+
+        match Message::from_value(value).unwrap() {
+            Message::Forward(forward) => {
+                assert!(matches!(*forward.content, Message::Setting(DeviceSetting::MirrorFlip(3))))
+            }
+            other => panic!("unexpected message: {:?}", other),
         }
+
+        // A setting code without its value is kept as it came
+        let message = decode(br#"{"code":6}"#).unwrap();
+        assert!(matches!(message, Message::Unknown { code: 6, .. }));
+    }
+
+    #[test]
+    fn test_streaming_request_is_distinguished_from_forward() {
+        let message = decode(br#"{"code":301,"uid":"0800c00128F8"}"#).unwrap();
+        assert!(matches!(message, Message::StreamingRequest(_)));
     }
 }
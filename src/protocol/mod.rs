@@ -1,5 +1,9 @@
 pub mod binary;
+pub mod codec;
 pub mod messages;
+pub mod pending;
 
-pub use binary::ProtocolHeader;
+pub use binary::{ProtocolHeader, RetransmissionConfirm, RetransmissionRequest};
+pub use codec::{ControlWriter, ProtocolCodec};
 pub use messages::*;
+pub use pending::{PendingRequests, ReplyKey, RequestError};
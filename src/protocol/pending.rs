@@ -0,0 +1,150 @@
+//! Matching camera replies to the commands waiting for them
+//!
+//! Commands sent over the control channel carry a per-camera pkg_id in the
+//! protocol header. A reply is handed to the waiter whose command carried the
+//! same pkg_id; firmware that answers with pkg_id 0 is matched to the oldest
+//! waiter expecting that kind of reply. A reply with any other pkg_id answers
+//! a command nobody waits for any more, or one sent without waiting, and is
+//! handed to no one.
+
+use tokio::sync::oneshot;
+
+use super::Message;
+
+/// The kind of reply a command waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyKey {
+    /// A 301 forward command whose content has this code, e.g. an echoed 301/5
+    Forward(u32),
+    /// A top-level message with this code, e.g. the code 12 NAT response
+    Code(u32),
+}
+
+impl ReplyKey {
+    /// The key a received message answers
+    pub fn of(message: &Message) -> Self {
+        match message {
+            Message::Forward(forward) => ReplyKey::Forward(forward.content.code()),
+            message => ReplyKey::Code(message.code()),
+        }
+    }
+}
+
+/// Why a command got no reply
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    #[error("camera {0} not found")]
+    UnknownCamera(String),
+    #[error("camera has no control connection")]
+    NotConnected,
+    #[error("camera disconnected before replying")]
+    Disconnected,
+    #[error("failed to send command: {0:#}")]
+    Send(anyhow::Error),
+    #[error("no reply from the camera after {attempts} attempts")]
+    Timeout { attempts: u32 },
+}
+
+#[derive(Debug)]
+struct Waiter {
+    key: ReplyKey,
+    pkg_id: u32,
+    tx: oneshot::Sender<Message>,
+}
+
+/// Commands sent to one camera that are still waiting for their reply
+#[derive(Debug)]
+pub struct PendingRequests {
+    waiters: Vec<Waiter>,
+    next_pkg_id: u32,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        Self {
+            waiters: Vec::new(),
+            next_pkg_id: 1,
+        }
+    }
+
+    /// Wait for a reply matching `key`
+    ///
+    /// Returns the pkg_id to send the command with and the receiver of the reply.
+    pub fn register(&mut self, key: ReplyKey) -> (u32, oneshot::Receiver<Message>) {
+        // Drop waiters that gave up
+        self.waiters.retain(|waiter| !waiter.tx.is_closed());
+        let pkg_id = self.next_pkg_id();
+        let (tx, rx) = oneshot::channel();
+        self.waiters.push(Waiter { key, pkg_id, tx });
+        (pkg_id, rx)
+    }
+
+    /// pkg_id for a command that waits for nothing, so its echo resolves no waiter
+    pub fn next_pkg_id(&mut self) -> u32 {
+        let pkg_id = self.next_pkg_id;
+        // pkg_id 0 is what replies that cannot be correlated carry
+        self.next_pkg_id = self.next_pkg_id.checked_add(1).unwrap_or(1);
+        pkg_id
+    }
+
+    /// Hand a received message to the command waiting for it
+    ///
+    /// Returns false if nobody was waiting.
+    pub fn resolve(&mut self, pkg_id: u32, message: Message) -> bool {
+        self.waiters.retain(|waiter| !waiter.tx.is_closed());
+        let key = ReplyKey::of(&message);
+        let index = if pkg_id == 0 {
+            self.waiters.iter().position(|waiter| waiter.key == key)
+        } else {
+            self.waiters.iter().position(|waiter| waiter.key == key && waiter.pkg_id == pkg_id)
+        };
+        match index {
+            Some(index) => self.waiters.remove(index).tx.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Fail every waiting command, e.g. when the control connection closes
+    pub fn clear(&mut self) {
+        self.waiters.clear();
+    }
+}
+
+impl Default for PendingRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_by_pkg_id_or_oldest_without_one() {
+        let mut pending = PendingRequests::new();
+        let (first_id, mut first) = pending.register(ReplyKey::Forward(5));
+        let (second_id, mut second) = pending.register(ReplyKey::Forward(5));
+        let (_, mut nat) = pending.register(ReplyKey::Code(12));
+        assert_ne!(first_id, second_id);
+
+        let echo = || Message::Forward(crate::protocol::ForwardCommand::snapshot_request(&Default::default()));
+        assert!(pending.resolve(second_id, echo()));
+        assert!(second.try_recv().is_ok());
+        assert!(first.try_recv().is_err());
+
+        // A stale echo, or one of a command nobody waits for, resolves nothing
+        assert!(!pending.resolve(second_id, echo()));
+        let unawaited = pending.next_pkg_id();
+        assert!(!pending.resolve(unawaited, echo()));
+        assert!(first.try_recv().is_err());
+
+        // Without a pkg_id the oldest matching waiter gets the reply
+        assert!(pending.resolve(0, echo()));
+        assert!(first.try_recv().is_ok());
+        assert!(!pending.resolve(0, echo()));
+
+        pending.clear();
+        assert!(matches!(nat.try_recv(), Err(oneshot::error::TryRecvError::Closed)));
+    }
+}
@@ -0,0 +1,388 @@
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use crate::audio::SAMPLE_RATE as AUDIO_SAMPLE_RATE;
+use crate::config::AudioCodec;
+
+const VIDEO_CHUNK: &[u8; 4] = b"00dc";
+const AUDIO_CHUNK: &[u8; 4] = b"01wb";
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIF_ISINTERLEAVED: u32 = 0x100;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// Header fields only known once the segment is finished, as absolute file offsets
+#[derive(Debug, Default)]
+struct Patches {
+    riff_size: u64,
+    micro_sec_per_frame: u64,
+    max_bytes_per_sec: u64,
+    total_frames: u64,
+    suggested_buffer: u64,
+    video_scale: u64,
+    video_length: u64,
+    video_suggested_buffer: u64,
+    audio_length: u64,
+    audio_suggested_buffer: u64,
+    movi_size: u64,
+}
+
+/// Streaming writer for an AVI 1.0 file with one MJPEG and one G.711 stream
+///
+/// Chunks are appended as they arrive. The frame rate is not known up
+/// front, so `finish` derives it from the frame count and the segment
+/// duration, then writes the `idx1` index and patches the header sizes.
+pub struct AviWriter<W: Write + Seek> {
+    out: W,
+    position: u64,
+    patches: Patches,
+    /// Offset of the `movi` fourcc; `idx1` offsets are relative to it
+    movi_start: u64,
+    index: Vec<([u8; 4], u32, u32)>,
+    frames: u32,
+    audio_bytes: u32,
+    max_video_chunk: u32,
+    max_audio_chunk: u32,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// Write the headers for a `width` x `height` video stream
+    pub fn new(mut out: W, width: u16, height: u16, audio_codec: AudioCodec) -> io::Result<Self> {
+        let mut header = Header::default();
+        let mut patches = Patches::default();
+
+        header.fourcc(b"RIFF");
+        patches.riff_size = header.u32(0);
+        header.fourcc(b"AVI ");
+
+        let hdrl = header.begin_list(b"hdrl");
+        header.begin_chunk(b"avih");
+        patches.micro_sec_per_frame = header.u32(0);
+        patches.max_bytes_per_sec = header.u32(0);
+        header.u32(0); // padding granularity
+        header.u32(AVIF_HASINDEX | AVIF_ISINTERLEAVED);
+        patches.total_frames = header.u32(0);
+        header.u32(0); // initial frames
+        header.u32(2); // streams
+        patches.suggested_buffer = header.u32(0);
+        header.u32(width as u32);
+        header.u32(height as u32);
+        header.zeros(16); // reserved
+        header.end_chunk();
+
+        // Stream 0: MJPEG video
+        let strl = header.begin_list(b"strl");
+        header.begin_chunk(b"strh");
+        header.fourcc(b"vids");
+        header.fourcc(b"MJPG");
+        header.u32(0); // flags
+        header.u32(0); // priority, language
+        header.u32(0); // initial frames
+        patches.video_scale = header.u32(0);
+        header.u32(1_000_000); // rate: scale is in microseconds per frame
+        header.u32(0); // start
+        patches.video_length = header.u32(0);
+        patches.video_suggested_buffer = header.u32(0);
+        header.u32(u32::MAX); // quality: driver default
+        header.u32(0); // sample size: varies per frame
+        header.u16(0);
+        header.u16(0);
+        header.u16(width);
+        header.u16(height);
+        header.end_chunk();
+        header.begin_chunk(b"strf");
+        header.u32(40); // BITMAPINFOHEADER size
+        header.u32(width as u32);
+        header.u32(height as u32);
+        header.u16(1); // planes
+        header.u16(24); // bit count
+        header.fourcc(b"MJPG");
+        header.u32(width as u32 * height as u32 * 3);
+        header.zeros(16); // resolution, palette
+        header.end_chunk();
+        header.end_list(strl);
+
+        // Stream 1: G.711 audio, passed through as received
+        let strl = header.begin_list(b"strl");
+        header.begin_chunk(b"strh");
+        header.fourcc(b"auds");
+        header.u32(0); // handler
+        header.u32(0); // flags
+        header.u32(0); // priority, language
+        header.u32(0); // initial frames
+        header.u32(1); // scale
+        header.u32(AUDIO_SAMPLE_RATE);
+        header.u32(0); // start
+        patches.audio_length = header.u32(0);
+        patches.audio_suggested_buffer = header.u32(0);
+        header.u32(u32::MAX); // quality
+        header.u32(1); // sample size
+        header.zeros(8); // frame rectangle
+        header.end_chunk();
+        header.begin_chunk(b"strf");
+        header.u16(format_tag(audio_codec));
+        header.u16(1); // channels
+        header.u32(AUDIO_SAMPLE_RATE);
+        header.u32(AUDIO_SAMPLE_RATE); // bytes per second
+        header.u16(1); // block align
+        header.u16(8); // bits per sample
+        header.u16(0); // extra size
+        header.end_chunk();
+        header.end_list(strl);
+        header.end_list(hdrl);
+
+        header.fourcc(b"LIST");
+        patches.movi_size = header.u32(0);
+        let movi_start = header.bytes.len() as u64;
+        header.fourcc(b"movi");
+
+        out.write_all(&header.bytes)?;
+        Ok(Self {
+            out,
+            position: header.bytes.len() as u64,
+            patches,
+            movi_start,
+            index: Vec::new(),
+            frames: 0,
+            audio_bytes: 0,
+            max_video_chunk: 0,
+            max_audio_chunk: 0,
+        })
+    }
+
+    /// Append one JPEG frame
+    pub fn write_video(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        self.write_chunk(VIDEO_CHUNK, jpeg)?;
+        self.frames += 1;
+        self.max_video_chunk = self.max_video_chunk.max(jpeg.len() as u32);
+        Ok(())
+    }
+
+    /// Append G.711 samples
+    pub fn write_audio(&mut self, samples: &[u8]) -> io::Result<()> {
+        self.write_chunk(AUDIO_CHUNK, samples)?;
+        self.audio_bytes += samples.len() as u32;
+        self.max_audio_chunk = self.max_audio_chunk.max(samples.len() as u32);
+        Ok(())
+    }
+
+    /// Bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.position
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Write the index and the final header values; `duration` is the wall time the segment covers
+    pub fn finish(mut self, duration: Duration) -> io::Result<W> {
+        let idx1_start = self.position;
+        let mut idx1 = Vec::with_capacity(8 + self.index.len() * 16);
+        idx1.extend_from_slice(b"idx1");
+        idx1.extend_from_slice(&(self.index.len() as u32 * 16).to_le_bytes());
+        for (fourcc, offset, size) in &self.index {
+            idx1.extend_from_slice(fourcc);
+            idx1.extend_from_slice(&AVIIF_KEYFRAME.to_le_bytes());
+            idx1.extend_from_slice(&offset.to_le_bytes());
+            idx1.extend_from_slice(&size.to_le_bytes());
+        }
+        self.out.write_all(&idx1)?;
+        let end = idx1_start + idx1.len() as u64;
+
+        let micros = duration.as_micros().max(1) as u64;
+        let micro_sec_per_frame = (micros / self.frames.max(1) as u64).max(1) as u32;
+        let movi_bytes = idx1_start - self.movi_start;
+        let bytes_per_sec = (movi_bytes * 1_000_000 / micros).min(u32::MAX as u64) as u32;
+        let suggested_buffer = self.max_video_chunk.max(self.max_audio_chunk) + 8;
+
+        let patches = [
+            (self.patches.riff_size, (end - 8) as u32),
+            (self.patches.micro_sec_per_frame, micro_sec_per_frame),
+            (self.patches.max_bytes_per_sec, bytes_per_sec),
+            (self.patches.total_frames, self.frames),
+            (self.patches.suggested_buffer, suggested_buffer),
+            (self.patches.video_scale, micro_sec_per_frame),
+            (self.patches.video_length, self.frames),
+            (self.patches.video_suggested_buffer, self.max_video_chunk + 8),
+            (self.patches.audio_length, self.audio_bytes),
+            (self.patches.audio_suggested_buffer, self.max_audio_chunk + 8),
+            (self.patches.movi_size, movi_bytes as u32),
+        ];
+        for (offset, value) in patches {
+            self.out.seek(SeekFrom::Start(offset))?;
+            self.out.write_all(&value.to_le_bytes())?;
+        }
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_chunk(&mut self, fourcc: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        let offset = (self.position - self.movi_start) as u32;
+        self.out.write_all(fourcc)?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(data)?;
+        self.position += 8 + data.len() as u64;
+        if data.len() % 2 == 1 {
+            // RIFF chunks are word aligned
+            self.out.write_all(&[0])?;
+            self.position += 1;
+        }
+        self.index.push((*fourcc, offset, data.len() as u32));
+        Ok(())
+    }
+}
+
+/// WAVEFORMATEX format tag for the G.711 variant
+fn format_tag(codec: AudioCodec) -> u16 {
+    match codec {
+        AudioCodec::Alaw => 6,
+        AudioCodec::Ulaw => 7,
+    }
+}
+
+/// Header bytes under construction, with RIFF chunk and list sizes filled in on close
+#[derive(Default)]
+struct Header {
+    bytes: Vec<u8>,
+    open_chunk: Option<usize>,
+}
+
+impl Header {
+    fn fourcc(&mut self, fourcc: &[u8; 4]) {
+        self.bytes.extend_from_slice(fourcc);
+    }
+
+    /// Append a u32 and return its offset for later patching
+    fn u32(&mut self, value: u32) -> u64 {
+        let offset = self.bytes.len() as u64;
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        offset
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn zeros(&mut self, count: usize) {
+        self.bytes.resize(self.bytes.len() + count, 0);
+    }
+
+    fn begin_list(&mut self, list_type: &[u8; 4]) -> usize {
+        self.fourcc(b"LIST");
+        let size_at = self.u32(0) as usize;
+        self.fourcc(list_type);
+        size_at
+    }
+
+    fn end_list(&mut self, size_at: usize) {
+        let size = (self.bytes.len() - size_at - 4) as u32;
+        self.bytes[size_at..size_at + 4].copy_from_slice(&size.to_le_bytes());
+    }
+
+    fn begin_chunk(&mut self, fourcc: &[u8; 4]) {
+        self.fourcc(fourcc);
+        self.open_chunk = Some(self.u32(0) as usize);
+    }
+
+    fn end_chunk(&mut self) {
+        let size_at = self.open_chunk.take().expect("no open chunk");
+        let size = (self.bytes.len() - size_at - 4) as u32;
+        self.bytes[size_at..size_at + 4].copy_from_slice(&size.to_le_bytes());
+    }
+}
+
+/// Width and height from the first SOF segment of a JPEG image
+pub fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u16, u16)> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut i = 2;
+    while i + 4 <= jpeg.len() {
+        if jpeg[i] != 0xFF {
+            return None;
+        }
+        let marker = jpeg[i + 1];
+        if marker == 0xFF {
+            // Fill byte before a marker
+            i += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        // SOF0..SOF15, except DHT (C4), JPG (C8) and DAC (CC)
+        if matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            // Precision, then height and width
+            let sof = jpeg.get(i + 4..i + 9)?;
+            let height = u16::from_be_bytes([sof[1], sof[2]]);
+            let width = u16::from_be_bytes([sof[3], sof[4]]);
+            return Some((width, height));
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn find(data: &[u8], fourcc: &[u8]) -> usize {
+        data.windows(4).position(|w| w == fourcc).unwrap()
+    }
+
+    #[test]
+    fn test_finished_file_layout() {
+        let mut writer = AviWriter::new(Cursor::new(Vec::new()), 640, 480, AudioCodec::Alaw).unwrap();
+        writer.write_video(b"\xFF\xD8odd\xFF\xD9").unwrap();
+        writer.write_audio(&[0xD5; 160]).unwrap();
+        writer.write_video(b"\xFF\xD8even\xFF\xD9").unwrap();
+        let data = writer.finish(Duration::from_millis(200)).unwrap().into_inner();
+
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(&data[8..12], b"AVI ");
+
+        let avih = find(&data, b"avih") + 8;
+        assert_eq!(u32_at(&data, avih), 100_000); // two frames over 200 ms
+        assert_eq!(u32_at(&data, avih + 16), 2);
+        assert_eq!(u32_at(&data, avih + 32), 640);
+        assert_eq!(u32_at(&data, avih + 36), 480);
+
+        // The movi list ends exactly where idx1 begins
+        let movi = find(&data, b"movi");
+        let idx1 = find(&data, b"idx1");
+        assert_eq!(movi + u32_at(&data, movi - 4) as usize, idx1);
+
+        // Each index entry points at its chunk, relative to the movi fourcc
+        assert_eq!(u32_at(&data, idx1 + 4), 3 * 16);
+        let entries: Vec<(&[u8], usize, u32)> = data[idx1 + 8..]
+            .chunks(16)
+            .map(|e| (&e[..4], u32_at(e, 8) as usize, u32_at(e, 12)))
+            .collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, b"00dc");
+        assert_eq!(entries[1], (&b"01wb"[..], entries[0].1 + 8 + 8, 160));
+        for (fourcc, offset, size) in entries {
+            assert_eq!(&data[movi + offset..movi + offset + 4], fourcc);
+            assert_eq!(u32_at(&data, movi + offset + 4), size);
+        }
+    }
+
+    #[test]
+    fn test_jpeg_dimensions() {
+        // SOI, an APP0 stub, then SOF0 for 640x480
+        let jpeg = [
+            0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x01, 0xE0,
+            0x02, 0x80, 0x01, 0x01, 0x11, 0x00,
+        ];
+        assert_eq!(jpeg_dimensions(&jpeg), Some((640, 480)));
+        assert_eq!(jpeg_dimensions(b"not a jpeg"), None);
+    }
+}
@@ -0,0 +1,292 @@
+//! Recording of camera streams to time-segmented MJPEG AVI files
+//!
+//! A recording subscribes to a camera's completed frames and cmd 6 audio.
+//! An async task forwards them to a blocking writer thread, which rotates
+//! segments under `<recording_dir>/<device_id>/` and applies the retention
+//! limits each time a segment is closed.
+
+mod avi;
+pub mod retention;
+
+pub use avi::{jpeg_dimensions, AviWriter};
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::config::{AppConfig, AudioCodec};
+
+/// Segments are also closed at this size, well inside the 32-bit RIFF limits
+const MAX_SEGMENT_BYTES: u64 = 1 << 30;
+
+/// Samples queued between the async task and the writer thread
+const WRITER_QUEUE: usize = 64;
+
+/// Where and how recordings are written, from `AppConfig`
+#[derive(Debug, Clone)]
+pub struct RecordingSettings {
+    pub dir: PathBuf,
+    pub segment_length: Duration,
+    pub max_age: Option<Duration>,
+    pub max_total_bytes: Option<u64>,
+    pub audio_codec: AudioCodec,
+}
+
+impl RecordingSettings {
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            dir: PathBuf::from(&config.recording_dir),
+            segment_length: Duration::from_secs(config.recording_segment_secs.max(1)),
+            max_age: (config.recording_max_age_hours > 0)
+                .then(|| Duration::from_secs(config.recording_max_age_hours * 3600)),
+            max_total_bytes: (config.recording_max_total_mb > 0)
+                .then(|| config.recording_max_total_mb * 1024 * 1024),
+            audio_codec: config.audio_codec,
+        }
+    }
+}
+
+/// Progress of a recording, as reported by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordingStatus {
+    pub started_at: DateTime<Utc>,
+    pub current_file: Option<PathBuf>,
+    pub segments_completed: u64,
+    pub frames: u64,
+    pub audio_bytes: u64,
+}
+
+enum Sample {
+    Video(Bytes),
+    Audio(Bytes),
+}
+
+/// A running recording of one camera
+#[derive(Debug)]
+pub struct Recording {
+    status: Arc<Mutex<RecordingStatus>>,
+    stop_tx: oneshot::Sender<()>,
+    task: JoinHandle<Result<Vec<PathBuf>>>,
+}
+
+impl Recording {
+    /// Start recording the frames and audio published by a camera's `StreamBuffer`
+    pub fn start(
+        device_id: &str,
+        mut frames: broadcast::Receiver<Bytes>,
+        mut audio: broadcast::Receiver<Bytes>,
+        settings: RecordingSettings,
+    ) -> Self {
+        let status = Arc::new(Mutex::new(RecordingStatus {
+            started_at: Utc::now(),
+            current_file: None,
+            segments_completed: 0,
+            frames: 0,
+            audio_bytes: 0,
+        }));
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let (sample_tx, sample_rx) = mpsc::channel(WRITER_QUEUE);
+
+        let device_id = device_id.to_string();
+        let writer_status = status.clone();
+        let task = tokio::spawn(async move {
+            let writer = tokio::task::spawn_blocking(move || {
+                write_segments(&device_id, &settings, sample_rx, &writer_status)
+            });
+
+            loop {
+                let sample = tokio::select! {
+                    _ = &mut stop_rx => break,
+                    frame = frames.recv() => match frame {
+                        Ok(frame) => Sample::Video(frame),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::warn!("Recording fell behind, {} frames skipped", skipped);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    samples = audio.recv() => match samples {
+                        Ok(samples) => Sample::Audio(samples),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::warn!("Recording fell behind, {} audio packets skipped", skipped);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                };
+                if sample_tx.send(sample).await.is_err() {
+                    // The writer stopped on an error, reported below
+                    break;
+                }
+            }
+
+            drop(sample_tx);
+            writer.await.context("Recording writer panicked")?
+        });
+
+        Self { status, stop_tx, task }
+    }
+
+    pub fn status(&self) -> RecordingStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Whether the recording ended on its own, because of an error or the camera going away
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Close the current segment and return every file this recording wrote
+    pub async fn stop(self) -> Result<Vec<PathBuf>> {
+        // Fails only if the task already ended, in which case its result is what we want
+        let _ = self.stop_tx.send(());
+        self.task.await.context("Recording task panicked")?
+    }
+}
+
+/// One AVI file being written
+struct Segment {
+    path: PathBuf,
+    writer: AviWriter<BufWriter<File>>,
+    opened: Instant,
+}
+
+impl Segment {
+    /// Create the next segment, sized from its first frame; `None` if the frame has no size
+    fn create(camera_dir: &Path, device_id: &str, first_frame: &[u8], audio_codec: AudioCodec) -> Result<Option<Self>> {
+        let Some((width, height)) = jpeg_dimensions(first_frame) else {
+            return Ok(None);
+        };
+
+        let stem = format!("{}_{}", file_safe(device_id), Utc::now().format("%Y%m%dT%H%M%SZ"));
+        let mut path = camera_dir.join(format!("{}.avi", stem));
+        let mut n = 1;
+        while path.exists() {
+            path = camera_dir.join(format!("{}-{}.avi", stem, n));
+            n += 1;
+        }
+
+        let file = File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let writer = AviWriter::new(BufWriter::new(file), width, height, audio_codec)?;
+        retention::mark_open(&path);
+        tracing::info!("Recording segment {} started ({}x{})", path.display(), width, height);
+        Ok(Some(Self { path, writer, opened: Instant::now() }))
+    }
+
+    fn is_due(&self, settings: &RecordingSettings) -> bool {
+        self.opened.elapsed() >= settings.segment_length || self.writer.bytes_written() >= MAX_SEGMENT_BYTES
+    }
+
+    fn finish(self) -> Result<PathBuf> {
+        let frames = self.writer.frames();
+        let result = self.writer.finish(self.opened.elapsed());
+        retention::mark_closed(&self.path);
+        result.with_context(|| format!("Failed to finish {}", self.path.display()))?;
+        tracing::info!("Recording segment {} closed with {} frames", self.path.display(), frames);
+        Ok(self.path)
+    }
+}
+
+/// Writer thread: append samples to the current segment, rotating and pruning as it goes
+fn write_segments(
+    device_id: &str,
+    settings: &RecordingSettings,
+    mut samples: mpsc::Receiver<Sample>,
+    status: &Mutex<RecordingStatus>,
+) -> Result<Vec<PathBuf>> {
+    let camera_dir = settings.dir.join(file_safe(device_id));
+    fs::create_dir_all(&camera_dir).with_context(|| format!("Failed to create {}", camera_dir.display()))?;
+
+    let mut written = Vec::new();
+    let mut segment = None;
+    let result = append_samples(device_id, &camera_dir, settings, &mut samples, status, &mut segment, &mut written);
+
+    // Finish the last segment even after a write error, so what was recorded stays playable
+    let closed = close_segment(segment, settings, status, &mut written);
+    result.and(closed)?;
+    Ok(written)
+}
+
+fn append_samples(
+    device_id: &str,
+    camera_dir: &Path,
+    settings: &RecordingSettings,
+    samples: &mut mpsc::Receiver<Sample>,
+    status: &Mutex<RecordingStatus>,
+    segment: &mut Option<Segment>,
+    written: &mut Vec<PathBuf>,
+) -> Result<()> {
+    while let Some(sample) = samples.blocking_recv() {
+        match sample {
+            Sample::Video(frame) => {
+                if segment.as_ref().is_some_and(|s| s.is_due(settings)) {
+                    close_segment(segment.take(), settings, status, written)?;
+                }
+                if segment.is_none() {
+                    *segment = Segment::create(camera_dir, device_id, &frame, settings.audio_codec)?;
+                    let Some(opened) = segment.as_ref() else {
+                        tracing::warn!("Skipping frame without a readable size from {}", device_id);
+                        continue;
+                    };
+                    status.lock().unwrap().current_file = Some(opened.path.clone());
+                }
+                let current = segment.as_mut().expect("segment opened above");
+                current.writer.write_video(&frame)?;
+                status.lock().unwrap().frames += 1;
+            }
+            Sample::Audio(data) => {
+                // Audio before the first frame has no segment to go into
+                if let Some(current) = segment.as_mut() {
+                    current.writer.write_audio(&data)?;
+                    status.lock().unwrap().audio_bytes += data.len() as u64;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn close_segment(
+    segment: Option<Segment>,
+    settings: &RecordingSettings,
+    status: &Mutex<RecordingStatus>,
+    written: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let Some(segment) = segment else {
+        return Ok(());
+    };
+    let path = segment.finish()?;
+    {
+        let mut status = status.lock().unwrap();
+        status.current_file = None;
+        status.segments_completed += 1;
+    }
+    written.push(path);
+
+    match retention::enforce(&settings.dir, settings.max_age, settings.max_total_bytes) {
+        Ok(removed) => {
+            for path in removed {
+                tracing::info!("Retention removed {}", path.display());
+            }
+        }
+        Err(e) => tracing::warn!("Recording retention failed in {}: {}", settings.dir.display(), e),
+    }
+    Ok(())
+}
+
+/// Device IDs come from the network; keep only characters safe in a file name
+fn file_safe(device_id: &str) -> String {
+    device_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use once_cell::sync::Lazy;
+
+/// Segments currently being written by any camera; retention never deletes these
+static OPEN_SEGMENTS: Lazy<Mutex<HashSet<PathBuf>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+pub(super) fn mark_open(path: &Path) {
+    OPEN_SEGMENTS.lock().unwrap().insert(path.to_path_buf());
+}
+
+pub(super) fn mark_closed(path: &Path) {
+    OPEN_SEGMENTS.lock().unwrap().remove(path);
+}
+
+/// Delete finished segments under `dir` (one subdirectory per camera)
+/// older than `max_age`, then the oldest ones until the total fits `max_total_bytes`
+///
+/// Returns the deleted files.
+pub fn enforce(dir: &Path, max_age: Option<Duration>, max_total_bytes: Option<u64>) -> io::Result<Vec<PathBuf>> {
+    let open = OPEN_SEGMENTS.lock().unwrap().clone();
+    let mut segments = Vec::new();
+    for camera_dir in fs::read_dir(dir)? {
+        let camera_dir = camera_dir?.path();
+        if !camera_dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&camera_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "avi") || open.contains(&path) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            segments.push((metadata.modified()?, metadata.len(), path));
+        }
+    }
+    segments.sort();
+
+    let now = SystemTime::now();
+    let mut total: u64 = segments.iter().map(|(_, len, _)| len).sum();
+    let mut removed = Vec::new();
+    for (modified, len, path) in segments {
+        let expired = max_age.is_some_and(|max_age| now.duration_since(modified).unwrap_or_default() > max_age);
+        let over_budget = max_total_bytes.is_some_and(|max_total| total > max_total);
+        if !expired && !over_budget {
+            // Sorted oldest first: nothing newer is expired either
+            break;
+        }
+        fs::remove_file(&path)?;
+        total -= len;
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_age_then_size_limits() {
+        let dir = std::env::temp_dir().join(format!("a9-v720-retention-{}", std::process::id()));
+        let camera_dir = dir.join("camera");
+        fs::create_dir_all(&camera_dir).unwrap();
+
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        let mut paths = Vec::new();
+        for (name, age) in [("old.avi", 48 * hour), ("mid.avi", 2 * hour), ("new.avi", hour), ("open.avi", hour * 72)] {
+            let path = camera_dir.join(name);
+            let file = File::create(&path).unwrap();
+            file.set_len(100).unwrap();
+            file.set_modified(now - age).unwrap();
+            paths.push(path);
+        }
+        fs::write(camera_dir.join("notes.txt"), b"not a segment").unwrap();
+        mark_open(&paths[3]);
+
+        let removed = enforce(&dir, Some(24 * hour), Some(150)).unwrap();
+        assert_eq!(removed, vec![paths[0].clone(), paths[1].clone()]);
+        assert!(paths[2].exists());
+        assert!(paths[3].exists());
+        assert!(camera_dir.join("notes.txt").exists());
+
+        mark_closed(&paths[3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,411 @@
+//! Persistent device registry
+//!
+//! Cameras are identified by the uid they send as `devicesCode` in the
+//! config check and in the code 100 registration. The registry keeps one
+//! record per uid in a JSON file so names and device details survive
+//! restarts and IP changes.
+//!
+//! It also backs the cloud emulation: uids handed out by the bootstrap
+//! registration and the `pwd` each camera is given in the config check.
+//!
+//! A code 100 registration is only accepted from a paired device presenting
+//! its `pwd` (or `server_token` if it never got one). Unknown devices are
+//! paired automatically while the pairing window is open.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::types::DeviceInfo;
+
+/// Everything known about one camera
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    pub device_id: String,
+    /// Friendly name set through the API
+    pub name: Option<String>,
+    pub last_ip: Option<IpAddr>,
+    /// Token from the latest config check or registration
+    pub token: Option<String>,
+    /// Password handed out in the config check, which the camera registers with
+    pub pwd: Option<String>,
+    /// Set if the uid was handed out by the bootstrap registration
+    pub allocation: Option<Allocation>,
+    /// Whether the device may register; records saved before pairing existed were all registered cameras
+    #[serde(default = "paired_before_pairing")]
+    pub paired: bool,
+    pub firmware_version: Option<String>,
+    /// Latest 301/4 base info
+    pub device_info: Option<DeviceInfo>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+fn paired_before_pairing() -> bool {
+    true
+}
+
+/// Why a registration was accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Paired,
+    /// Not paired before, let in by the open pairing window
+    NewlyPaired,
+}
+
+/// Why a registration was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Rejection {
+    #[error("device is not paired and the pairing window is closed")]
+    NotPaired,
+    #[error("wrong token")]
+    BadToken,
+}
+
+impl Rejection {
+    /// Label of the rejection counter
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::NotPaired => "not_paired",
+            Rejection::BadToken => "bad_token",
+        }
+    }
+}
+
+/// A uid handed out by `registerDevices`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Allocation {
+    pub batch: Option<String>,
+    /// Nonce of the request; a retry with the same one gets the same uid
+    pub random: String,
+    /// MAC the camera sent, if any; it keeps getting the same uid
+    pub mac: Option<String>,
+    pub allocated: DateTime<Utc>,
+    /// When the camera confirmed it took the uid
+    pub confirmed: Option<DateTime<Utc>>,
+}
+
+impl DeviceRecord {
+    fn new(device_id: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            device_id: device_id.to_string(),
+            name: None,
+            last_ip: None,
+            token: None,
+            pwd: None,
+            allocation: None,
+            paired: false,
+            firmware_version: None,
+            device_info: None,
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+}
+
+/// Device records keyed by uid, written back to disk on every change
+#[derive(Debug)]
+pub struct DeviceRegistry {
+    path: Option<PathBuf>,
+    devices: BTreeMap<String, DeviceRecord>,
+    pairing_until: Option<DateTime<Utc>>, // Not saved: a restart closes the window
+}
+
+impl DeviceRegistry {
+    /// Load the registry stored at `path`; a missing file is an empty registry
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let devices = match fs::read_to_string(path) {
+            Ok(json) => {
+                let records: Vec<DeviceRecord> = serde_json::from_str(&json)
+                    .with_context(|| format!("Invalid device registry {}", path.display()))?;
+                records.into_iter().map(|record| (record.device_id.clone(), record)).collect()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Cannot read device registry {}", path.display())),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            devices,
+            pairing_until: None,
+        })
+    }
+
+    /// A registry that is never written to disk
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            devices: BTreeMap::new(),
+            pairing_until: None,
+        }
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&DeviceRecord> {
+        self.devices.get(device_id)
+    }
+
+    /// All records, ordered by device ID
+    pub fn devices(&self) -> impl Iterator<Item = &DeviceRecord> {
+        self.devices.values()
+    }
+
+    /// Create or change the record of `device_id`, refresh `last_seen` and save
+    ///
+    /// The in-memory record is updated even if saving fails.
+    pub fn update(&mut self, device_id: &str, change: impl FnOnce(&mut DeviceRecord)) -> Result<&DeviceRecord> {
+        let record = self
+            .devices
+            .entry(device_id.to_string())
+            .or_insert_with(|| DeviceRecord::new(device_id));
+        change(record);
+        record.last_seen = Utc::now();
+        self.save()?;
+        Ok(&self.devices[device_id])
+    }
+
+    /// Hand out a uid for a camera of `batch`: `prefix` followed by the next unused 4-digit hex serial
+    ///
+    /// A camera sending a `mac` seen before gets that uid again, confirmed or
+    /// not; a repeated request with the same `random` that has not been
+    /// confirmed yet does as well.
+    pub fn allocate(&mut self, prefix: &str, batch: Option<&str>, random: &str, mac: Option<&str>) -> Result<&DeviceRecord> {
+        let retry = self.devices.values().find(|record| {
+            record.allocation.as_ref().is_some_and(|allocation| {
+                let same_mac = mac.is_some() && allocation.mac.as_deref() == mac;
+                let same_request = !random.is_empty()
+                    && allocation.random == random
+                    && allocation.batch.as_deref() == batch
+                    && allocation.confirmed.is_none();
+                same_mac || same_request
+            })
+        });
+        if let Some(record) = retry {
+            let device_id = record.device_id.clone();
+            return Ok(&self.devices[&device_id]);
+        }
+
+        let serial = self
+            .devices
+            .keys()
+            .filter_map(|device_id| device_id.strip_prefix(prefix))
+            .filter(|serial| serial.len() == 4)
+            .filter_map(|serial| u16::from_str_radix(serial, 16).ok())
+            .max()
+            .map_or(Some(1), |last| last.checked_add(1));
+        let Some(serial) = serial else {
+            bail!("No uids left with prefix {}", prefix);
+        };
+        let device_id = format!("{}{:04X}", prefix, serial);
+        self.update(&device_id, |record| {
+            record.allocation = Some(Allocation {
+                batch: batch.map(String::from),
+                random: random.to_string(),
+                mac: mac.map(String::from),
+                allocated: Utc::now(),
+                confirmed: None,
+            });
+        })
+    }
+
+    /// Record that the camera took its allocated uid; `None` for a uid never handed out
+    pub fn confirm(&mut self, device_id: &str) -> Result<Option<&DeviceRecord>> {
+        if self.get(device_id).is_none_or(|record| record.allocation.is_none()) {
+            return Ok(None);
+        }
+        self.update(device_id, |record| {
+            if let Some(allocation) = &mut record.allocation {
+                allocation.confirmed.get_or_insert_with(Utc::now);
+            }
+        })
+        .map(Some)
+    }
+
+    /// The `pwd` of `device_id`, generated and saved the first time it is asked for
+    pub fn pwd(&mut self, device_id: &str) -> Result<String> {
+        if let Some(pwd) = self.get(device_id).and_then(|record| record.pwd.clone()) {
+            return Ok(pwd);
+        }
+        let pwd = format!("{:08x}", rand::thread_rng().gen::<u32>());
+        self.update(device_id, |record| record.pwd = Some(pwd.clone()))?;
+        Ok(pwd)
+    }
+
+    /// The `pwd` a config check presenting `token` is answered with, if any
+    ///
+    /// An unpaired device gets one, generated the first time, only while the
+    /// pairing window is open. A paired device gets its own back when `token`
+    /// is the one it registered with, or `server_token` from a `trusted`
+    /// caller (its known address, or anyone while pairing is open), so naming
+    /// a uid is not enough to learn its pwd.
+    pub fn check_pwd(&mut self, device_id: &str, token: &str, server_token: &str, trusted: bool) -> Result<Option<String>> {
+        let proven = match self.get(device_id) {
+            Some(record) if record.paired => {
+                record.token.as_deref() == Some(token) || (trusted && token == server_token)
+            }
+            _ => self.pairing_until().is_some(),
+        };
+        if !proven {
+            return Ok(None);
+        }
+        self.pwd(device_id).map(Some)
+    }
+
+    /// Let unknown devices pair for `duration` from now
+    pub fn open_pairing(&mut self, duration: chrono::Duration) -> DateTime<Utc> {
+        let until = Utc::now() + duration;
+        self.pairing_until = Some(until);
+        until
+    }
+
+    pub fn close_pairing(&mut self) {
+        self.pairing_until = None;
+    }
+
+    /// End of the pairing window, if it is open
+    pub fn pairing_until(&self) -> Option<DateTime<Utc>> {
+        self.pairing_until.filter(|until| *until > Utc::now())
+    }
+
+    /// Whether `device_id` may register with `token`
+    ///
+    /// The token must be the device's `pwd`, or `server_token` for a device
+    /// that never got one. The caller marks an admitted device as paired.
+    pub fn admit(&self, device_id: &str, token: &str, server_token: &str) -> Result<Admission, Rejection> {
+        let record = self.get(device_id);
+        let paired = record.is_some_and(|record| record.paired);
+        if !paired && self.pairing_until().is_none() {
+            return Err(Rejection::NotPaired);
+        }
+        let expected = record.and_then(|record| record.pwd.as_deref()).unwrap_or(server_token);
+        if token != expected {
+            return Err(Rejection::BadToken);
+        }
+        Ok(if paired { Admission::Paired } else { Admission::NewlyPaired })
+    }
+
+    /// Write all records, replacing the file atomically
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let records: Vec<&DeviceRecord> = self.devices.values().collect();
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&records)?)
+            .with_context(|| format!("Cannot write device registry {}", temp.display()))?;
+        fs::rename(&temp, path).with_context(|| format!("Cannot replace device registry {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_persists_across_loads() {
+        let path = std::env::temp_dir().join(format!("a9-v720-registry-{}/devices.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut registry = DeviceRegistry::load(&path).unwrap();
+        assert_eq!(registry.devices().count(), 0);
+        registry
+            .update("0800c00128F8", |record| {
+                record.last_ip = Some("192.168.1.50".parse().unwrap());
+                record.token = Some("deadbeef".to_string());
+            })
+            .unwrap();
+        let first_seen = registry.get("0800c00128F8").unwrap().first_seen;
+        registry
+            .update("0800c00128F8", |record| record.name = Some("Porch".to_string()))
+            .unwrap();
+
+        let reloaded = DeviceRegistry::load(&path).unwrap();
+        let record = reloaded.get("0800c00128F8").unwrap();
+        assert_eq!(record.name.as_deref(), Some("Porch"));
+        assert_eq!(record.last_ip, Some("192.168.1.50".parse().unwrap()));
+        assert_eq!(record.token.as_deref(), Some("deadbeef"));
+        assert_eq!(record.first_seen, first_seen);
+        assert!(record.last_seen >= first_seen);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_uids_are_allocated_in_sequence() {
+        let mut registry = DeviceRegistry::in_memory();
+        registry.update("0800c00128F8", |_| {}).unwrap();
+        registry.update("0800c001ZZZZ", |_| {}).unwrap();
+
+        let first = registry.allocate("0800c001", Some("A9_48PIN_B"), "ABCDEF", None).unwrap().device_id.clone();
+        assert_eq!(first, "0800c00128F9");
+        // A retried request gets the same uid until it is confirmed
+        assert_eq!(registry.allocate("0800c001", Some("A9_48PIN_B"), "ABCDEF", None).unwrap().device_id, first);
+        assert!(registry.confirm(&first).unwrap().is_some());
+        assert_eq!(registry.allocate("0800c001", Some("A9_48PIN_B"), "ABCDEF", None).unwrap().device_id, "0800c00128FA");
+        assert_eq!(registry.allocate("a9", None, "", None).unwrap().device_id, "a90001");
+
+        // A camera known by its MAC keeps its uid, even once confirmed
+        let by_mac = registry.allocate("a9", None, "1", Some("AA:BB:CC:00:11:22")).unwrap().device_id.clone();
+        assert!(registry.confirm(&by_mac).unwrap().is_some());
+        assert_eq!(registry.allocate("a9", None, "2", Some("AA:BB:CC:00:11:22")).unwrap().device_id, by_mac);
+        assert!(registry.confirm("0800c00128F8").unwrap().is_none());
+
+        let pwd = registry.pwd(&first).unwrap();
+        assert_eq!(pwd.len(), 8);
+        assert_eq!(registry.pwd(&first).unwrap(), pwd);
+    }
+
+    #[test]
+    fn test_registration_needs_pairing_and_token() {
+        let mut registry = DeviceRegistry::in_memory();
+        let pwd = registry.pwd("0800c00128F8").unwrap();
+        assert_eq!(registry.admit("0800c00128F8", &pwd, "deadbeef"), Err(Rejection::NotPaired));
+
+        registry.open_pairing(chrono::Duration::minutes(5));
+        assert_eq!(registry.admit("0800c00128F8", "deadbeef", "deadbeef"), Err(Rejection::BadToken));
+        assert_eq!(registry.admit("0800c00128F8", &pwd, "deadbeef"), Ok(Admission::NewlyPaired));
+        // Without a pwd the server token is expected
+        assert_eq!(registry.admit("0800c0019999", "deadbeef", "deadbeef"), Ok(Admission::NewlyPaired));
+
+        registry.update("0800c00128F8", |record| record.paired = true).unwrap();
+        registry.close_pairing();
+        assert_eq!(registry.admit("0800c00128F8", &pwd, "deadbeef"), Ok(Admission::Paired));
+        assert_eq!(registry.admit("0800c00128F8", "deadbeef", "deadbeef"), Err(Rejection::BadToken));
+        assert_eq!(registry.admit("0800c0019999", "deadbeef", "deadbeef"), Err(Rejection::NotPaired));
+    }
+
+    #[test]
+    fn test_config_check_pwd_needs_pairing_or_token() {
+        let mut registry = DeviceRegistry::in_memory();
+        assert_eq!(registry.check_pwd("0800c00128F8", "deadbeef", "deadbeef", false).unwrap(), None);
+        assert!(registry.get("0800c00128F8").is_none());
+
+        registry.open_pairing(chrono::Duration::minutes(5));
+        let pwd = registry.check_pwd("0800c00128F8", "deadbeef", "deadbeef", true).unwrap().unwrap();
+        assert_eq!(registry.check_pwd("0800c00128F8", "other", "deadbeef", true).unwrap(), Some(pwd.clone()));
+
+        // Once paired, the token it registered with gets the pwd back, and the
+        // server token only from a trusted caller
+        registry.update("0800c00128F8", |record| {
+            record.paired = true;
+            record.token = Some(pwd.clone());
+        })
+        .unwrap();
+        registry.close_pairing();
+        assert_eq!(registry.check_pwd("0800c00128F8", "other", "deadbeef", true).unwrap(), None);
+        assert_eq!(registry.check_pwd("0800c00128F8", "deadbeef", "deadbeef", false).unwrap(), None);
+        assert_eq!(registry.check_pwd("0800c00128F8", "deadbeef", "deadbeef", true).unwrap(), Some(pwd.clone()));
+        assert_eq!(registry.check_pwd("0800c00128F8", &pwd, "deadbeef", false).unwrap(), Some(pwd));
+    }
+}
@@ -1,24 +1,47 @@
 use anyhow::Result;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+use crate::actor::{CameraHandle, MediaPacket};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use futures::{SinkExt, StreamExt};
+use bytes::Bytes;
 use crate::config::AppConfig;
-use crate::types::{CameraManager, ProtocolState};
-use crate::protocol::{ProtocolHeader, RegistrationRequest, RegistrationResponse, SnapshotRequest, SnapshotResponse, StreamingRequest, StreamingResponse};
-use std::net::IpAddr;
-use tokio::sync::Mutex;
-use crate::protocol::ForwardCommand;
+use crate::registry::Admission;
+use crate::types::{CameraConnection, CameraManager, DeviceInfo, ProtocolState};
+use crate::protocol::{decode, encode, ControlWriter, Message, ProtocolCodec, ProtocolHeader, RegistrationRequest, RegistrationResponse, SnapshotRequest, SnapshotResponse, StreamingRequest, StreamingResponse};
+use crate::protocol::{Code50Request, Code51Response, DeviceStatusRequest, NatProbeRequest};
+use crate::protocol::{ForwardCommand, ReplyKey, RequestError};
 
 pub struct TcpRouter {
-    config: AppConfig,
-    camera_manager: Arc<RwLock<CameraManager>>,
+    camera_manager: Arc<CameraManager>,
+}
+
+/// One camera control connection
+struct ControlConnection {
+    addr: SocketAddr,
+    writer: Arc<Mutex<ControlWriter>>,
+    /// Stops the read loop, e.g. when the watchdog gives up on the camera
+    stop: Arc<tokio::sync::Notify>,
+    /// The camera this connection belongs to, known once it sends code 100
+    camera: Option<CameraHandle>,
+    /// Set when the registration was refused; the connection is closed
+    refused: bool,
+}
+
+impl ControlConnection {
+    /// Move the camera to `state`, if the connection has registered
+    async fn set_state(&self, state: ProtocolState, reason: &str) {
+        if let Some(camera) = &self.camera {
+            camera.connection.write().await.set_state(state, reason);
+        }
+    }
 }
 
 impl TcpRouter {
-    pub fn new(config: AppConfig, camera_manager: Arc<RwLock<CameraManager>>) -> Self {
+    pub fn new(camera_manager: Arc<CameraManager>) -> Self {
         Self {
-            config,
             camera_manager,
         }
     }
@@ -28,14 +51,15 @@ impl TcpRouter {
             match listener.accept().await {
                 Ok((socket, addr)) => {
                     tracing::info!("TCP connection from {}", addr);
-                    
+
                     let camera_manager = self.camera_manager.clone();
-                    let config = self.config.clone();
-                    
+
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_connection(socket, addr, camera_manager, config).await {
+                        camera_manager.metrics.tcp_connection_opened();
+                        if let Err(e) = Self::handle_connection(socket, addr, camera_manager.clone()).await {
                             tracing::error!("TCP connection error: {}", e);
                         }
+                        camera_manager.metrics.tcp_connection_closed();
                     });
                 }
                 Err(e) => {
@@ -46,748 +70,561 @@ impl TcpRouter {
     }
 
     async fn handle_connection(
-        mut socket: TcpStream,
-        addr: std::net::SocketAddr,
-        camera_manager: Arc<RwLock<CameraManager>>,
-        config: AppConfig,
+        socket: TcpStream,
+        addr: SocketAddr,
+        camera_manager: Arc<CameraManager>,
     ) -> Result<()> {
-        let source_ip = addr.ip();
-        
         // Split TCP stream for concurrent read/write
-        let (mut read_half, write_half) = socket.into_split();
-        
-        // Store TCP connection in camera manager
-        {
-            let mut manager = camera_manager.write().await;
-            let camera = manager.get_or_create_camera(source_ip).await;
-            let mut camera_guard = camera.write().await;
-            camera_guard.tcp_conn = Some(Arc::new(tokio::sync::Mutex::new(write_half)));
-            camera_guard.state = ProtocolState::Configuring;
-        }
+        let (read_half, write_half) = socket.into_split();
 
-        let mut buffer = [0u8; 4096];
-        
+        // The camera is only known once it registers; until then replies go out on this writer alone
+        let mut connection = ControlConnection {
+            addr,
+            writer: Arc::new(Mutex::new(FramedWrite::new(write_half, ProtocolCodec::new()))),
+            stop: Arc::new(tokio::sync::Notify::new()),
+            camera: None,
+            refused: false,
+        };
+
+        // Decode complete frames regardless of how TCP segments them
+        let mut frames = FramedRead::new(read_half, ProtocolCodec::new());
+
+        let stop = connection.stop.clone();
         loop {
-            match read_half.read(&mut buffer).await {
-                Ok(0) => {
+            let frame = tokio::select! {
+                frame = frames.next() => frame,
+                _ = stop.notified() => {
+                    tracing::info!("Stopped reading the control connection of {}", addr);
+                    break;
+                }
+            };
+            match frame {
+                None => {
                     tracing::info!("TCP connection closed by {}", addr);
                     break;
                 }
-                Ok(n) => {
-                    let data = &buffer[..n];
-                    if let Err(e) = Self::process_message(data, source_ip, &camera_manager, &config).await {
-                        tracing::error!("Error processing message from {}: {}", source_ip, e);
+                Some(Ok((header, payload))) => {
+                    // Each message sees the configuration as it is now
+                    let config = camera_manager.config.current();
+                    if let Err(e) = Self::process_message(header, &payload, &mut connection, &camera_manager, &config).await {
+                        tracing::error!("Error processing message from {}: {}", addr, e);
+                        break;
+                    }
+                    if connection.refused {
                         break;
                     }
                 }
-                Err(e) => {
+                Some(Err(e)) => {
                     tracing::error!("TCP read error from {}: {}", addr, e);
                     break;
                 }
             }
         }
 
-        // Clean up connection
-        {
-            let mut manager = camera_manager.write().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
-                let mut camera_guard = camera.write().await;
+        // Clean up connection, unless the camera has already reconnected on a new one
+        if let Some(camera) = &connection.camera {
+            let mut camera_guard = camera.connection.write().await;
+            if camera_guard.tcp_conn.as_ref().is_some_and(|tcp_conn| Arc::ptr_eq(tcp_conn, &connection.writer)) {
                 camera_guard.tcp_conn = None;
-                camera_guard.state = ProtocolState::Disconnected;
+                camera_guard.tcp_reader_stop = None;
+                camera_guard.end_session(ProtocolState::Disconnected, "control connection closed");
             }
         }
-        
+
         Ok(())
     }
 
     async fn process_message(
-        data: &[u8],
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-        _config: &AppConfig,
+        header: ProtocolHeader,
+        payload: &[u8],
+        connection: &mut ControlConnection,
+        camera_manager: &Arc<CameraManager>,
+        config: &AppConfig,
     ) -> Result<()> {
-        // Parse protocol header
-        let (header, payload) = ProtocolHeader::from_bytes(data)?;
-        
-        tracing::debug!("Received message from {}: CMD={}, length={}", 
+        let source_ip = connection.addr.ip();
+        tracing::debug!("Received message from {}: CMD={}, length={}",
                        source_ip, header.cmd, payload.len());
-        
+        // Any frame shows the control connection is alive; the watchdog relies on this
+        if let Some(camera) = &connection.camera {
+            camera.connection.write().await.update_keepalive();
+        }
+
         match header.cmd {
             0 | 87 => {
                 // JSON message (CMD=0 or CMD=87)
-                if let Ok(json_str) = String::from_utf8(payload.to_vec()) {
-                    // Strip null bytes from the beginning of the JSON string
-                    let clean_json_str = json_str.trim_start_matches('\0');
-                    tracing::debug!("Received JSON message from {}: {}", source_ip, clean_json_str);
-                    
-                    match serde_json::from_str::<serde_json::Value>(&clean_json_str) {
-                        Ok(json) => {
-                            tracing::debug!("Parsed JSON successfully");
-                            if let Some(code) = json["code"].as_u64() {
-                                match code {
-                                    100 => {
-                                        // Registration request
-                                        Self::handle_registration(clean_json_str.to_string(), source_ip, camera_manager).await?;
-                                    }
-                                    12 => {
-                                        // NAT probe response
-                                        Self::handle_nat_probe_response(clean_json_str.to_string(), source_ip, camera_manager).await?;
-                                    }
-                                    51 => {
-                                        // Device info request - send device info response with Command 51
-                                        Self::handle_device_info_request(clean_json_str.to_string(), source_ip, camera_manager).await?;
-                                    }
-                                    201 => {
-                                        // Snapshot request (simple protocol)
-                                        Self::handle_snapshot_request(clean_json_str.to_string(), source_ip, camera_manager).await?;
-                                    }
-                                    301 => {
-                                        // Check if this is a forward command echo or actual streaming request
-                                        if json.get("target").is_some() && json.get("content").is_some() {
-                                            // This is an echoed forward command
-                                            if let Some(content) = json.get("content") {
-                                                if let Some(code) = content.get("code") {
-                                                    if let Some(code_val) = code.as_u64() {
-                                                        match code_val {
-                                                            298 => {
-                                                                // 301/298 (retransmission) - no response expected
-                                                                tracing::debug!("Ignoring 301/298 retransmission command from {}: {}", source_ip, clean_json_str);
-                                                            }
-                                                             4 => {
-                                                                // 301/4 (base info) - camera is responding with device info
-                                                                tracing::info!("Received 301/4 device info response from {}: {}", source_ip, clean_json_str);
-                                                                
-                                                                // Now send the streaming command (301/3)
-                                                                Self::send_streaming_command(source_ip, camera_manager).await?;
-                                                            }
-                                                            3 => {
-                                                                // 301/3 (streaming) - echoed command
-                                                                tracing::info!("Received echoed 301/3 streaming command from {}: {}", source_ip, clean_json_str);
-                                                                
-                                                                // Now send 301/0 (stop streaming command) to complete the sequence
-                                                                Self::send_stop_streaming_command(source_ip, camera_manager).await?;
-                                                            }
-                                                            0 => {
-                                                                // 301/0 (stop streaming) - echoed command
-                                                                tracing::info!("Received echoed 301/0 stop streaming command from {}: {}", source_ip, clean_json_str);
-                                                                
-                                                                // Streaming sequence is now complete!
-                                                                tracing::info!("Camera {} streaming sequence complete - video should start on UDP", source_ip);
-                                                            }
-                                                            _ => {
-                                                                tracing::debug!("Ignoring echoed forward command with unknown content code {} from {}: {}", code_val, source_ip, clean_json_str);
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        } else if json.get("uid").is_some() {
-                                            // This is an actual streaming request
-                                            Self::handle_streaming_request(clean_json_str.to_string(), source_ip, camera_manager).await?;
-                                        } else {
-                                            tracing::debug!("Unknown Code 301 message format from {}: {}", source_ip, clean_json_str);
-                                        }
-                                    }
-                                    _ => {
-                                        tracing::debug!("Unhandled JSON message code {} from {}", code, source_ip);
-                                    }
+                let json_str = String::from_utf8_lossy(payload);
+                // Strip null bytes from the beginning of the JSON string
+                let clean_json_str = json_str.trim_start_matches('\0');
+                tracing::debug!("Received JSON message from {}: {}", source_ip, clean_json_str);
+
+                let message = decode(payload);
+                if let Ok(message) = &message {
+                    camera_manager.metrics.json_received("tcp", message.code());
+                }
+                // Hand replies to commands sent with send_request; the sequences below still see them
+                if let (Ok(message), Some(camera)) = (&message, &connection.camera) {
+                    if camera.connection.write().await.pending_requests.resolve(header.pkg_id, message.clone()) {
+                        tracing::debug!("Matched code {} from {} to a pending request", message.code(), source_ip);
+                    }
+                }
+
+                match message {
+                    Ok(Message::Register(request)) => {
+                        // Registration request
+                        Self::handle_registration(request, connection, camera_manager, config).await?;
+                    }
+                    Ok(Message::NatResponse(_)) => {
+                        // NAT probe response; start_streaming_for_camera carries on from here
+                        tracing::info!("Received NAT probe response from {}", source_ip);
+                    }
+                    Ok(Message::ProbeResponse(response)) => {
+                        // Device info request - send device info response with Command 51
+                        Self::handle_device_info_request(response, connection).await?;
+                    }
+                    Ok(Message::SnapshotRequest(request)) => {
+                        // Snapshot request (simple protocol)
+                        Self::handle_snapshot_request(request, connection).await?;
+                    }
+                    Ok(Message::Forward(forward)) => {
+                        // This is an echoed forward command
+                        match *forward.content {
+                            Message::Retransmission => {
+                                // 301/298 (retransmission) - no response expected
+                                tracing::debug!("Ignoring 301/298 retransmission command from {}: {}", source_ip, clean_json_str);
+                            }
+                            content @ (Message::DeviceInfoRequest { .. } | Message::DeviceInfo(_)) => {
+                                // 301/4 (base info) - camera is responding with device info
+                                tracing::info!("Received 301/4 device info response from {}: {}", source_ip, clean_json_str);
+                                if let Message::DeviceInfo(info) = content {
+                                    Self::store_device_info(info, connection, camera_manager).await;
                                 }
                             }
-                        }
-                        Err(e) => {
-                            tracing::error!("Failed to parse JSON from {}: {} - Error: {}", source_ip, clean_json_str, e);
+                            Message::StartStreaming => {
+                                // 301/3 (streaming) - echoed command
+                                tracing::info!("Received echoed 301/3 streaming command from {}: {}", source_ip, clean_json_str);
+                            }
+                            Message::StopStreaming => {
+                                // 301/0 (stop streaming) - echoed command
+                                tracing::info!("Received echoed 301/0 stop streaming command from {}: {}", source_ip, clean_json_str);
+                            }
+                            content @ Message::Setting(_) => {
+                                // 301/6-9 (settings) - the camera applied the setting
+                                tracing::info!("Received echoed 301/{} setting command from {}: {}", content.code(), source_ip, clean_json_str);
+                            }
+                            content => {
+                                tracing::debug!("Ignoring echoed forward command with unknown content code {} from {}: {}", content.code(), source_ip, clean_json_str);
+                            }
                         }
                     }
+                    Ok(Message::StreamingRequest(request)) => {
+                        // This is an actual streaming request
+                        Self::handle_streaming_request(request, connection).await?;
+                    }
+                    Ok(message) => {
+                        tracing::debug!("Unhandled JSON message code {} from {}", message.code(), source_ip);
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to parse JSON from {}: {} - Error: {:#}", source_ip, clean_json_str, e);
+                    }
                 }
             }
             99 => {
                 // Keepalive message (20 bytes)
                 tracing::debug!("Received keepalive from {}", source_ip);
-                Self::handle_keepalive(source_ip, camera_manager).await?;
+                Self::handle_keepalive(connection).await?;
             }
             100 => {
                 // Binary heartbeat
                 tracing::debug!("Received heartbeat from {}", source_ip);
-                Self::handle_heartbeat(source_ip, camera_manager).await?;
+                Self::handle_heartbeat(connection).await?;
             }
             1 | 4 | 6 | 7 => {
                 // Video/audio frames - process on TCP
-                Self::handle_video_frame_tcp(payload, source_ip, camera_manager).await?;
+                Self::handle_video_frame_tcp(payload, connection).await?;
             }
             _ => {
                 tracing::debug!("Unhandled binary message CMD {} from {}", header.cmd, source_ip);
             }
         }
-        
+
         Ok(())
     }
 
     async fn handle_registration(
-        json_str: String,
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
+        request: RegistrationRequest,
+        connection: &mut ControlConnection,
+        camera_manager: &Arc<CameraManager>,
+        config: &AppConfig,
     ) -> Result<()> {
-        match serde_json::from_str::<RegistrationRequest>(&json_str) {
-            Ok(request) => {
-                tracing::info!("Registration request from {}: device_id={}", source_ip, request.uid);
-            
-                // Update camera state
-                {
-                    let mut manager = camera_manager.write().await;
-                    let camera = manager.get_or_create_camera(source_ip).await;
-                    let mut camera_guard = camera.write().await;
-                    camera_guard.device_id = Some(request.uid.clone());
-                    camera_guard.state = ProtocolState::Registering;
-                }
-                
-                            // Send registration response
-            let response = RegistrationResponse::new();
-            let response_json = serde_json::to_string(&response)?;
-            
-            let header = ProtocolHeader::json(0, response_json.len());
-            
-            let mut message = Vec::new();
-            message.extend_from_slice(&header.to_bytes());
-            message.extend_from_slice(response_json.as_bytes());
-            
-            // Get TCP connection from camera manager
-            {
-                let manager = camera_manager.read().await;
-                if let Some(camera) = manager.get_camera(source_ip).await {
-                    let camera_guard = camera.read().await;
-                    if let Some(tcp_conn) = &camera_guard.tcp_conn {
-                        let mut socket_guard = tcp_conn.lock().await;
-                        socket_guard.write_all(&message).await?;
-                        tracing::info!("Registration response sent to {}", source_ip);
-                    }
-                }
-            }
-                
-                // Update camera state to Idle (simple protocol)
-                {
-                    let mut manager = camera_manager.write().await;
-                    let camera = manager.get_or_create_camera(source_ip).await;
-                    let mut camera_guard = camera.write().await;
-                    camera_guard.state = ProtocolState::Idle;
-                    tracing::info!("Camera {} registered successfully, state set to Idle", source_ip);
-                }
+        let source_ip = connection.addr.ip();
+        tracing::info!("Registration request from {}: device_id={}", source_ip, request.uid);
+
+        // Only paired devices with the right token, or new ones while pairing is open
+        let admission = camera_manager.registry.lock().await.admit(&request.uid, &request.token, &config.server_token);
+        match admission {
+            Ok(Admission::Paired) => {}
+            Ok(Admission::NewlyPaired) => tracing::info!("Camera {} paired from {}", request.uid, source_ip),
+            Err(rejection) => {
+                tracing::warn!("Refused registration of {} from {}: {}", request.uid, source_ip, rejection);
+                camera_manager.metrics.registration_rejected(rejection.reason());
+                let response = encode(&Message::RegisterResponse(RegistrationResponse::refused()))?;
+                let header = ProtocolHeader::json(0, response.len());
+                connection.writer.lock().await.send((header, Bytes::from(response))).await?;
+                connection.refused = true;
+                return Ok(());
             }
-            Err(e) => {
-                tracing::error!("Failed to parse RegistrationRequest from {}: {} - Error: {}", source_ip, json_str, e);
+        }
+
+        // Look up the camera by its uid and attach this connection to it
+        let camera = camera_manager
+            .register_camera(&request.uid, connection.addr, &request.token)
+            .await;
+        {
+            let mut camera_guard = camera.connection.write().await;
+            // A previous connection may be half-open after a reboot; its reader would wait forever
+            if camera_guard.tcp_conn.as_ref().is_some_and(|tcp_conn| !Arc::ptr_eq(tcp_conn, &connection.writer)) {
+                camera_guard.close_control();
             }
+            camera_guard.tcp_conn = Some(connection.writer.clone());
+            camera_guard.tcp_reader_stop = Some(connection.stop.clone());
+            // Possibly a reboot the old connection has not noticed yet
+            camera_guard.end_session(ProtocolState::Registering, "code 100 registration");
         }
-        
-        Ok(())
-    }
+        connection.camera = Some(camera);
 
+        // Send registration response
+        let response = encode(&Message::RegisterResponse(RegistrationResponse::new()))?;
+        let header = ProtocolHeader::json(0, response.len());
+        connection.writer.lock().await.send((header, Bytes::from(response))).await?;
+        tracing::info!("Registration response sent to {}", source_ip);
 
+        connection.set_state(ProtocolState::Idle, "code 101 registration response sent").await;
+        tracing::info!("Camera {} ({}) registered successfully", request.uid, source_ip);
 
-    async fn handle_snapshot_request(
-        json_str: String,
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-    ) -> Result<()> {
-        match serde_json::from_str::<SnapshotRequest>(&json_str) {
-            Ok(request) => {
-                tracing::info!("Snapshot request from {}: device_id={}", source_ip, request.uid);
-                
-                // Send snapshot response (Code 202)
-                let response = SnapshotResponse {
-                    code: 202,
-                    status: 200,
-                };
-                let response_json = serde_json::to_string(&response)?;
-                
-                let header = ProtocolHeader::json(0, response_json.len());
-                let mut message = Vec::new();
-                message.extend_from_slice(&header.to_bytes());
-                message.extend_from_slice(response_json.as_bytes());
-                
-                // Get TCP connection from camera manager
-                {
-                    let manager = camera_manager.read().await;
-                    if let Some(camera) = manager.get_camera(source_ip).await {
-                        let camera_guard = camera.read().await;
-                        if let Some(tcp_conn) = &camera_guard.tcp_conn {
-                            let mut socket_guard = tcp_conn.lock().await;
-                            socket_guard.write_all(&message).await?;
-                            tracing::info!("Snapshot response (Code 202) sent to {}", source_ip);
-                        }
-                    }
-                }
-                
-                // Update camera state
-                {
-                    let mut manager = camera_manager.write().await;
-                    let camera = manager.get_or_create_camera(source_ip).await;
-                    let mut camera_guard = camera.write().await;
-                    camera_guard.state = ProtocolState::Idle;
-                    tracing::info!("Camera {} snapshot request handled, state set to Idle", source_ip);
+        // A camera that dropped mid-stream picks up where it left off
+        let resume = match &connection.camera {
+            Some(camera) => std::mem::take(&mut camera.connection.write().await.resume_streaming),
+            None => false,
+        };
+        if resume {
+            tracing::info!("Camera {} was streaming before it dropped, restarting the stream", request.uid);
+            let (device_id, camera_manager) = (request.uid.clone(), camera_manager.clone());
+            tokio::spawn(async move {
+                if let Err(e) = Self::start_streaming_for_camera(&device_id, &camera_manager).await {
+                    tracing::error!("Failed to resume streaming for camera {}: {}", device_id, e);
                 }
-            }
-            Err(e) => {
-                tracing::error!("Failed to parse SnapshotRequest from {}: {} - Error: {}", source_ip, json_str, e);
-            }
+            });
         }
-        
-        Ok(())
-    }
 
-    async fn handle_device_info_request(
-        json_str: String,
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-    ) -> Result<()> {
-        tracing::info!("Device info request (code 51) from {}: {}", source_ip, json_str);
-        
-        // Parse the code 51 message to get device info
-        match serde_json::from_str::<serde_json::Value>(&json_str) {
-            Ok(json) => {
-                if let Some(dev_target) = json["devTarget"].as_str() {
-                    tracing::info!("Camera {} sending device info with target: {}", source_ip, dev_target);
-                }
-                if let Some(status) = json["status"].as_u64() {
-                    tracing::info!("Camera {} status: {}", source_ip, status);
-                }
-            }
-            Err(e) => {
-                tracing::error!("Failed to parse code 51 JSON from {}: {} - Error: {}", source_ip, json_str, e);
-            }
-        }
-        
-        // Respond with code 50 (as per STA mode protocol)
-        {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
-                let camera_guard = camera.read().await;
-                if let Some(tcp_conn) = &camera_guard.tcp_conn {
-                    let mut socket_guard = tcp_conn.lock().await;
-                    
-                    // Create code 50 response (matching working Python script)
-                    let code_50_response = serde_json::json!({
-                        "code": 50
-                    });
-                    
-                    let json_str = serde_json::to_string(&code_50_response)?;
-                    let json_bytes = json_str.as_bytes();
-                    
-                    // Create protocol header with Command 0 (JSON)
-                    let header = ProtocolHeader::new(0, json_bytes.len() as u32, 0, 0);
-                    let message = header.to_bytes();
-                    
-                    socket_guard.write_all(&message).await?;
-                    socket_guard.write_all(json_bytes).await?;
-                    tracing::info!("Code 50 response sent to {}: {}", source_ip, json_str);
-                    
-                    // Update camera state to indicate streaming is ready
-                    {
-                        let mut manager = camera_manager.write().await;
-                        let camera = manager.get_or_create_camera(source_ip).await;
-                        let mut camera_guard = camera.write().await;
-                        camera_guard.state = ProtocolState::Streaming;
-                        tracing::info!("Camera {} code 50/51 exchange complete, streaming should start", source_ip);
-                    }
-                }
-            }
-        }
-        
         Ok(())
     }
 
-    async fn handle_nat_probe_response(
-        json_str: String,
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-    ) -> Result<()> {
-        tracing::info!("Received NAT probe response from {}", source_ip);
-        
-        // NAT traversal is complete - send device status (code 53) and then 301 sequence
-        // This follows the exact pattern from the working Python script
-        let tcp_conn = {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
-                let camera_guard = camera.read().await;
-                camera_guard.tcp_conn.clone()
-            } else {
-                None
-            }
+    /// Keep the 301/4 base info on the camera and in the device registry
+    async fn store_device_info(
+        info: DeviceInfo,
+        connection: &ControlConnection,
+        camera_manager: &Arc<CameraManager>,
+    ) {
+        let Some(camera) = &connection.camera else {
+            return;
         };
-        
-        if let Some(tcp_conn) = tcp_conn {
-            let mut socket_guard = tcp_conn.lock().await;
-            
-            // Step 1: Send device status (Code 53)
-            let device_status_command = serde_json::json!({
-                "code": 53,
-                "status": 1
-            });
-            let json_str = serde_json::to_string(&device_status_command)?;
-            let json_bytes = json_str.as_bytes();
-            let header = ProtocolHeader::new(0, json_bytes.len() as u32, 0, 0);
-            let message = header.to_bytes();
-            socket_guard.write_all(&message).await?;
-            socket_guard.write_all(json_bytes).await?;
-            tracing::info!("Device status (Code 53) sent to {}: {}", source_ip, json_str);
-            
-            // Step 2: Send 301 sequence (298, 4)
-            let code_301_298 = serde_json::json!({
-                "code": 301,
-                "target": "00112233445566778899aabbccddeeff",
-                "content": {
-                    "code": 298
-                }
-            });
-            let json_str = serde_json::to_string(&code_301_298)?;
-            let json_bytes = json_str.as_bytes();
-            let header = ProtocolHeader::new(0, json_bytes.len() as u32, 0, 0);
-            let message = header.to_bytes();
-            socket_guard.write_all(&message).await?;
-            socket_guard.write_all(json_bytes).await?;
-            tracing::info!("Code 301/298 sent to {}: {}", source_ip, json_str);
-            
-            let code_301_4 = serde_json::json!({
-                "code": 301,
-                "target": "00112233445566778899aabbccddeeff",
-                "content": {
-                    "unitTimer": 1755338876,
-                    "code": 4
-                }
-            });
-            let json_str = serde_json::to_string(&code_301_4)?;
-            let json_bytes = json_str.as_bytes();
-            let header = ProtocolHeader::new(0, json_bytes.len() as u32, 0, 0);
-            let message = header.to_bytes();
-            socket_guard.write_all(&message).await?;
-            socket_guard.write_all(json_bytes).await?;
-            tracing::info!("Code 301/4 sent to {}: {}", source_ip, json_str);
-        }
-        
-        // Update camera state (separate lock)
-        {
-            let mut manager = camera_manager.write().await;
-            let camera = manager.get_or_create_camera(source_ip).await;
-            let mut camera_guard = camera.write().await;
-            camera_guard.state = ProtocolState::Streaming;
-            tracing::info!("Camera {} state set to Streaming, sent 53 and 301 sequence", source_ip);
+        camera.connection.write().await.device_info = Some(info.clone());
+        if let Err(e) = camera_manager.registry.lock().await.update(&camera.device_id, |record| {
+            record.firmware_version = Some(info.version.clone());
+            record.device_info = Some(info);
+        }) {
+            tracing::warn!("Failed to save device registry: {:#}", e);
         }
-        
-        Ok(())
     }
 
+    async fn handle_snapshot_request(
+        request: SnapshotRequest,
+        connection: &ControlConnection,
+    ) -> Result<()> {
+        let source_ip = connection.addr.ip();
+        tracing::info!("Snapshot request from {}: device_id={}", source_ip, request.uid);
 
+        // Send snapshot response (Code 202)
+        let response = encode(&Message::SnapshotResponse(SnapshotResponse {
+            code: 202,
+            status: 200,
+        }))?;
+        let header = ProtocolHeader::json(0, response.len());
+        connection.writer.lock().await.send((header, Bytes::from(response))).await?;
+        tracing::info!("Snapshot response (Code 202) sent to {}", source_ip);
 
-    async fn send_streaming_command(
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-    ) -> Result<()> {
-        tracing::info!("Sending streaming command to {} after receiving base info response", source_ip);
-        
-        let tcp_conn = {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
-                let camera_guard = camera.read().await;
-                camera_guard.tcp_conn.clone()
-            } else {
-                None
+        // The snapshot detour ends here; a streaming camera keeps streaming
+        if let Some(camera) = &connection.camera {
+            let mut camera_guard = camera.connection.write().await;
+            if *camera_guard.state.current() == ProtocolState::Snapshot {
+                camera_guard.set_state(ProtocolState::Idle, "code 201 snapshot request answered");
             }
-        };
-        
-        if let Some(tcp_conn) = tcp_conn {
-            let mut socket_guard = tcp_conn.lock().await;
-            
-            // Send forward streaming command (Code 301 with content code 3)
-            let forward_streaming_command = serde_json::json!({
-                "code": 301,
-                "target": "00112233445566778899aabbccddeeff",
-                "content": {
-                    "code": 3
-                }
-            });
-            
-            let json_str = serde_json::to_string(&forward_streaming_command)?;
-            let json_bytes = json_str.as_bytes();
-            let header = ProtocolHeader::new(0, json_bytes.len() as u32, 0, 0);
-            let message = header.to_bytes();
-            
-            socket_guard.write_all(&message).await?;
-            socket_guard.write_all(json_bytes).await?;
-            tracing::info!("Forward streaming command sent to {}: {}", source_ip, json_str);
         }
-        
+        tracing::info!("Camera {} snapshot request handled", source_ip);
+
         Ok(())
     }
 
-    async fn send_stop_streaming_command(
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
+    async fn handle_device_info_request(
+        response: Code51Response,
+        connection: &ControlConnection,
     ) -> Result<()> {
-        tracing::info!("Sending stop streaming command to {} to complete sequence", source_ip);
-        
-        let tcp_conn = {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
-                let camera_guard = camera.read().await;
-                camera_guard.tcp_conn.clone()
-            } else {
-                None
-            }
-        };
-        
-        if let Some(tcp_conn) = tcp_conn {
-            let mut socket_guard = tcp_conn.lock().await;
-            
-            // Send stop streaming command (Code 301 with content code 0)
-            let stop_streaming_command = serde_json::json!({
-                "code": 301,
-                "target": "00112233445566778899aabbccddeeff",
-                "content": {
-                    "code": 0
-                }
-            });
-            
-            let json_str = serde_json::to_string(&stop_streaming_command)?;
-            let json_bytes = json_str.as_bytes();
-            let header = ProtocolHeader::new(0, json_bytes.len() as u32, 0, 0);
-            let message = header.to_bytes();
-            
-            socket_guard.write_all(&message).await?;
-            socket_guard.write_all(json_bytes).await?;
-            tracing::info!("Stop streaming command sent to {}: {}", source_ip, json_str);
-        }
-        
+        let source_ip = connection.addr.ip();
+        tracing::info!("Device info request (code 51) from {}: {:?}", source_ip, response);
+        tracing::info!("Camera {} sending device info with target: {}", source_ip, response.dev_target);
+        tracing::info!("Camera {} status: {}", source_ip, response.status);
+
+        // Respond with code 50 (as per STA mode protocol)
+        // Create code 50 response (matching working Python script)
+        let request = encode(&Message::ProbeRequest(Code50Request::new()))?;
+
+        // Create protocol header with Command 0 (JSON)
+        let header = ProtocolHeader::new(0, request.len() as u32, 0, 0);
+        connection.writer.lock().await.send((header, Bytes::from(request))).await?;
+        tracing::info!("Code 50 response sent to {}", source_ip);
+
+        // Streaming starts with the first frame
+        tracing::info!("Camera {} code 50/51 exchange complete, streaming should start", source_ip);
+
         Ok(())
     }
 
     async fn handle_streaming_request(
-        json_str: String,
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
+        request: StreamingRequest,
+        connection: &ControlConnection,
     ) -> Result<()> {
-        match serde_json::from_str::<StreamingRequest>(&json_str) {
-            Ok(request) => {
-                tracing::info!("Streaming request from {}: device_id={}", source_ip, request.uid);
-                
-                // Send streaming response (Code 302)
-                let response = StreamingResponse {
-                    code: 302,
-                    status: 200,
-                };
-                let response_json = serde_json::to_string(&response)?;
-                
-                let header = ProtocolHeader::json(0, response_json.len());
-                let mut message = Vec::new();
-                message.extend_from_slice(&header.to_bytes());
-                message.extend_from_slice(response_json.as_bytes());
-                
-                // Get TCP connection from camera manager
-                {
-                    let manager = camera_manager.read().await;
-                    if let Some(camera) = manager.get_camera(source_ip).await {
-                        let camera_guard = camera.read().await;
-                        if let Some(tcp_conn) = &camera_guard.tcp_conn {
-                            let mut socket_guard = tcp_conn.lock().await;
-                            socket_guard.write_all(&message).await?;
-                            tracing::info!("Streaming response (Code 302) sent to {}", source_ip);
-                        }
-                    }
-                }
-                
-                // Update camera state to Streaming
-                {
-                    let mut manager = camera_manager.write().await;
-                    let camera = manager.get_or_create_camera(source_ip).await;
-                    let mut camera_guard = camera.write().await;
-                    camera_guard.state = ProtocolState::Streaming;
-                    tracing::info!("Camera {} streaming request handled, state set to Streaming", source_ip);
-                }
-            }
-            Err(e) => {
-                tracing::error!("Failed to parse StreamingRequest from {}: {} - Error: {}", source_ip, json_str, e);
-            }
+        let source_ip = connection.addr.ip();
+        tracing::info!("Streaming request from {}: device_id={}", source_ip, request.uid);
+
+        // Send streaming response (Code 302)
+        let response = encode(&Message::StreamingResponse(StreamingResponse {
+            code: 302,
+            status: 200,
+        }))?;
+        let header = ProtocolHeader::json(0, response.len());
+        connection.writer.lock().await.send((header, Bytes::from(response))).await?;
+        tracing::info!("Streaming response (Code 302) sent to {}", source_ip);
+
+        // The camera asked to stream; it is Streaming once its first frame arrives
+        connection.set_state(ProtocolState::NatTraversal, "code 302 streaming response sent").await;
+        if let Some(camera) = &connection.camera {
+            camera.stream_starting().await;
         }
-        
+        tracing::info!("Camera {} streaming request handled", source_ip);
+
         Ok(())
     }
 
-    pub async fn start_streaming_for_camera(
-        source_ip: IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-    ) -> Result<()> {
-        tracing::info!("Starting streaming for camera {}", source_ip);
-        
-        // Send NAT probe request to initiate NAT traversal
-        // Camera needs to complete NAT traversal before responding to forward commands
-        {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
-                let camera_guard = camera.read().await;
-                if let Some(tcp_conn) = &camera_guard.tcp_conn {
-                    let mut socket_guard = tcp_conn.lock().await;
-                    
-                    // Send NAT probe request: {"code": 11, "cliTarget": "00112233445566778899aabbccddeeff", "cliToken": "deadc0de", ...}
-                    // This initiates NAT traversal (Code 11 = CODE_S2D_NAT_REQ)
-                    let nat_probe_command = serde_json::json!({
-                        "code": 11,
-                        "cliTarget": "00112233445566778899aabbccddeeff",
-                        "cliToken": "deadc0de",
-                        "cliIp": "255.255.255.255",
-                        "cliPort": 0,
-                        "cliNatIp": "192.168.1.99",
-                        "cliNatPort": 6123
-                    });
-                    
-                    let json_str = serde_json::to_string(&nat_probe_command)?;
-                    let json_bytes = json_str.as_bytes();
-                    
-                    // Create protocol header: cmd=0, length, msg_flag=0, pkg_id=0
-                    let header = ProtocolHeader::new(0, json_bytes.len() as u32, 0, 0);
-                    let message = header.to_bytes();
-                    
-                    socket_guard.write_all(&message).await?;
-                    socket_guard.write_all(json_bytes).await?;
-                    tracing::info!("NAT probe request sent to {}: {}", source_ip, json_str);
-                }
+    /// Send `message` to the camera and wait for the reply matching `reply`
+    ///
+    /// Each attempt waits `retry_timeout_ms`; the command is resent up to
+    /// `max_retries` times with the same pkg_id.
+    pub async fn send_request(
+        camera: &Arc<RwLock<CameraConnection>>,
+        message: &Message,
+        reply: ReplyKey,
+        config: &AppConfig,
+    ) -> Result<Message, RequestError> {
+        let (tcp_conn, pkg_id, mut receiver) = {
+            let mut camera_guard = camera.write().await;
+            let tcp_conn = camera_guard.tcp_conn.clone().ok_or(RequestError::NotConnected)?;
+            let (pkg_id, receiver) = camera_guard.pending_requests.register(reply);
+            (tcp_conn, pkg_id, receiver)
+        };
+        let payload = Bytes::from(encode(message).map_err(RequestError::Send)?);
+        let timeout = std::time::Duration::from_millis(config.retry_timeout_ms);
+        let attempts = config.max_retries + 1;
+
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                tracing::warn!("No {:?} reply to code {} (pkg_id {}), resending ({}/{})",
+                    reply, message.code(), pkg_id, attempt, attempts);
+            }
+            let header = ProtocolHeader::json(pkg_id, payload.len());
+            tcp_conn
+                .lock()
+                .await
+                .send((header, payload.clone()))
+                .await
+                .map_err(RequestError::Send)?;
+            match tokio::time::timeout(timeout, &mut receiver).await {
+                Ok(Ok(reply)) => return Ok(reply),
+                Ok(Err(_)) => return Err(RequestError::Disconnected),
+                Err(_) => continue,
             }
         }
-        
-        // Update camera state to Streaming (camera will send Code 301 request after NAT traversal)
-        {
-            let mut manager = camera_manager.write().await;
-            let camera = manager.get_or_create_camera(source_ip).await;
+
+        Err(RequestError::Timeout { attempts })
+    }
+
+    /// Send `message` to the camera without waiting for a reply
+    ///
+    /// It carries a pkg_id of its own, so its echo is not taken for the reply
+    /// to a command someone waits for.
+    async fn send_command(camera: &Arc<RwLock<CameraConnection>>, message: &Message) -> Result<(), RequestError> {
+        let (tcp_conn, pkg_id) = {
             let mut camera_guard = camera.write().await;
-            camera_guard.state = ProtocolState::Streaming;
-            // Reset first_retransmission_sent flag when starting streaming
-            camera_guard.first_retransmission_sent = false;
-            tracing::info!("Camera {} state set to Streaming, first_retransmission_sent reset to false", source_ip);
-        }
-        
-        Ok(())
+            let tcp_conn = camera_guard.tcp_conn.clone().ok_or(RequestError::NotConnected)?;
+            (tcp_conn, camera_guard.pending_requests.next_pkg_id())
+        };
+        let payload = Bytes::from(encode(message).map_err(RequestError::Send)?);
+        let header = ProtocolHeader::json(pkg_id, payload.len());
+        let mut writer = tcp_conn.lock().await;
+        writer.send((header, payload)).await.map_err(RequestError::Send)
     }
 
-    pub async fn stop_streaming_for_camera(
-        source_ip: IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-    ) -> Result<()> {
-        tracing::info!("Stopping streaming for camera {}", source_ip);
-        
-        // Close all UDP connections for this camera
-        {
-            let mut manager = camera_manager.write().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
+    /// Run the NAT/301 start sequence and wait for the camera to echo 301/3
+    ///
+    /// Code 11, 301/4 and 301/3 each wait for their reply; one that goes
+    /// unanswered is resent on its own rather than starting over.
+    pub async fn start_streaming_for_camera(
+        device_id: &str,
+        camera_manager: &Arc<CameraManager>,
+    ) -> Result<Message, RequestError> {
+        tracing::info!("Starting streaming for camera {}", device_id);
+        let config = &camera_manager.config.current();
+        let handle = camera_manager
+            .handle(device_id)
+            .ok_or_else(|| RequestError::UnknownCamera(device_id.to_string()))?;
+        let camera = &handle.connection;
+
+        // The first frame moves the camera on to Streaming
+        camera.write().await.set_state(ProtocolState::NatTraversal, "code 11 NAT request sent");
+        // The new stream starts with an empty retransmission confirmation
+        handle.stream_starting().await;
+
+        match Self::start_sequence(camera, config).await {
+            Ok(reply) => {
+                tracing::info!("Camera {} accepted the streaming command", device_id);
+                Ok(reply)
+            }
+            Err(e) => {
                 let mut camera_guard = camera.write().await;
-                
-                // Close all UDP sockets
-                camera_guard.udp_ports.clear();
-                tracing::info!("Closed {} UDP connections for camera {}", 
-                    camera_guard.udp_ports.len(), source_ip);
-                
-                // Clear video buffer
-                camera_guard.stream_buffer.clear();
-                tracing::info!("Cleared video buffer for camera {}", source_ip);
-                
-                // Set state back to Idle
-                camera_guard.state = ProtocolState::Idle;
-                tracing::info!("Camera {} state set to Idle", source_ip);
+                if *camera_guard.state.current() == ProtocolState::NatTraversal {
+                    camera_guard.set_state(ProtocolState::Error, &format!("start sequence failed: {}", e));
+                }
+                Err(e)
             }
         }
-        
+    }
 
-        
-        Ok(())
+    /// The steps of `start_streaming_for_camera`, following the Python reference script
+    async fn start_sequence(camera: &Arc<RwLock<CameraConnection>>, config: &AppConfig) -> Result<Message, RequestError> {
+        // Camera needs to complete NAT traversal (Code 11 = CODE_S2D_NAT_REQ) before it
+        // answers the 301/4 and 301/3 that follow
+        let nat_probe = Message::NatRequest(NatProbeRequest::new(config));
+        Self::send_request(camera, &nat_probe, ReplyKey::Code(12), config).await?;
+
+        Self::send_command(camera, &Message::DeviceStatus(DeviceStatusRequest::new())).await?;
+        Self::send_command(camera, &Message::Forward(ForwardCommand::retransmission_request(config))).await?;
+        let base_info = Message::Forward(ForwardCommand::device_info_request(config));
+        Self::send_request(camera, &base_info, ReplyKey::Forward(4), config).await?;
+
+        let start = Message::Forward(ForwardCommand::start_streaming_request(config));
+        let reply = Self::send_request(camera, &start, ReplyKey::Forward(3), config).await?;
+        // The reference closes the handshake with a 301/0; its echo must not answer a pending stop
+        Self::send_command(camera, &Message::Forward(ForwardCommand::stop_streaming_request(config))).await?;
+        Ok(reply)
     }
 
+    /// Drop the local stream state, then send 301/0 and wait for the camera's echo
+    pub async fn stop_streaming_for_camera(
+        device_id: &str,
+        camera_manager: &Arc<CameraManager>,
+    ) -> Result<Message, RequestError> {
+        tracing::info!("Stopping streaming for camera {}", device_id);
+        let config = &camera_manager.config.current();
+        let handle = camera_manager
+            .handle(device_id)
+            .ok_or_else(|| RequestError::UnknownCamera(device_id.to_string()))?;
 
+        // Close all UDP sockets
+        tracing::info!("Closing {} UDP connections for camera {}", handle.udp_ports.len(), device_id);
+        handle.udp_ports.clear();
+
+        // Clear video and audio buffers
+        handle.clear().await;
+        handle.connection.write().await.set_state(ProtocolState::Idle, "streaming stopped");
+
+        let stop = Message::Forward(ForwardCommand::stop_streaming_request(config));
+        Self::send_request(&handle.connection, &stop, ReplyKey::Forward(0), config).await
+    }
 
     async fn handle_heartbeat(
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
+        connection: &ControlConnection,
     ) -> Result<()> {
         // Update camera heartbeat
-        {
-            let mut manager = camera_manager.write().await;
-            let camera = manager.get_or_create_camera(source_ip).await;
-            let mut camera_guard = camera.write().await;
-            camera_guard.update_heartbeat();
+        if let Some(camera) = &connection.camera {
+            camera.connection.write().await.update_heartbeat();
         }
-        
+
         Ok(())
     }
 
     async fn handle_keepalive(
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
+        connection: &ControlConnection,
     ) -> Result<()> {
         // Send keepalive response (20 bytes)
         let header = ProtocolHeader::binary(99, 0, 0);
-        let message = header.to_bytes();
-        
-        // Get TCP connection from camera manager
-        {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.get_camera(source_ip).await {
-                let camera_guard = camera.read().await;
-                if let Some(tcp_conn) = &camera_guard.tcp_conn {
-                    let mut socket_guard = tcp_conn.lock().await;
-                    socket_guard.write_all(&message).await?;
-                    tracing::debug!("Keepalive response sent to {}", source_ip);
-                }
-            }
-        }
-        
+        connection.writer.lock().await.send((header, Bytes::new())).await?;
+        tracing::debug!("Keepalive response sent to {}", connection.addr.ip());
+
         Ok(())
     }
 
 
 
+    /// Send 301/5 and wait for the camera's echo; the image follows on UDP
     pub async fn trigger_snapshot_for_camera(
-        source_ip: IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
-    ) -> Result<()> {
-        tracing::info!("Triggering snapshot for camera {}", source_ip);
-        
-        // Update camera state to trigger snapshot (camera will send Code 201 request)
+        device_id: &str,
+        camera_manager: &Arc<CameraManager>,
+    ) -> Result<Message, RequestError> {
+        tracing::info!("Triggering snapshot for camera {}", device_id);
+        let config = &camera_manager.config.current();
+        let camera = camera_manager
+            .get_camera(device_id)
+            .ok_or_else(|| RequestError::UnknownCamera(device_id.to_string()))?;
+
+        // A streaming camera takes the snapshot without leaving Streaming
         {
-            let mut manager = camera_manager.write().await;
-            let camera = manager.get_or_create_camera(source_ip).await;
             let mut camera_guard = camera.write().await;
-            camera_guard.state = ProtocolState::Idle;
-            tracing::info!("Camera {} snapshot triggered, waiting for Code 201 request", source_ip);
+            if *camera_guard.state.current() == ProtocolState::Idle {
+                camera_guard.set_state(ProtocolState::Snapshot, "301/5 snapshot sent");
+            }
         }
-        
-        Ok(())
+
+        let snapshot = Message::Forward(ForwardCommand::snapshot_request(config));
+        let result = Self::send_request(&camera, &snapshot, ReplyKey::Forward(5), config).await;
+        if result.is_err() {
+            let mut camera_guard = camera.write().await;
+            if *camera_guard.state.current() == ProtocolState::Snapshot {
+                camera_guard.set_state(ProtocolState::Idle, "301/5 snapshot not acknowledged");
+            }
+        }
+        result
     }
 
     async fn handle_video_frame_tcp(
         frame_payload: &[u8],
-        source_ip: std::net::IpAddr,
-        camera_manager: &Arc<RwLock<CameraManager>>,
+        connection: &ControlConnection,
     ) -> Result<()> {
-        tracing::info!("Processing TCP video frame from {} with {} bytes", source_ip, frame_payload.len());
+        let source_ip = connection.addr.ip();
+        tracing::debug!("Processing TCP video frame from {} with {} bytes", source_ip, frame_payload.len());
 
-        // Get camera and update heartbeat
-        let camera = {
-            let mut manager = camera_manager.write().await;
-            manager.get_or_create_camera(source_ip).await
+        let Some(camera) = &connection.camera else {
+            tracing::warn!("Ignoring TCP video frame from unregistered connection {}", connection.addr);
+            return Ok(());
         };
-        let mut camera_guard = camera.write().await;
-        camera_guard.update_heartbeat();
-        
-        // Add frame to camera's buffer (simplified for TCP - no fragmentation needed)
-        // Since TCP is reliable, we can assume the frame is complete
-        let frame_complete = camera_guard.stream_buffer.add_fragment(
-            1, // cmd (video frame)
-            0, // msg_flag
-            0, // pkg_id (not used for TCP)
-            frame_payload
-        );
-        
-        if frame_complete {
-            tracing::info!("Complete TCP frame added to buffer for {}: {} bytes (buffer: {}/{} frames)", 
-                source_ip, frame_payload.len(), 
-                camera_guard.stream_buffer.frame_count(), 
-                camera_guard.stream_buffer.max_frames);
-        }
-        
+        camera.connection.write().await.update_heartbeat();
+
+        // Since TCP is reliable, we can assume the frame is complete; there is nothing to confirm
+        let packet = MediaPacket {
+            header: ProtocolHeader::new(1, frame_payload.len() as u32, 0, 0),
+            payload: Bytes::copy_from_slice(frame_payload),
+            reply_to: None,
+        };
+        camera.push_packet(packet);
+
         Ok(())
     }
 }
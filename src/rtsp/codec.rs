@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Longest request head accepted before giving up on the connection
+const MAX_HEAD_LEN: usize = 8192;
+
+/// Largest request body accepted; RTSP clients only send small parameter bodies
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// An RTSP request from a client
+#[derive(Debug, Clone)]
+pub struct RtspRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl RtspRequest {
+    /// Header value by case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An RTSP response; `CSeq` is copied from the request when sending
+#[derive(Debug, Clone)]
+pub struct RtspResponse {
+    pub status: u16,
+    pub reason: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<(&'static str, String)>,
+}
+
+impl RtspResponse {
+    pub fn ok() -> Self {
+        Self::error(200, "OK")
+    }
+
+    pub fn error(status: u16, reason: &'static str) -> Self {
+        Self {
+            status,
+            reason,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, content_type: &'static str, body: String) -> Self {
+        self.body = Some((content_type, body));
+        self
+    }
+}
+
+/// What a client sends on the RTSP connection
+#[derive(Debug)]
+pub enum Incoming {
+    Request(RtspRequest),
+    /// `$`-framed data, typically RTCP receiver reports in interleaved mode
+    Interleaved { channel: u8, data: Bytes },
+}
+
+/// What the server sends on the RTSP connection
+#[derive(Debug)]
+pub enum Outgoing {
+    Response(RtspResponse),
+    Interleaved { channel: u8, data: Bytes },
+}
+
+/// RTSP/1.0 text messages with interleaved binary data (RFC 2326 section 10.12)
+#[derive(Debug, Clone, Default)]
+pub struct RtspCodec;
+
+impl Decoder for RtspCodec {
+    type Item = Incoming;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Incoming>> {
+        if src.first() == Some(&b'$') {
+            if src.len() < 4 {
+                return Ok(None);
+            }
+            let channel = src[1];
+            let len = u16::from_be_bytes([src[2], src[3]]) as usize;
+            if src.len() < 4 + len {
+                src.reserve(4 + len - src.len());
+                return Ok(None);
+            }
+            src.advance(4);
+            let data = src.split_to(len).freeze();
+            return Ok(Some(Incoming::Interleaved { channel, data }));
+        }
+
+        let Some(head_len) = src.windows(4).position(|w| w == b"\r\n\r\n") else {
+            if src.len() > MAX_HEAD_LEN {
+                return Err(anyhow!("RTSP request head exceeds {} bytes", MAX_HEAD_LEN));
+            }
+            return Ok(None);
+        };
+
+        let head = std::str::from_utf8(&src[..head_len]).map_err(|_| anyhow!("RTSP request head is not UTF-8"))?;
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(uri), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(anyhow!("Malformed RTSP request line: {:?}", request_line));
+        };
+        if !version.starts_with("RTSP/") {
+            return Err(anyhow!("Not an RTSP request: {:?}", request_line));
+        }
+
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, value)| value.parse::<usize>())
+            .transpose()
+            .map_err(|_| invalid_data("Invalid Content-Length".to_string()))?
+            .unwrap_or(0);
+        if content_length > MAX_BODY_LEN {
+            return Err(invalid_data(format!("RTSP Content-Length exceeds {} bytes", MAX_BODY_LEN)));
+        }
+
+        let message_len = (head_len + 4)
+            .checked_add(content_length)
+            .ok_or_else(|| invalid_data("RTSP Content-Length overflows".to_string()))?;
+        if src.len() < message_len {
+            src.reserve(message_len - src.len());
+            return Ok(None);
+        }
+
+        let request = RtspRequest {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers,
+            body: Bytes::new(),
+        };
+        src.advance(head_len + 4);
+        let body = src.split_to(content_length).freeze();
+        Ok(Some(Incoming::Request(RtspRequest { body, ..request })))
+    }
+}
+
+fn invalid_data(message: String) -> anyhow::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message).into()
+}
+
+impl Encoder<Outgoing> for RtspCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Outgoing, dst: &mut BytesMut) -> Result<()> {
+        match item {
+            Outgoing::Response(response) => {
+                let mut head = format!("RTSP/1.0 {} {}\r\n", response.status, response.reason);
+                for (name, value) in &response.headers {
+                    head.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                match &response.body {
+                    Some((content_type, body)) => {
+                        head.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n", content_type, body.len()));
+                        head.push_str(body);
+                    }
+                    None => head.push_str("\r\n"),
+                }
+                dst.put_slice(head.as_bytes());
+            }
+            Outgoing::Interleaved { channel, data } => {
+                if data.len() > u16::MAX as usize {
+                    return Err(anyhow!("Interleaved packet too large: {} bytes", data.len()));
+                }
+                dst.reserve(4 + data.len());
+                dst.put_u8(b'$');
+                dst.put_u8(channel);
+                dst.put_u16(data.len() as u16);
+                dst.put_slice(&data);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_split_request_and_interleaved_data() {
+        let mut codec = RtspCodec;
+        let mut buffer = BytesMut::from(&b"SET_PARAMETER rtsp://host/cam RTSP/1.0\r\nCSeq: 7\r\ncontent-length: 5\r\n\r\nab"[..]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+
+        buffer.extend_from_slice(b"cde$\x01\x00\x03xyz");
+        let Some(Incoming::Request(request)) = codec.decode(&mut buffer).unwrap() else {
+            panic!("expected a request");
+        };
+        assert_eq!(request.method, "SET_PARAMETER");
+        assert_eq!(request.uri, "rtsp://host/cam");
+        assert_eq!(request.header("cseq"), Some("7"));
+        assert_eq!(&request.body[..], b"abcde");
+
+        let Some(Incoming::Interleaved { channel, data }) = codec.decode(&mut buffer).unwrap() else {
+            panic!("expected interleaved data");
+        };
+        assert_eq!((channel, &data[..]), (1, &b"xyz"[..]));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_content_length() {
+        for length in ["65537", "18446744073709551615", "18446744073709551616"] {
+            let mut codec = RtspCodec;
+            let mut buffer = BytesMut::from(format!("ANNOUNCE rtsp://host/cam RTSP/1.0\r\nCSeq: 1\r\nContent-Length: {}\r\n\r\n", length).as_bytes());
+            let error = codec.decode(&mut buffer).unwrap_err();
+            let kind = error.downcast_ref::<std::io::Error>().map(|e| e.kind());
+            assert_eq!(kind, Some(std::io::ErrorKind::InvalidData), "Content-Length {}", length);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! RTSP server publishing every registered camera as `rtsp://host:port/<device_id>`
+//!
+//! Completed frames are repacketized as RTP/JPEG (RFC 2435) and cmd 6
+//! audio as RTP PCMA/PCMU, over UDP or interleaved in the RTSP connection.
+
+mod codec;
+mod rtp;
+mod session;
+
+pub use codec::{Incoming, Outgoing, RtspCodec, RtspRequest, RtspResponse};
+pub use rtp::{JpegFrame, RtpStream};
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::types::CameraManager;
+use session::Connection;
+
+/// Responses and interleaved packets queued per connection; a slow client makes the player skip frames
+const OUTGOING_QUEUE: usize = 256;
+
+/// Accept RTSP clients until the listener fails
+pub async fn serve(listener: TcpListener, camera_manager: Arc<CameraManager>) -> Result<()> {
+    tracing::info!("RTSP server listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let camera_manager = camera_manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer, camera_manager).await {
+                tracing::warn!("RTSP connection from {} failed: {:#}", peer, e);
+            }
+            tracing::debug!("RTSP connection from {} closed", peer);
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    camera_manager: Arc<CameraManager>,
+) -> Result<()> {
+    let local = stream.local_addr()?;
+    let (read_half, write_half) = stream.into_split();
+    let mut incoming = FramedRead::new(read_half, RtspCodec);
+
+    // One writer, so responses and interleaved RTP never mix mid-message
+    let (out_tx, mut out_rx) = mpsc::channel(OUTGOING_QUEUE);
+    let writer = tokio::spawn(async move {
+        let mut framed = FramedWrite::new(write_half, RtspCodec);
+        while let Some(item) = out_rx.recv().await {
+            if let Err(e) = framed.send(item).await {
+                tracing::debug!("RTSP write to {} failed: {:#}", peer, e);
+                break;
+            }
+        }
+    });
+
+    let mut connection = Connection::new(camera_manager, peer, local, out_tx.clone());
+    let result = async {
+        while let Some(message) = incoming.next().await {
+            match message? {
+                Incoming::Request(request) => {
+                    let response = connection.handle(&request).await;
+                    if out_tx.send(Outgoing::Response(response)).await.is_err() {
+                        break;
+                    }
+                    connection.start_pending_player();
+                }
+                Incoming::Interleaved { .. } => {
+                    // RTCP receiver reports; nothing to act on
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    // Dropping the session stops its player, which releases the last senders
+    drop(connection);
+    drop(out_tx);
+    let _ = writer.await;
+    result
+}
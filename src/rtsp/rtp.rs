@@ -0,0 +1,297 @@
+use anyhow::{bail, Result};
+
+use crate::config::AudioCodec;
+
+/// RTP/JPEG (RFC 2435)
+pub const JPEG_PAYLOAD_TYPE: u8 = 26;
+pub const JPEG_CLOCK_RATE: u32 = 90_000;
+
+/// Largest RTP payload, leaving room for IP/UDP/RTP headers within a 1500 byte MTU
+pub const MAX_PAYLOAD: usize = 1400;
+
+/// Static payload type of the G.711 variant (RFC 3551)
+pub fn audio_payload_type(codec: AudioCodec) -> u8 {
+    match codec {
+        AudioCodec::Ulaw => 0,
+        AudioCodec::Alaw => 8,
+    }
+}
+
+/// SDP encoding name of the G.711 variant
+pub fn audio_encoding_name(codec: AudioCodec) -> &'static str {
+    match codec {
+        AudioCodec::Ulaw => "PCMU",
+        AudioCodec::Alaw => "PCMA",
+    }
+}
+
+/// Sequence numbering and SSRC of one outgoing RTP stream
+#[derive(Debug)]
+pub struct RtpStream {
+    payload_type: u8,
+    ssrc: u32,
+    sequence: u16,
+    /// Random offset added to every timestamp
+    pub timestamp_base: u32,
+}
+
+impl RtpStream {
+    pub fn new(payload_type: u8) -> Self {
+        Self {
+            payload_type,
+            ssrc: rand::random(),
+            sequence: rand::random(),
+            timestamp_base: rand::random(),
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Sequence number of the next packet
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Build one packet; `timestamp` is relative to `timestamp_base`
+    pub fn packet(&mut self, timestamp: u32, marker: bool, payload: &[&[u8]]) -> Vec<u8> {
+        let payload_len: usize = payload.iter().map(|part| part.len()).sum();
+        let mut packet = Vec::with_capacity(12 + payload_len);
+        packet.push(0x80); // version 2, no padding, extension or CSRCs
+        packet.push(if marker { 0x80 } else { 0 } | self.payload_type);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp_base.wrapping_add(timestamp).to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        for part in payload {
+            packet.extend_from_slice(part);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        packet
+    }
+}
+
+/// The parts of a baseline JPEG that RFC 2435 carries
+#[derive(Debug)]
+pub struct JpegFrame<'a> {
+    /// 0 for 4:2:2, 1 for 4:2:0 (before adding 64 for restart markers)
+    pub jpeg_type: u8,
+    pub width: u16,
+    pub height: u16,
+    pub restart_interval: u16,
+    /// Luma then chroma quantization table, in zigzag order
+    pub tables: Vec<u8>,
+    /// Bit 0 set if the luma table is 16-bit, bit 1 for the chroma table
+    pub precision: u8,
+    /// Entropy-coded data between SOS and EOI
+    pub scan: &'a [u8],
+}
+
+impl<'a> JpegFrame<'a> {
+    /// Parse a JFIF/baseline frame as produced by the camera
+    ///
+    /// Receivers rebuild the headers from the RTP/JPEG fields, so only
+    /// three-component YCbCr images using the standard Huffman tables come
+    /// out right.
+    pub fn parse(jpeg: &'a [u8]) -> Result<Self> {
+        if !jpeg.starts_with(&[0xFF, 0xD8]) {
+            bail!("Missing SOI marker");
+        }
+
+        let mut tables: [Option<(u8, &[u8])>; 4] = [None; 4];
+        let mut components: Option<(u8, u8, u8)> = None; // luma sampling, luma table, chroma table
+        let mut size = (0u16, 0u16);
+        let mut restart_interval = 0;
+
+        let mut i = 2;
+        loop {
+            if i + 4 > jpeg.len() {
+                bail!("No SOS marker");
+            }
+            if jpeg[i] != 0xFF {
+                bail!("Expected a marker at offset {}", i);
+            }
+            let marker = jpeg[i + 1];
+            if marker == 0xFF {
+                i += 1;
+                continue;
+            }
+            let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+            let Some(segment) = jpeg.get(i + 4..i + 2 + length) else {
+                bail!("Truncated segment {:02X}", marker);
+            };
+
+            match marker {
+                // DQT: one or more tables
+                0xDB => {
+                    let mut rest = segment;
+                    while let Some((&pq_tq, data)) = rest.split_first() {
+                        let precision = pq_tq >> 4;
+                        let table_len = if precision == 0 { 64 } else { 128 };
+                        if data.len() < table_len {
+                            bail!("Truncated quantization table");
+                        }
+                        tables[(pq_tq & 0x03) as usize] = Some((precision, &data[..table_len]));
+                        rest = &data[table_len..];
+                    }
+                }
+                // SOF0/SOF1: baseline or extended sequential Huffman
+                0xC0 | 0xC1 => {
+                    if segment.len() < 15 || segment[5] != 3 {
+                        bail!("Only three-component YCbCr frames are supported");
+                    }
+                    size = (
+                        u16::from_be_bytes([segment[3], segment[4]]),
+                        u16::from_be_bytes([segment[1], segment[2]]),
+                    );
+                    let (luma_sampling, luma_table) = (segment[7], segment[8]);
+                    if segment[10] != 0x11 || segment[13] != 0x11 || segment[11] != segment[14] {
+                        bail!("Unsupported chroma layout");
+                    }
+                    components = Some((luma_sampling, luma_table, segment[11]));
+                }
+                0xC2..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                    bail!("Unsupported JPEG coding process (SOF{:X})", marker - 0xC0);
+                }
+                // DRI
+                0xDD if segment.len() >= 2 => {
+                    restart_interval = u16::from_be_bytes([segment[0], segment[1]]);
+                }
+                // SOS: the scan runs to EOI
+                0xDA => {
+                    let start = i + 2 + length;
+                    let end = if jpeg.ends_with(&[0xFF, 0xD9]) { jpeg.len() - 2 } else { jpeg.len() };
+                    let (luma_sampling, luma_table, chroma_table) =
+                        components.ok_or_else(|| anyhow::anyhow!("SOS before SOF"))?;
+
+                    let jpeg_type = match luma_sampling {
+                        0x21 => 0,
+                        0x22 => 1,
+                        other => bail!("Unsupported luma sampling {:02X}", other),
+                    };
+                    let (width, height) = size;
+                    if width == 0 || height == 0 || width > 2040 || height > 2040 {
+                        bail!("Unsupported frame size {}x{}", width, height);
+                    }
+
+                    let mut precision = 0;
+                    let mut table_data = Vec::with_capacity(128);
+                    for (bit, id) in [luma_table, chroma_table].into_iter().enumerate() {
+                        let Some((table_precision, table)) = tables[(id & 0x03) as usize] else {
+                            bail!("Missing quantization table {}", id);
+                        };
+                        if table_precision != 0 {
+                            precision |= 1 << bit;
+                        }
+                        table_data.extend_from_slice(table);
+                    }
+
+                    return Ok(Self {
+                        jpeg_type,
+                        width,
+                        height,
+                        restart_interval,
+                        tables: table_data,
+                        precision,
+                        scan: &jpeg[start.min(end)..end],
+                    });
+                }
+                _ => {}
+            }
+            i += 2 + length;
+        }
+    }
+
+    /// Split into RTP packets; the last one carries the marker bit
+    pub fn packetize(&self, stream: &mut RtpStream, timestamp: u32) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let jpeg_type = if self.restart_interval > 0 { self.jpeg_type + 64 } else { self.jpeg_type };
+        // Restart interval, then F=1, L=1 and a restart count of 0x3FFF: packets hold whole intervals
+        let restart_header = [
+            (self.restart_interval >> 8) as u8,
+            self.restart_interval as u8,
+            0xFF,
+            0xFF,
+        ];
+        let table_header = [0, self.precision, (self.tables.len() >> 8) as u8, self.tables.len() as u8];
+
+        let mut offset = 0;
+        loop {
+            let mut header = Vec::with_capacity(16 + self.tables.len());
+            header.push(0); // type-specific
+            header.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+            header.push(jpeg_type);
+            header.push(255); // Q: tables sent in-band
+            header.push(self.width.div_ceil(8) as u8);
+            header.push(self.height.div_ceil(8) as u8);
+            if self.restart_interval > 0 {
+                header.extend_from_slice(&restart_header);
+            }
+            if offset == 0 {
+                header.extend_from_slice(&table_header);
+                header.extend_from_slice(&self.tables);
+            }
+
+            let room = MAX_PAYLOAD.saturating_sub(header.len()).max(1);
+            let end = (offset + room).min(self.scan.len());
+            let last = end == self.scan.len();
+            packets.push(stream.packet(timestamp, last, &[&header, &self.scan[offset..end]]));
+            if last {
+                break packets;
+            }
+            offset = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PATTERN: &[u8] = include_bytes!("../bin/a9-v720-sim/test_pattern.jpg");
+
+    #[test]
+    fn test_parse_and_packetize_test_pattern() {
+        let frame = JpegFrame::parse(TEST_PATTERN).unwrap();
+        assert_eq!((frame.jpeg_type, frame.width, frame.height), (1, 640, 480));
+        assert_eq!((frame.tables.len(), frame.precision, frame.restart_interval), (128, 0, 0));
+
+        let mut stream = RtpStream::new(JPEG_PAYLOAD_TYPE);
+        let first_sequence = stream.sequence();
+        let packets = frame.packetize(&mut stream, 9000);
+        assert!(packets.len() > 1);
+
+        let mut scan = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= 12 + MAX_PAYLOAD);
+            assert_eq!(packet[1] & 0x7F, JPEG_PAYLOAD_TYPE);
+            assert_eq!(packet[1] & 0x80 != 0, i == packets.len() - 1, "marker only on the last packet");
+            let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+            assert_eq!(sequence, first_sequence.wrapping_add(i as u16));
+            assert_eq!(
+                u32::from_be_bytes(packet[4..8].try_into().unwrap()),
+                stream.timestamp_base.wrapping_add(9000)
+            );
+
+            let jpeg_header = &packet[12..20];
+            let offset = u32::from_be_bytes([0, jpeg_header[1], jpeg_header[2], jpeg_header[3]]) as usize;
+            assert_eq!(offset, scan.len());
+            assert_eq!(&jpeg_header[4..], &[1, 255, 80, 60]);
+            let mut data = &packet[20..];
+            if i == 0 {
+                assert_eq!(&data[..4], &[0, 0, 0, 128]);
+                data = &data[4 + 128..];
+            }
+            scan.extend_from_slice(data);
+        }
+        assert_eq!(scan, frame.scan);
+    }
+
+    #[test]
+    fn test_rejects_unsupported_frames() {
+        assert!(JpegFrame::parse(b"not a jpeg").is_err());
+        // SOI, then SOF2 (progressive)
+        let progressive = [0xFF, 0xD8, 0xFF, 0xC2, 0x00, 0x02];
+        assert!(JpegFrame::parse(&progressive).is_err());
+    }
+}
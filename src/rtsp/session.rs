@@ -0,0 +1,583 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use base64::Engine;
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use super::codec::{Outgoing, RtspRequest, RtspResponse};
+use super::rtp::{self, JpegFrame, RtpStream};
+use crate::config::AudioCodec;
+use crate::types::{CameraManager, ProtocolState, ViewerGuard};
+use crate::web::auth;
+
+/// Seconds a client may stay silent, as advertised in the `Session` header
+const SESSION_TIMEOUT_SECS: u32 = 60;
+
+const PUBLIC_METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER, SET_PARAMETER";
+
+const VIDEO_TRACK: usize = 0;
+const AUDIO_TRACK: usize = 1;
+
+/// How one track reaches the client, as negotiated by SETUP
+#[derive(Debug)]
+enum Transport {
+    Udp {
+        rtp: Arc<UdpSocket>,
+        /// Bound so the advertised RTCP port exists; reports are not read
+        _rtcp: UdpSocket,
+        client: SocketAddr,
+    },
+    Interleaved { channel: u8 },
+}
+
+/// Transport requested by the client in SETUP
+#[derive(Debug, PartialEq)]
+enum RequestedTransport {
+    Udp { rtp_port: u16, rtcp_port: u16 },
+    Interleaved { channel: Option<u8> },
+}
+
+#[derive(Debug)]
+struct Session {
+    id: String,
+    device_id: String,
+    tracks: [Option<Transport>; 2],
+    player: Option<JoinHandle<()>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(player) = self.player.take() {
+            player.abort();
+        }
+    }
+}
+
+/// State of one RTSP control connection
+///
+/// A connection carries at most one session, which is how RTSP clients
+/// (VLC, ffmpeg, NVRs) use it in practice.
+pub(super) struct Connection {
+    camera_manager: Arc<CameraManager>,
+    peer: SocketAddr,
+    local: SocketAddr,
+    out: mpsc::Sender<Outgoing>,
+    session: Option<Session>,
+    /// Player prepared by PLAY, started once the response has been queued
+    pending_player: Option<Player>,
+    /// User or API key the client authenticated as; checked once per connection
+    principal: Option<String>,
+}
+
+impl Connection {
+    pub(super) fn new(
+        camera_manager: Arc<CameraManager>,
+        peer: SocketAddr,
+        local: SocketAddr,
+        out: mpsc::Sender<Outgoing>,
+    ) -> Self {
+        Self {
+            camera_manager,
+            peer,
+            local,
+            out,
+            session: None,
+            pending_player: None,
+            principal: None,
+        }
+    }
+
+    pub(super) async fn handle(&mut self, request: &RtspRequest) -> RtspResponse {
+        tracing::debug!("RTSP {} {} from {}", request.method, request.uri, self.peer);
+
+        let response = match self.authorize(request).await {
+            Err(response) => response,
+            Ok(()) => self.dispatch(request).await,
+        };
+
+        let mut response = response.header("Server", "a9-v720-server");
+        if let Some(session) = &self.session {
+            response = response.header("Session", format!("{};timeout={}", session.id, SESSION_TIMEOUT_SECS));
+        }
+        if let Some(cseq) = request.header("CSeq") {
+            response.headers.insert(0, ("CSeq".to_string(), cseq.to_string()));
+        }
+        response
+    }
+
+    async fn dispatch(&mut self, request: &RtspRequest) -> RtspResponse {
+        match request.method.as_str() {
+            "OPTIONS" => RtspResponse::ok().header("Public", PUBLIC_METHODS),
+            "DESCRIBE" => self.describe(request).await,
+            "SETUP" => self.setup(request).await,
+            "PLAY" => self.play(request).await,
+            "PAUSE" => self.pause(request),
+            "TEARDOWN" => self.teardown(request),
+            // Keepalives
+            "GET_PARAMETER" | "SET_PARAMETER" => match self.check_session(request) {
+                Ok(()) => RtspResponse::ok(),
+                Err(response) => response,
+            },
+            _ => RtspResponse::error(501, "Not Implemented").header("Public", PUBLIC_METHODS),
+        }
+    }
+
+    /// Ask for Basic credentials of a user or API key whenever the web interface asks for them
+    ///
+    /// OPTIONS is always answered.
+    async fn authorize(&mut self, request: &RtspRequest) -> Result<(), RtspResponse> {
+        let config = self.camera_manager.config.current();
+        if self.principal.is_some() || request.method == "OPTIONS" || !auth::enabled(&config) {
+            return Ok(());
+        }
+        let unauthorized = || RtspResponse::error(401, "Unauthorized").header("WWW-Authenticate", "Basic realm=\"a9-v720-server\"");
+        let Some((username, password)) = request.header("Authorization").and_then(basic_credentials) else {
+            return Err(unauthorized());
+        };
+        match self.camera_manager.auth.basic_auth(&username, &password, &config, self.peer.ip()).await {
+            Ok(Some(principal)) => {
+                tracing::debug!("RTSP client {} authenticated as {}", self.peer, principal);
+                self.principal = Some(principal);
+                Ok(())
+            }
+            Ok(None) => {
+                tracing::warn!("Wrong RTSP credentials for {:?} from {}", username, self.peer.ip());
+                Err(unauthorized())
+            }
+            Err(_) => {
+                tracing::warn!("Refused RTSP credentials from {}: locked out after repeated failures", self.peer.ip());
+                Err(RtspResponse::error(403, "Forbidden"))
+            }
+        }
+    }
+
+    /// Start streaming if the last request was a successful PLAY
+    pub(super) fn start_pending_player(&mut self) {
+        if let (Some(player), Some(session)) = (self.pending_player.take(), self.session.as_mut()) {
+            session.player = Some(tokio::spawn(player.run()));
+        }
+    }
+
+    async fn describe(&self, request: &RtspRequest) -> RtspResponse {
+        let segments = path_segments(&request.uri);
+        let Some(&device_id) = segments.first() else {
+            return RtspResponse::error(404, "Not Found");
+        };
+        if !self.camera_manager.contains(device_id) {
+            return RtspResponse::error(404, "Not Found");
+        }
+
+        let codec = self.camera_manager.config.current().audio_codec;
+        let base = format!("{}/", request.uri.trim_end_matches('/'));
+        RtspResponse::ok()
+            .header("Content-Base", base)
+            .body("application/sdp", sdp(device_id, self.local, codec))
+    }
+
+    async fn setup(&mut self, request: &RtspRequest) -> RtspResponse {
+        let segments = path_segments(&request.uri);
+        let Some(&device_id) = segments.first() else {
+            return RtspResponse::error(404, "Not Found");
+        };
+        let track = match segments.get(1) {
+            None => VIDEO_TRACK,
+            Some(control) => match control.strip_prefix("trackID=").and_then(|id| id.parse::<usize>().ok()) {
+                Some(track @ (VIDEO_TRACK | AUDIO_TRACK)) => track,
+                _ => return RtspResponse::error(404, "Not Found"),
+            },
+        };
+
+        match &self.session {
+            Some(session) => {
+                if let Err(response) = self.check_session(request) {
+                    return response;
+                }
+                if session.device_id != device_id {
+                    return RtspResponse::error(459, "Aggregate Operation Not Allowed");
+                }
+            }
+            None => {
+                if !self.camera_manager.contains(device_id) {
+                    return RtspResponse::error(404, "Not Found");
+                }
+            }
+        }
+
+        let Some(requested) = request.header("Transport").and_then(parse_transport) else {
+            return RtspResponse::error(461, "Unsupported Transport");
+        };
+        let (transport, reply) = match requested {
+            RequestedTransport::Udp { rtp_port, rtcp_port } => {
+                let bind = SocketAddr::new(self.local.ip(), 0);
+                let (rtp, rtcp) = match (UdpSocket::bind(bind).await, UdpSocket::bind(bind).await) {
+                    (Ok(rtp), Ok(rtcp)) => (rtp, rtcp),
+                    (Err(e), _) | (_, Err(e)) => {
+                        tracing::error!("Failed to bind RTP sockets for {}: {}", self.peer, e);
+                        return RtspResponse::error(500, "Internal Server Error");
+                    }
+                };
+                let (server_rtp, server_rtcp) = match (rtp.local_addr(), rtcp.local_addr()) {
+                    (Ok(rtp), Ok(rtcp)) => (rtp.port(), rtcp.port()),
+                    _ => return RtspResponse::error(500, "Internal Server Error"),
+                };
+                let reply = format!(
+                    "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                    rtp_port, rtcp_port, server_rtp, server_rtcp
+                );
+                let transport = Transport::Udp {
+                    rtp: Arc::new(rtp),
+                    _rtcp: rtcp,
+                    client: SocketAddr::new(self.peer.ip(), rtp_port),
+                };
+                (transport, reply)
+            }
+            RequestedTransport::Interleaved { channel } => {
+                let channel = channel.unwrap_or(track as u8 * 2);
+                let reply = format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel.wrapping_add(1));
+                (Transport::Interleaved { channel }, reply)
+            }
+        };
+
+        let session = self.session.get_or_insert_with(|| Session {
+            id: format!("{:016X}", rand::random::<u64>()),
+            device_id: device_id.to_string(),
+            tracks: [None, None],
+            player: None,
+        });
+        session.tracks[track] = Some(transport);
+        tracing::info!("RTSP session {} for {}: track {} via {}", session.id, device_id, track, reply);
+
+        RtspResponse::ok().header("Transport", reply)
+    }
+
+    async fn play(&mut self, request: &RtspRequest) -> RtspResponse {
+        if let Err(response) = self.check_session(request) {
+            return response;
+        }
+        let Some(session) = self.session.as_ref() else {
+            return RtspResponse::error(455, "Method Not Valid in This State");
+        };
+        if session.player.as_ref().is_some_and(|player| !player.is_finished()) {
+            return RtspResponse::ok().header("Range", "npt=0.000-");
+        }
+        if session.tracks.iter().all(Option::is_none) {
+            return RtspResponse::error(455, "Method Not Valid in This State");
+        }
+
+        let codec = self.camera_manager.config.current().audio_codec;
+        let Some(camera) = self.camera_manager.handle(&session.device_id) else {
+            return RtspResponse::error(404, "Not Found");
+        };
+        let Some(media) = camera.subscribe().await else {
+            return RtspResponse::error(404, "Not Found");
+        };
+        let (frames, samples) = (media.frames, media.audio);
+
+        let viewer_id = format!("rtsp-{}", session.id);
+        let (viewer, state) = {
+            let mut camera_guard = camera.connection.write().await;
+            camera_guard.add_viewer(viewer_id.clone());
+            (
+                ViewerGuard::new(camera.connection.clone(), viewer_id),
+                camera_guard.state.current().clone(),
+            )
+        };
+
+        // An NVR only speaks RTSP, so PLAY brings up the camera stream itself
+        if matches!(state, ProtocolState::Idle | ProtocolState::Error) {
+            let camera_manager = self.camera_manager.clone();
+            let device_id = session.device_id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = crate::router::tcp::TcpRouter::start_streaming_for_camera(&device_id, &camera_manager).await {
+                    tracing::error!("Failed to start streaming for RTSP client of {}: {}", device_id, e);
+                }
+            });
+        }
+
+        let base = format!("{}/", request.uri.trim_end_matches('/'));
+        let mut rtp_info = Vec::new();
+        let mut sinks: [Option<(RtpSink, RtpStream)>; 2] = [None, None];
+        for (track, transport) in session.tracks.iter().enumerate() {
+            let Some(transport) = transport else {
+                continue;
+            };
+            let payload_type = if track == VIDEO_TRACK {
+                rtp::JPEG_PAYLOAD_TYPE
+            } else {
+                rtp::audio_payload_type(codec)
+            };
+            let stream = RtpStream::new(payload_type);
+            rtp_info.push(format!(
+                "url={}trackID={};seq={};rtptime={}",
+                base,
+                track,
+                stream.sequence(),
+                stream.timestamp_base
+            ));
+            let sink = match transport {
+                Transport::Udp { rtp, client, .. } => RtpSink::Udp {
+                    socket: rtp.clone(),
+                    client: *client,
+                },
+                Transport::Interleaved { channel } => RtpSink::Interleaved {
+                    channel: *channel,
+                    out: self.out.clone(),
+                },
+            };
+            sinks[track] = Some((sink, stream));
+        }
+
+        let [video, audio] = sinks;
+        tracing::info!("RTSP session {} playing {}", session.id, session.device_id);
+        self.pending_player = Some(Player {
+            video,
+            audio,
+            frames,
+            samples,
+            _viewer: viewer,
+        });
+
+        RtspResponse::ok()
+            .header("Range", "npt=0.000-")
+            .header("RTP-Info", rtp_info.join(","))
+    }
+
+    fn pause(&mut self, request: &RtspRequest) -> RtspResponse {
+        if let Err(response) = self.check_session(request) {
+            return response;
+        }
+        if let Some(player) = self.session.as_mut().and_then(|session| session.player.take()) {
+            player.abort();
+        }
+        RtspResponse::ok()
+    }
+
+    fn teardown(&mut self, request: &RtspRequest) -> RtspResponse {
+        if let Err(response) = self.check_session(request) {
+            return response;
+        }
+        if let Some(session) = self.session.take() {
+            tracing::info!("RTSP session {} torn down", session.id);
+        }
+        RtspResponse::ok()
+    }
+
+    /// The request must name this connection's session, if it has one
+    fn check_session(&self, request: &RtspRequest) -> Result<(), RtspResponse> {
+        let requested = request.header("Session").map(|value| value.split(';').next().unwrap_or_default().trim());
+        match (&self.session, requested) {
+            (Some(session), Some(id)) if session.id == id => Ok(()),
+            (None, None) => Ok(()),
+            _ => Err(RtspResponse::error(454, "Session Not Found")),
+        }
+    }
+}
+
+/// Where one track's RTP packets go
+enum RtpSink {
+    Udp { socket: Arc<UdpSocket>, client: SocketAddr },
+    Interleaved { channel: u8, out: mpsc::Sender<Outgoing> },
+}
+
+impl RtpSink {
+    /// Returns false once the client connection is gone
+    async fn send(&self, packet: Vec<u8>) -> bool {
+        match self {
+            RtpSink::Udp { socket, client } => {
+                // UDP errors (e.g. ICMP port unreachable) are transient; the control connection decides
+                if let Err(e) = socket.send_to(&packet, client).await {
+                    tracing::debug!("RTP send to {} failed: {}", client, e);
+                }
+                true
+            }
+            RtpSink::Interleaved { channel, out } => out
+                .send(Outgoing::Interleaved {
+                    channel: *channel,
+                    data: Bytes::from(packet),
+                })
+                .await
+                .is_ok(),
+        }
+    }
+}
+
+/// Packetizes a camera's frames and audio for one session
+struct Player {
+    video: Option<(RtpSink, RtpStream)>,
+    audio: Option<(RtpSink, RtpStream)>,
+    frames: broadcast::Receiver<Bytes>,
+    samples: broadcast::Receiver<Bytes>,
+    _viewer: ViewerGuard,
+}
+
+impl Player {
+    async fn run(mut self) {
+        let started = Instant::now();
+        let mut audio_clock: u32 = 0;
+        let mut reported_unsupported = false;
+
+        loop {
+            tokio::select! {
+                frame = self.frames.recv(), if self.video.is_some() => {
+                    let frame = match frame {
+                        Ok(frame) => frame,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::debug!("RTSP player lagging, skipped {} frames", skipped);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    };
+                    let jpeg = match JpegFrame::parse(&frame) {
+                        Ok(jpeg) => jpeg,
+                        Err(e) => {
+                            if !reported_unsupported {
+                                tracing::warn!("Frame cannot be sent as RTP/JPEG: {:#}", e);
+                                reported_unsupported = true;
+                            }
+                            continue;
+                        }
+                    };
+
+                    // 90 kHz clock from wall time, since the camera's frame rate varies
+                    let timestamp = (started.elapsed().as_micros() * 9 / 100) as u32;
+                    let (sink, stream) = self.video.as_mut().expect("checked by the select guard");
+                    for packet in jpeg.packetize(stream, timestamp) {
+                        if !sink.send(packet).await {
+                            return;
+                        }
+                    }
+                }
+                samples = self.samples.recv(), if self.audio.is_some() => {
+                    let samples = match samples {
+                        Ok(samples) => samples,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            tracing::debug!("RTSP player lagging, skipped {} audio packets", skipped);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    };
+
+                    // One byte per sample at 8 kHz
+                    let (sink, stream) = self.audio.as_mut().expect("checked by the select guard");
+                    let packet = stream.packet(audio_clock, false, &[&samples]);
+                    audio_clock = audio_clock.wrapping_add(samples.len() as u32);
+                    if !sink.send(packet).await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Session description with a JPEG video track and a G.711 audio track
+fn sdp(device_id: &str, local: SocketAddr, codec: AudioCodec) -> String {
+    let family = if local.is_ipv4() { "IP4" } else { "IP6" };
+    let audio_type = rtp::audio_payload_type(codec);
+    [
+        "v=0".to_string(),
+        format!("o=- {} 1 IN {} {}", rand::random::<u32>(), family, local.ip()),
+        format!("s={}", device_id),
+        format!("c=IN {} {}", family, if local.is_ipv4() { "0.0.0.0" } else { "::" }),
+        "t=0 0".to_string(),
+        "a=control:*".to_string(),
+        format!("m=video 0 RTP/AVP {}", rtp::JPEG_PAYLOAD_TYPE),
+        format!("a=rtpmap:{} JPEG/{}", rtp::JPEG_PAYLOAD_TYPE, rtp::JPEG_CLOCK_RATE),
+        format!("a=control:trackID={}", VIDEO_TRACK),
+        format!("m=audio 0 RTP/AVP {}", audio_type),
+        format!(
+            "a=rtpmap:{} {}/{}/1",
+            audio_type,
+            rtp::audio_encoding_name(codec),
+            crate::audio::SAMPLE_RATE
+        ),
+        format!("a=control:trackID={}", AUDIO_TRACK),
+    ]
+    .join("\r\n")
+        + "\r\n"
+}
+
+/// Path segments of `rtsp://host:port/<device_id>/<control>`
+/// Username and password of an `Authorization: Basic ...` header
+fn basic_credentials(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?.trim();
+    let decoded = String::from_utf8(base64::engine::general_purpose::STANDARD.decode(encoded).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+fn path_segments(uri: &str) -> Vec<&str> {
+    let path = match uri.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        None => uri,
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    path.split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+/// First unicast RTP transport in a `Transport` header we can serve
+fn parse_transport(header: &str) -> Option<RequestedTransport> {
+    header.split(',').find_map(|spec| {
+        let mut params = spec.split(';').map(str::trim);
+        let protocol = params.next()?;
+        let params: Vec<&str> = params.collect();
+        if params.contains(&"multicast") {
+            return None;
+        }
+        let value = |name: &str| {
+            params
+                .iter()
+                .find_map(|param| param.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+        };
+
+        match protocol {
+            "RTP/AVP/TCP" => Some(RequestedTransport::Interleaved {
+                channel: value("interleaved").and_then(|range| range.split('-').next()?.parse().ok()),
+            }),
+            "RTP/AVP" | "RTP/AVP/UDP" => {
+                let ports = value("client_port")?;
+                let (rtp, rtcp) = match ports.split_once('-') {
+                    Some((rtp, rtcp)) => (rtp.parse().ok()?, rtcp.parse().ok()?),
+                    None => {
+                        let rtp: u16 = ports.parse().ok()?;
+                        (rtp, rtp.checked_add(1)?)
+                    }
+                };
+                Some(RequestedTransport::Udp { rtp_port: rtp, rtcp_port: rtcp })
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_credentials() {
+        assert_eq!(basic_credentials("Basic YWRtaW46aHVudGVyOjI="), Some(("admin".to_string(), "hunter:2".to_string())));
+        assert_eq!(basic_credentials("Basic !!!"), None);
+        assert_eq!(basic_credentials("Bearer ha.s3cret"), None);
+    }
+
+    #[test]
+    fn test_parse_transport_and_uri() {
+        assert_eq!(
+            parse_transport("RTP/AVP;unicast;client_port=5000-5001"),
+            Some(RequestedTransport::Udp { rtp_port: 5000, rtcp_port: 5001 })
+        );
+        assert_eq!(
+            parse_transport("RTP/AVP;multicast, RTP/AVP/TCP;unicast;interleaved=2-3"),
+            Some(RequestedTransport::Interleaved { channel: Some(2) })
+        );
+        assert_eq!(parse_transport("RAW/RAW/UDP;unicast"), None);
+
+        assert_eq!(path_segments("rtsp://10.0.0.1:8554/0800c00128F8/trackID=1"), vec!["0800c00128F8", "trackID=1"]);
+        assert_eq!(path_segments("rtsp://10.0.0.1/0800c00128F8/?tcp"), vec!["0800c00128F8"]);
+    }
+}
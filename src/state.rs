@@ -0,0 +1,148 @@
+//! Camera protocol state machine
+//!
+//! A camera moves Configuring → Registering → Idle → NatTraversal →
+//! Streaming, with Snapshot as a short detour from Idle. Every change goes
+//! through `StateMachine::transition`, which refuses moves the protocol does
+//! not allow and keeps a bounded, timestamped history for the API.
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::types::ProtocolState;
+
+/// Transitions kept per camera
+pub const HISTORY_LEN: usize = 32;
+
+impl ProtocolState {
+    /// Whether the protocol allows moving from `self` to `next`
+    pub fn can_transition_to(&self, next: &ProtocolState) -> bool {
+        use ProtocolState::*;
+
+        match (self, next) {
+            // A camera can lose its connection or power cycle at any point
+            (_, Disconnected | Error | Configuring | Registering) => true,
+            (Registering, Idle) => true,
+            (Idle | Streaming | Error, NatTraversal) => true,
+            (NatTraversal, Streaming) => true,
+            (Idle, Snapshot) => true,
+            (NatTraversal | Streaming | Snapshot | Error, Idle) => true,
+            _ => false,
+        }
+    }
+}
+
+/// One recorded state change
+#[derive(Debug, Clone, Serialize)]
+pub struct StateTransition {
+    pub from: ProtocolState,
+    pub to: ProtocolState,
+    pub at: DateTime<Utc>,
+    pub reason: String,
+}
+
+/// A transition the protocol does not allow
+#[derive(Debug, thiserror::Error)]
+#[error("illegal state transition {from:?} -> {to:?} ({reason})")]
+pub struct IllegalTransition {
+    pub from: ProtocolState,
+    pub to: ProtocolState,
+    pub reason: String,
+}
+
+/// Current protocol state of a camera plus its recent transitions
+#[derive(Debug)]
+pub struct StateMachine {
+    current: ProtocolState,
+    since: DateTime<Utc>,
+    history: VecDeque<StateTransition>,
+}
+
+impl StateMachine {
+    pub fn new() -> Self {
+        Self {
+            current: ProtocolState::Disconnected,
+            since: Utc::now(),
+            history: VecDeque::with_capacity(HISTORY_LEN),
+        }
+    }
+
+    pub fn current(&self) -> &ProtocolState {
+        &self.current
+    }
+
+    /// When the current state was entered
+    pub fn since(&self) -> DateTime<Utc> {
+        self.since
+    }
+
+    /// Recorded transitions, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &StateTransition> {
+        self.history.iter()
+    }
+
+    /// Move to `to`, recording why
+    ///
+    /// Staying in the current state is a no-op and is not recorded.
+    pub fn transition(&mut self, to: ProtocolState, reason: &str) -> Result<(), IllegalTransition> {
+        if self.current == to {
+            return Ok(());
+        }
+        if !self.current.can_transition_to(&to) {
+            return Err(IllegalTransition {
+                from: self.current.clone(),
+                to,
+                reason: reason.to_string(),
+            });
+        }
+
+        let now = Utc::now();
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(StateTransition {
+            from: std::mem::replace(&mut self.current, to.clone()),
+            to,
+            at: now,
+            reason: reason.to_string(),
+        });
+        self.since = now;
+        Ok(())
+    }
+}
+
+impl Default for StateMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions_are_validated_and_recorded() {
+        let mut state = StateMachine::new();
+        state.transition(ProtocolState::Registering, "code 100").unwrap();
+        state.transition(ProtocolState::Idle, "code 101 sent").unwrap();
+        // Streaming needs NAT traversal first
+        assert!(state.transition(ProtocolState::Streaming, "code 12").is_err());
+        assert_eq!(state.current(), &ProtocolState::Idle);
+
+        state.transition(ProtocolState::NatTraversal, "code 11 sent").unwrap();
+        state.transition(ProtocolState::Streaming, "first frame").unwrap();
+        state.transition(ProtocolState::Streaming, "again").unwrap();
+
+        let reasons: Vec<_> = state.history().map(|t| t.reason.as_str()).collect();
+        assert_eq!(reasons, ["code 100", "code 101 sent", "code 11 sent", "first frame"]);
+
+        for _ in 0..HISTORY_LEN {
+            state.transition(ProtocolState::Idle, "stop").unwrap();
+            state.transition(ProtocolState::NatTraversal, "start").unwrap();
+        }
+        assert_eq!(state.history().count(), HISTORY_LEN);
+        assert_eq!(state.history().last().unwrap().to, ProtocolState::NatTraversal);
+    }
+}
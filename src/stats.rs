@@ -0,0 +1,183 @@
+//! Rolling stream quality statistics
+//!
+//! Each camera's `StreamBuffer` records every media packet and every frame
+//! it assembles. Rates, frame sizes and inter-frame jitter are computed over
+//! the last `WINDOW`; counters since registration come from the reassembly
+//! (`ReassemblyStats`) and the media task (CMD 605 confirmations and
+//! retransmission requests).
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::types::ReassemblyStats;
+
+/// Span the rolling figures are computed over
+pub const WINDOW: Duration = Duration::from_secs(10);
+
+/// Frames kept for the window at most, whatever the frame rate
+const MAX_FRAME_SAMPLES: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct FrameSample {
+    at: Instant,
+    bytes: usize,
+    fragments: usize,
+}
+
+/// Packets and bytes received within one second of the window
+#[derive(Debug, Clone, Copy)]
+struct PacketBucket {
+    second: u64,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Rolling record of the media of one camera
+#[derive(Debug, Clone)]
+pub struct StreamStats {
+    started: Instant,
+    frames: VecDeque<FrameSample>,
+    packets: VecDeque<PacketBucket>,
+}
+
+/// Stream quality of one camera at one moment
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamQuality {
+    /// Seconds the rolling figures cover; less than the window for a camera that just registered
+    pub window_secs: f64,
+    pub fps: f64,
+    /// Media payload received, video and audio
+    pub bytes_per_sec: f64,
+    pub packets_per_sec: f64,
+    pub frame_bytes_avg: Option<f64>,
+    pub frame_bytes_min: Option<usize>,
+    pub frame_bytes_max: Option<usize>,
+    pub fragments_per_frame: Option<f64>,
+    /// Mean time between assembled frames
+    pub frame_interval_ms: Option<f64>,
+    /// Standard deviation of the time between assembled frames
+    pub jitter_ms: Option<f64>,
+    /// Counters since the camera registered
+    pub reassembly: ReassemblyStats,
+    pub confirmations_sent: u64,
+    pub requests_sent: u64,
+}
+
+impl StreamStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            frames: VecDeque::new(),
+            packets: VecDeque::new(),
+        }
+    }
+
+    pub fn record_packet(&mut self, now: Instant, bytes: usize) {
+        let second = now.saturating_duration_since(self.started).as_secs();
+        match self.packets.back_mut() {
+            Some(bucket) if bucket.second == second => {
+                bucket.packets += 1;
+                bucket.bytes += bytes as u64;
+            }
+            _ => self.packets.push_back(PacketBucket { second, packets: 1, bytes: bytes as u64 }),
+        }
+        self.expire(now);
+    }
+
+    pub fn record_frame(&mut self, now: Instant, bytes: usize, fragments: usize) {
+        self.frames.push_back(FrameSample { at: now, bytes, fragments });
+        if self.frames.len() > MAX_FRAME_SAMPLES {
+            self.frames.pop_front();
+        }
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.frames.front().is_some_and(|frame| now.saturating_duration_since(frame.at) > WINDOW) {
+            self.frames.pop_front();
+        }
+        let oldest_second = now.saturating_duration_since(self.started).saturating_sub(WINDOW).as_secs();
+        while self.packets.front().is_some_and(|bucket| bucket.second < oldest_second) {
+            self.packets.pop_front();
+        }
+    }
+
+    /// Rolling figures as of `now`, together with the counters
+    pub fn quality(&mut self, now: Instant, reassembly: ReassemblyStats, confirmations_sent: u64, requests_sent: u64) -> StreamQuality {
+        self.expire(now);
+        let window = now.saturating_duration_since(self.started).min(WINDOW).as_secs_f64();
+        let per_sec = |count: f64| if window > 0.0 { count / window } else { 0.0 };
+
+        let frame_count = self.frames.len();
+        let frame_bytes: usize = self.frames.iter().map(|frame| frame.bytes).sum();
+        let fragments: usize = self.frames.iter().map(|frame| frame.fragments).sum();
+        let average = |total: usize| (frame_count > 0).then(|| total as f64 / frame_count as f64);
+
+        let intervals: Vec<f64> = self
+            .frames
+            .iter()
+            .zip(self.frames.iter().skip(1))
+            .map(|(earlier, later)| later.at.duration_since(earlier.at).as_secs_f64() * 1000.0)
+            .collect();
+        let frame_interval_ms = (!intervals.is_empty()).then(|| intervals.iter().sum::<f64>() / intervals.len() as f64);
+        let jitter_ms = frame_interval_ms.map(|mean| {
+            let variance = intervals.iter().map(|interval| (interval - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+            variance.sqrt()
+        });
+
+        StreamQuality {
+            window_secs: window,
+            fps: per_sec(frame_count as f64),
+            bytes_per_sec: per_sec(self.packets.iter().map(|bucket| bucket.bytes).sum::<u64>() as f64),
+            packets_per_sec: per_sec(self.packets.iter().map(|bucket| bucket.packets).sum::<u64>() as f64),
+            frame_bytes_avg: average(frame_bytes),
+            frame_bytes_min: self.frames.iter().map(|frame| frame.bytes).min(),
+            frame_bytes_max: self.frames.iter().map(|frame| frame.bytes).max(),
+            fragments_per_frame: average(fragments),
+            frame_interval_ms,
+            jitter_ms,
+            reassembly,
+            confirmations_sent,
+            requests_sent,
+        }
+    }
+}
+
+impl Default for StreamStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates_and_jitter_over_the_window() {
+        let mut stats = StreamStats::new();
+        let start = stats.started;
+        // 10 frames 100 ms apart, then one 300 ms late
+        for (i, delay) in [0, 100, 200, 300, 400, 500, 600, 700, 800, 900, 1200].into_iter().enumerate() {
+            let at = start + Duration::from_millis(delay);
+            stats.record_packet(at, 1000);
+            stats.record_packet(at, 500);
+            stats.record_frame(at, 1500 + i, 2);
+        }
+
+        let quality = stats.quality(start + Duration::from_secs(2), ReassemblyStats::default(), 3, 0);
+        assert_eq!(quality.window_secs, 2.0);
+        assert_eq!(quality.fps, 5.5);
+        assert_eq!(quality.bytes_per_sec, 8250.0);
+        assert_eq!((quality.frame_bytes_min, quality.frame_bytes_max), (Some(1500), Some(1510)));
+        assert_eq!(quality.fragments_per_frame, Some(2.0));
+        assert_eq!(quality.frame_interval_ms.map(f64::round), Some(120.0));
+        assert!(quality.jitter_ms.unwrap() > 50.0);
+
+        // Everything has left the window
+        let quality = stats.quality(start + Duration::from_secs(30), ReassemblyStats::default(), 3, 0);
+        assert_eq!((quality.fps, quality.bytes_per_sec, quality.jitter_ms), (0.0, 0.0, None));
+    }
+}
@@ -1,14 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::IpAddr;
+use std::ops::Bound;
 use std::sync::Arc;
+use arc_swap::ArcSwap;
+use crate::actor::CameraHandle;
+use crate::metrics::Metrics;
+use crate::registry::DeviceRegistry;
+use crate::stats::{StreamQuality, StreamStats};
 use tokio::sync::RwLock;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, Mutex};
+use bytes::Bytes;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-/// Camera protocol states
+/// Camera protocol states; `crate::state` defines the allowed transitions
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ProtocolState {
     Disconnected,    // No connection
@@ -46,231 +53,428 @@ pub struct VideoFrame {
     pub is_keyframe: bool,
 }
 
+/// Fragments beyond a missing pkg_id after which it is given up as lost
+pub const REORDER_WINDOW: u32 = 64;
+
+/// A gap trailing the newest pkg_id by this many packets is asked to be resent; less is reordering
+pub const RETRANSMIT_AFTER: u32 = 2;
+
+/// How long a pkg_id asked to be resent is waited for
+pub const RETRANSMISSION_BUDGET: Duration = Duration::from_millis(500);
+
+/// Packets held back behind a gap at most, whatever is left of its budget
+const MAX_HELD: u32 = 1024;
+
+/// A partial frame is discarded once no packet has arrived for this long
+pub const STALE_FRAME: Duration = Duration::from_secs(1);
+
+/// A pkg_id this far from the highest one seen means the camera started a new sequence
+///
+/// This also covers pkg_ids wrapping around past `u32::MAX`: the frame
+/// spanning the wrap is lost and the camera's next start fragment begins a
+/// fresh sequence.
+const SEQUENCE_RESTART: u32 = 4096;
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_EOI: [u8; 2] = [0xFF, 0xD9];
+
+/// Frame reassembly counters of one camera, since it registered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ReassemblyStats {
+    pub frames_completed: u64,
+    /// Given up because a fragment never arrived
+    pub frames_dropped: u64,
+    /// All fragments arrived but they do not form a JPEG (SOI ... EOI)
+    pub frames_corrupt: u64,
+    /// Partial frames discarded because the stream went quiet
+    pub frames_expired: u64,
+    /// pkg_ids given up as lost
+    pub fragments_missing: u64,
+    /// Fragments that arrived after their frame was completed or given up
+    pub fragments_late: u64,
+    pub fragments_duplicate: u64,
+    /// pkg_ids asked to be resent
+    pub retransmits_requested: u64,
+    /// Requested pkg_ids that arrived in time
+    pub retransmits_recovered: u64,
+    /// Requested pkg_ids given up after `RETRANSMISSION_BUDGET`
+    pub retransmits_abandoned: u64,
+}
+
 /// Stream buffer for video data
+///
+/// Fragments are reassembled by pkg_id: a frame is the run of consecutive
+/// pkg_ids from a 250 start to a 252 end, where pkg_ids of audio packets in
+/// between belong to no frame. Fragments may arrive out of order within
+/// `REORDER_WINDOW`, and a fragment asked to be resent is waited for up to
+/// `RETRANSMISSION_BUDGET`; a frame missing a fragment is dropped rather
+/// than delivered as a corrupt JPEG.
 #[derive(Debug, Clone)]
 pub struct StreamBuffer {
-    frames: VecDeque<Vec<u8>>,  // Store complete video frames
+    frames: VecDeque<Bytes>,  // Store complete video frames
     pub max_frames: usize,          // Maximum number of frames to keep
-    current_frame: Option<FrameFragment>, // Current frame being assembled
+    pending: BTreeMap<u32, Fragment>, // Video fragments not yet assembled, by pkg_id
+    other: BTreeSet<u32>,           // Non-video pkg_ids at or after `next_id`
+    next_id: Option<u32>,           // Lowest pkg_id not yet assembled or given up; None until the first start fragment
+    highest_id: u32,                // Highest pkg_id seen in this sequence
+    skipping: bool,                 // Passing the rest of a frame that was already given up
+    requested: BTreeMap<u32, Instant>, // Missing pkg_ids asked to be resent, and when
+    last_packet: Option<Instant>,
+    stats: ReassemblyStats,
+    rolling: StreamStats,           // Rates, frame sizes and jitter over the last few seconds
+    frame_tx: broadcast::Sender<Bytes>, // Publishes every completed frame to live viewers
 }
 
 #[derive(Debug, Clone)]
-struct FrameFragment {
-    frame_type: u16,           // CMD field (1=JPEG, 4=G711, 7=AVI)
-    pkg_id: u32,               // Packet ID for this frame
-    fragments: Vec<Vec<u8>>,   // Accumulated fragments
-    expected_size: Option<usize>, // Expected total size (from last fragment)
-    is_complete: bool,         // Whether frame is complete
+struct Fragment {
+    msg_flag: u8,
+    payload: Vec<u8>,
+}
+
+/// Where the frame starting at some pkg_id stands
+enum FrameScan {
+    /// Every fragment up to this end fragment is there
+    Complete(u32),
+    /// The frame runs into the next start fragment without an end
+    Unterminated(u32),
+    /// Waiting for this pkg_id
+    Gap(u32),
+    /// The frame would run past `u32::MAX`
+    Truncated,
 }
 
 impl StreamBuffer {
+    /// Completed frames a live viewer may fall behind by before it starts skipping frames
+    pub const BROADCAST_CAPACITY: usize = 8;
+
     pub fn new(max_frames: usize) -> Self {
+        let (frame_tx, _) = broadcast::channel(Self::BROADCAST_CAPACITY);
         Self {
             frames: VecDeque::with_capacity(max_frames),
             max_frames,
-            current_frame: None,
+            pending: BTreeMap::new(),
+            other: BTreeSet::new(),
+            next_id: None,
+            highest_id: 0,
+            skipping: false,
+            requested: BTreeMap::new(),
+            last_packet: None,
+            stats: ReassemblyStats::default(),
+            rolling: StreamStats::new(),
+            frame_tx,
         }
     }
 
-    /// Add a UDP packet fragment to the buffer
-    /// Returns true if a complete frame was assembled
-        pub fn add_fragment(&mut self, cmd: u16, msg_flag: u8, pkg_id: u32, payload: &[u8]) -> bool {
+    /// Receive every frame completed from now on
+    ///
+    /// A receiver that falls more than `BROADCAST_CAPACITY` frames behind gets
+    /// `RecvError::Lagged` and resumes from the oldest frame still queued.
+    pub fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.frame_tx.subscribe()
+    }
+
+    /// Add a UDP packet to the buffer
+    ///
+    /// Packets other than video fragments (cmd=6 audio and the like) only
+    /// account for their pkg_id. Returns true if at least one complete frame
+    /// was assembled.
+    pub fn add_fragment(&mut self, cmd: u16, msg_flag: u8, pkg_id: u32, payload: &[u8]) -> bool {
         tracing::debug!("add_fragment: cmd={}, msg_flag={}, pkg_id={}, payload_len={}",
             cmd, msg_flag, pkg_id, payload.len());
 
-        match (cmd, msg_flag) {
-            (6, 255) => {
-                // cmd=6 frames are PCM audio frames (not video)
-                // These should be processed as audio data, not added to video buffer
-                tracing::debug!("Received PCM audio frame (cmd=6): {} bytes", payload.len());
-                // For now, just acknowledge receipt but don't add to video buffer
-                true
-            }
-            (6, _) => {
-                // Other cmd=6 frames (fallback)
-                tracing::debug!("Received PCM audio frame (cmd=6, msg_flag={}): {} bytes", msg_flag, payload.len());
-                true
-            }
-            (1, 250) => {
-                // Start of JPEG frame - begin new frame assembly
-                tracing::info!("Starting JPEG frame assembly (msg_flag=250): {} bytes", payload.len());
-                self.start_new_frame(pkg_id, payload.to_vec());
-                true
-            }
-            (1, 251) => {
-                // Continuation of JPEG frame - add to current frame
-                tracing::info!("Continuing JPEG frame assembly (msg_flag=251): {} bytes", payload.len());
-                self.add_frame_fragment(pkg_id, payload.to_vec());
-                true
-            }
-            (1, 252) => {
-                // End of JPEG frame - complete frame assembly
-                tracing::info!("Completing JPEG frame assembly (msg_flag=252): {} bytes", payload.len());
-                if self.complete_frame(pkg_id, payload.to_vec()) {
-                    tracing::info!("JPEG frame assembly completed successfully");
-                } else {
-                    tracing::warn!("Failed to complete JPEG frame assembly");
-                }
-                true
-            }
+        let is_video = match (cmd, msg_flag) {
+            (1, 250..=252) => true,
+            (6, _) => false, // G.711 audio; callers route the payload to the audio buffer
             _ => {
-                // Unknown command/flag combination
                 tracing::debug!("Unknown frame type: cmd={}, msg_flag={}, pkg_id={}", cmd, msg_flag, pkg_id);
                 false
             }
+        };
+        let now = Instant::now();
+        self.last_packet = Some(now);
+        self.rolling.record_packet(now, payload.len());
+
+        let Some(next_id) = self.next_id else {
+            // Nothing to reassemble until the first start fragment
+            if is_video && msg_flag == 250 {
+                self.next_id = Some(pkg_id);
+                self.highest_id = pkg_id;
+                self.pending.insert(pkg_id, Fragment { msg_flag, payload: payload.to_vec() });
+                return self.advance();
+            }
+            return false;
+        };
+
+        if pkg_id.abs_diff(self.highest_id) > SEQUENCE_RESTART {
+            tracing::info!("pkg_id jumped from {} to {}, restarting frame reassembly", self.highest_id, pkg_id);
+            self.reset_sequence();
+            return self.add_fragment(cmd, msg_flag, pkg_id, payload);
         }
-    }
+        if pkg_id < next_id {
+            if is_video {
+                tracing::debug!("Late fragment pkg_id={} (expecting {} onwards)", pkg_id, next_id);
+                self.stats.fragments_late += 1;
+            }
+            return false;
+        }
+        self.highest_id = self.highest_id.max(pkg_id);
 
-    /// Assemble fragments into a complete frame
-    fn assemble_frame(&self, frame: FrameFragment) -> Option<Vec<u8>> {
-        if frame.fragments.is_empty() {
-            return None;
+        let duplicate = if is_video {
+            self.pending.insert(pkg_id, Fragment { msg_flag, payload: payload.to_vec() }).is_some()
+        } else {
+            !self.other.insert(pkg_id)
+        };
+        if duplicate {
+            self.stats.fragments_duplicate += 1;
+        } else if self.requested.remove(&pkg_id).is_some() {
+            tracing::debug!("Requested pkg_id={} arrived", pkg_id);
+            self.stats.retransmits_recovered += 1;
         }
+        self.advance()
+    }
 
-        // Calculate total size (excluding size bytes from last fragment if we have them)
-        let mut total_size = 0;
-        let mut has_size_info = false;
-        
-        for (i, fragment) in frame.fragments.iter().enumerate() {
-            if i == frame.fragments.len() - 1 {
-                // Last fragment: check if it has size information
-                if fragment.len() >= 4 {
-                    // Try to extract size from last 4 bytes
-                    let size_bytes = &fragment[fragment.len() - 4..];
-                    let potential_size = u32::from_le_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3]]) as usize;
-                    
-                    // Validate the size (should be reasonable for a JPEG frame)
-                    if potential_size > 0 && potential_size < 1000000 { // Max 1MB
-                        total_size += fragment.len().saturating_sub(4);
-                        has_size_info = true;
-                        tracing::debug!("Using size info from last fragment: {} bytes", potential_size);
-                    } else {
-                        // Size info seems invalid, include the whole fragment
-                        total_size += fragment.len();
-                        tracing::debug!("Invalid size info in last fragment, including whole fragment");
+    /// Assemble or give up frames in pkg_id order until one has to wait for a fragment
+    fn advance(&mut self) -> bool {
+        let mut assembled = false;
+        while let Some(cursor) = self.next_id {
+            if self.other.contains(&cursor) {
+                self.resume_after(cursor);
+                continue;
+            }
+            match self.pending.get(&cursor).map(|fragment| fragment.msg_flag) {
+                Some(250) => {
+                    self.skipping = false;
+                    match self.scan_frame(cursor) {
+                        FrameScan::Complete(end) => {
+                            assembled |= self.assemble(cursor, end);
+                            self.resume_after(end);
+                        }
+                        FrameScan::Unterminated(next_start) => {
+                            tracing::warn!("Frame at pkg_id {} has no end fragment, dropping it", cursor);
+                            self.stats.frames_dropped += 1;
+                            self.next_id = Some(next_start);
+                        }
+                        FrameScan::Gap(missing) if self.given_up(missing) => {
+                            tracing::warn!("Fragment pkg_id={} lost, dropping frame at pkg_id {}", missing, cursor);
+                            self.lost(missing);
+                            self.stats.frames_dropped += 1;
+                            self.skipping = true;
+                            self.resume_after(missing);
+                        }
+                        FrameScan::Gap(_) => break,
+                        FrameScan::Truncated => {
+                            tracing::warn!("Frame at pkg_id {} runs past the last pkg_id, dropping it", cursor);
+                            self.stats.frames_dropped += 1;
+                            self.reset_sequence();
+                        }
+                    }
+                }
+                Some(msg_flag) => {
+                    // A continuation or end fragment whose start was lost
+                    if !self.skipping {
+                        tracing::warn!("Fragment pkg_id={} has no start fragment, dropping its frame", cursor);
+                        self.stats.frames_dropped += 1;
                     }
-                } else {
-                    // Fragment too small to have size info, include the whole fragment
-                    total_size += fragment.len();
+                    self.skipping = msg_flag != 252;
+                    self.resume_after(cursor);
                 }
-            } else {
-                total_size += fragment.len();
+                None if self.given_up(cursor) => {
+                    tracing::debug!("pkg_id={} lost", cursor);
+                    self.lost(cursor);
+                    self.resume_after(cursor);
+                }
+                None => break,
             }
         }
 
-        // Verify size if we have expected size
-        if let Some(expected_size) = frame.expected_size {
-            if has_size_info && total_size != expected_size {
-                tracing::warn!("Frame size mismatch: expected {}, got {}", expected_size, total_size);
-            }
+        // Forget everything before the cursor
+        if let Some(next_id) = self.next_id {
+            self.pending = self.pending.split_off(&next_id);
+            self.other = self.other.split_off(&next_id);
+            self.requested = self.requested.split_off(&next_id);
+        }
+        assembled
+    }
+
+    /// Move the cursor past `id`; past `u32::MAX` there is nothing left to assemble
+    fn resume_after(&mut self, id: u32) {
+        match id.checked_add(1) {
+            Some(next) => self.next_id = Some(next),
+            None => self.reset_sequence(),
         }
+    }
 
-        // Assemble frame
-        let mut complete_frame = Vec::with_capacity(total_size);
-        for (i, fragment) in frame.fragments.iter().enumerate() {
-            if i == frame.fragments.len() - 1 && has_size_info {
-                // Last fragment: exclude the 4 size bytes
-                complete_frame.extend_from_slice(&fragment[..fragment.len().saturating_sub(4)]);
-            } else {
-                complete_frame.extend_from_slice(fragment);
+    fn scan_frame(&self, start: u32) -> FrameScan {
+        for id in (start..=u32::MAX).skip(1) {
+            if self.other.contains(&id) {
+                continue;
+            }
+            match self.pending.get(&id).map(|fragment| fragment.msg_flag) {
+                Some(252) => return FrameScan::Complete(id),
+                Some(250) => return FrameScan::Unterminated(id),
+                Some(_) => {}
+                None => return FrameScan::Gap(id),
             }
         }
+        FrameScan::Truncated
+    }
 
-        Some(complete_frame)
+    /// Whether a missing pkg_id will not be waited for any longer
+    ///
+    /// A requested one is waited for until its budget runs out. Otherwise
+    /// that is once `REORDER_WINDOW` newer packets have been seen, or as soon
+    /// as a later frame is complete: an older frame is of no use after it.
+    fn given_up(&self, missing: u32) -> bool {
+        let held = self.highest_id.saturating_sub(missing);
+        if held >= MAX_HELD {
+            return true;
+        }
+        if let Some(&asked) = self.requested.get(&missing) {
+            let now = self.last_packet.unwrap_or(asked);
+            return now.saturating_duration_since(asked) >= RETRANSMISSION_BUDGET;
+        }
+        if held >= REORDER_WINDOW {
+            return true;
+        }
+        self.pending
+            .range((Bound::Excluded(missing), Bound::Unbounded))
+            .filter(|(_, fragment)| fragment.msg_flag == 250)
+            .any(|(&start, _)| matches!(self.scan_frame(start), FrameScan::Complete(_)))
     }
 
-    /// Add a complete frame to the buffer
-    pub fn add_complete_frame(&mut self, frame: Vec<u8>) {
-        // Remove oldest frame if buffer is full
-        if self.frames.len() >= self.max_frames {
-            self.frames.pop_front();
+    fn lost(&mut self, pkg_id: u32) {
+        self.stats.fragments_missing += 1;
+        if self.requested.remove(&pkg_id).is_some() {
+            tracing::debug!("Requested pkg_id={} did not arrive in time", pkg_id);
+            self.stats.retransmits_abandoned += 1;
         }
-        
-        let frame_len = frame.len();
-        self.frames.push_back(frame);
-        tracing::debug!("Added complete frame: {} bytes (buffer: {}/{})", 
-            frame_len, self.frames.len(), self.max_frames);
     }
 
-    // Frame assembly methods for fragmented JPEG frames
-    fn start_new_frame(&mut self, _pkg_id: u32, payload: Vec<u8>) {
-        let payload_len = payload.len();
-        // Start assembling a new frame
-        self.current_frame = Some(FrameFragment {
-            frame_type: 1, // JPEG
-            pkg_id: 0, // Don't use package ID for assembly
-            fragments: vec![payload],
-            expected_size: None,
-            is_complete: false,
-        });
-        tracing::debug!("Started new frame assembly: {} bytes", payload_len);
+    /// pkg_ids between the oldest awaited and the newest seen that have not arrived
+    fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        let first = self.next_id.unwrap_or(self.highest_id);
+        (first..self.highest_id).filter(|id| !self.pending.contains_key(id) && !self.other.contains(id))
     }
 
-    fn add_frame_fragment(&mut self, _pkg_id: u32, payload: Vec<u8>) {
-        if let Some(ref mut frame) = self.current_frame {
-            // Don't check package ID - just add the fragment
-            frame.fragments.push(payload);
-            tracing::debug!("Added fragment to frame, total fragments: {}", frame.fragments.len());
-        } else {
-            tracing::warn!("No current frame to add fragment to");
-        }
-    }
-
-    fn complete_frame(&mut self, _pkg_id: u32, payload: Vec<u8>) -> bool {
-        if let Some(mut frame) = self.current_frame.take() {
-            // Don't check package ID - just add the final fragment
-            frame.fragments.push(payload);
-            
-            // The last 4 bytes of the last fragment contain the total frame size
-            if let Some(last_fragment) = frame.fragments.last() {
-                if last_fragment.len() >= 4 {
-                    let size_bytes = &last_fragment[last_fragment.len() - 4..];
-                    let expected_size = u32::from_le_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3]]) as usize;
-                    frame.expected_size = Some(expected_size);
-                    tracing::debug!("Expected frame size: {} bytes", expected_size);
+    /// Whether a gap is old enough to ask for and has not been asked for yet
+    pub fn wants_retransmission(&self) -> bool {
+        let trailing = self.highest_id.saturating_sub(RETRANSMIT_AFTER);
+        self.missing()
+            .take_while(|&id| id <= trailing)
+            .any(|id| !self.requested.contains_key(&id))
+    }
+
+    /// Record that every gap up to the newest pkg_id has just been asked to be resent
+    ///
+    /// Gaps asked for before keep their original deadline. Returns the pkg_ids
+    /// newly asked for.
+    pub fn request_missing(&mut self, now: Instant) -> Vec<u32> {
+        let new: Vec<u32> = self.missing().filter(|id| !self.requested.contains_key(id)).collect();
+        for &id in &new {
+            self.requested.insert(id, now);
+        }
+        self.stats.retransmits_requested += new.len() as u64;
+        new
+    }
+
+    /// Join the fragments `start..=end` and keep the result if it is a JPEG
+    fn assemble(&mut self, start: u32, end: u32) -> bool {
+        let mut data = Vec::new();
+        let mut fragments = 0;
+        for fragment in self.pending.range(start..=end).map(|(_, fragment)| fragment) {
+            data.extend_from_slice(&fragment.payload);
+            fragments += 1;
+        }
+
+        // The end fragment carries the frame size as a trailing little-endian u32
+        if data.len() >= 4 {
+            let body = data.len() - 4;
+            let declared = u32::from_le_bytes([data[body], data[body + 1], data[body + 2], data[body + 3]]) as usize;
+            if declared == body || data[..body].ends_with(&JPEG_EOI) {
+                if declared != body {
+                    tracing::debug!("Frame size mismatch: declared {}, assembled {}", declared, body);
                 }
+                data.truncate(body);
             }
+        }
 
-            // Assemble the complete frame
-            if let Some(complete_frame) = self.assemble_frame(frame) {
-                tracing::info!("Successfully assembled JPEG frame: {} bytes", complete_frame.len());
-                self.add_complete_frame(complete_frame);
-                return true;
-            } else {
-                tracing::error!("Failed to assemble frame");
-                return false;
-            }
-        } else {
-            tracing::warn!("No current frame to complete");
+        if !(data.starts_with(&JPEG_SOI) && data.ends_with(&JPEG_EOI)) {
+            tracing::warn!("Frame at pkg_id {}..={} is not a JPEG ({} bytes), dropping it", start, end, data.len());
+            self.stats.frames_corrupt += 1;
             return false;
         }
+        tracing::debug!("Assembled JPEG frame from pkg_id {}..={}: {} bytes", start, end, data.len());
+        self.stats.frames_completed += 1;
+        self.rolling.record_frame(self.last_packet.unwrap_or_else(Instant::now), data.len(), fragments);
+        self.add_complete_frame(data);
+        true
     }
 
-    // Add a method to complete frames without end fragment (for missing end frames)
-    pub fn complete_incomplete_frame(&mut self) -> bool {
-        if let Some(frame) = self.current_frame.take() {
-            if frame.fragments.len() > 1 {
-                // We have multiple fragments, try to assemble what we have
-                tracing::info!("Completing incomplete frame with {} fragments", frame.fragments.len());
-                if let Some(complete_frame) = self.assemble_frame(frame) {
-                    tracing::info!("Successfully assembled incomplete JPEG frame: {} bytes", complete_frame.len());
-                    self.add_complete_frame(complete_frame);
-                    return true;
-                }
-            } else {
-                tracing::warn!("Incomplete frame has only {} fragment(s), discarding", frame.fragments.len());
-            }
+    /// Discard a partial frame if no packet arrived for `STALE_FRAME`
+    ///
+    /// Returns true if anything was discarded. The next start fragment begins
+    /// a fresh sequence.
+    pub fn expire_stale(&mut self, now: Instant) -> bool {
+        let quiet = self.last_packet.is_some_and(|last| now.duration_since(last) >= STALE_FRAME);
+        if !quiet || self.pending.is_empty() {
+            return false;
+        }
+        let starts = self.pending.values().filter(|fragment| fragment.msg_flag == 250).count();
+        self.stats.frames_expired += starts.max(1) as u64;
+        self.stats.retransmits_abandoned += self.requested.len() as u64;
+        self.reset_sequence();
+        true
+    }
+
+    /// Forget partial frames and wait for the next start fragment
+    pub fn reset_sequence(&mut self) {
+        self.pending.clear();
+        self.other.clear();
+        self.requested.clear();
+        self.next_id = None;
+        self.highest_id = 0;
+        self.skipping = false;
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /// Rolling stream quality as of `now`
+    pub fn quality(&mut self, now: Instant, confirmations_sent: u64, requests_sent: u64) -> StreamQuality {
+        self.rolling.quality(now, self.stats, confirmations_sent, requests_sent)
+    }
+
+    /// Add a complete frame to the buffer
+    pub fn add_complete_frame(&mut self, frame: Vec<u8>) {
+        // Remove oldest frame if buffer is full
+        if self.frames.len() >= self.max_frames {
+            self.frames.pop_front();
+        }
+        
+        let frame = Bytes::from(frame);
+        let frame_len = frame.len();
+        if self.frame_tx.receiver_count() > 0 {
+            // Only fails when the last receiver went away in the meantime
+            let _ = self.frame_tx.send(frame.clone());
         }
-        false
+        self.frames.push_back(frame);
+        tracing::debug!("Added complete frame: {} bytes (buffer: {}/{})", 
+            frame_len, self.frames.len(), self.max_frames);
     }
 
     /// Get the latest complete frame
     pub fn get_latest_frame(&self) -> Option<&[u8]> {
-        self.frames.back().map(|frame| frame.as_slice())
+        self.frames.back().map(|frame| frame.as_ref())
+    }
+
+    /// The latest complete frame, shared rather than copied
+    pub fn latest(&self) -> Option<Bytes> {
+        self.frames.back().cloned()
     }
 
     /// Get all frames (for debugging)
-    pub fn get_all_frames(&self) -> &VecDeque<Vec<u8>> {
+    pub fn get_all_frames(&self) -> &VecDeque<Bytes> {
         &self.frames
     }
 
@@ -282,22 +486,7 @@ impl StreamBuffer {
     /// Clear all frames
     pub fn clear(&mut self) {
         self.frames.clear();
-        self.current_frame = None;
-    }
-
-    /// Get list of received package IDs for retransmission confirmation
-    pub fn get_received_packages(&self) -> Vec<u32> {
-        let mut packages = Vec::new();
-        
-        // Add package ID from current frame being assembled
-        if let Some(ref frame) = self.current_frame {
-            packages.push(frame.pkg_id);
-        }
-        
-        // Add package IDs from recently received frames (last 10 frames)
-        // This is a simplified approach - in a real implementation you'd want to track
-        // all received package IDs and manage the list size
-        packages
+        self.reset_sequence();
     }
 
     // Legacy method for backward compatibility
@@ -328,19 +517,83 @@ pub struct ViewerInfo {
     pub last_activity: chrono::DateTime<chrono::Utc>,
 }
 
+/// Keeps a viewer listed in `CameraConnection::viewers` for as long as the guard lives
+///
+/// Used by streaming responses, which have no explicit disconnect event.
+#[derive(Debug)]
+pub struct ViewerGuard {
+    camera: Arc<RwLock<CameraConnection>>,
+    pub id: String,
+}
+
+impl ViewerGuard {
+    /// Guard a viewer already added with `CameraConnection::add_viewer`
+    pub fn new(camera: Arc<RwLock<CameraConnection>>, id: String) -> Self {
+        Self { camera, id }
+    }
+}
+
+impl Drop for ViewerGuard {
+    fn drop(&mut self) {
+        let camera = self.camera.clone();
+        let id = std::mem::take(&mut self.id);
+        tokio::spawn(async move {
+            camera.write().await.remove_viewer(&id);
+            tracing::info!("Viewer {} disconnected", id);
+        });
+    }
+}
+
+/// UDP source ports a camera has been seen sending from
+///
+/// Shared between `CameraConnection` and the camera's `CameraHandle`, so the
+/// UDP routers can attribute packets without taking the camera lock.
+#[derive(Debug, Default)]
+pub struct UdpPorts(ArcSwap<BTreeSet<u16>>);
+
+impl UdpPorts {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.load().contains(&port)
+    }
+
+    /// Remember `port`; a known port costs a lookup only
+    pub fn insert(&self, port: u16) {
+        if !self.contains(port) {
+            self.0.rcu(|ports| {
+                let mut ports = BTreeSet::clone(ports);
+                ports.insert(port);
+                ports
+            });
+        }
+    }
+
+    pub fn clear(&self) {
+        self.0.store(Arc::default());
+    }
+
+    pub fn ports(&self) -> Vec<u16> {
+        self.0.load().iter().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.load().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.load().is_empty()
+    }
+}
+
 /// Camera connection information
 #[derive(Debug)]
 pub struct CameraConnection {
     pub device_id: String,
     pub ip: IpAddr,
     pub addr: SocketAddr,
-    pub state: ProtocolState,
-    pub protocol_state: ProtocolState,
-    pub tcp_conn: Option<tokio::net::TcpStream>,
-    pub stream_buffer: StreamBuffer,
-    pub received_packages: Vec<u32>,
-    pub last_retransmission_time: chrono::DateTime<chrono::Utc>,
-    pub udp_ports: HashMap<u16, u16>, // port -> dummy value (just for tracking)
+    pub state: crate::state::StateMachine,
+    pub tcp_conn: Option<Arc<Mutex<crate::protocol::ControlWriter>>>,
+    pub tcp_reader_stop: Option<Arc<tokio::sync::Notify>>, // Stops the reader of `tcp_conn`, which a half-open socket never wakes
+    pub udp_ports: Arc<UdpPorts>, // Source ports seen, shared with the camera's handle
     pub viewers: HashMap<String, ViewerInfo>,
     pub last_heartbeat: chrono::DateTime<chrono::Utc>,
     pub last_keepalive: chrono::DateTime<chrono::Utc>,
@@ -350,13 +603,15 @@ pub struct CameraConnection {
     pub device_info_ack_sent: bool,
     pub random_video_socket: Option<Arc<tokio::net::UdpSocket>>, // Random port socket for video streaming
     pub random_video_port: Option<u16>, // Random port number for video streaming
+    pub random_video_task: Option<tokio::task::AbortHandle>, // Receive loop serving the random port
     pub probe_state: ProbeState, // Track Code 50/51 probe exchange
-    pub first_retransmission_sent: bool, // Track if first empty retransmission was sent
-    pub retransmission_bucket: Vec<u32>, // Bucket to collect package IDs between end frames
     pub token: Option<String>,
     pub camera_nat_port: Option<u16>,
     pub code51_count: u32,
     pub pending_command: Option<String>,
+    pub recording: Option<crate::recording::Recording>, // Active AVI recording, if any
+    pub pending_requests: crate::protocol::PendingRequests, // Commands waiting for the camera's reply
+    pub resume_streaming: bool, // Streaming when the camera dropped; restarted once it registers again
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -372,13 +627,10 @@ impl CameraConnection {
             device_id,
             ip,
             addr,
-            state: ProtocolState::Disconnected,
-            protocol_state: ProtocolState::Disconnected,
+            state: crate::state::StateMachine::new(),
             tcp_conn: None,
-            stream_buffer: StreamBuffer::new(100), // Keep 100 frames
-            received_packages: Vec::new(),
-            last_retransmission_time: chrono::Utc::now(),
-            udp_ports: HashMap::new(),
+            tcp_reader_stop: None,
+            udp_ports: Arc::new(UdpPorts::default()),
             viewers: HashMap::new(),
             last_heartbeat: chrono::Utc::now(),
             last_keepalive: chrono::Utc::now(),
@@ -388,14 +640,47 @@ impl CameraConnection {
             device_info_ack_sent: false,
             random_video_socket: None,
             random_video_port: None,
+            random_video_task: None,
             probe_state: ProbeState::NotStarted,
-            first_retransmission_sent: false,
-            retransmission_bucket: Vec::new(),
             token: None,
             camera_nat_port: None,
             code51_count: 0,
             pending_command: None,
+            recording: None,
+            pending_requests: crate::protocol::PendingRequests::new(),
+            resume_streaming: false,
+        }
+    }
+
+    /// Forget the media path: stop serving the random port and drop known UDP ports
+    pub fn release_media(&mut self) {
+        if let Some(task) = self.random_video_task.take() {
+            task.abort();
+        }
+        if let Some(port) = self.random_video_port.take() {
+            tracing::info!("Released random port {} of camera {}", port, self.device_id);
+        }
+        self.random_video_socket = None;
+        self.udp_ports.clear();
+        self.probe_state = ProbeState::NotStarted;
+    }
+
+    /// Detach the control connection and stop its reader; returns its writer to close
+    pub fn close_control(&mut self) -> Option<Arc<Mutex<crate::protocol::ControlWriter>>> {
+        if let Some(stop) = self.tcp_reader_stop.take() {
+            stop.notify_one();
         }
+        self.tcp_conn.take()
+    }
+
+    /// End the camera's session because it dropped or started over
+    ///
+    /// A stream it was sending is restarted when it registers again.
+    pub fn end_session(&mut self, state: ProtocolState, reason: &str) {
+        self.resume_streaming |= matches!(self.state.current(), ProtocolState::NatTraversal | ProtocolState::Streaming);
+        self.pending_requests.clear();
+        self.release_media();
+        self.set_state(state, reason);
     }
 
     pub fn add_viewer(&mut self, viewer_id: String) {
@@ -426,7 +711,18 @@ impl CameraConnection {
     }
 
     pub fn is_connected(&self) -> bool {
-        self.state == ProtocolState::Idle || self.state == ProtocolState::Streaming
+        matches!(self.state.current(), ProtocolState::Idle | ProtocolState::Streaming)
+    }
+
+    /// Move to `state`, logging and ignoring transitions the protocol does not allow
+    pub fn set_state(&mut self, state: ProtocolState, reason: &str) -> bool {
+        match self.state.transition(state, reason) {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::warn!("Camera {}: {}", self.device_id, e);
+                false
+            }
+        }
     }
 
     pub fn is_connection_healthy(&self) -> bool {
@@ -439,88 +735,300 @@ impl CameraConnection {
         self.last_keepalive = chrono::Utc::now();
     }
 
-    pub fn add_video_frame(&mut self, data: &[u8]) {
-        // Add video frame to stream buffer
-        self.stream_buffer.add_complete_frame(data.to_vec());
-    }
-
     pub fn take_pending_command(&mut self) -> Option<String> {
         self.pending_command.take()
     }
+}
 
-    pub fn add_received_package(&mut self, pkg_id: u32) {
-        if !self.received_packages.contains(&pkg_id) {
-            self.received_packages.push(pkg_id);
+/// Camera manager for handling multiple cameras
+///
+/// Shared as a plain `Arc`: the camera index is swapped as a whole on every
+/// change, so the UDP routers and web handlers look cameras up without
+/// waiting on each other. Each camera's own state sits behind its
+/// `CameraHandle`.
+#[derive(Debug)]
+pub struct CameraManager {
+    cameras: ArcSwap<HashMap<String, CameraHandle>>, // Keyed by device ID
+    changes: std::sync::Mutex<()>, // Serializes copy-on-write updates of `cameras`
+    pub registry: Mutex<DeviceRegistry>,
+    pub config: crate::config::LiveConfig,
+    pub metrics: Metrics,
+    pub auth: crate::web::auth::WebAuth,
+}
+
+impl CameraManager {
+    pub fn new(config: crate::config::AppConfig, registry: DeviceRegistry) -> Self {
+        Self::with_live_config(crate::config::LiveConfig::new(config), registry)
+    }
+
+    /// Camera manager whose configuration is reloaded from a file
+    ///
+    /// Opens the pairing window if `pair_on_start` is set.
+    pub fn with_live_config(config: crate::config::LiveConfig, mut registry: DeviceRegistry) -> Self {
+        let settings = config.current();
+        if settings.pair_on_start {
+            let until = registry.open_pairing(chrono::Duration::seconds(settings.pairing_window_secs as i64));
+            tracing::info!("Pairing window open until {}", until);
+        }
+        Self {
+            cameras: ArcSwap::default(),
+            changes: std::sync::Mutex::new(()),
+            registry: Mutex::new(registry),
+            config,
+            metrics: Metrics::new(),
+            auth: crate::web::auth::WebAuth::new(),
         }
     }
 
-    pub fn get_and_clear_received_packages(&mut self) -> Vec<u32> {
-        let packages = self.received_packages.clone();
-        self.received_packages.clear();
-        self.last_retransmission_time = chrono::Utc::now();
-        packages
+    /// Apply `change` to a copy of the index and publish it
+    fn update_index<T>(&self, change: impl FnOnce(&mut HashMap<String, CameraHandle>) -> T) -> T {
+        let _changing = self.changes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut cameras = HashMap::clone(&self.cameras.load());
+        let result = change(&mut cameras);
+        self.cameras.store(Arc::new(cameras));
+        result
     }
 
-    pub fn should_send_retransmission(&self) -> bool {
-        let now = chrono::Utc::now();
-        let duration = now.signed_duration_since(self.last_retransmission_time);
-        duration.num_milliseconds() >= 100 // Send every 100ms
+    /// Camera that just sent a code 100 registration from `addr`
+    ///
+    /// Reuses the existing connection of the device ID, so a camera that
+    /// comes back from a new IP keeps its buffers, viewers and recording,
+    /// and records the registration in the device registry. The caller has
+    /// admitted the device already, so it counts as paired from now on.
+    pub async fn register_camera(&self, device_id: &str, addr: SocketAddr, token: &str) -> CameraHandle {
+        let camera = match self.handle(device_id) {
+            Some(camera) => {
+                let mut camera_guard = camera.connection.write().await;
+                if camera_guard.ip != addr.ip() {
+                    tracing::info!("Camera {} moved from {} to {}", device_id, camera_guard.ip, addr.ip());
+                    // UDP ports learned from the old address no longer apply
+                    camera_guard.udp_ports.clear();
+                }
+                camera_guard.ip = addr.ip();
+                camera_guard.addr = addr;
+                drop(camera_guard);
+                self.update_index(|cameras| {
+                    let camera = cameras.entry(device_id.to_string()).or_insert(camera);
+                    camera.addr = addr;
+                    camera.clone()
+                })
+            }
+            None => {
+                let request_retransmissions = self.config.current().request_retransmissions;
+                let camera = CameraHandle::spawn(CameraConnection::new(device_id.to_string(), addr.ip(), addr), request_retransmissions);
+                // A concurrent registration of the same device may have won; its task then serves both
+                self.update_index(|cameras| cameras.entry(device_id.to_string()).or_insert(camera).clone())
+            }
+        };
+
+        if let Err(e) = self.registry.lock().await.update(device_id, |record| {
+            record.paired = true;
+            record.last_ip = Some(addr.ip());
+            record.token = Some(token.to_string());
+        }) {
+            tracing::warn!("Failed to save device registry: {:#}", e);
+        }
+        camera
     }
 
-    // Retransmission bucket methods
-    pub fn add_to_retransmission_bucket(&mut self, pkg_id: u32) {
-        if !self.retransmission_bucket.contains(&pkg_id) {
-            self.retransmission_bucket.push(pkg_id);
+    /// Remove `camera` from the index, unless its device ID has been registered anew since
+    ///
+    /// Its random video port is released; its media task stops once the last
+    /// handle is gone.
+    pub async fn remove_camera(&self, camera: &CameraHandle) -> bool {
+        let removed = self.update_index(|cameras| {
+            let current = cameras.get(&camera.device_id).is_some_and(|current| current.same_camera(camera));
+            if current {
+                cameras.remove(&camera.device_id);
+            }
+            current
+        });
+        if removed {
+            camera.connection.write().await.release_media();
         }
+        removed
     }
 
-    pub fn get_and_clear_retransmission_bucket(&mut self) -> Vec<u32> {
-        let packages = self.retransmission_bucket.clone();
-        self.retransmission_bucket.clear();
-        packages
+    /// Look up a connected camera by device ID
+    pub fn handle(&self, device_id: &str) -> Option<CameraHandle> {
+        self.cameras.load().get(device_id).cloned()
     }
 
-    pub fn is_retransmission_bucket_empty(&self) -> bool {
-        self.retransmission_bucket.is_empty()
+    /// Control state of a connected camera
+    pub fn get_camera(&self, device_id: &str) -> Option<Arc<RwLock<CameraConnection>>> {
+        self.cameras.load().get(device_id).map(|camera| camera.connection.clone())
     }
-}
 
-/// Camera manager for handling multiple cameras
-#[derive(Debug)]
-pub struct CameraManager {
-    pub cameras: HashMap<IpAddr, Arc<RwLock<CameraConnection>>>,
-    pub config: crate::config::AppConfig,
-}
+    pub fn contains(&self, device_id: &str) -> bool {
+        self.cameras.load().contains_key(device_id)
+    }
 
-impl CameraManager {
-    pub fn new(config: crate::config::AppConfig) -> Self {
-        Self {
-            cameras: HashMap::new(),
-            config,
+    /// Every connected camera at this moment
+    pub fn handles(&self) -> Vec<CameraHandle> {
+        self.cameras.load().values().cloned().collect()
+    }
+
+    pub fn list_cameras(&self) -> Vec<String> {
+        self.cameras.load().keys().cloned().collect()
+    }
+
+    /// Camera that a UDP datagram from `addr` came from
+    ///
+    /// UDP packets carry no device ID, so this goes by source IP. When several
+    /// cameras share the IP (behind one NAT), the one already using the source
+    /// port wins; otherwise the packet cannot be attributed.
+    pub fn find_by_addr(&self, addr: SocketAddr) -> Option<CameraHandle> {
+        let cameras = self.cameras.load();
+        let mut candidates: Vec<_> = cameras
+            .values()
+            .filter(|camera| camera.addr.ip() == addr.ip())
+            .map(|camera| (camera.addr == addr || camera.udp_ports.contains(addr.port()), camera))
+            .collect();
+        if candidates.len() > 1 {
+            let matches = candidates.len();
+            candidates.retain(|(port_known, _)| *port_known);
+            if candidates.len() != 1 {
+                tracing::warn!("UDP packet from {} matches {} cameras, dropping it", addr, matches);
+                return None;
+            }
         }
+        candidates.pop().map(|(_, camera)| camera.clone())
     }
+}
 
-    pub async fn get_or_create_camera(&mut self, ip: IpAddr) -> Arc<RwLock<CameraConnection>> {
-        if let Some(camera) = self.cameras.get(&ip) {
-            camera.clone()
-        } else {
-            let device_id = format!("cam{}", ip.to_string().split('.').last().unwrap_or("0"));
-            let addr = SocketAddr::new(ip, 6123);
-            let camera = Arc::new(RwLock::new(CameraConnection::new(device_id, ip, addr)));
-            self.cameras.insert(ip, camera.clone());
-            camera
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fragments of a JPEG `FF D8 <n> FF D9` with its size trailer, as (msg_flag, payload)
+    fn jpeg_fragments(n: u8) -> [(u8, Vec<u8>); 3] {
+        [(250, vec![0xFF, 0xD8]), (251, vec![n]), (252, vec![0xFF, 0xD9, 5, 0, 0, 0])]
+    }
+
+    #[test]
+    fn test_fragments_are_reassembled_by_pkg_id() {
+        let mut buffer = StreamBuffer::new(10);
+        // Frame 1 with its end fragment ahead of the continuation, then audio
+        let [start, middle, end] = jpeg_fragments(1);
+        assert!(!buffer.add_fragment(1, start.0, 1, &start.1));
+        assert!(!buffer.add_fragment(1, end.0, 3, &end.1));
+        assert!(buffer.add_fragment(1, middle.0, 2, &middle.1));
+        assert!(!buffer.add_fragment(6, 0, 4, &[0xD5; 8]));
+        assert!(!buffer.add_fragment(1, middle.0, 2, &middle.1));
+
+        // Frame 2 loses its continuation; frame 3 completing gives up on it
+        let [start, _, end] = jpeg_fragments(2);
+        buffer.add_fragment(1, start.0, 5, &start.1);
+        buffer.add_fragment(1, end.0, 7, &end.1);
+        for (pkg_id, (msg_flag, payload)) in (8..).zip(jpeg_fragments(3)) {
+            buffer.add_fragment(1, msg_flag, pkg_id, &payload);
         }
+
+        // Frame 4 is complete but not a JPEG
+        for (pkg_id, (msg_flag, payload)) in (11..).zip(jpeg_fragments(4)) {
+            let payload = if msg_flag == 250 { vec![0x00, 0xD8] } else { payload };
+            buffer.add_fragment(1, msg_flag, pkg_id, &payload);
+        }
+
+        let frames: Vec<_> = buffer.get_all_frames().iter().map(|frame| frame.to_vec()).collect();
+        assert_eq!(frames, [vec![0xFF, 0xD8, 1, 0xFF, 0xD9], vec![0xFF, 0xD8, 3, 0xFF, 0xD9]]);
+        assert_eq!(buffer.stats(), ReassemblyStats {
+            frames_completed: 2,
+            frames_dropped: 1,
+            frames_corrupt: 1,
+            frames_expired: 0,
+            fragments_missing: 1,
+            fragments_late: 1,
+            fragments_duplicate: 0,
+            ..ReassemblyStats::default()
+        });
+
+        // A frame cut off by the stream going quiet expires
+        buffer.add_fragment(1, 250, 14, &[0xFF, 0xD8]);
+        assert!(!buffer.expire_stale(Instant::now()));
+        assert!(buffer.expire_stale(Instant::now() + STALE_FRAME));
+        assert_eq!(buffer.stats().frames_expired, 1);
     }
 
-    pub async fn remove_camera(&mut self, ip: IpAddr) {
-        self.cameras.remove(&ip);
+    #[test]
+    fn test_requested_fragments_are_waited_for() {
+        let mut buffer = StreamBuffer::new(10);
+        let [start, middle, end] = jpeg_fragments(1);
+        buffer.add_fragment(1, start.0, 1, &start.1);
+        buffer.add_fragment(1, end.0, 3, &end.1);
+        // Within reordering distance
+        assert!(!buffer.wants_retransmission());
+        buffer.add_fragment(6, 0, 4, &[0xD5; 8]);
+        assert!(buffer.wants_retransmission());
+        assert_eq!(buffer.request_missing(Instant::now()), [2]);
+        assert!(!buffer.wants_retransmission());
+
+        // A complete later frame does not give up on a requested fragment
+        for (pkg_id, (msg_flag, payload)) in (5..).zip(jpeg_fragments(2)) {
+            buffer.add_fragment(1, msg_flag, pkg_id, &payload);
+        }
+        assert_eq!(buffer.frame_count(), 0);
+        assert!(buffer.add_fragment(1, middle.0, 2, &middle.1));
+        assert_eq!(buffer.frame_count(), 2);
+
+        // One that does not arrive within its budget is abandoned
+        let [start, _, end] = jpeg_fragments(3);
+        buffer.add_fragment(1, start.0, 8, &start.1);
+        buffer.add_fragment(1, end.0, 10, &end.1);
+        buffer.add_fragment(6, 0, 11, &[0xD5; 8]);
+        buffer.request_missing(Instant::now() - RETRANSMISSION_BUDGET);
+        buffer.add_fragment(6, 0, 12, &[0xD5; 8]);
+
+        let stats = buffer.stats();
+        assert_eq!((stats.retransmits_requested, stats.retransmits_recovered, stats.retransmits_abandoned), (2, 1, 1));
+        assert_eq!(stats.frames_dropped, 1);
     }
 
-    pub async fn get_camera(&self, ip: IpAddr) -> Option<Arc<RwLock<CameraConnection>>> {
-        self.cameras.get(&ip).cloned()
+    #[test]
+    fn test_pkg_ids_wrap_around() {
+        let mut buffer = StreamBuffer::new(10);
+        for (pkg_id, (msg_flag, payload)) in (u32::MAX - 2..=u32::MAX).zip(jpeg_fragments(1)) {
+            buffer.add_fragment(1, msg_flag, pkg_id, &payload);
+        }
+        assert_eq!(buffer.frame_count(), 1);
+
+        // The camera wraps to 0 and the next frame starts a fresh sequence
+        for (pkg_id, (msg_flag, payload)) in (0..).zip(jpeg_fragments(2)) {
+            buffer.add_fragment(1, msg_flag, pkg_id, &payload);
+        }
+        assert_eq!(buffer.frame_count(), 2);
+
+        // A frame whose end would need a pkg_id past u32::MAX cannot complete
+        buffer.add_fragment(1, 250, u32::MAX - 1, &[0xFF, 0xD8]);
+        buffer.add_fragment(1, 251, u32::MAX, &[3]);
+        assert!(!buffer.wants_retransmission());
+        assert_eq!(buffer.stats().frames_dropped, 1);
+        assert_eq!(buffer.frame_count(), 2);
     }
 
-    pub async fn list_cameras(&self) -> Vec<IpAddr> {
-        self.cameras.keys().cloned().collect()
+    #[tokio::test]
+    async fn test_udp_packets_are_attributed_by_address() {
+        let manager = CameraManager::new(crate::config::AppConfig::default(), DeviceRegistry::in_memory());
+        let first = manager.register_camera("0800c00128F8", "192.168.1.50:40000".parse().unwrap(), "t1").await;
+        let second = manager.register_camera("0800c0012A00", "192.168.1.50:40001".parse().unwrap(), "t2").await;
+        let alone = manager.register_camera("0800c0012B00", "192.168.1.60:40000".parse().unwrap(), "t3").await;
+
+        // Any port of a camera alone on its IP
+        let found = manager.find_by_addr("192.168.1.60:5000".parse().unwrap()).unwrap();
+        assert!(found.same_camera(&alone));
+        // Behind one NAT only a known port decides
+        assert!(manager.find_by_addr("192.168.1.50:5000".parse().unwrap()).is_none());
+        second.udp_ports.insert(5000);
+        let found = manager.find_by_addr("192.168.1.50:5000".parse().unwrap()).unwrap();
+        assert!(found.same_camera(&second));
+        let found = manager.find_by_addr("192.168.1.50:40000".parse().unwrap()).unwrap();
+        assert!(found.same_camera(&first));
+
+        // Registering again keeps the camera; removal only takes the current one
+        let again = manager.register_camera("0800c00128F8", "192.168.1.51:40000".parse().unwrap(), "t1").await;
+        assert!(again.same_camera(&first));
+        assert!(manager.remove_camera(&first).await);
+        assert!(!manager.remove_camera(&first).await);
+        assert_eq!(manager.list_cameras().len(), 2);
     }
 }
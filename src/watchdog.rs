@@ -0,0 +1,132 @@
+//! Supervisor that notices dead cameras
+//!
+//! Every `health_check_interval_ms` each camera is checked. A camera is stale
+//! once it has been quiet for `max_retries` of those intervals:
+//!
+//! - no control traffic: the control connection is dropped and the camera
+//!   becomes `Disconnected`
+//! - still talking but no media while starting or streaming: `Error`
+//!
+//! Either way its random UDP port is released, and a dropped connection's
+//! reader is stopped rather than left waiting on a half-open socket. A camera
+//! left without a control connection for `eviction_grace_ms` is removed from
+//! the camera manager; the device registry keeps its record. A camera that dropped while
+//! streaming is restarted by the TCP router when it registers again.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::SinkExt;
+
+use crate::config::AppConfig;
+use crate::types::{CameraConnection, CameraManager, ProtocolState};
+
+/// What the watchdog decided for one camera
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Healthy,
+    /// Drop the control connection
+    Dead,
+    /// Control connection alive, media stalled
+    Stalled,
+    Evict,
+}
+
+/// Check every camera each `health_check_interval_ms`, forever
+///
+/// The interval is read again after every round, so a reload applies to the next one.
+pub async fn run(camera_manager: Arc<CameraManager>) {
+    loop {
+        let interval = camera_manager.config.current().health_check_interval_ms;
+        tokio::time::sleep(Duration::from_millis(interval.max(1))).await;
+        check_cameras(&camera_manager).await;
+    }
+}
+
+async fn check_cameras(camera_manager: &Arc<CameraManager>) {
+    let config = &camera_manager.config.current();
+    for camera in camera_manager.handles() {
+        let now = Utc::now();
+        let last_media = camera.last_media();
+        let mut camera_guard = camera.connection.write().await;
+        match verdict(&camera_guard, last_media, now, config) {
+            Verdict::Healthy => {}
+            Verdict::Dead => {
+                let quiet = (now - camera_guard.last_keepalive).num_seconds();
+                tracing::warn!("Camera {} silent for {} s, dropping its control connection", camera_guard.device_id, quiet);
+                let writer = camera_guard.close_control();
+                camera_guard.end_session(ProtocolState::Disconnected, &format!("no control traffic for {} s", quiet));
+                drop(camera_guard);
+                if let Some(writer) = writer {
+                    // The peer is probably gone, so do not wait long for the FIN to go out
+                    let close = async { writer.lock().await.close().await };
+                    if let Ok(Err(e)) = tokio::time::timeout(Duration::from_secs(1), close).await {
+                        tracing::debug!("Closing a dead control connection failed: {:#}", e);
+                    }
+                }
+            }
+            Verdict::Stalled => {
+                let quiet = (now - quiet_since(&camera_guard, last_media)).num_seconds();
+                tracing::warn!("Camera {} sent no media for {} s", camera_guard.device_id, quiet);
+                camera_guard.release_media();
+                camera_guard.set_state(ProtocolState::Error, &format!("no media for {} s", quiet));
+            }
+            Verdict::Evict => {
+                // The control connection is gone by now; make sure its reader is too
+                camera_guard.close_control();
+                drop(camera_guard);
+                // Only if the camera has not come back in the meantime
+                if camera_manager.remove_camera(&camera).await {
+                    tracing::info!("Evicted camera {} after its grace period", camera.device_id);
+                }
+            }
+        }
+    }
+}
+
+fn verdict(camera: &CameraConnection, last_media: DateTime<Utc>, now: DateTime<Utc>, config: &AppConfig) -> Verdict {
+    let stale_after = chrono::Duration::milliseconds((config.health_check_interval_ms * config.max_retries.max(1) as u64) as i64);
+    let grace = chrono::Duration::milliseconds(config.eviction_grace_ms as i64);
+
+    let state = camera.state.current();
+    if camera.tcp_conn.is_none() {
+        // Also covers a camera that passed its config check but never registered
+        let evict = now - camera.state.since() > grace;
+        return if evict { Verdict::Evict } else { Verdict::Healthy };
+    }
+    if now - camera.last_keepalive > stale_after {
+        return Verdict::Dead;
+    }
+    let expects_media = matches!(state, ProtocolState::NatTraversal | ProtocolState::Streaming);
+    if expects_media && now - quiet_since(camera, last_media) > stale_after {
+        return Verdict::Stalled;
+    }
+    Verdict::Healthy
+}
+
+/// Latest media, counting the start of the current state so a new stream gets a full period
+fn quiet_since(camera: &CameraConnection, last_media: DateTime<Utc>) -> DateTime<Utc> {
+    last_media.max(camera.state.since())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disconnected_camera_is_evicted_after_grace() {
+        let config = AppConfig {
+            eviction_grace_ms: 60_000,
+            ..AppConfig::default()
+        };
+        let addr: std::net::SocketAddr = "192.168.1.50:40000".parse().unwrap();
+        let mut camera = CameraConnection::new("0800c00128F8".to_string(), addr.ip(), addr);
+        camera.set_state(ProtocolState::Registering, "code 100");
+        camera.set_state(ProtocolState::Disconnected, "control connection closed");
+
+        let now = Utc::now();
+        assert_eq!(verdict(&camera, now, now, &config), Verdict::Healthy);
+        assert_eq!(verdict(&camera, now, now + chrono::Duration::seconds(61), &config), Verdict::Evict);
+    }
+}
@@ -0,0 +1,489 @@
+//! Authentication of the management interface
+//!
+//! Users log in with a password checked against the argon2 hash in `users`
+//! and get a session cookie; automation sends `Authorization: Bearer
+//! <name>.<secret>` with a key from `api_keys`. RTSP clients send either as
+//! Basic credentials. The camera-facing cloud
+//! emulation has its own listener and never asks for credentials. With no
+//! users and no keys configured everything stays open, as before
+//! authentication existed.
+//!
+//! argon2 runs on the blocking pool, at most once per request, and an
+//! address that keeps failing to log in or sending wrong API keys is locked
+//! out for longer and longer.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::AppConfig;
+use crate::types::CameraManager;
+
+/// Cookie holding the session token
+pub const SESSION_COOKIE: &str = "a9_session";
+
+/// Failed logins or API keys an address may send before each further one locks it out, starting at a second and doubling
+const LOGIN_FREE_FAILURES: u32 = 5;
+
+/// Longest lockout after repeated failures
+const LOGIN_MAX_LOCKOUT: Duration = Duration::from_secs(300);
+
+/// Failures of an address are forgotten this long after its latest one
+const LOGIN_FAILURE_MEMORY: Duration = Duration::from_secs(900);
+
+/// Checked for unknown usernames, so they take as long to refuse as a wrong password
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| hash_password("not a password").unwrap_or_default());
+
+/// Hash `password` for the `users` or `api_keys` settings
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// `verify_password` on the blocking pool, where argon2 does not hold up other requests
+async fn verify_password_blocking(password: String, hash: String) -> bool {
+    tokio::task::spawn_blocking(move || verify_password(&password, &hash)).await.unwrap_or(false)
+}
+
+/// Whether `password` is that of the configured user `username`
+///
+/// Unknown users are checked against a dummy hash, so timing does not tell
+/// which usernames exist.
+async fn verify_user(config: &AppConfig, username: &str, password: &str) -> bool {
+    match config.users.get(username) {
+        Some(hash) => verify_password_blocking(password.to_string(), hash.clone()).await,
+        None => {
+            verify_password_blocking(password.to_string(), DUMMY_HASH.clone()).await;
+            false
+        }
+    }
+}
+
+/// Whether the management interface asks for credentials
+pub fn enabled(config: &AppConfig) -> bool {
+    !config.users.is_empty() || !config.api_keys.is_empty()
+}
+
+#[derive(Debug)]
+struct Session {
+    user: String,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+struct LoginFailures {
+    count: u32,
+    latest: Instant,
+}
+
+impl LoginFailures {
+    fn locked_until(&self) -> Instant {
+        let lockout = match self.count.checked_sub(LOGIN_FREE_FAILURES) {
+            Some(excess) => Duration::from_secs(1 << excess.min(16)).min(LOGIN_MAX_LOCKOUT),
+            None => Duration::ZERO,
+        };
+        self.latest + lockout
+    }
+}
+
+/// Logged-in sessions and recently verified API keys, kept in memory only
+#[derive(Debug, Default)]
+pub struct WebAuth {
+    sessions: std::sync::Mutex<HashMap<String, Session>>,
+    /// Bearer token to the hash it matched, so argon2 runs once per key and not per request
+    verified_keys: std::sync::Mutex<HashMap<String, String>>,
+    login_failures: std::sync::Mutex<HashMap<IpAddr, LoginFailures>>,
+}
+
+impl WebAuth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start a session for `user`; returns its token and expiry
+    fn create_session(&self, user: &str, ttl: chrono::Duration) -> (String, DateTime<Utc>) {
+        let token = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let now = Utc::now();
+        let expires = now + ttl;
+        let mut sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(token.clone(), Session { user: user.to_string(), expires });
+        (token, expires)
+    }
+
+    fn session_user(&self, token: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        sessions
+            .get(token)
+            .filter(|session| session.expires > Utc::now())
+            .map(|session| session.user.clone())
+    }
+
+    fn end_session(&self, token: &str) {
+        self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(token);
+    }
+
+    /// Name of the API key `token` (`<name>.<secret>`) belongs to
+    ///
+    /// The key is looked up by name, so only its own hash is ever checked.
+    async fn key_name(&self, token: &str, config: &AppConfig) -> Option<String> {
+        let (name, secret) = token.split_once('.')?;
+        let hash = config.api_keys.get(name)?;
+        let verified = self.verified_keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(token) == Some(hash);
+        if !verified {
+            if !verify_password_blocking(secret.to_string(), hash.clone()).await {
+                return None;
+            }
+            self.verified_keys
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(token.to_string(), hash.clone());
+        }
+        Some(name.to_string())
+    }
+
+    /// Who sent `headers`: a session of a configured user, or an API key
+    async fn authenticate(&self, headers: &HeaderMap, config: &AppConfig) -> Option<String> {
+        if let Some(token) = bearer_token(headers) {
+            return self.key_name(token, config).await.map(|name| format!("key {}", name));
+        }
+        let user = self.session_user(session_cookie(headers)?)?;
+        // Removing a user from the configuration ends their sessions
+        config.users.contains_key(&user).then_some(user)
+    }
+
+    /// Who Basic credentials belong to: a user, or an API key sent as its name and secret
+    ///
+    /// Failures count towards the lockout of `ip` like failed logins; `Err`
+    /// is how much longer that lasts.
+    pub async fn basic_auth(&self, username: &str, password: &str, config: &AppConfig, ip: IpAddr) -> Result<Option<String>, Duration> {
+        if let Some(left) = self.login_lockout(ip, Instant::now()) {
+            return Err(left);
+        }
+        let principal = if config.api_keys.contains_key(username) && !config.users.contains_key(username) {
+            let token = format!("{}.{}", username, password);
+            self.key_name(&token, config).await.map(|name| format!("key {}", name))
+        } else {
+            verify_user(config, username, password).await.then(|| username.to_string())
+        };
+        match &principal {
+            Some(_) => self.login_succeeded(ip),
+            None => self.login_failed(ip, Instant::now()),
+        }
+        Ok(principal)
+    }
+
+    /// How much longer logins and API keys from `ip` are refused after repeated failures
+    fn login_lockout(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let failures = self.login_failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let left = failures.get(&ip)?.locked_until().saturating_duration_since(now);
+        (!left.is_zero()).then_some(left)
+    }
+
+    fn login_failed(&self, ip: IpAddr, now: Instant) {
+        let mut failures = self.login_failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        failures.retain(|_, failure| now.saturating_duration_since(failure.latest) < LOGIN_FAILURE_MEMORY);
+        let failure = failures.entry(ip).or_insert(LoginFailures { count: 0, latest: now });
+        failure.count += 1;
+        failure.latest = now;
+    }
+
+    fn login_succeeded(&self, ip: IpAddr) {
+        self.login_failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&ip);
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ").map(str::trim)
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix(SESSION_COOKIE)?.strip_prefix('='))
+}
+
+/// 429 for an address locked out for `left` after repeated failures
+fn locked_out(left: Duration) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, left.as_secs().max(1).to_string())],
+        Json(json!({
+            "code": 429,
+            "message": "Too many failed attempts, try again later",
+            "data": null
+        })),
+    ).into_response()
+}
+
+/// Let a request through only with a valid session or API key
+///
+/// Pages redirect to the login form; everything else gets 401. Wrong API
+/// keys count towards the same lockout as failed logins.
+pub async fn require_auth(
+    State(camera_manager): State<Arc<CameraManager>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = camera_manager.config.current();
+    if !enabled(&config) {
+        return next.run(request).await;
+    }
+    let auth = &camera_manager.auth;
+    let bearer = bearer_token(request.headers()).is_some();
+    if bearer {
+        if let Some(left) = auth.login_lockout(peer.ip(), Instant::now()) {
+            tracing::warn!("Refused API key from {}: locked out after repeated failures", peer.ip());
+            return locked_out(left);
+        }
+    }
+    match auth.authenticate(request.headers(), &config).await {
+        Some(principal) => {
+            tracing::debug!("{} {} by {}", request.method(), request.uri().path(), principal);
+            next.run(request).await
+        }
+        None if bearer => {
+            auth.login_failed(peer.ip(), Instant::now());
+            tracing::warn!("Wrong API key from {}", peer.ip());
+            unauthorized()
+        }
+        None if matches!(request.uri().path(), "/" | "/dashboard") => Redirect::to("/login").into_response(),
+        None => unauthorized(),
+    }
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        Json(json!({
+            "code": 401,
+            "message": "Authentication required",
+            "data": null
+        })),
+    ).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+pub async fn login(
+    State(camera_manager): State<Arc<CameraManager>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Json(request): Json<LoginRequest>,
+) -> Response {
+    let auth = &camera_manager.auth;
+    if let Some(left) = auth.login_lockout(peer.ip(), Instant::now()) {
+        tracing::warn!("Refused login for user {:?} from {}: locked out after repeated failures", request.username, peer.ip());
+        return locked_out(left);
+    }
+
+    let config = camera_manager.config.current();
+    if !verify_user(&config, &request.username, &request.password).await {
+        auth.login_failed(peer.ip(), Instant::now());
+        tracing::warn!("Failed login for user {:?} from {}", request.username, peer.ip());
+        return (StatusCode::UNAUTHORIZED, Json(json!({
+            "code": 401,
+            "message": "Invalid username or password",
+            "data": null
+        }))).into_response();
+    }
+
+    auth.login_succeeded(peer.ip());
+    let ttl = chrono::Duration::seconds(config.session_ttl_secs as i64);
+    let (token, expires) = auth.create_session(&request.username, ttl);
+    tracing::info!("User {} logged in", request.username);
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE, token, config.session_ttl_secs
+    );
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(json!({
+            "code": 200,
+            "message": "Logged in",
+            "data": { "username": request.username, "expires": expires }
+        })),
+    ).into_response()
+}
+
+pub async fn logout(
+    State(camera_manager): State<Arc<CameraManager>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(token) = session_cookie(&headers) {
+        camera_manager.auth.end_session(token);
+    }
+    let cookie = format!("{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0", SESSION_COOKIE);
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(json!({
+            "code": 200,
+            "message": "Logged out",
+            "data": null
+        })),
+    ).into_response()
+}
+
+pub async fn serve_login_page() -> impl IntoResponse {
+    let html = r#"
+<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>A9 V720 Camera Server - Log in</title>
+    <style>
+        body {
+            font-family: Arial, sans-serif;
+            background-color: #2c2c2c;
+            color: #ffffff;
+            display: flex;
+            justify-content: center;
+            padding-top: 100px;
+        }
+        form {
+            background: #3a3a3a;
+            padding: 30px;
+            border-radius: 8px;
+            display: flex;
+            flex-direction: column;
+            gap: 12px;
+            width: 280px;
+        }
+        input, button {
+            padding: 8px;
+            border-radius: 4px;
+            border: none;
+        }
+        button {
+            background: #4CAF50;
+            color: white;
+            cursor: pointer;
+        }
+        #error {
+            color: #ff6b6b;
+            min-height: 1em;
+        }
+    </style>
+</head>
+<body>
+    <form id="login">
+        <h2>🎥 A9 V720 Camera Server</h2>
+        <input name="username" placeholder="Username" autocomplete="username" required>
+        <input name="password" type="password" placeholder="Password" autocomplete="current-password" required>
+        <button type="submit">Log in</button>
+        <div id="error"></div>
+    </form>
+    <script>
+        document.getElementById('login').addEventListener('submit', async (event) => {
+            event.preventDefault();
+            const form = new FormData(event.target);
+            const response = await fetch('/api/login', {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ username: form.get('username'), password: form.get('password') })
+            });
+            if (response.ok) {
+                window.location.href = '/dashboard';
+            } else {
+                document.getElementById('error').textContent = 'Invalid username or password';
+            }
+        });
+    </script>
+</body>
+</html>
+    "#;
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "text/html")
+        .body(axum::body::Body::from(html))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sessions_and_api_keys() {
+        let config = AppConfig {
+            users: [("admin".to_string(), hash_password("hunter2").unwrap())].into(),
+            api_keys: [("ha".to_string(), hash_password("s3cret").unwrap())].into(),
+            ..AppConfig::default()
+        };
+        let auth = WebAuth::new();
+        let headers = |name: header::HeaderName, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(auth.authenticate(&headers(header::AUTHORIZATION, "Bearer ha.s3cret"), &config).await.as_deref(), Some("key ha"));
+        // Served from the cache the second time
+        assert_eq!(auth.authenticate(&headers(header::AUTHORIZATION, "Bearer ha.s3cret"), &config).await.as_deref(), Some("key ha"));
+        assert_eq!(auth.authenticate(&headers(header::AUTHORIZATION, "Bearer ha.wrong"), &config).await, None);
+        assert_eq!(auth.authenticate(&headers(header::AUTHORIZATION, "Bearer s3cret"), &config).await, None);
+
+        let (token, _) = auth.create_session("admin", chrono::Duration::minutes(1));
+        let cookie = format!("theme=dark; {}={}", SESSION_COOKIE, token);
+        assert_eq!(auth.authenticate(&headers(header::COOKIE, &cookie), &config).await.as_deref(), Some("admin"));
+        auth.end_session(&token);
+        assert_eq!(auth.authenticate(&headers(header::COOKIE, &cookie), &config).await, None);
+
+        let (expired, _) = auth.create_session("admin", chrono::Duration::seconds(-1));
+        assert_eq!(auth.session_user(&expired), None);
+    }
+
+    #[test]
+    fn test_failed_logins_lock_the_address_out() {
+        let auth = WebAuth::new();
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..LOGIN_FREE_FAILURES - 1 {
+            auth.login_failed(ip, start);
+        }
+        assert_eq!(auth.login_lockout(ip, start), None);
+
+        // Each further failure doubles the lockout
+        auth.login_failed(ip, start);
+        assert_eq!(auth.login_lockout(ip, start), Some(Duration::from_secs(1)));
+        auth.login_failed(ip, start);
+        assert_eq!(auth.login_lockout(ip, start), Some(Duration::from_secs(2)));
+        assert_eq!(auth.login_lockout(ip, start + Duration::from_secs(2)), None);
+        assert_eq!(auth.login_lockout("192.168.1.21".parse().unwrap(), start), None);
+
+        auth.login_succeeded(ip);
+        assert_eq!(auth.login_lockout(ip, start), None);
+    }
+}
@@ -1,29 +1,29 @@
-use crate::types::CameraManager;
-use crate::protocol::{ProtocolHeader, ForwardCommand};
+use crate::audio::{decode_pcm16, wav_header, WAV_STREAMING_SIZE};
+use crate::config::{AudioCodec, ConfigError};
+use crate::recording::{Recording, RecordingSettings};
+use crate::actor::CameraHandle;
+use crate::types::{CameraManager, ViewerGuard};
+use crate::protocol::{DeviceSetting, ForwardCommand, Message, ReplyKey, RequestError};
+use crate::router::tcp::TcpRouter;
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
 use serde_json::json;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use std::net::IpAddr;
-use tokio::io::AsyncWriteExt;
+use futures::StreamExt;
+use serde::Deserialize;
+use bytes::Bytes;
+use std::convert::Infallible;
+use tokio::sync::broadcast;
 
 pub async fn list_cameras(
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
+    State(camera_manager): State<Arc<CameraManager>>,
 ) -> impl IntoResponse {
-    let manager = camera_manager.read().await;
-    let mut cameras = Vec::new();
-    
-    for camera in manager.cameras.values() {
-        let camera_guard = camera.read().await;
-        if let Some(device_id) = &camera_guard.device_id {
-            cameras.push(device_id.clone());
-        }
-    }
+    let mut cameras = camera_manager.list_cameras();
+    cameras.sort();
     
     Json(json!({
         "code": 200,
@@ -37,370 +37,556 @@ pub async fn list_cameras(
 
 pub async fn get_camera_info(
     Path(device_id): Path<String>,
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
+    State(camera_manager): State<Arc<CameraManager>>,
 ) -> Response {
-    let manager = camera_manager.read().await;
-    
-    // Find camera by device ID
-    let mut target_camera = None;
-    for camera in manager.cameras.values() {
-        let camera_guard = camera.read().await;
-        if let Some(id) = &camera_guard.device_id {
-            if id == &device_id {
-                target_camera = Some((
-                    camera_guard.ip,
-                    camera_guard.is_connected(),
-                    camera_guard.last_heartbeat,
-                    camera_guard.udp_ports.keys().cloned().collect::<Vec<_>>(),
-                    camera_guard.nat_ports.clone(),
-                    camera_guard.state.clone()
-                ));
-                break;
-            }
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "code": 404,
+            "message": "Camera not found",
+            "data": null
+        }))).into_response();
+    };
+    let name = camera_manager.registry.lock().await.get(&device_id).and_then(|record| record.name.clone());
+
+    let buffer_info = camera.buffer().await.map(|buffer| json!({
+        "frame_count": buffer.frame_sizes.len(),
+        "max_frames": buffer.max_frames,
+        "total_bytes": buffer.total_bytes(),
+        "reassembly": buffer.reassembly
+    }));
+    let camera_guard = camera.connection.read().await;
+
+    Json(json!({
+        "code": 200,
+        "message": "OK",
+        "data": {
+            "device_id": device_id,
+            "name": name,
+            "ip": camera_guard.ip.to_string(),
+            "connected": camera_guard.is_connected(),
+            "last_heartbeat": camera_guard.last_heartbeat.max(camera.last_media()).to_rfc3339(),
+            "udp_ports": camera.udp_ports.ports(),
+            "nat_ports": camera_guard.nat_ports,
+            "state": camera_guard.state.current(),
+            "streaming": *camera_guard.state.current() == crate::types::ProtocolState::Streaming,
+            "viewers": camera_guard.viewer_count(),
+            "recording": camera_guard.recording.as_ref().map(|recording| recording.status()),
+            "stream_buffer": buffer_info
         }
-    }
-    
-    if let Some((ip, connected, last_heartbeat, udp_ports, nat_ports, state)) = target_camera {
-        // Get buffer information
-        let buffer_info = {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.cameras.get(&ip) {
-                if let Ok(camera_guard) = camera.try_read() {
-                    let stream_buffer = &camera_guard.stream_buffer;
-                    json!({
-                        "frame_count": stream_buffer.frame_count(),
-                        "max_frames": stream_buffer.max_frames,
-                        "total_bytes": stream_buffer.get_all_frames().iter().map(|frame| frame.len()).sum::<usize>()
-                    })
-                } else {
-                    json!({
-                        "frame_count": 0,
-                        "max_frames": 100,
-                        "total_bytes": 0
-                    })
-                }
-            } else {
-                json!({
-                    "frame_count": 0,
-                    "max_frames": 100,
-                    "total_bytes": 0
-                })
-            }
-        };
+    })).into_response()
+}
 
-        Json(json!({
-            "code": 200,
-            "message": "OK",
-            "data": {
-                "device_id": device_id,
-                "ip": ip.to_string(),
-                "connected": connected,
-                "last_heartbeat": last_heartbeat.to_rfc3339(),
-                "udp_ports": udp_ports,
-                "nat_ports": nat_ports,
-                "streaming": state == crate::types::ProtocolState::Streaming,
-                "stream_buffer": buffer_info
-            }
-        })).into_response()
-    } else {
-        (StatusCode::NOT_FOUND, Json(json!({
+/// Current protocol state and the recent transitions that led to it
+pub async fn get_camera_state(
+    Path(device_id): Path<String>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
             "code": 404,
             "message": "Camera not found",
             "data": null
-        }))).into_response()
-    }
+        }))).into_response();
+    };
+
+    let camera_guard = camera.connection.read().await;
+    let history: Vec<_> = camera_guard.state.history().collect();
+    Json(json!({
+        "code": 200,
+        "message": "OK",
+        "data": {
+            "device_id": device_id,
+            "state": camera_guard.state.current(),
+            "since": camera_guard.state.since().to_rfc3339(),
+            "history": history
+        }
+    })).into_response()
 }
 
-pub async fn start_streaming(
+/// Rolling stream quality of a camera plus its reassembly counters
+pub async fn get_camera_stats(
     Path(device_id): Path<String>,
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
+    State(camera_manager): State<Arc<CameraManager>>,
 ) -> Response {
-    // First, quickly check if camera exists and get its IP without holding locks for long
-    let target_ip = {
-        let manager = camera_manager.read().await;
-        let mut found_ip = None;
-        for camera in manager.cameras.values() {
-            if let Ok(camera_guard) = camera.try_read() {
-                if let Some(id) = &camera_guard.device_id {
-                    if id == &device_id {
-                        found_ip = Some(camera_guard.ip);
-                        break;
-                    }
-                }
-            }
-        }
-        found_ip
+    let Some(stats) = (match find_camera(&camera_manager, &device_id) {
+        Some(camera) => camera.stats().await,
+        None => None,
+    }) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "code": 404,
+            "message": "Camera not found",
+            "data": null
+        }))).into_response();
     };
-    
-    if let Some(ip_addr) = target_ip {
-        // Get the camera's TCP connection without blocking
-        let tcp_conn = {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.cameras.get(&ip_addr) {
-                if let Ok(camera_guard) = camera.try_read() {
-                    camera_guard.tcp_conn.clone()
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        };
-        
-        if let Some(tcp_conn) = tcp_conn {
-            // Spawn the streaming control in a separate task to avoid blocking the response
-            let tcp_conn_clone = tcp_conn.clone();
-            let camera_manager_clone = camera_manager.clone();
-            let device_id_clone = device_id.clone();
-            
-            tokio::spawn(async move {
-                match crate::router::tcp::TcpRouter::start_streaming_for_camera(ip_addr, &camera_manager_clone).await {
-                    Ok(_) => {
-                        tracing::info!("Successfully started streaming for camera {}", ip_addr);
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to start streaming for camera {}: {}", device_id_clone, e);
-                    }
-                }
-            });
-            
-            // Return immediately with success response
-            Json(json!({
-                "code": 200,
-                "message": "Streaming start command sent successfully",
-                "data": {
-                    "device_id": device_id,
-                    "ip": ip_addr.to_string(),
-                    "timestamp": chrono::Utc::now().to_rfc3339(),
-                    "note": "Streaming control commands are being sent in background"
-                }
-            })).into_response()
-        } else {
+
+    Json(json!({
+        "code": 200,
+        "message": "OK",
+        "data": {
+            "device_id": device_id,
+            "stats": stats
+        }
+    })).into_response()
+}
+
+/// Every device in the registry, whether or not it is connected
+pub async fn list_devices(
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> impl IntoResponse {
+    let registry = camera_manager.registry.lock().await;
+    let devices: Vec<_> = registry
+        .devices()
+        .map(|record| {
+            let mut device = json!(record);
+            device["online"] = json!(camera_manager.contains(&record.device_id));
+            device
+        })
+        .collect();
+
+    Json(json!({
+        "code": 200,
+        "message": "OK",
+        "data": {
+            "devices": devices,
+            "count": devices.len()
+        }
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceUpdate {
+    /// Friendly name; empty or null clears it
+    name: Option<String>,
+}
+
+pub async fn update_device(
+    Path(device_id): Path<String>,
+    State(camera_manager): State<Arc<CameraManager>>,
+    Json(update): Json<DeviceUpdate>,
+) -> Response {
+    let mut registry = camera_manager.registry.lock().await;
+    if registry.get(&device_id).is_none() {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "code": 404,
+            "message": "Device not found",
+            "data": null
+        }))).into_response();
+    }
+
+    let name = update.name.map(|name| name.trim().to_string()).filter(|name| !name.is_empty());
+    match registry.update(&device_id, |record| record.name = name) {
+        Ok(record) => Json(json!({
+            "code": 200,
+            "message": "Device updated",
+            "data": record
+        })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to save device registry: {:#}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
                 "code": 500,
-                "message": "No TCP connection found for camera",
+                "message": format!("Failed to save device registry: {:#}", e),
                 "data": null
             }))).into_response()
         }
-    } else {
-        (StatusCode::NOT_FOUND, Json(json!({
+    }
+}
+
+/// Whether unknown devices may pair, and until when
+pub async fn get_pairing(
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let until = camera_manager.registry.lock().await.pairing_until();
+    Json(json!({
+        "code": 200,
+        "message": "OK",
+        "data": { "open": until.is_some(), "until": until }
+    })).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PairingRequest {
+    secs: Option<u64>,
+}
+
+/// Let unknown devices pair for `secs` (default `pairing_window_secs`)
+pub async fn open_pairing(
+    State(camera_manager): State<Arc<CameraManager>>,
+    request: Option<Json<PairingRequest>>,
+) -> Response {
+    let secs = request
+        .and_then(|Json(request)| request.secs)
+        .unwrap_or(camera_manager.config.current().pairing_window_secs);
+    let until = camera_manager.registry.lock().await.open_pairing(chrono::Duration::seconds(secs as i64));
+    tracing::info!("Pairing window open until {}", until);
+    Json(json!({
+        "code": 200,
+        "message": "Pairing open",
+        "data": { "open": true, "until": until }
+    })).into_response()
+}
+
+pub async fn close_pairing(
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    camera_manager.registry.lock().await.close_pairing();
+    tracing::info!("Pairing window closed");
+    Json(json!({
+        "code": 200,
+        "message": "Pairing closed",
+        "data": { "open": false, "until": null }
+    })).into_response()
+}
+
+/// The running configuration, secrets redacted, and the changes waiting for a restart
+pub async fn get_config(
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let live = &camera_manager.config;
+    Json(json!({
+        "code": 200,
+        "message": "OK",
+        "data": {
+            "config": live.current().redacted(),
+            "path": live.path(),
+            "restart_required": live.pending_restart()
+        }
+    })).into_response()
+}
+
+/// Change settings, e.g. `{"retry_timeout_ms": 2000}`, apply what can be applied live and save
+pub async fn update_config(
+    State(camera_manager): State<Arc<CameraManager>>,
+    Json(patch): Json<serde_json::Map<String, serde_json::Value>>,
+) -> Response {
+    match camera_manager.config.update(&patch).await {
+        Ok(change) => Json(json!({
+            "code": 200,
+            "message": "Configuration updated",
+            "data": change
+        })).into_response(),
+        Err(e @ ConfigError::Save { .. }) => {
+            tracing::error!("{}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "code": 500,
+                "message": e.to_string(),
+                "data": null
+            }))).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, Json(json!({
+            "code": 400,
+            "message": e.to_string(),
+            "data": null
+        }))).into_response(),
+    }
+}
+
+/// Settings to change; fields left out keep their current value
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsUpdate {
+    ir_led: Option<u32>,
+    inst_led: Option<u32>,
+    mirror_flip: Option<u32>,
+    speed_grade: Option<u32>,
+}
+
+impl SettingsUpdate {
+    fn settings(&self) -> Vec<DeviceSetting> {
+        [
+            self.ir_led.map(DeviceSetting::IrLed),
+            self.inst_led.map(DeviceSetting::InstLed),
+            self.mirror_flip.map(DeviceSetting::MirrorFlip),
+            self.speed_grade.map(DeviceSetting::SpeedGrade),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+/// The settings last reported by the camera in its 301/4 base info
+pub async fn get_settings(
+    Path(device_id): Path<String>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
             "code": 404,
             "message": "Camera not found",
             "data": null
-        }))).into_response()
-    }
+        }))).into_response();
+    };
+
+    let device_info = camera.connection.read().await.device_info.clone();
+    Json(json!({
+        "code": 200,
+        "message": "OK",
+        "data": {
+            "device_id": device_id,
+            "device_info": device_info
+        }
+    })).into_response()
 }
 
-pub async fn stop_streaming(
+/// Send each requested setting as a 301 command and wait for the camera to echo it
+pub async fn update_settings(
     Path(device_id): Path<String>,
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
+    State(camera_manager): State<Arc<CameraManager>>,
+    Json(update): Json<SettingsUpdate>,
 ) -> Response {
-    // First, quickly check if camera exists and get its IP without holding locks for long
-    let target_ip = {
-        let manager = camera_manager.read().await;
-        let mut found_ip = None;
-        for camera in manager.cameras.values() {
-            if let Ok(camera_guard) = camera.try_read() {
-                if let Some(id) = &camera_guard.device_id {
-                    if id == &device_id {
-                        found_ip = Some(camera_guard.ip);
-                        break;
-                    }
-                }
+    let settings = update.settings();
+    if settings.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "code": 400,
+            "message": "No settings given",
+            "data": null
+        }))).into_response();
+    }
+
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "code": 404,
+            "message": "Camera not found",
+            "data": null
+        }))).into_response();
+    };
+    let config = &camera_manager.config.current();
+    let mut applied = Vec::new();
+    let mut failure = None;
+    for setting in settings {
+        let command = Message::Forward(ForwardCommand::setting_request(config, setting));
+        match TcpRouter::send_request(&camera.connection, &command, ReplyKey::Forward(setting.code()), config).await {
+            Ok(_) => applied.push(setting),
+            Err(e) => {
+                failure = Some((setting, e));
+                break;
+            }
+        }
+    }
+
+    // Keep the cached base info in line with what the camera acknowledged
+    let device_info = {
+        let mut camera_guard = camera.connection.write().await;
+        if let Some(info) = camera_guard.device_info.as_mut() {
+            for setting in &applied {
+                setting.apply(info);
             }
         }
-        found_ip
+        camera_guard.device_info.clone()
     };
-    
-    if let Some(ip_addr) = target_ip {
-        // Spawn the stop streaming logic in a separate task to avoid blocking the response
-        let camera_manager_clone = camera_manager.clone();
-        let device_id_clone = device_id.clone();
-        
-        tokio::spawn(async move {
-            // Close all UDP connections for this camera
-            if let Ok(mut manager) = camera_manager_clone.try_write() {
-                if let Some(camera) = manager.cameras.get(&ip_addr) {
-                    if let Ok(mut camera_guard) = camera.try_write() {
-                        // Close all UDP sockets
-                        camera_guard.udp_ports.clear();
-                        tracing::info!("Closed {} UDP connections for camera {}", 
-                            camera_guard.udp_ports.len(), ip_addr);
-                        
-                        // Clear video buffer
-                        camera_guard.stream_buffer.clear();
-                        tracing::info!("Cleared video buffer for camera {}", ip_addr);
-                        
-                        // Set state back to Idle
-                        camera_guard.state = crate::types::ProtocolState::Idle;
-                        tracing::info!("Camera {} state set to Idle", ip_addr);
-                    }
-                }
+    if let Some(info) = device_info.clone() {
+        if let Err(e) = camera_manager.registry.lock().await.update(&device_id, |record| record.device_info = Some(info)) {
+            tracing::warn!("Failed to save device registry: {:#}", e);
+        }
+    }
+
+    let applied: serde_json::Map<_, _> = applied
+        .iter()
+        .map(|setting| (setting.field().to_string(), json!(setting.value())))
+        .collect();
+    let data = json!({
+        "device_id": device_id,
+        "applied": applied,
+        "device_info": device_info
+    });
+    match failure {
+        None => Json(json!({
+            "code": 200,
+            "message": "Settings applied",
+            "data": data
+        })).into_response(),
+        Some((setting, e)) => {
+            tracing::warn!("Setting {} on camera {} failed: {}", setting.field(), device_id, e);
+            request_error_response(&e, data)
+        }
+    }
+}
+
+pub async fn start_streaming(
+    Path(device_id): Path<String>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    match TcpRouter::start_streaming_for_camera(&device_id, &camera_manager).await {
+        Ok(reply) => Json(json!({
+            "code": 200,
+            "message": "Camera started streaming",
+            "data": {
+                "device_id": device_id,
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "reply": reply
             }
-            
-            // Optionally send a stop streaming command to the camera
-            let tcp_conn = {
-                if let Ok(manager) = camera_manager_clone.try_read() {
-                    if let Some(camera) = manager.cameras.get(&ip_addr) {
-                        if let Ok(camera_guard) = camera.try_read() {
-                            camera_guard.tcp_conn.clone()
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            };
-            
-            if let Some(tcp_conn) = tcp_conn {
-                // Send stop streaming command (Code 301/0)
-                let stop_streaming = serde_json::json!({
-                    "code": 301,
-                    "target": "00112233445566778899aabbccddeeff",
-                    "content": {
-                        "code": 0  // Stop streaming
-                    }
-                });
-                
-                match send_tcp_message(&tcp_conn, &stop_streaming).await {
-                    Ok(_) => {
-                        tracing::info!("Stop streaming command sent to {}", ip_addr);
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to send stop streaming command to {}: {}", ip_addr, e);
-                    }
-                }
+        })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to start streaming for camera {}: {}", device_id, e);
+            request_error_response(&e, json!({ "device_id": device_id }))
+        }
+    }
+}
+
+pub async fn stop_streaming(
+    Path(device_id): Path<String>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    match TcpRouter::stop_streaming_for_camera(&device_id, &camera_manager).await {
+        Ok(reply) => Json(json!({
+            "code": 200,
+            "message": "Camera stopped streaming",
+            "data": {
+                "device_id": device_id,
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "reply": reply
             }
-        });
-        
-        // Return immediately with success response
-        Json(json!({
+        })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to stop streaming for camera {}: {}", device_id, e);
+            request_error_response(&e, json!({ "device_id": device_id }))
+        }
+    }
+}
+
+pub async fn trigger_snapshot(
+    Path(device_id): Path<String>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    match TcpRouter::trigger_snapshot_for_camera(&device_id, &camera_manager).await {
+        Ok(reply) => Json(json!({
             "code": 200,
-            "message": "Streaming stop command sent successfully",
+            "message": "Snapshot taken",
             "data": {
                 "device_id": device_id,
-                "ip": ip_addr.to_string(),
                 "timestamp": chrono::Utc::now().to_rfc3339(),
-                "note": "Streaming stop commands are being sent in background"
+                "reply": reply
             }
-        })).into_response()
-    } else {
-        (StatusCode::NOT_FOUND, Json(json!({
+        })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to trigger snapshot for camera {}: {}", device_id, e);
+            request_error_response(&e, json!({ "device_id": device_id }))
+        }
+    }
+}
+
+/// Map a failed camera command to its HTTP status
+fn request_error_response(error: &RequestError, data: serde_json::Value) -> Response {
+    let status = match error {
+        RequestError::UnknownCamera(_) => StatusCode::NOT_FOUND,
+        RequestError::NotConnected | RequestError::Send(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        RequestError::Disconnected => StatusCode::BAD_GATEWAY,
+        RequestError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+    };
+    let message = match error {
+        RequestError::UnknownCamera(_) => "Camera not found".to_string(),
+        RequestError::NotConnected => "No TCP connection found for camera".to_string(),
+        error => error.to_string(),
+    };
+    (status, Json(json!({
+        "code": status.as_u16(),
+        "message": message,
+        "data": data
+    }))).into_response()
+}
+
+pub async fn start_recording(
+    Path(device_id): Path<String>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let settings = RecordingSettings::from_config(&camera_manager.config.current());
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "code": 404,
+            "message": "Camera not found",
+            "data": null
+        }))).into_response();
+    };
+    let Some(media) = camera.subscribe().await else {
+        return (StatusCode::NOT_FOUND, Json(json!({
             "code": 404,
             "message": "Camera not found",
             "data": null
-        }))).into_response()
+        }))).into_response();
+    };
+
+    let mut camera_guard = camera.connection.write().await;
+    if let Some(recording) = &camera_guard.recording {
+        if !recording.is_finished() {
+            return (StatusCode::CONFLICT, Json(json!({
+                "code": 409,
+                "message": "Camera is already recording",
+                "data": recording.status()
+            }))).into_response();
+        }
     }
+    if let Some(finished) = camera_guard.recording.take() {
+        // Ended on its own after a write error; collecting its result does not block
+        if let Err(e) = finished.stop().await {
+            tracing::warn!("Previous recording of {} failed: {:#}", device_id, e);
+        }
+    }
+
+    let recording = Recording::start(&device_id, media.frames, media.audio, settings.clone());
+    let status = recording.status();
+    camera_guard.recording = Some(recording);
+    tracing::info!("Recording of {} started under {}", device_id, settings.dir.display());
+
+    Json(json!({
+        "code": 200,
+        "message": "Recording started",
+        "data": status
+    })).into_response()
 }
 
-pub async fn trigger_snapshot(
+pub async fn stop_recording(
     Path(device_id): Path<String>,
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
+    State(camera_manager): State<Arc<CameraManager>>,
 ) -> Response {
-    // First, quickly check if camera exists and get its IP without holding locks for long
-    let target_ip = {
-        let manager = camera_manager.read().await;
-        let mut found_ip = None;
-        for camera in manager.cameras.values() {
-            if let Ok(camera_guard) = camera.try_read() {
-                if let Some(id) = &camera_guard.device_id {
-                    if id == &device_id {
-                        found_ip = Some(camera_guard.ip);
-                        break;
-                    }
-                }
-            }
-        }
-        found_ip
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "code": 404,
+            "message": "Camera not found",
+            "data": null
+        }))).into_response();
     };
-    
-    if let Some(ip_addr) = target_ip {
-        // Get the camera's TCP connection without blocking
-        let tcp_conn = {
-            let manager = camera_manager.read().await;
-            if let Some(camera) = manager.cameras.get(&ip_addr) {
-                if let Ok(camera_guard) = camera.try_read() {
-                    camera_guard.tcp_conn.clone()
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        };
-        
-        if let Some(tcp_conn) = tcp_conn {
-            // Spawn the snapshot command in a separate task to avoid blocking the response
-            let tcp_conn_clone = tcp_conn.clone();
-            let device_id_clone = device_id.clone();
-            
-            tokio::spawn(async move {
-                // Send snapshot command (Code 301/5)
-                let snapshot_command = serde_json::json!({
-                    "code": 301,
-                    "target": "00112233445566778899aabbccddeeff",
-                    "content": {
-                        "code": 5  // Snapshot
-                    }
-                });
-                
-                match send_tcp_message(&tcp_conn_clone, &snapshot_command).await {
-                    Ok(_) => {
-                        tracing::info!("Snapshot command sent to {}", ip_addr);
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to trigger snapshot for camera {}: {}", device_id_clone, e);
-                    }
-                }
-            });
-            
-            // Return immediately with success response
+
+    // Finishing the segment happens without the camera lock held
+    let Some(recording) = camera.connection.write().await.recording.take() else {
+        return (StatusCode::CONFLICT, Json(json!({
+            "code": 409,
+            "message": "Camera is not recording",
+            "data": null
+        }))).into_response();
+    };
+
+    match recording.stop().await {
+        Ok(files) => {
+            tracing::info!("Recording of {} stopped, {} segment(s) written", device_id, files.len());
             Json(json!({
                 "code": 200,
-                "message": "Snapshot command sent successfully",
+                "message": "Recording stopped",
                 "data": {
                     "device_id": device_id,
-                    "ip": ip_addr.to_string(),
-                    "timestamp": chrono::Utc::now().to_rfc3339(),
-                    "note": "Snapshot command is being sent in background"
+                    "files": files
                 }
             })).into_response()
-        } else {
+        }
+        Err(e) => {
+            tracing::error!("Recording of {} failed: {:#}", device_id, e);
             (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
                 "code": 500,
-                "message": "No TCP connection found for camera",
+                "message": format!("Recording failed: {:#}", e),
                 "data": null
             }))).into_response()
         }
-    } else {
-        (StatusCode::NOT_FOUND, Json(json!({
-            "code": 404,
-            "message": "Camera not found",
-            "data": null
-        }))).into_response()
     }
 }
 
 pub async fn get_video_stream(
     Path(device_id): Path<String>,
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
+    State(camera_manager): State<Arc<CameraManager>>,
 ) -> impl IntoResponse {
-    let manager = camera_manager.read().await;
-    
     // Find camera by device ID
-    let mut target_camera = None;
-    for camera in manager.cameras.values() {
-        let camera_guard = camera.read().await;
-        if let Some(id) = &camera_guard.device_id {
-            if id == &device_id {
-                target_camera = Some(camera_guard.stream_buffer.get_latest_frame().map(|f| f.to_vec()));
-                break;
-            }
-        }
-    }
+    let target_camera = match find_camera(&camera_manager, &device_id) {
+        Some(camera) => camera.buffer().await.map(|buffer| buffer.latest_frame),
+        None => None,
+    };
     
     if let Some(latest_frame) = target_camera {
         if let Some(frame) = latest_frame {
@@ -421,80 +607,187 @@ pub async fn get_video_stream(
 
 pub async fn get_mjpeg_stream(
     Path(device_id): Path<String>,
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
-) -> impl IntoResponse {
-    let manager = camera_manager.read().await;
-    
-    // Find camera by device ID
-    let mut target_camera = None;
-    for camera in manager.cameras.values() {
-        let camera_guard = camera.read().await;
-        if let Some(id) = &camera_guard.device_id {
-            if id == &device_id {
-                target_camera = Some(camera_guard.stream_buffer.get_latest_frame().map(|f| f.to_vec()));
-                break;
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, "Camera not found").into_response();
+    };
+    // The media task hands out the latest frame and the subscription together, so no frame slips in between
+    let Some(media) = camera.subscribe().await else {
+        return (StatusCode::NOT_FOUND, "Camera not found").into_response();
+    };
+    let (latest_frame, frames) = (media.latest_frame, media.frames);
+
+    let viewer = {
+        let mut camera_guard = camera.connection.write().await;
+        let viewer_id = format!("mjpeg-{:08x}", rand::random::<u32>());
+        camera_guard.add_viewer(viewer_id.clone());
+        tracing::info!("MJPEG viewer {} connected to {} ({} viewers)", viewer_id, device_id, camera_guard.viewer_count());
+        ViewerGuard::new(camera.connection.clone(), viewer_id)
+    };
+
+    // Start with the latest buffered frame, then push every new one as its own part
+    let parts = futures::stream::unfold(
+        (latest_frame, frames, viewer),
+        |(pending, mut frames, viewer)| async move {
+            let frame = match pending {
+                Some(frame) => frame,
+                None => loop {
+                    match frames.recv().await {
+                        Ok(frame) => break frame,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            // Slow viewer: drop the frames it missed and carry on with newer ones
+                            tracing::debug!("MJPEG viewer {} lagging, skipped {} frames", viewer.id, skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                },
+            };
+            Some((Ok::<_, Infallible>(mjpeg_part(&frame)), (None, frames, viewer)))
+        },
+    );
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "multipart/x-mixed-replace; boundary=frame")
+        .header("Cache-Control", "no-cache")
+        .header("Connection", "keep-alive")
+        .body(axum::body::Body::from_stream(parts))
+        .unwrap()
+}
+
+/// One multipart part carrying a JPEG frame
+fn mjpeg_part(frame: &[u8]) -> Bytes {
+    let mut part = Vec::with_capacity(frame.len() + 96);
+    part.extend_from_slice(b"--frame\r\n");
+    part.extend_from_slice(b"Content-Type: image/jpeg\r\n");
+    part.extend_from_slice(format!("Content-Length: {}\r\n", frame.len()).as_bytes());
+    part.extend_from_slice(b"\r\n");
+    part.extend_from_slice(frame);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AudioParams {
+    /// G.711 variant to decode with, overriding `audio_codec` from the config
+    codec: Option<AudioCodec>,
+    /// Clip length for `/audio/clip`
+    seconds: Option<u64>,
+}
+
+/// Longest clip `/audio/clip` serves by default
+const DEFAULT_CLIP_SECONDS: u64 = 10;
+
+pub async fn get_audio_stream(
+    Path(device_id): Path<String>,
+    Query(params): Query<AudioParams>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let codec = params.codec.unwrap_or(camera_manager.config.current().audio_codec);
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, "Camera not found").into_response();
+    };
+    let Some(media) = camera.subscribe().await else {
+        return (StatusCode::NOT_FOUND, "Camera not found").into_response();
+    };
+    let packets = media.audio;
+
+    let listener = {
+        let mut camera_guard = camera.connection.write().await;
+        let listener_id = format!("audio-{:08x}", rand::random::<u32>());
+        camera_guard.add_viewer(listener_id.clone());
+        tracing::info!("Audio listener {} connected to {}", listener_id, device_id);
+        ViewerGuard::new(camera.connection.clone(), listener_id)
+    };
+
+    // A WAV header of unbounded length, then PCM as the camera sends it
+    let header = Bytes::copy_from_slice(&wav_header(WAV_STREAMING_SIZE));
+    let header = futures::stream::once(async { Ok::<_, Infallible>(header) });
+    let samples = futures::stream::unfold((packets, listener), move |(mut packets, listener)| async move {
+        loop {
+            match packets.recv().await {
+                Ok(packet) => {
+                    let pcm = Bytes::from(decode_pcm16(codec, &packet));
+                    return Some((Ok::<_, Infallible>(pcm), (packets, listener)));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::debug!("Audio listener {} lagging, skipped {} packets", listener.id, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
             }
         }
+    });
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "audio/wav")
+        .header("Cache-Control", "no-cache")
+        .body(axum::body::Body::from_stream(header.chain(samples)))
+        .unwrap()
+}
+
+pub async fn get_audio_clip(
+    Path(device_id): Path<String>,
+    Query(params): Query<AudioParams>,
+    State(camera_manager): State<Arc<CameraManager>>,
+) -> Response {
+    let config = camera_manager.config.current();
+    let codec = params.codec.unwrap_or(config.audio_codec);
+    let Some(camera) = find_camera(&camera_manager, &device_id) else {
+        return (StatusCode::NOT_FOUND, "Camera not found").into_response();
+    };
+
+    let seconds = params.seconds.unwrap_or(DEFAULT_CLIP_SECONDS.min(config.audio_clip_max_secs));
+    if !(1..=config.audio_clip_max_secs).contains(&seconds) {
+        let message = format!("seconds must be between 1 and {}", config.audio_clip_max_secs);
+        return (StatusCode::BAD_REQUEST, message).into_response();
     }
-    
-    if let Some(latest_frame) = target_camera {
-        if let Some(frame) = latest_frame {
-            // Create a simple MJPEG stream with just the latest frame
-            let mut mjpeg_data = Vec::new();
-            mjpeg_data.extend_from_slice(b"--frame\r\n");
-            mjpeg_data.extend_from_slice(b"Content-Type: image/jpeg\r\n");
-            mjpeg_data.extend_from_slice(format!("Content-Length: {}\r\n", frame.len()).as_bytes());
-            mjpeg_data.extend_from_slice(b"\r\n");
-            mjpeg_data.extend_from_slice(&frame);
-            mjpeg_data.extend_from_slice(b"\r\n");
-            
-            Response::builder()
-                .status(200)
-                .header("Content-Type", "multipart/x-mixed-replace; boundary=frame")
-                .header("Cache-Control", "no-cache")
-                .header("Connection", "keep-alive")
-                .body(axum::body::Body::from(mjpeg_data))
-                .unwrap()
-        } else {
-            (StatusCode::NO_CONTENT, "No video frames available").into_response()
-        }
-    } else {
-        (StatusCode::NOT_FOUND, "Camera not found").into_response()
+    let samples = camera.audio_clip(std::time::Duration::from_secs(seconds)).await;
+    if samples.is_empty() {
+        return (StatusCode::NO_CONTENT, "No audio available").into_response();
     }
+
+    let pcm = decode_pcm16(codec, &samples);
+    let mut wav = Vec::with_capacity(44 + pcm.len());
+    wav.extend_from_slice(&wav_header(pcm.len() as u32));
+    wav.extend_from_slice(&pcm);
+
+    let filename = format!("{}_{}.wav", device_id, chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "audio/wav")
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+        .body(axum::body::Body::from(wav))
+        .unwrap()
 }
 
 // Debug endpoint to examine buffer contents
 pub async fn debug_buffer(
     Path(device_id): Path<String>,
-    State(camera_manager): State<Arc<RwLock<CameraManager>>>,
+    State(camera_manager): State<Arc<CameraManager>>,
 ) -> Response {
-    let manager = camera_manager.read().await;
-    
     // Find camera by device ID
     let mut target_camera = None;
-    for camera in manager.cameras.values() {
-        let camera_guard = camera.read().await;
-        if let Some(id) = &camera_guard.device_id {
-            if id == &device_id {
-                let buffer = &camera_guard.stream_buffer;
-                let frames = buffer.get_all_frames();
-                let latest_frame = buffer.get_latest_frame();
-                
-                target_camera = Some(json!({
-                    "frame_count": frames.len(),
-                    "max_frames": buffer.max_frames,
-                    "latest_frame_size": latest_frame.map(|f| f.len()),
-                    "latest_frame_hex": latest_frame.map(|f| {
-                        f.iter().take(16).map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
-                    }),
-                    "latest_frame_ascii": latest_frame.map(|f| {
-                        String::from_utf8_lossy(&f.iter().take(32).map(|&b| if b >= 32 && b <= 126 { b } else { b'.' }).collect::<Vec<_>>()).to_string()
-                    }),
-                    "all_frame_sizes": frames.iter().map(|f| f.len()).collect::<Vec<_>>()
-                }));
-                break;
-            }
-        }
+    if let Some(buffer) = match find_camera(&camera_manager, &device_id) {
+        Some(camera) => camera.buffer().await,
+        None => None,
+    } {
+        let latest_frame = buffer.latest_frame.as_deref();
+        
+        target_camera = Some(json!({
+            "frame_count": buffer.frame_sizes.len(),
+            "max_frames": buffer.max_frames,
+            "latest_frame_size": latest_frame.map(|f| f.len()),
+            "latest_frame_hex": latest_frame.map(|f| {
+                f.iter().take(16).map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+            }),
+            "latest_frame_ascii": latest_frame.map(|f| {
+                String::from_utf8_lossy(&f.iter().take(32).map(|&b| if (32..=126).contains(&b) { b } else { b'.' }).collect::<Vec<_>>()).to_string()
+            }),
+            "all_frame_sizes": buffer.frame_sizes,
+            "reassembly": buffer.reassembly
+        }));
     }
     
     if let Some(buffer_info) = target_camera {
@@ -508,21 +801,10 @@ pub async fn debug_buffer(
     }
 }
 
-// Helper function for TCP communication (used by snapshot and stop streaming)
-async fn send_tcp_message(
-    tcp_conn: &Arc<tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>>,
-    json_data: &serde_json::Value,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let json_str = serde_json::to_string(json_data)?;
-    let header = ProtocolHeader::json(0, json_str.len());
-    let mut message = Vec::new();
-    message.extend_from_slice(&header.to_bytes());
-    message.extend_from_slice(json_str.as_bytes());
-    
-    let mut socket_guard = tcp_conn.lock().await;
-    socket_guard.write_all(&message).await?;
-    
-    Ok(())
+/// Look up a camera by device ID
+fn find_camera(
+    camera_manager: &Arc<CameraManager>,
+    device_id: &str,
+) -> Option<CameraHandle> {
+    camera_manager.handle(device_id)
 }
-
-
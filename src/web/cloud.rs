@@ -0,0 +1,249 @@
+//! Cloud emulation for the cameras
+//!
+//! The firmware asks the vendor cloud where to register (`getA9ConfCheck`)
+//! and, on first boot, for a uid (`ApiSysDevicesBatch`). These routes are
+//! served on their own listener, usually port 80 where the cameras' DNS
+//! points, and never ask for credentials. The management interface is not
+//! reachable there.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::protocol::ConfigCheckResponse;
+use crate::types::{CameraManager, ProtocolState};
+use crate::web::server::track_latency;
+
+/// Serve the cloud emulation on an already bound listener
+pub async fn serve_cloud(
+    listener: tokio::net::TcpListener,
+    camera_manager: Arc<CameraManager>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tracing::info!("Cloud emulation listening on {}", listener.local_addr()?);
+    // The config check trusts a camera's known address
+    axum::serve(listener, router(camera_manager).into_make_service_with_connect_info::<SocketAddr>()).await?;
+    Ok(())
+}
+
+pub fn router(camera_manager: Arc<CameraManager>) -> Router {
+    Router::new()
+        .route("/app/api/ApiServer/getA9ConfCheck", post(handle_config_check).get(handle_config_check))
+        .route("/app/api/ApiSysDevicesBatch/registerDevices", post(handle_bootstrap_registration))
+        .route("/app/api/ApiSysDevicesBatch/confirm", post(handle_bootstrap_confirm))
+        .route_layer(middleware::from_fn_with_state(camera_manager.clone(), track_latency))
+        .with_state(camera_manager)
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigCheckParams {
+    #[serde(rename = "devicesCode")]
+    devices_code: String,
+    random: String,
+    token: String,
+}
+
+/// Send the camera to the TCP server with its `pwd`
+///
+/// Only a check from the camera's known address (its last registration or
+/// its live control connection) or one while the pairing window is open may
+/// record a token or end the camera's session; anyone else can name any
+/// `devicesCode` and only gets the answer. The `pwd` in it is empty unless
+/// `DeviceRegistry::check_pwd` lets the caller have it: a paired camera
+/// presenting `server_token` after a reset gets its pwd back from its known
+/// address, or from anywhere while the pairing window is open.
+async fn handle_config_check(
+    State(camera_manager): State<Arc<CameraManager>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<ConfigCheckParams>,
+) -> impl IntoResponse {
+    tracing::info!("Config check from {} for device {}", peer, params.devices_code);
+    tracing::debug!("Config check random={}", params.random);
+
+    let camera = camera_manager.handle(&params.devices_code);
+    let (trusted, pwd, firmware_version) = {
+        let mut registry = camera_manager.registry.lock().await;
+        let record = registry.get(&params.devices_code);
+        let paired = record.is_some_and(|record| record.paired);
+        let known = record.and_then(|record| record.last_ip) == Some(peer.ip())
+            || camera.as_ref().is_some_and(|camera| camera.addr.ip() == peer.ip());
+        let trusted = known || registry.pairing_until().is_some();
+        if !trusted {
+            tracing::warn!("Config check for device {} from unknown address {} while pairing is closed, not recorded",
+                params.devices_code, peer.ip());
+        } else if !paired {
+            // First contact of a camera; the TCP registration fills in the rest
+            if let Err(e) = registry.update(&params.devices_code, |record| record.token = Some(params.token.clone())) {
+                tracing::warn!("Failed to save device registry: {:#}", e);
+            }
+        }
+        let server_token = camera_manager.config.current().server_token.clone();
+        let pwd = match registry.check_pwd(&params.devices_code, &params.token, &server_token, trusted) {
+            Ok(pwd) => pwd,
+            Err(e) => {
+                tracing::warn!("Failed to save device registry: {:#}", e);
+                // Generated and kept in memory even though saving failed
+                registry.get(&params.devices_code).and_then(|record| record.pwd.clone())
+            }
+        };
+        if pwd.is_none() {
+            tracing::info!("No pwd for device {}: {}", params.devices_code,
+                if paired { "neither its registered token nor the server token from a trusted address" } else { "pairing is closed" });
+        }
+        let record = registry.get(&params.devices_code);
+        (
+            trusted,
+            pwd.unwrap_or_default(),
+            record.and_then(|record| record.firmware_version.clone()),
+        )
+    };
+    // A camera seen before is starting over, e.g. after a power cycle
+    if let Some(camera) = camera.filter(|_| trusted) {
+        camera.connection.write().await.end_session(ProtocolState::Configuring, "config check");
+    }
+
+    let config = camera_manager.config.current();
+    let response = ConfigCheckResponse::new(&params.devices_code, &pwd, &config)
+        .with_firmware(&config, firmware_version.as_deref());
+
+    tracing::debug!(
+        "Config check response for device {}: host={}, tcpPort={}, version={:?}",
+        params.devices_code,
+        response.data.host,
+        response.data.tcp_port,
+        response.data.version
+    );
+
+    Json(response)
+}
+
+/// Hand out a uid to a camera that has none yet: `uid_prefix` plus the next serial
+///
+/// Only served while the pairing window is open or to an address a known
+/// camera used last, so an anonymous loop cannot use up the uid space.
+async fn handle_bootstrap_registration(
+    State(camera_manager): State<Arc<CameraManager>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    tracing::info!("Bootstrap registration request from {}: {:?}", peer, params);
+
+    let batch = params.get("batch").cloned();
+    let random = params.get("random").cloned().unwrap_or_default();
+    let mac = params.get("mac").cloned();
+    let prefix = camera_manager.config.current().uid_prefix.clone();
+    // Saving the registry writes the file synchronously
+    let allocated = spawn_blocking(move || {
+        let mut registry = camera_manager.registry.blocking_lock();
+        let known = registry.devices().any(|record| record.last_ip == Some(peer.ip()));
+        if !known && registry.pairing_until().is_none() {
+            return Ok(None);
+        }
+        registry
+            .allocate(&prefix, batch.as_deref(), &random, mac.as_deref())
+            .map(|record| Some(record.device_id.clone()))
+    })
+    .await;
+
+    match allocated {
+        Ok(None) => {
+            tracing::warn!("Bootstrap registration from unknown address {} while pairing is closed, refused", peer.ip());
+            cloud_response(StatusCode::FORBIDDEN, json!({
+                "code": 403,
+                "message": "Pairing is closed",
+                "data": null
+            }))
+        }
+        Ok(Some(device_id)) => {
+            tracing::info!("Allocated uid {} for {}", device_id, peer.ip());
+            cloud_response(StatusCode::OK, json!({
+                "code": 200,
+                "message": "操作成功",
+                "data": device_id
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to allocate a uid: {:#}", e);
+            cloud_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                "code": 500,
+                "message": format!("{:#}", e),
+                "data": null
+            }))
+        }
+    }
+}
+
+/// The camera took the uid handed out by `registerDevices`
+async fn handle_bootstrap_confirm(
+    State(camera_manager): State<Arc<CameraManager>>,
+    Query(params): Query<std::collections::HashMap<String, String>>,
+) -> Response {
+    tracing::info!("Bootstrap confirmation request: {:?}", params);
+
+    let Some(device_id) = params.get("devicesCode") else {
+        return cloud_response(StatusCode::BAD_REQUEST, json!({
+            "code": 400,
+            "message": "devicesCode is required",
+            "data": null
+        }));
+    };
+
+    let confirmed = {
+        let device_id = device_id.clone();
+        spawn_blocking(move || camera_manager.registry.blocking_lock().confirm(&device_id).map(|record| record.is_some())).await
+    };
+    match confirmed {
+        Ok(true) => {
+            tracing::info!("Device {} confirmed its uid", device_id);
+            cloud_response(StatusCode::OK, json!({
+                "code": 200,
+                "message": "操作成功",
+                "data": null
+            }))
+        }
+        Ok(false) => {
+            tracing::warn!("Confirmation for uid {} that was never allocated", device_id);
+            cloud_response(StatusCode::NOT_FOUND, json!({
+                "code": 404,
+                "message": "Device not found",
+                "data": null
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to save device registry: {:#}", e);
+            cloud_response(StatusCode::INTERNAL_SERVER_ERROR, json!({
+                "code": 500,
+                "message": format!("Failed to save device registry: {:#}", e),
+                "data": null
+            }))
+        }
+    }
+}
+
+/// Run blocking registry work off the runtime
+async fn spawn_blocking<T: Send + 'static>(work: impl FnOnce() -> anyhow::Result<T> + Send + 'static) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(work).await.unwrap_or_else(|e| Err(e.into()))
+}
+
+/// JSON response with the headers of the original cloud's nginx, to match the pcap
+fn cloud_response(status: StatusCode, body: serde_json::Value) -> Response {
+    Response::builder()
+        .status(status)
+        .header("Server", "nginx/1.14.0 (Ubuntu)")
+        .header("Connection", "keep-alive")
+        .header("Content-Type", "application/json; charset=utf-8")
+        .header("Vary", "Origin")
+        .header("Vary", "Access-Control-Request-Method")
+        .header("Vary", "Access-Control-Request-Headers")
+        .body(axum::body::Body::from(body.to_string()))
+        .unwrap()
+}
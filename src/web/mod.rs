@@ -1,3 +1,5 @@
+pub mod auth;
+pub mod cloud;
 pub mod server;
 pub mod camera_endpoints;
 
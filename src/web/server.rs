@@ -1,54 +1,113 @@
 use crate::types::CameraManager;
+use crate::metrics::{self, HttpLabels};
 use axum::{
-    extract::Query,
-    http::StatusCode,
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
-    Json, Router,
+    routing::{get, post, put},
+    Router,
 };
-use serde::Deserialize;
-use serde_json::json;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Instant;
 use tower_http::services::ServeDir;
 
+use crate::web::auth;
 use crate::web::camera_endpoints::*;
 
 pub async fn start_web_server(
-    camera_manager: Arc<RwLock<CameraManager>>,
-    port: u16,
+    camera_manager: Arc<CameraManager>,
+    addr: std::net::SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let app = Router::new()
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    serve_web(listener, camera_manager).await
+}
+
+/// Serve the web interface and API on an already bound listener
+pub async fn serve_web(
+    listener: tokio::net::TcpListener,
+    camera_manager: Arc<CameraManager>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tracing::info!("HTTP server listening on {}", listener.local_addr()?);
+    // Failed logins are counted per client address
+    axum::serve(listener, router(camera_manager).into_make_service_with_connect_info::<std::net::SocketAddr>()).await?;
+    Ok(())
+}
+
+pub fn router(camera_manager: Arc<CameraManager>) -> Router {
+    // Management interface: needs a session or API key once users or keys are configured
+    let management = Router::new()
         // Camera management endpoints
         .route("/api/cameras", get(list_cameras))
         .route("/api/cameras/:device_id", get(get_camera_info))
         .route("/api/cameras/:device_id/snapshot", post(trigger_snapshot))
         .route("/api/cameras/:device_id/stream", get(get_video_stream))
         .route("/api/cameras/:device_id/mjpeg", get(get_mjpeg_stream))
+        .route("/api/cameras/:device_id/audio", get(get_audio_stream))
+        .route("/api/cameras/:device_id/audio/clip", get(get_audio_clip))
         .route("/api/cameras/:device_id/debug", get(debug_buffer))
         .route("/api/cameras/:device_id/streaming/start", get(start_streaming))
         .route("/api/cameras/:device_id/streaming/stop", get(stop_streaming))
-        
-        // Legacy endpoints for camera registration
-        .route("/app/api/ApiServer/getA9ConfCheck", post(handle_config_check))
-        .route("/app/api/ApiServer/getA9ConfCheck", get(handle_config_check))
-        .route("/app/api/ApiSysDevicesBatch/registerDevices", post(handle_bootstrap_registration))
-        .route("/app/api/ApiSysDevicesBatch/confirm", post(handle_bootstrap_confirm))
-        
+        .route("/api/cameras/:device_id/recording/start", post(start_recording))
+        .route("/api/cameras/:device_id/recording/stop", post(stop_recording))
+        .route("/api/cameras/:device_id/settings", get(get_settings).put(update_settings))
+        .route("/api/cameras/:device_id/state", get(get_camera_state))
+        .route("/api/cameras/:device_id/stats", get(get_camera_stats))
+        .route("/api/devices", get(list_devices))
+        .route("/api/devices/:device_id", put(update_device))
+        .route("/api/config", get(get_config).put(update_config))
+        .route("/api/pairing", get(get_pairing).post(open_pairing).delete(close_pairing))
+
         // Web interface
         .route("/", get(serve_web_interface))
         .route("/dashboard", get(serve_dashboard))
+
+        // Prometheus scrape target
+        .route("/metrics", get(serve_metrics))
+        .route_layer(middleware::from_fn_with_state(camera_manager.clone(), auth::require_auth));
+
+    // Logging in needs no credentials
+    let public = Router::new()
+        .route("/login", get(auth::serve_login_page))
+        .route("/api/login", post(auth::login))
+        .route("/api/logout", post(auth::logout));
+
+    management
+        .merge(public)
+        .route_layer(middleware::from_fn_with_state(camera_manager.clone(), track_latency))
         
         // Static files
         .nest_service("/static", ServeDir::new("static"))
         
-        .with_state(camera_manager);
+        .with_state(camera_manager)
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
-    tracing::info!("HTTP server listening on port {}", port);
+async fn serve_metrics(State(camera_manager): State<Arc<CameraManager>>) -> Response {
+    match metrics::render(&camera_manager).await {
+        Ok(body) => ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to encode metrics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
 
-    axum::serve(listener, app).await?;
-    Ok(())
+/// Record the latency of every routed request, labelled by route pattern
+pub(crate) async fn track_latency(
+    State(camera_manager): State<Arc<CameraManager>>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let path = matched_path.map(|path| path.as_str().to_string()).unwrap_or_default();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    camera_manager.metrics.http_request(
+        HttpLabels { method, path, status: response.status().as_u16() },
+        started.elapsed(),
+    );
+    response
 }
 
 async fn serve_web_interface() -> impl IntoResponse {
@@ -263,7 +322,7 @@ async fn serve_web_interface() -> impl IntoResponse {
                     <button class="btn btn-primary" onclick="triggerSnapshot('${deviceId}')">📸 Snapshot</button>
                     <button class="btn btn-success" onclick="startStreaming('${deviceId}')" id="start-${deviceId}">▶️ Start Stream</button>
                     <button class="btn btn-danger" onclick="stopStreaming('${deviceId}')" id="stop-${deviceId}" style="display:none;">⏹️ Stop Stream</button>
-                    <a class="btn btn-info" href="/api/cameras/${deviceId}/stream" target="_blank">🎬 View Stream</a>
+                    <a class="btn btn-info" href="/api/cameras/${deviceId}/mjpeg" target="_blank">🎬 View Stream</a>
                 </div>
             `;
             return card;
@@ -336,30 +395,17 @@ async fn serve_web_interface() -> impl IntoResponse {
                                     noVideoElement.textContent = 'Waiting for video frames...';
                                 }
                                 
-                                // Set up periodic refresh for live video
-                                if (window.videoRefreshIntervals && window.videoRefreshIntervals[deviceId]) {
-                                    clearInterval(window.videoRefreshIntervals[deviceId]);
-                                }
-                                
-                                if (!window.videoRefreshIntervals) {
-                                    window.videoRefreshIntervals = {};
+                                // Attach the live MJPEG stream once (this runs on every refresh)
+                                if (!videoElement.src.includes('/mjpeg')) {
+                                    videoElement.src = `/api/cameras/${deviceId}/mjpeg`;
                                 }
                                 
-                                window.videoRefreshIntervals[deviceId] = setInterval(() => {
-                                    if (videoElement.style.display !== 'none') {
-                                        videoElement.src = `/api/cameras/${deviceId}/stream?t=${Date.now()}`;
-                                    }
-                                }, 100); // Refresh every 100ms for 10 fps
-                                
                                                             // Handle video load errors
                             videoElement.onerror = function() {
                                 console.error('Video load error for camera:', deviceId);
                                 this.style.display = 'none';
                                 noVideoElement.style.display = 'block';
                                 noVideoElement.textContent = 'Video stream not available...';
-                                if (window.videoRefreshIntervals && window.videoRefreshIntervals[deviceId]) {
-                                    clearInterval(window.videoRefreshIntervals[deviceId]);
-                                }
                             };
                             
                             // Handle video load success
@@ -454,35 +500,17 @@ async fn serve_web_interface() -> impl IntoResponse {
                     // Start video stream after a short delay to allow camera to start streaming
                     setTimeout(() => {
                         if (videoElement && noVideoElement) {
-                            // Use a simple approach: refresh the image every 100ms
-                            videoElement.src = `/api/cameras/${deviceId}/stream?t=${Date.now()}`;
+                            // The server pushes every new frame over this one multipart response
+                            videoElement.src = `/api/cameras/${deviceId}/mjpeg`;
                             videoElement.style.display = 'block';
                             noVideoElement.style.display = 'none';
                             
-                            // Set up periodic refresh for live video
-                            if (window.videoRefreshIntervals && window.videoRefreshIntervals[deviceId]) {
-                                clearInterval(window.videoRefreshIntervals[deviceId]);
-                            }
-                            
-                            if (!window.videoRefreshIntervals) {
-                                window.videoRefreshIntervals = {};
-                            }
-                            
-                            window.videoRefreshIntervals[deviceId] = setInterval(() => {
-                                if (videoElement.style.display !== 'none') {
-                                    videoElement.src = `/api/cameras/${deviceId}/stream?t=${Date.now()}`;
-                                }
-                            }, 100); // Refresh every 100ms for 10 fps
-                            
                             // Handle video load errors
                             videoElement.onerror = function() {
                                 console.error('Video load error for camera:', deviceId);
                                 this.style.display = 'none';
                                 noVideoElement.style.display = 'block';
                                 noVideoElement.textContent = 'Video stream not available yet...';
-                                if (window.videoRefreshIntervals && window.videoRefreshIntervals[deviceId]) {
-                                    clearInterval(window.videoRefreshIntervals[deviceId]);
-                                }
                             };
                             
                             // Handle video load success
@@ -542,15 +570,9 @@ async fn serve_web_interface() -> impl IntoResponse {
                     // Hide video stream and show placeholder
                     if (videoElement && noVideoElement) {
                         videoElement.style.display = 'none';
-                        videoElement.src = '';
+                        videoElement.src = ''; // Closes the MJPEG response
                         noVideoElement.style.display = 'block';
                         noVideoElement.textContent = 'Click "Start Stream" to begin video streaming';
-                        
-                        // Clear the refresh interval
-                        if (window.videoRefreshIntervals && window.videoRefreshIntervals[deviceId]) {
-                            clearInterval(window.videoRefreshIntervals[deviceId]);
-                            delete window.videoRefreshIntervals[deviceId];
-                        }
                     }
                     
                     // Show success message
@@ -732,6 +754,7 @@ async fn serve_dashboard() -> impl IntoResponse {
 <body>
     <div class="container">
         <h1>📊 Camera Dashboard</h1>
+        <button onclick="logout()">Log out</button>
         
         <div class="stats-grid">
             <div class="stat-card">
@@ -756,9 +779,19 @@ async fn serve_dashboard() -> impl IntoResponse {
     </div>
 
     <script>
+        async function logout() {
+            await fetch('/api/logout', { method: 'POST' });
+            window.location.href = '/login';
+        }
+
         async function loadDashboard() {
             try {
                 const response = await fetch('/api/cameras');
+                if (response.status === 401) {
+                    // The session expired
+                    window.location.href = '/login';
+                    return;
+                }
                 const data = await response.json();
                 
                 const cameras = data.data.cameras;
@@ -773,8 +806,9 @@ async fn serve_dashboard() -> impl IntoResponse {
                     if (cameraInfo && cameraInfo.connected) {
                         connectedCount++;
                     }
+                    const cameraStats = await getCameraStats(ip);
                     
-                    const cameraItem = createCameraItem(ip, cameraInfo);
+                    const cameraItem = createCameraItem(ip, cameraInfo, cameraStats);
                     cameraList.appendChild(cameraItem);
                 }
                 
@@ -799,7 +833,27 @@ async fn serve_dashboard() -> impl IntoResponse {
             }
         }
         
-        function createCameraItem(ip, info) {
+        async function getCameraStats(ip) {
+            try {
+                const response = await fetch(`/api/cameras/${ip}/stats`);
+                const data = await response.json();
+                return data.code === 200 ? data.data.stats : null;
+            } catch (error) {
+                console.error(`Error loading camera stats for ${ip}:`, error);
+                return null;
+            }
+        }
+        
+        function formatStats(stats) {
+            const r = stats.reassembly;
+            const jitter = stats.jitter_ms === null ? '-' : `${stats.jitter_ms.toFixed(1)} ms`;
+            const frameSize = stats.frame_bytes_avg === null ? '-' : `${Math.round(stats.frame_bytes_avg / 1024)} KB`;
+            return `${stats.fps.toFixed(1)} fps | ${Math.round(stats.bytes_per_sec / 1024)} KB/s | frame ${frameSize}`
+                + ` | jitter ${jitter} | missing ${r.fragments_missing}, duplicate ${r.fragments_duplicate}`
+                + ` | dropped ${r.frames_dropped}, expired ${r.frames_expired} | 605 sent ${stats.confirmations_sent}, requests ${stats.requests_sent}`;
+        }
+        
+        function createCameraItem(ip, info, stats) {
             const item = document.createElement('div');
             item.className = 'camera-item';
             
@@ -813,10 +867,11 @@ async fn serve_dashboard() -> impl IntoResponse {
                         Status: <span style="color: ${statusColor};">${status}</span>
                         ${info ? `| Last heartbeat: ${new Date(info.last_heartbeat).toLocaleString()}` : ''}
                     </div>
+                    ${stats ? `<div class="camera-details">${formatStats(stats)}</div>` : ''}
                 </div>
                 <div class="camera-actions">
                     <button class="btn btn-primary" onclick="triggerSnapshot('${ip}')">📸 Snapshot</button>
-                    <a class="btn btn-success" href="/api/cameras/${ip}/stream" target="_blank">🎬 Stream</a>
+                    <a class="btn btn-success" href="/api/cameras/${ip}/mjpeg" target="_blank">🎬 Stream</a>
                 </div>
             `;
             return item;
@@ -841,8 +896,8 @@ async fn serve_dashboard() -> impl IntoResponse {
         // Load dashboard on page load
         loadDashboard();
         
-        // Refresh every 30 seconds
-        setInterval(loadDashboard, 30000);
+        // Refresh every 5 seconds so the stream figures stay current
+        setInterval(loadDashboard, 5000);
     </script>
 </body>
 </html>
@@ -854,107 +909,3 @@ async fn serve_dashboard() -> impl IntoResponse {
         .body(axum::body::Body::from(html))
         .unwrap()
 }
-
-#[derive(Debug, Deserialize)]
-struct ConfigCheckParams {
-    devicesCode: String,
-    random: String,
-    token: String,
-}
-
-async fn handle_config_check(
-    Query(params): Query<ConfigCheckParams>,
-) -> impl IntoResponse {
-    tracing::info!(
-        "Config check request (POST): {{\"devicesCode\": \"{}\", \"random\": \"{}\", \"token\": \"{}\"}}",
-        params.devicesCode, params.random, params.token
-    );
-
-    let response = json!({
-        "code": 200,
-        "message": "OK",
-        "data": {
-            "uid": params.devicesCode,
-            "host": "192.168.1.99",
-            "domain": "v720.naxclow.com",
-            "tcpPort": 6123,
-            "pwd": "deadbeef",
-            "isBind": "8",
-            "currTime": chrono::Utc::now().timestamp().to_string(),
-            "updateUrl": null,
-            "version": null
-        }
-    });
-
-    tracing::info!(
-        "Config check response for device {}: {}",
-        params.devicesCode,
-        serde_json::to_string(&response).unwrap()
-    );
-
-    Json(response)
-}
-
-async fn handle_bootstrap_registration(
-    Query(params): Query<std::collections::HashMap<String, String>>,
-) -> impl IntoResponse {
-    tracing::info!("Bootstrap registration request: {:?}", params);
-    
-    // Extract batch and random parameters
-    let batch = params.get("batch").cloned().unwrap_or_else(|| "A9_48PIN_B".to_string());
-    let random = params.get("random").cloned().unwrap_or_else(|| "DEFGHI".to_string());
-    
-    // Generate device ID from random parameter (like the archived version)
-    let device_id = format!("0800c001{}", &random[..4].to_uppercase());
-    
-    // Create bootstrap response
-    let response = json!({
-        "code": 200,
-        "message": "操作成功",
-        "data": device_id
-    });
-
-    tracing::info!("Bootstrap registration response: {:?}", response);
-    
-    // Return custom response with specific headers to match pcap
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Server", "nginx/1.14.0 (Ubuntu)")
-        .header("Connection", "keep-alive")
-        .header("Content-Type", "application/json; charset=utf-8")
-        .header("Vary", "Origin")
-        .header("Vary", "Access-Control-Request-Method")
-        .header("Vary", "Access-Control-Request-Headers")
-        .body(axum::body::Body::from(serde_json::to_string(&response).unwrap()))
-        .unwrap()
-}
-
-async fn handle_bootstrap_confirm(
-    Query(params): Query<std::collections::HashMap<String, String>>,
-) -> impl IntoResponse {
-    tracing::info!("Bootstrap confirmation request: {:?}", params);
-    
-    // Extract device ID from query parameters
-    let device_id = params.get("devicesCode").cloned().unwrap_or_else(|| "unknown".to_string());
-    
-    // Create confirmation response
-    let response = json!({
-        "code": 200,
-        "message": "操作成功",
-        "data": null
-    });
-
-    tracing::info!("Bootstrap confirmation response for device {}: {:?}", device_id, response);
-    
-    // Return custom response with specific headers to match pcap
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Server", "nginx/1.14.0 (Ubuntu)")
-        .header("Connection", "keep-alive")
-        .header("Content-Type", "application/json; charset=utf-8")
-        .header("Vary", "Origin")
-        .header("Vary", "Access-Control-Request-Method")
-        .header("Vary", "Access-Control-Request-Headers")
-        .body(axum::body::Body::from(serde_json::to_string(&response).unwrap()))
-        .unwrap()
-}
@@ -0,0 +1,263 @@
+//! Loopback test harness: the whole server on ephemeral ports plus the camera simulator
+
+// Each test crate uses its own subset of the helpers
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use a9_v720_server::config::AppConfig;
+use a9_v720_server::registry::DeviceRegistry;
+use a9_v720_server::router::{TcpRouter, UdpRouter};
+use a9_v720_server::rtsp;
+use a9_v720_server::types::CameraManager;
+use a9_v720_server::watchdog;
+use a9_v720_server::web::cloud::serve_cloud;
+use a9_v720_server::web::server::serve_web;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+pub struct TestServer {
+    pub config: AppConfig,
+    pub web_addr: SocketAddr,
+    /// The cloud emulation the simulator fetches its config from
+    pub cloud_addr: SocketAddr,
+    /// Holds the recordings and the device registry; removed on drop
+    state_dir: PathBuf,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start the TCP router, the three UDP routers, the cloud emulation, the web server, RTSP and the watchdog on 127.0.0.1
+    pub async fn start() -> TestServer {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Like `start`, with `configure` adjusting the config before anything runs
+    pub async fn start_with(configure: impl FnOnce(&mut AppConfig)) -> TestServer {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cloud_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rtsp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let udp_protocol = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_stream_1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_stream_2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let web_addr = web_listener.local_addr().unwrap();
+        let cloud_addr = cloud_listener.local_addr().unwrap();
+        static SERVERS: AtomicUsize = AtomicUsize::new(0);
+        let state_dir = std::env::temp_dir().join(format!(
+            "a9-v720-test-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut config = AppConfig {
+            server_ip: "127.0.0.1".to_string(),
+            tcp_registration_port: cloud_addr.port(),
+            tcp_protocol_port: tcp_listener.local_addr().unwrap().port(),
+            udp_protocol_port: udp_protocol.local_addr().unwrap().port(),
+            udp_stream_port_1: udp_stream_1.local_addr().unwrap().port(),
+            udp_stream_port_2: udp_stream_2.local_addr().unwrap().port(),
+            web_port: web_addr.port(),
+            rtsp_enabled: true,
+            rtsp_port: rtsp_listener.local_addr().unwrap().port(),
+            recording_dir: state_dir.join("recordings").to_string_lossy().into_owned(),
+            registry_path: state_dir.join("devices.json").to_string_lossy().into_owned(),
+            // Simulated cameras pair as they first register
+            pair_on_start: true,
+            ..AppConfig::default()
+        };
+        configure(&mut config);
+        let registry = DeviceRegistry::load(&config.registry_path).unwrap();
+        let camera_manager = Arc::new(CameraManager::new(config.clone(), registry));
+
+        let mut tasks = Vec::new();
+        let tcp_router = TcpRouter::new(camera_manager.clone());
+        tasks.push(tokio::spawn(async move {
+            tcp_router.run(tcp_listener).await.unwrap();
+        }));
+        for socket in [udp_protocol, udp_stream_1, udp_stream_2] {
+            let camera_manager = camera_manager.clone();
+            tasks.push(tokio::spawn(async move {
+                UdpRouter::start(socket, camera_manager).await.unwrap();
+            }));
+        }
+        let web_camera_manager = camera_manager.clone();
+        tasks.push(tokio::spawn(async move {
+            serve_web(web_listener, web_camera_manager).await.unwrap();
+        }));
+        let cloud_camera_manager = camera_manager.clone();
+        tasks.push(tokio::spawn(async move {
+            serve_cloud(cloud_listener, cloud_camera_manager).await.unwrap();
+        }));
+        let rtsp_camera_manager = camera_manager.clone();
+        tasks.push(tokio::spawn(async move {
+            rtsp::serve(rtsp_listener, rtsp_camera_manager).await.unwrap();
+        }));
+        tasks.push(tokio::spawn(watchdog::run(camera_manager.clone())));
+
+        TestServer {
+            config,
+            web_addr,
+            cloud_addr,
+            state_dir,
+            tasks,
+        }
+    }
+
+    /// Launch `a9-v720-sim` against this server; it is killed when the handle is dropped
+    pub fn spawn_camera(&self, uid: &str, extra_args: &[&str]) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_a9-v720-sim"))
+            .args(["--server", "127.0.0.1"])
+            .args(["--http-port", &self.cloud_addr.port().to_string()])
+            .args([
+                "--control-addr",
+                &format!("127.0.0.1:{}", self.config.tcp_protocol_port),
+            ])
+            .args(["--uid", uid])
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .expect("failed to start a9-v720-sim")
+    }
+
+    /// Send a GET for `path` and return the connection to read the raw response from
+    pub async fn open(&self, path: &str) -> TcpStream {
+        send(self.web_addr, "GET", path, &[], None).await
+    }
+
+    /// GET `path` from the web server, returning the status code and body
+    pub async fn get(&self, path: &str) -> (u16, Vec<u8>) {
+        self.request("GET", path, None).await
+    }
+
+    /// POST an empty body to `path` and parse the response as JSON
+    pub async fn post_json(&self, path: &str) -> (u16, serde_json::Value) {
+        let (status, body) = self.request("POST", path, None).await;
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    /// POST an empty body to `path` on the cloud emulation, as a camera does, and parse the response as JSON
+    pub async fn cloud_post_json(&self, path: &str) -> (u16, serde_json::Value) {
+        let (status, _, body) = exchange(self.cloud_addr, "POST", path, &[], None).await;
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    /// PUT `body` as JSON to `path` and parse the response as JSON
+    pub async fn put_json(&self, path: &str, body: serde_json::Value) -> (u16, serde_json::Value) {
+        let (status, body) = self.request("PUT", path, Some(&body)).await;
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    async fn request(&self, method: &str, path: &str, body: Option<&serde_json::Value>) -> (u16, Vec<u8>) {
+        let (status, _, body) = self.exchange(method, path, &[], body).await;
+        (status, body)
+    }
+
+    /// Send a request with extra `headers` to the web server, returning the status code, response head and body
+    pub async fn exchange(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&serde_json::Value>,
+    ) -> (u16, String, Vec<u8>) {
+        exchange(self.web_addr, method, path, headers, body).await
+    }
+
+    /// GET `path` and parse the body as JSON
+    pub async fn get_json(&self, path: &str) -> (u16, serde_json::Value) {
+        let (status, body) = self.get(path).await;
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    /// `rtsp://` URL of a camera on this server
+    pub fn rtsp_url(&self, device_id: &str) -> String {
+        format!("rtsp://127.0.0.1:{}/{}", self.config.rtsp_port, device_id)
+    }
+}
+
+async fn send(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&serde_json::Value>,
+) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let content_type = if body.is_empty() { "" } else { "Content-Type: application/json\r\n" };
+    let extra_headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n{}{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        content_type,
+        extra_headers,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream
+}
+
+async fn exchange(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&serde_json::Value>,
+) -> (u16, String, Vec<u8>) {
+    let mut stream = send(addr, method, path, headers, body).await;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("malformed HTTP response");
+    let head = String::from_utf8_lossy(&response[..split]).into_owned();
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .expect("missing HTTP status");
+    (status, head, response[split + 4..].to_vec())
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        let _ = std::fs::remove_dir_all(&self.state_dir);
+    }
+}
+
+/// Poll `check` every 50 ms until it yields a value, panicking after `timeout`
+pub async fn eventually<T, F, Fut>(what: &str, timeout: Duration, mut check: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if let Some(value) = check().await {
+            return value;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            panic!("timed out after {:?} waiting for {}", timeout, what);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
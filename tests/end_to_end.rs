@@ -0,0 +1,559 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{eventually, TestServer};
+use tokio::io::AsyncReadExt;
+
+const UID: &str = "0800c00128F8";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn is_jpeg(data: &[u8]) -> bool {
+    data.starts_with(&[0xFF, 0xD8]) && data.ends_with(&[0xFF, 0xD9])
+}
+
+/// Registration: config check, code 100 and the camera shows up in the API
+async fn wait_for_registration(server: &TestServer) {
+    eventually("camera registration", TIMEOUT, || async {
+        let (status, body) = server.get_json("/api/cameras").await;
+        let listed = status == 200 && body["data"]["cameras"].as_array()?.iter().any(|id| id == UID);
+        listed.then_some(())
+    })
+    .await;
+}
+
+/// Register a simulated camera and start streaming; returns the first frame served by `/stream`
+async fn start_streaming(server: &TestServer) -> Vec<u8> {
+    wait_for_registration(server).await;
+
+    // NAT (11/12, 20/21), probe (51/50) and 301 sequence, then video over the random port
+    let (status, body) = server.get_json(&format!("/api/cameras/{}/streaming/start", UID)).await;
+    assert_eq!(status, 200, "{}", body);
+    // The answer is the camera's echo of 301/3
+    assert_eq!(body["data"]["reply"]["content"]["code"], 3);
+
+    eventually("a complete JPEG frame", TIMEOUT, || async {
+        let (status, body) = server.get(&format!("/api/cameras/{}/stream", UID)).await;
+        (status == 200 && is_jpeg(&body)).then_some(body)
+    })
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_register_stream_and_stop() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20"]);
+
+    let frame = start_streaming(&server).await;
+    let comment = b"a9-v720-sim frame ";
+    assert!(frame.windows(comment.len()).any(|w| w == comment));
+
+    let (status, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+    assert_eq!(status, 200);
+    assert_eq!(info["data"]["state"], "Streaming");
+
+    // Stopping returns the camera to Idle
+    let (status, _) = server.get(&format!("/api/cameras/{}/streaming/stop", UID)).await;
+    assert_eq!(status, 200);
+
+    eventually("camera back in Idle", TIMEOUT, || async {
+        let (_, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+        (info["data"]["state"] == "Idle").then_some(())
+    })
+    .await;
+
+    let (status, body) = server.get_json(&format!("/api/cameras/{}/state", UID)).await;
+    assert_eq!(status, 200);
+    let states: Vec<_> = body["data"]["history"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|transition| transition["to"].as_str().unwrap())
+        .collect();
+    assert_eq!(states, ["Registering", "Idle", "NatTraversal", "Streaming", "Idle"]);
+    assert_eq!(body["data"]["history"][0]["from"], "Disconnected");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_mjpeg_pushes_frames_until_viewer_leaves() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20"]);
+    start_streaming(&server).await;
+
+    let mut stream = server.open(&format!("/api/cameras/{}/mjpeg", UID)).await;
+    let mut body = Vec::new();
+    let mut buffer = [0u8; 8192];
+    let part = b"Content-Type: image/jpeg";
+    tokio::time::timeout(TIMEOUT, async {
+        while body.windows(part.len()).filter(|w| w == part).count() < 5 {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0, "MJPEG response ended early");
+            body.extend_from_slice(&buffer[..n]);
+        }
+    })
+    .await
+    .expect("timed out waiting for MJPEG parts");
+
+    let (_, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+    assert_eq!(info["data"]["viewers"], 1);
+
+    drop(stream);
+    eventually("viewer removal", TIMEOUT, || async {
+        let (_, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+        (info["data"]["viewers"] == 0).then_some(())
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_recording_writes_avi_segment() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20"]);
+    start_streaming(&server).await;
+
+    let (status, body) = server.post_json(&format!("/api/cameras/{}/recording/start", UID)).await;
+    assert_eq!(status, 200, "{}", body);
+    let (status, _) = server.post_json(&format!("/api/cameras/{}/recording/start", UID)).await;
+    assert_eq!(status, 409);
+
+    eventually("recorded frames", TIMEOUT, || async {
+        let (_, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+        let recording = &info["data"]["recording"];
+        (recording["frames"].as_u64()? >= 10 && recording["audio_bytes"].as_u64()? > 0).then_some(())
+    })
+    .await;
+
+    let (status, body) = server.post_json(&format!("/api/cameras/{}/recording/stop", UID)).await;
+    assert_eq!(status, 200, "{}", body);
+    let files = body["data"]["files"].as_array().unwrap();
+    assert_eq!(files.len(), 1);
+
+    let path = std::path::Path::new(files[0].as_str().unwrap());
+    assert!(path.starts_with(&server.config.recording_dir));
+    let avi = std::fs::read(path).unwrap();
+    assert_eq!(&avi[..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes(avi[4..8].try_into().unwrap()) as usize, avi.len() - 8);
+    assert_eq!(&avi[8..12], b"AVI ");
+    for fourcc in [b"MJPG", b"00dc", b"01wb", b"idx1"] {
+        assert!(avi.windows(4).any(|w| w == fourcc), "{} missing", String::from_utf8_lossy(fourcc));
+    }
+
+    let (_, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+    assert!(info["data"]["recording"].is_null());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_audio_stream_and_clip() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20"]);
+    start_streaming(&server).await;
+
+    // The simulator sends A-law silence (0xD5), which decodes to 8
+    let mut stream = server.open(&format!("/api/cameras/{}/audio", UID)).await;
+    let mut response = Vec::new();
+    let mut buffer = [0u8; 4096];
+    tokio::time::timeout(TIMEOUT, async {
+        while !response.windows(4).any(|w| w == [8, 0, 8, 0]) {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0, "audio response ended early");
+            response.extend_from_slice(&buffer[..n]);
+        }
+    })
+    .await
+    .expect("timed out waiting for audio samples");
+    let head = String::from_utf8_lossy(&response);
+    assert!(head.contains("audio/wav"));
+    assert!(head.contains("RIFF") && head.contains("WAVEfmt "));
+    drop(stream);
+
+    let (status, wav) = server.get(&format!("/api/cameras/{}/audio/clip?seconds=1", UID)).await;
+    assert_eq!(status, 200);
+    assert_eq!(&wav[..4], b"RIFF");
+    let data_size = u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize;
+    assert_eq!(data_size, wav.len() - 44);
+    assert!(data_size > 0 && data_size <= 16000);
+    assert!(wav[44..].chunks(2).all(|sample| sample == [8, 0]));
+
+    // µ-law decoding of the same bytes gives a different waveform
+    let (_, wav) = server.get(&format!("/api/cameras/{}/audio/clip?seconds=1&codec=ulaw", UID)).await;
+    assert!(wav[44..].chunks(2).all(|sample| sample != [8, 0]));
+
+    let (status, _) = server.get(&format!("/api/cameras/{}/audio/clip?seconds=3600", UID)).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_device_registry_persists_identity() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20"]);
+    start_streaming(&server).await;
+
+    // The 301/4 answer carries the firmware version
+    let device = eventually("registry record with firmware", TIMEOUT, || async {
+        let (_, body) = server.get_json("/api/devices").await;
+        let device = body["data"]["devices"].as_array()?.iter().find(|device| device["device_id"] == UID)?.clone();
+        device["firmware_version"].is_string().then_some(device)
+    })
+    .await;
+    assert_eq!(device["online"], true);
+    assert_eq!(device["last_ip"], "127.0.0.1");
+    // The camera registers with the pwd handed out in the config check
+    assert_eq!(device["pwd"].as_str().map(str::len), Some(8));
+    assert_eq!(device["token"], device["pwd"]);
+
+    let (status, body) = server.put_json(&format!("/api/devices/{}", UID), serde_json::json!({"name": "Porch"})).await;
+    assert_eq!(status, 200, "{}", body);
+    let (_, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+    assert_eq!(info["data"]["name"], "Porch");
+    let (status, _) = server.put_json("/api/devices/nosuchcamera", serde_json::json!({"name": "x"})).await;
+    assert_eq!(status, 404);
+
+    // What a restarted server would load
+    let registry = a9_v720_server::registry::DeviceRegistry::load(&server.config.registry_path).unwrap();
+    let record = registry.get(UID).unwrap();
+    assert_eq!(record.name.as_deref(), Some("Porch"));
+    assert_eq!(record.firmware_version, device["firmware_version"].as_str().map(String::from));
+    assert!(record.device_info.is_some());
+
+    // A paired camera's pwd only goes to the token it registered with, or to
+    // the server token from its known address
+    let check = |token: &str| format!("/app/api/ApiServer/getA9ConfCheck?devicesCode={}&random=ABCDEF&token={}", UID, token);
+    let (_, body) = server.cloud_post_json(&check("cafef00d")).await;
+    assert_eq!(body["data"]["pwd"], "");
+    let (_, body) = server.cloud_post_json(&check(device["pwd"].as_str().unwrap())).await;
+    assert_eq!(body["data"]["pwd"], device["pwd"]);
+    let (_, body) = server.cloud_post_json(&check("deadbeef")).await;
+    assert_eq!(body["data"]["pwd"], device["pwd"]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_config_changes_apply_without_restart() {
+    let server = TestServer::start().await;
+
+    let patch = serde_json::json!({"server_ip": "127.0.0.2", "retry_timeout_ms": 1000, "rtsp_port": 1});
+    let (status, body) = server.put_json("/api/config", patch).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["applied"], serde_json::json!(["retry_timeout_ms", "server_ip"]));
+    assert_eq!(body["data"]["restart_required"], serde_json::json!(["rtsp_port"]));
+
+    // The next camera to check in is sent to the new address
+    let (_, body) = server.cloud_post_json(&format!("/app/api/ApiServer/getA9ConfCheck?devicesCode={}&random=ABCDEF&token=deadbeef", UID)).await;
+    assert_eq!(body["data"]["host"], "127.0.0.2");
+
+    let (_, body) = server.get_json("/api/config").await;
+    assert_eq!(body["data"]["config"]["retry_timeout_ms"], 1000);
+    assert_eq!(body["data"]["config"]["server_token"], "<redacted>");
+    assert_eq!(body["data"]["config"]["rtsp_port"], server.config.rtsp_port);
+    assert_eq!(body["data"]["restart_required"], serde_json::json!(["rtsp_port"]));
+
+    let (status, body) = server.put_json("/api/config", serde_json::json!({"client_token": ""})).await;
+    assert_eq!(status, 400, "{}", body);
+    // What GET shows for a secret is not a value to save
+    let (status, body) = server.put_json("/api/config", serde_json::json!({"server_token": "<redacted>"})).await;
+    assert_eq!(status, 400, "{}", body);
+    let (status, _) = server.put_json("/api/config", serde_json::json!({"no_such_setting": 1})).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_cloud_emulation_hands_out_uids_and_firmware() {
+    let server = TestServer::start_with(|config| {
+        config.uid_prefix = "a9test".to_string();
+        config.firmware_version = "2.0.1".to_string();
+        config.firmware_url = "http://127.0.0.1/fw.bin".to_string();
+    })
+    .await;
+
+    let register = "/app/api/ApiSysDevicesBatch/registerDevices?batch=A9_48PIN_B&random=AB";
+    let (status, body) = server.cloud_post_json(register).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"], "a9test0001");
+    let (_, body) = server.cloud_post_json(register).await;
+    assert_eq!(body["data"], "a9test0001", "a retry gets the same uid");
+
+    let (status, _) = server.cloud_post_json("/app/api/ApiSysDevicesBatch/confirm?devicesCode=a9test0001").await;
+    assert_eq!(status, 200);
+    let (status, _) = server.cloud_post_json("/app/api/ApiSysDevicesBatch/confirm?devicesCode=a9test9999").await;
+    assert_eq!(status, 404);
+    let (_, body) = server.cloud_post_json(register).await;
+    assert_eq!(body["data"], "a9test0002");
+
+    let check = "/app/api/ApiServer/getA9ConfCheck?devicesCode=a9test0001&random=ABCDEF&token=t";
+    let (_, first) = server.cloud_post_json(check).await;
+    let (_, second) = server.cloud_post_json(check).await;
+    assert_eq!(first["data"]["tcpPort"], server.config.tcp_protocol_port);
+    assert_eq!(first["data"]["pwd"].as_str().map(str::len), Some(8));
+    assert_eq!(first["data"]["pwd"], second["data"]["pwd"]);
+    assert_eq!(first["data"]["version"], "2.0.1");
+    assert_eq!(first["data"]["updateUrl"], "http://127.0.0.1/fw.bin");
+
+    // An unpaired device gets no pwd once the pairing window closes
+    assert_eq!(server.exchange("DELETE", "/api/pairing", &[], None).await.0, 200);
+    let (_, closed) = server.cloud_post_json(check).await;
+    assert_eq!(closed["data"]["pwd"], "");
+    // ...and no address that never registered gets a uid
+    let (status, body) = server.cloud_post_json("/app/api/ApiSysDevicesBatch/registerDevices?batch=A9_48PIN_B&random=CD").await;
+    assert_eq!(status, 403, "{}", body);
+
+    // Each listener serves only its own routes
+    assert_eq!(server.post_json(check).await.0, 404);
+    assert_eq!(server.cloud_post_json("/api/pairing").await.0, 404);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_registration_needs_pairing_and_token() {
+    let server = TestServer::start_with(|config| config.pair_on_start = false).await;
+    let rejected = |reason: &'static str| {
+        let server = &server;
+        async move {
+            let (_, body) = server.get("/metrics").await;
+            let series = format!(r#"a9_registrations_rejected_total{{reason="{}"}} "#, reason);
+            let metrics = String::from_utf8(body).unwrap();
+            metrics.lines().find_map(|line| line.strip_prefix(&series)?.parse::<f64>().ok())
+        }
+    };
+
+    // Anyone may ask the config check about a device, but only a camera's known address records anything
+    let check = "/app/api/ApiServer/getA9ConfCheck?devicesCode=0800c0019999&random=ABCDEF&token=t";
+    assert_eq!(server.cloud_post_json(check).await.0, 200);
+    let (_, body) = server.get_json("/api/devices").await;
+    assert_eq!(body["data"]["devices"], serde_json::json!([]));
+
+    let _camera = server.spawn_camera(UID, &["--reconnect", "--reconnect-delay-ms", "200"]);
+    eventually("a registration refused while pairing is closed", TIMEOUT, || rejected("not_paired")).await;
+    let (_, body) = server.get_json("/api/cameras").await;
+    assert_eq!(body["data"]["cameras"], serde_json::json!([]));
+
+    let (status, body) = server.post_json("/api/pairing").await;
+    assert_eq!(status, 200, "{}", body);
+    wait_for_registration(&server).await;
+    let (_, body) = server.get_json("/api/devices").await;
+    assert_eq!(body["data"]["devices"][0]["paired"], true);
+
+    // A spoofer knowing the uid but not its pwd
+    let _spoofer = server.spawn_camera(UID, &["--registration-token", "deadbeef"]);
+    eventually("a registration refused for its token", TIMEOUT, || rejected("bad_token")).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_management_needs_credentials() {
+    use a9_v720_server::web::auth::hash_password;
+    let server = TestServer::start_with(|config| {
+        config.users.insert("admin".to_string(), hash_password("hunter2").unwrap());
+        config.api_keys.insert("ha".to_string(), hash_password("s3cret").unwrap());
+    })
+    .await;
+
+    assert_eq!(server.get("/api/cameras").await.0, 401);
+    assert_eq!(server.get("/metrics").await.0, 401);
+    let (status, head, _) = server.exchange("GET", "/dashboard", &[], None).await;
+    assert_eq!(status, 303);
+    assert!(head.contains("location: /login"), "{}", head);
+    // The cameras know no credentials
+    let (status, _) = server.cloud_post_json(&format!("/app/api/ApiServer/getA9ConfCheck?devicesCode={}&random=ABCDEF&token=t", UID)).await;
+    assert_eq!(status, 200);
+
+    let bearer = [("Authorization", "Bearer ha.s3cret")];
+    assert_eq!(server.exchange("GET", "/api/cameras", &bearer, None).await.0, 200);
+    assert_eq!(server.exchange("GET", "/api/cameras", &[("Authorization", "Bearer ha.guess")], None).await.0, 401);
+
+    let wrong = serde_json::json!({"username": "admin", "password": "hunter3"});
+    assert_eq!(server.exchange("POST", "/api/login", &[], Some(&wrong)).await.0, 401);
+    let login = serde_json::json!({"username": "admin", "password": "hunter2"});
+    let (status, head, _) = server.exchange("POST", "/api/login", &[], Some(&login)).await;
+    assert_eq!(status, 200);
+    let cookie = head
+        .lines()
+        .find_map(|line| line.strip_prefix("set-cookie: "))
+        .and_then(|cookie| cookie.split(';').next())
+        .expect("session cookie")
+        .to_string();
+    let session = [("Cookie", cookie.as_str())];
+    assert_eq!(server.exchange("GET", "/dashboard", &session, None).await.0, 200);
+
+    assert_eq!(server.exchange("POST", "/api/logout", &session, None).await.0, 200);
+    assert_eq!(server.exchange("GET", "/api/cameras", &session, None).await.0, 401);
+
+    // Guessing keys locks the address out like failed logins do
+    for _ in 0..5 {
+        assert_eq!(server.exchange("GET", "/api/cameras", &[("Authorization", "Bearer ha.guess")], None).await.0, 401);
+    }
+    assert_eq!(server.exchange("GET", "/api/cameras", &bearer, None).await.0, 429);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_settings_are_acknowledged_and_cached() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20"]);
+    start_streaming(&server).await;
+
+    let path = format!("/api/cameras/{}/settings", UID);
+    let info = eventually("cached device info", TIMEOUT, || async {
+        let (_, body) = server.get_json(&path).await;
+        body["data"]["device_info"].is_object().then(|| body["data"]["device_info"].clone())
+    })
+    .await;
+    assert_eq!(info["ir_led"], 0);
+
+    let (status, body) = server.put_json(&path, serde_json::json!({"ir_led": 1, "mirror_flip": 3})).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["applied"], serde_json::json!({"ir_led": 1, "mirror_flip": 3}));
+    assert_eq!(body["data"]["device_info"]["ir_led"], 1);
+    assert_eq!(body["data"]["device_info"]["mirror_flip"], 3);
+    assert_eq!(body["data"]["device_info"]["inst_led"], info["inst_led"]);
+
+    let (status, _) = server.put_json(&path, serde_json::json!({})).await;
+    assert_eq!(status, 400);
+    let (status, _) = server.put_json("/api/cameras/nosuchcamera/settings", serde_json::json!({"ir_led": 1})).await;
+    assert_eq!(status, 404);
+
+    let registry = a9_v720_server::registry::DeviceRegistry::load(&server.config.registry_path).unwrap();
+    assert_eq!(registry.get(UID).unwrap().device_info.as_ref().unwrap().mirror_flip, 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_unanswered_command_times_out() {
+    let server = TestServer::start_with(|config| {
+        config.retry_timeout_ms = 200;
+        config.max_retries = 1;
+    })
+    .await;
+    let _camera = server.spawn_camera(UID, &["--ignore-command", "5"]);
+    wait_for_registration(&server).await;
+
+    let (status, body) = server.post_json(&format!("/api/cameras/{}/snapshot", UID)).await;
+    assert_eq!(status, 504, "{}", body);
+    assert_eq!(body["message"], "no reply from the camera after 2 attempts");
+
+    // Commands the camera does answer still get through
+    let (status, body) = server.get_json(&format!("/api/cameras/{}/streaming/stop", UID)).await;
+    assert_eq!(status, 200, "{}", body);
+    assert_eq!(body["data"]["reply"]["content"]["code"], 0);
+
+    let (status, _) = server.post_json("/api/cameras/nosuchcamera/snapshot").await;
+    assert_eq!(status, 404);
+}
+
+/// Target states of the camera's recorded transitions, oldest first
+async fn state_history(server: &TestServer) -> Vec<(String, String)> {
+    let (_, body) = server.get_json(&format!("/api/cameras/{}/state", UID)).await;
+    body["data"]["history"]
+        .as_array()
+        .map(|history| {
+            history
+                .iter()
+                .map(|t| (t["to"].as_str().unwrap().to_string(), t["reason"].as_str().unwrap().to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_lossy_link_serves_only_whole_frames() {
+    let server = TestServer::start_with(|config| config.request_retransmissions = true).await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20", "--loss", "0.05", "--reorder", "0.2", "--seed", "7"]);
+    start_streaming(&server).await;
+
+    let stats = eventually("frames reassembled across loss and reordering", TIMEOUT, || async {
+        let (_, info) = server.get_json(&format!("/api/cameras/{}", UID)).await;
+        let stats = info["data"]["stream_buffer"]["reassembly"].clone();
+        (stats["frames_completed"].as_u64()? >= 20).then_some(stats)
+    })
+    .await;
+    assert_eq!(stats["frames_corrupt"], 0, "{}", stats);
+    // Lost fragments are asked for again and mostly arrive
+    assert!(stats["retransmits_recovered"].as_u64().unwrap() > 0, "{}", stats);
+
+    let (status, frame) = server.get(&format!("/api/cameras/{}/stream", UID)).await;
+    assert_eq!(status, 200);
+    assert!(is_jpeg(&frame));
+
+    let (status, body) = server.get_json(&format!("/api/cameras/{}/stats", UID)).await;
+    assert_eq!(status, 200);
+    let stats = &body["data"]["stats"];
+    assert!(stats["fps"].as_f64().unwrap() > 0.0, "{}", stats);
+    assert!(stats["fragments_per_frame"].as_f64().unwrap() >= 1.0, "{}", stats);
+    assert!(stats["confirmations_sent"].as_u64().unwrap() > 0, "{}", stats);
+    assert!(stats["requests_sent"].as_u64().unwrap() > 0, "{}", stats);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_metrics_cover_cameras_and_traffic() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20"]);
+    start_streaming(&server).await;
+
+    let (status, body) = server.get("/metrics").await;
+    assert_eq!(status, 200);
+    let metrics = String::from_utf8(body).unwrap();
+    let value = |series: &str| -> f64 {
+        let line = metrics
+            .lines()
+            .find(|line| line.starts_with(series))
+            .unwrap_or_else(|| panic!("no {} in\n{}", series, metrics));
+        line.rsplit(' ').next().unwrap().parse().unwrap()
+    };
+
+    assert_eq!(value(r#"a9_cameras{state="Streaming"}"#), 1.0);
+    assert_eq!(value(r#"a9_cameras{state="Idle"}"#), 0.0);
+    assert_eq!(value("a9_tcp_connections"), 1.0);
+    assert!(value(&format!(r#"a9_frames_assembled_total{{device_id="{}"}}"#, UID)) >= 1.0);
+    // Video arrives on the random port negotiated by the probes
+    assert!(value(r#"a9_udp_packets_total{port="random"}"#) > 0.0);
+    assert_eq!(value(r#"a9_json_messages_total{transport="tcp",code="100"}"#), 1.0);
+    assert!(metrics.contains(r#"a9_http_request_duration_seconds_count{method="GET",path="/api/cameras/:device_id/stream",status="200"}"#));
+    assert!(metrics.ends_with("# EOF\n"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_stream_resumes_after_reconnect() {
+    let server = TestServer::start().await;
+    let _camera = server.spawn_camera(UID, &[
+        "--fps", "20", "--disconnect-after", "3", "--reconnect", "--reconnect-delay-ms", "200",
+    ]);
+    start_streaming(&server).await;
+
+    // Nobody asks for the stream again: re-registration replays the start sequence
+    eventually("streaming after a reconnect", TIMEOUT, || async {
+        let history = state_history(&server).await;
+        let dropped = history.iter().position(|(to, _)| to == "Disconnected")?;
+        history[dropped..].iter().any(|(to, _)| to == "Streaming").then_some(())
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_watchdog_drops_and_evicts_hung_camera() {
+    let server = TestServer::start_with(|config| {
+        config.health_check_interval_ms = 250;
+        config.max_retries = 4;
+        config.eviction_grace_ms = 1000;
+    })
+    .await;
+    let _camera = server.spawn_camera(UID, &["--fps", "20", "--keepalive-ms", "200", "--hang-after", "3"]);
+    start_streaming(&server).await;
+
+    eventually("the watchdog dropping the camera", TIMEOUT, || async {
+        let history = state_history(&server).await;
+        history
+            .iter()
+            .any(|(to, reason)| to == "Disconnected" && reason.starts_with("no control traffic"))
+            .then_some(())
+    })
+    .await;
+    // The hung camera never closes its end, so only the watchdog ends the connection's reader
+    eventually("the control connection closing", TIMEOUT, || async {
+        let (_, body) = server.get("/metrics").await;
+        String::from_utf8(body).unwrap().lines().any(|line| line == "a9_tcp_connections 0").then_some(())
+    })
+    .await;
+
+    eventually("eviction", TIMEOUT, || async {
+        let (_, body) = server.get_json("/api/cameras").await;
+        (body["data"]["count"] == 0).then_some(())
+    })
+    .await;
+    let (_, body) = server.get_json("/api/devices").await;
+    assert_eq!(body["data"]["devices"][0]["device_id"], UID);
+    assert_eq!(body["data"]["devices"][0]["online"], false);
+}